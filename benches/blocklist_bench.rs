@@ -1,15 +1,24 @@
-//! Benchmarks for blocklist domain lookup.
+//! Benchmarks for blocklist domain lookup and loading.
 //!
-//! Measures how quickly we can check if a domain is blocked.
+//! Measures how quickly we can check if a domain is blocked, with and without
+//! regex rules loaded and with the domains in compact storage, and how long a
+//! large hosts-format list takes to load and compact.
 
-use criterion::{black_box, BenchmarkId, Criterion, Throughput};
+use criterion::{BenchmarkId, Criterion, Throughput, black_box};
 
 use detour::filter::Blocklist;
 
 fn bench_is_blocked(c: &mut Criterion) {
-    let blocklist = Blocklist::new();
+    bench_lookups(c, "blocklist", &Blocklist::new());
+}
+
+fn bench_is_blocked_compact(c: &mut Criterion) {
+    let blocklist = Blocklist::new().with_compact_storage(true);
+    bench_lookups(c, "blocklist_compact", &blocklist);
+}
 
-    let mut group = c.benchmark_group("blocklist");
+fn bench_lookups(c: &mut Criterion, name: &str, blocklist: &Blocklist) {
+    let mut group = c.benchmark_group(name);
 
     // Benchmark exact match (blocked domain)
     group.throughput(Throughput::Elements(1));
@@ -35,8 +44,75 @@ fn bench_is_blocked(c: &mut Criterion) {
     group.finish();
 }
 
+/// Regex rules loaded for the regex benchmarks.
+const REGEX_RULES: usize = 100;
+
+fn bench_is_blocked_with_regexes(c: &mut Criterion) {
+    let rules: String = (0..REGEX_RULES)
+        .map(|i| format!("^[a-z0-9]+\\.metrics{}\\.vendor\\.com$\n", i))
+        .collect();
+    let blocklist = Blocklist::new().with_regex_rules(&rules).unwrap();
+
+    let mut group = c.benchmark_group("blocklist_regex");
+    group.throughput(Throughput::Elements(1));
+
+    // A hash lookup hit never reaches the regexes
+    group.bench_function(BenchmarkId::new("is_blocked", "exact_match"), |b| {
+        b.iter(|| blocklist.is_blocked(black_box("doubleclick.com")))
+    });
+
+    // Every lookup misses, then the regex set is tried
+    group.bench_function(BenchmarkId::new("is_blocked", "miss"), |b| {
+        b.iter(|| blocklist.is_blocked(black_box("www.google.com")))
+    });
+
+    group.bench_function(BenchmarkId::new("is_blocked", "regex_match"), |b| {
+        b.iter(|| blocklist.is_blocked(black_box("a1b2c3.metrics42.vendor.com")))
+    });
+
+    group.finish();
+}
+
+/// Lines in the generated hosts file, about the size of the popular lists.
+const HOSTS_FILE_LINES: usize = 300_000;
+
+fn bench_load_hosts_file(c: &mut Criterion) {
+    let path = std::env::temp_dir().join(format!("detour-bench-hosts-{}.txt", std::process::id()));
+    let mut content =
+        String::from("# generated\n127.0.0.1 localhost\n255.255.255.255 broadcasthost\n");
+    for i in 0..HOSTS_FILE_LINES {
+        match i % 3 {
+            0 => content.push_str(&format!("0.0.0.0 ads{}.example.com\n", i)),
+            1 => content.push_str(&format!("127.0.0.1 tracker{}.example.net # inline\n", i)),
+            _ => content.push_str(&format!("plain{}.example.org\n", i)),
+        }
+    }
+    std::fs::write(&path, content).unwrap();
+    let path_str = path.to_str().unwrap();
+
+    let mut group = c.benchmark_group("blocklist_load");
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(HOSTS_FILE_LINES as u64));
+    group.bench_function(BenchmarkId::new("from_hosts_file", HOSTS_FILE_LINES), |b| {
+        b.iter(|| Blocklist::from_hosts_file(black_box(path_str)).unwrap())
+    });
+    group.bench_function(BenchmarkId::new("compact", HOSTS_FILE_LINES), |b| {
+        b.iter(|| {
+            Blocklist::from_hosts_file(black_box(path_str))
+                .unwrap()
+                .with_compact_storage(true)
+        })
+    });
+    group.finish();
+
+    std::fs::remove_file(&path).unwrap();
+}
+
 fn main() {
     let mut criterion = Criterion::default().configure_from_args();
     bench_is_blocked(&mut criterion);
+    bench_is_blocked_compact(&mut criterion);
+    bench_is_blocked_with_regexes(&mut criterion);
+    bench_load_hosts_file(&mut criterion);
     criterion.final_summary();
 }
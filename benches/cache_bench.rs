@@ -0,0 +1,77 @@
+//! Benchmarks for concurrent cache access.
+//!
+//! Measures cache throughput with 8 threads doing mostly lookups (with some
+//! inserts), comparing a single shard against the default shard count.
+
+use criterion::{BenchmarkId, Criterion, Throughput};
+use std::hint::black_box;
+use std::sync::Barrier;
+use std::time::{Duration, Instant};
+
+use detour::cache::{DEFAULT_SHARDS, DnsCache};
+use detour::dns::{DnsQuery, TYPE_A};
+use detour::filter::BlockedResponseStyle;
+
+const THREADS: usize = 8;
+const DOMAINS: usize = 4096;
+
+fn bench_concurrent_access(c: &mut Criterion) {
+    let queries: Vec<_> = (0..DOMAINS)
+        .map(|i| DnsQuery::new(1, &format!("host{}.example.com", i), TYPE_A))
+        .collect();
+    let responses: Vec<_> = queries
+        .iter()
+        .map(|q| q.blocked_response(BlockedResponseStyle::NullIp).to_bytes())
+        .collect();
+
+    let mut group = c.benchmark_group("cache");
+    group.throughput(Throughput::Elements(THREADS as u64));
+
+    for shards in [1, DEFAULT_SHARDS] {
+        let cache = DnsCache::new().with_shards(shards);
+        for (query, response) in queries.iter().zip(&responses) {
+            cache.put(query, response);
+        }
+
+        group.bench_function(BenchmarkId::new("8_threads", shards), |b| {
+            b.iter_custom(|iters| {
+                let barrier = Barrier::new(THREADS);
+                let mut elapsed = Duration::ZERO;
+                std::thread::scope(|scope| {
+                    let handles: Vec<_> = (0..THREADS)
+                        .map(|t| {
+                            let (cache, queries, responses, barrier) =
+                                (&cache, &queries, &responses, &barrier);
+                            scope.spawn(move || {
+                                barrier.wait();
+                                let start = Instant::now();
+                                for i in 0..iters as usize {
+                                    let n = (i * 7919 + t * 104_729) % DOMAINS;
+                                    // One insert for every 16 lookups
+                                    if i % 16 == 0 {
+                                        cache.put(&queries[n], &responses[n]);
+                                    } else {
+                                        black_box(cache.get(&queries[n]));
+                                    }
+                                }
+                                start.elapsed()
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        elapsed = elapsed.max(handle.join().unwrap());
+                    }
+                });
+                elapsed
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn main() {
+    let mut criterion = Criterion::default().configure_from_args();
+    bench_concurrent_access(&mut criterion);
+    criterion.final_summary();
+}
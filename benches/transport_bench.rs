@@ -4,25 +4,39 @@
 //! Uses realistic latency based on public DNS benchmarks:
 //! - Cloudflare (1.1.1.1): ~5-18ms average
 //! - Google (8.8.8.8): ~7-24ms average
+//!
 //! We simulate ~15ms average with ±5ms jitter.
 //!
 //! Also includes zero-latency benchmarks to measure pure proxy overhead.
+//!
+//! Set `DETOUR_BENCH_DOT=1` to also benchmark TCP forwarding to a
+//! DNS-over-TLS upstream (zero latency, one TLS handshake per query).
+//!
+//! The `udp_concurrent` group sends bursts of queries from many clients at
+//! once to a single UDP socket and to four `SO_REUSEPORT` sockets, showing
+//! how much `--udp-sockets` gains on a multi-core machine.
 
 use criterion::{BenchmarkId, Criterion, Throughput};
 use rand::Rng;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc;
-use std::sync::Arc;
 use std::time::Duration;
 
 static QUERY_COUNTER: AtomicU64 = AtomicU64::new(0);
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream, UdpSocket};
 use tokio::runtime::Runtime;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::RootCertStore;
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
 
 use detour::filter::Blocklist;
 use detour::resolver::Resolver;
+use detour::transport::dot;
 use detour::transport::tcp::TcpTransport;
 use detour::transport::udp::UdpTransport;
 
@@ -40,6 +54,28 @@ const UDP_PROXY_ADDR_ZERO: &str = "127.0.0.1:15361";
 const TCP_UPSTREAM_ADDR_ZERO: &str = "127.0.0.1:15362";
 const UDP_UPSTREAM_ADDR_ZERO: &str = "127.0.0.1:15363";
 
+// Ports for the DNS-over-TLS upstream benchmark
+const DOT_PROXY_ADDR_ZERO: &str = "127.0.0.1:15364";
+const DOT_UPSTREAM_ADDR_ZERO: &str = "127.0.0.1:15365";
+
+// Ports for the concurrent UDP benchmark
+const UDP_PROXY_ADDR_SINGLE: &str = "127.0.0.1:15366";
+const UDP_PROXY_ADDR_REUSEPORT: &str = "127.0.0.1:15367";
+const UDP_UPSTREAM_ADDR_CONCURRENT: &str = "127.0.0.1:15368";
+
+/// Queries sent at once, each from its own client, per concurrent iteration
+const CONCURRENT_QUERIES: usize = 64;
+
+/// Self-signed certificate for `localhost`, shared with the unit tests
+const TLS_CERT: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/transport/testdata/cert.pem"
+);
+const TLS_KEY: &str = concat!(
+    env!("CARGO_MANIFEST_DIR"),
+    "/src/transport/testdata/key.pem"
+);
+
 /// Simulated upstream latency (based on real-world DNS benchmarks)
 const BASE_LATENCY_MS: u64 = 15;
 const JITTER_MS: u64 = 5;
@@ -47,7 +83,7 @@ const JITTER_MS: u64 = 5;
 fn build_dns_query() -> Vec<u8> {
     let n = QUERY_COUNTER.fetch_add(1, Ordering::Relaxed);
     let subdomain = format!("q{}", n);
-    
+
     let mut query = Vec::new();
     query.extend_from_slice(&[0x12, 0x34]); // Query ID
     query.extend_from_slice(&[0x01, 0x00]); // Flags: standard query
@@ -128,20 +164,41 @@ async fn mock_tcp_upstream(listener: TcpListener, with_latency: bool) {
     }
 }
 
-/// Mock UDP upstream with simulated latency
+/// Mock UDP upstream with simulated latency, answering with the query's ID
 async fn mock_udp_upstream(socket: UdpSocket, with_latency: bool) {
-    let response = build_dns_response();
+    let mut response = build_dns_response();
     let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
     loop {
         if let Ok((_, src)) = socket.recv_from(&mut buf).await {
             if with_latency {
                 simulate_upstream_latency().await;
             }
+            response[..2].copy_from_slice(&buf[..2]);
             let _ = socket.send_to(&response, src).await;
         }
     }
 }
 
+/// Mock DNS-over-TLS upstream without latency
+async fn mock_dot_upstream(listener: TcpListener, acceptor: TlsAcceptor) {
+    let response = build_tcp_dns_response();
+    loop {
+        if let Ok((stream, _)) = listener.accept().await {
+            let (acceptor, response) = (acceptor.clone(), response.clone());
+            tokio::spawn(async move {
+                let Ok(mut stream) = acceptor.accept(stream).await else {
+                    return;
+                };
+                let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+                if stream.read(&mut buf).await.is_ok() {
+                    let _ = stream.write_all(&response).await;
+                    let _ = stream.shutdown().await;
+                }
+            });
+        }
+    }
+}
+
 fn start_tcp_mock_upstream(addr: &str, with_latency: bool) {
     let upstream_addr: SocketAddr = addr.parse().unwrap();
     let (tx, rx) = mpsc::channel();
@@ -158,6 +215,29 @@ fn start_tcp_mock_upstream(addr: &str, with_latency: bool) {
     rx.recv().expect("Failed to start TCP mock upstream");
 }
 
+fn start_dot_mock_upstream(addr: &str) {
+    let upstream_addr: SocketAddr = addr.parse().unwrap();
+    let tls = dot::load_tls_config(Path::new(TLS_CERT), Path::new(TLS_KEY)).unwrap();
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::spawn(move || {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = TcpListener::bind(upstream_addr).await.unwrap();
+            tx.send(()).unwrap(); // Signal ready
+            mock_dot_upstream(listener, TlsAcceptor::from(tls)).await;
+        });
+    });
+
+    rx.recv().expect("Failed to start DoT mock upstream");
+
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_file_iter(TLS_CERT).unwrap() {
+        roots.add(cert.unwrap()).unwrap();
+    }
+    dot::register_upstream_with_roots(upstream_addr, "localhost", roots).unwrap();
+}
+
 fn start_udp_mock_upstream(addr: &str, with_latency: bool) {
     let upstream_addr: SocketAddr = addr.parse().unwrap();
     let (tx, rx) = mpsc::channel();
@@ -197,7 +277,7 @@ fn start_tcp_proxy(proxy_addr: &str, upstream_addr: &str) {
     rx.recv().expect("Failed to start TCP proxy");
 }
 
-fn start_udp_proxy(proxy_addr: &str, upstream_addr: &str) {
+fn start_udp_proxy(proxy_addr: &str, upstream_addr: &str, sockets: usize) {
     let proxy_addr: SocketAddr = proxy_addr.parse().unwrap();
     let upstream_addr: SocketAddr = upstream_addr.parse().unwrap();
     let (tx, rx) = mpsc::channel();
@@ -206,7 +286,13 @@ fn start_udp_proxy(proxy_addr: &str, upstream_addr: &str) {
         let rt = Runtime::new().unwrap();
 
         rt.block_on(async {
-            let transport = UdpTransport::bind(proxy_addr, 1).await.unwrap();
+            let transport = if sockets > 1 {
+                UdpTransport::bind_reuseport(proxy_addr, sockets, 1)
+                    .await
+                    .unwrap()
+            } else {
+                UdpTransport::bind(proxy_addr, 1).await.unwrap()
+            };
             let resolver = Arc::new(Resolver::new(Blocklist::new()));
             transport.start(vec![upstream_addr], resolver, false);
             tx.send(()).unwrap(); // Signal ready
@@ -293,7 +379,7 @@ fn bench_tcp_realistic(c: &mut Criterion) {
 
 fn bench_udp_realistic(c: &mut Criterion) {
     start_udp_mock_upstream(UDP_UPSTREAM_ADDR, true);
-    start_udp_proxy(UDP_PROXY_ADDR, UDP_UPSTREAM_ADDR);
+    start_udp_proxy(UDP_PROXY_ADDR, UDP_UPSTREAM_ADDR, 1);
 
     let rt = Runtime::new().unwrap();
     let proxy_addr: SocketAddr = UDP_PROXY_ADDR.parse().unwrap();
@@ -411,7 +497,7 @@ fn bench_tcp_zero_latency(c: &mut Criterion) {
 
 fn bench_udp_zero_latency(c: &mut Criterion) {
     start_udp_mock_upstream(UDP_UPSTREAM_ADDR_ZERO, false);
-    start_udp_proxy(UDP_PROXY_ADDR_ZERO, UDP_UPSTREAM_ADDR_ZERO);
+    start_udp_proxy(UDP_PROXY_ADDR_ZERO, UDP_UPSTREAM_ADDR_ZERO, 1);
 
     let rt = Runtime::new().unwrap();
     let proxy_addr: SocketAddr = UDP_PROXY_ADDR_ZERO.parse().unwrap();
@@ -456,6 +542,96 @@ fn bench_udp_zero_latency(c: &mut Criterion) {
     group.finish();
 }
 
+// ============================================================================
+// Concurrent UDP clients, one socket vs SO_REUSEPORT sockets
+// ============================================================================
+
+/// Send one query from a fresh client and wait for the answer.
+async fn udp_round_trip(proxy_addr: SocketAddr) -> usize {
+    let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+    client
+        .send_to(&build_dns_query(), proxy_addr)
+        .await
+        .unwrap();
+
+    let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+    tokio::time::timeout(Duration::from_secs(5), client.recv_from(&mut buf))
+        .await
+        .unwrap()
+        .unwrap()
+        .0
+}
+
+fn bench_udp_concurrent(c: &mut Criterion) {
+    start_udp_mock_upstream(UDP_UPSTREAM_ADDR_CONCURRENT, false);
+    start_udp_proxy(UDP_PROXY_ADDR_SINGLE, UDP_UPSTREAM_ADDR_CONCURRENT, 1);
+    start_udp_proxy(UDP_PROXY_ADDR_REUSEPORT, UDP_UPSTREAM_ADDR_CONCURRENT, 4);
+
+    let rt = Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("udp_concurrent");
+    group.throughput(Throughput::Elements(CONCURRENT_QUERIES as u64));
+
+    for (sockets, addr) in [(1, UDP_PROXY_ADDR_SINGLE), (4, UDP_PROXY_ADDR_REUSEPORT)] {
+        let proxy_addr: SocketAddr = addr.parse().unwrap();
+        group.bench_function(BenchmarkId::new("sockets", sockets), |b| {
+            b.to_async(&rt).iter(|| async {
+                let clients =
+                    (0..CONCURRENT_QUERIES).map(|_| tokio::spawn(udp_round_trip(proxy_addr)));
+                let mut total = 0;
+                for client in clients.collect::<Vec<_>>() {
+                    total += client.await.unwrap();
+                }
+                total
+            });
+        });
+    }
+
+    group.finish();
+}
+
+// ============================================================================
+// DNS-over-TLS upstream benchmark (set DETOUR_BENCH_DOT=1)
+// ============================================================================
+
+fn bench_tcp_dot_zero_latency(c: &mut Criterion) {
+    start_dot_mock_upstream(DOT_UPSTREAM_ADDR_ZERO);
+    start_tcp_proxy(DOT_PROXY_ADDR_ZERO, DOT_UPSTREAM_ADDR_ZERO);
+
+    let rt = Runtime::new().unwrap();
+    let proxy_addr: SocketAddr = DOT_PROXY_ADDR_ZERO.parse().unwrap();
+
+    let mut group = c.benchmark_group("tcp_dot_zero_latency");
+    group.throughput(Throughput::Elements(1));
+
+    group.bench_function(BenchmarkId::new("request", "latency"), |b| {
+        b.to_async(&rt).iter(|| async {
+            let mut client = TcpStream::connect(proxy_addr).await.unwrap();
+            let query = build_tcp_dns_query();
+            client.write_all(&query).await.unwrap();
+
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let mut total = 0;
+            loop {
+                let n = client.read(&mut buf[total..]).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                total += n;
+                if total >= 2 {
+                    let msg_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
+                    if total >= 2 + msg_len {
+                        break;
+                    }
+                }
+            }
+            total
+        });
+    });
+
+    group.finish();
+}
+
 fn main() {
     let mut criterion = Criterion::default().configure_from_args();
 
@@ -463,6 +639,10 @@ fn main() {
     bench_udp_realistic(&mut criterion);
     bench_tcp_zero_latency(&mut criterion);
     bench_udp_zero_latency(&mut criterion);
+    bench_udp_concurrent(&mut criterion);
+    if std::env::var_os("DETOUR_BENCH_DOT").is_some() {
+        bench_tcp_dot_zero_latency(&mut criterion);
+    }
 
     criterion.final_summary();
     std::process::exit(0);
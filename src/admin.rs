@@ -0,0 +1,218 @@
+//! HTTP admin API.
+//!
+//! Serves JSON over plain HTTP/1.1, one request per connection:
+//!
+//! - `GET /cache` lists the cached responses with the TTL they have left
+//! - `DELETE /cache/<domain>/<qtype>` evicts one response
+//! - `DELETE /cache` evicts every response
+//! - `GET /stats` returns the running totals
+//!
+//! There is no authentication, so the listener belongs on a loopback or
+//! otherwise trusted address.
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cache::{CacheEntryInfo, CacheStats};
+use crate::dns::parse_qtype;
+use crate::logging::{self, json_escape};
+use crate::resolver::Resolver;
+use crate::stats::Totals;
+use crate::stats::prometheus::{REQUEST_TIMEOUT, read_head};
+
+/// HTTP server for inspecting and flushing the cache.
+pub struct AdminServer {
+    listener: TcpListener,
+}
+
+impl AdminServer {
+    /// Bind the admin listener.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving admin requests.
+    pub fn start(self, resolver: Arc<Resolver>) {
+        tokio::spawn(async move {
+            loop {
+                match self.listener.accept().await {
+                    Ok((stream, _)) => {
+                        let resolver = resolver.clone();
+                        tokio::spawn(async move {
+                            let _ = serve(stream, &resolver).await;
+                        });
+                    }
+                    Err(e) => logging::error(format_args!("admin accept error: {}", e)),
+                }
+            }
+        });
+    }
+}
+
+async fn serve(stream: TcpStream, resolver: &Resolver) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some(method), Some(path)) => route(method, path, resolver),
+        _ => ("400 Bad Request", error("malformed request line")),
+    };
+    let message = format!(
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    let stream = stream.get_mut();
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Answer one request, returning the status line and JSON body.
+fn route(method: &str, path: &str, resolver: &Resolver) -> (&'static str, String) {
+    let path = path.trim_end_matches('/');
+    match (method, path) {
+        ("GET", "/cache") => ("200 OK", render_entries(&resolver.cache_entries())),
+        ("DELETE", "/cache") => {
+            let removed = resolver.clear_cache();
+            logging::info(format_args!(
+                "Cache flushed over the admin API ({} entries dropped)",
+                removed
+            ));
+            ("200 OK", format!("{{\"removed\":{}}}", removed))
+        }
+        ("GET", "/stats") => (
+            "200 OK",
+            render_stats(&resolver.stats().totals(), resolver.cache_stats()),
+        ),
+        ("DELETE", path) if path.starts_with("/cache/") => {
+            let Some((domain, qtype)) = path["/cache/".len()..].rsplit_once('/') else {
+                return ("400 Bad Request", error("expected /cache/<domain>/<qtype>"));
+            };
+            let Some(qtype) = parse_qtype(qtype) else {
+                return ("400 Bad Request", error("unknown query type"));
+            };
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            if resolver.remove_cached(&domain, qtype) {
+                ("200 OK", "{\"removed\":1}".to_string())
+            } else {
+                ("404 Not Found", error("not cached"))
+            }
+        }
+        ("GET" | "DELETE", _) => ("404 Not Found", error("not found")),
+        _ => ("405 Method Not Allowed", error("method not allowed")),
+    }
+}
+
+fn error(message: &str) -> String {
+    format!("{{\"error\":\"{}\"}}", json_escape(message))
+}
+
+/// Format cache entries as `{"entries":[...]}`, TTLs in whole seconds.
+fn render_entries(entries: &[CacheEntryInfo]) -> String {
+    let mut out = String::from("{\"entries\":[");
+    for (i, entry) in entries.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        let _ = write!(
+            out,
+            "{{\"domain\":\"{}\",\"qtype\":{},\"ttl\":{},\"negative\":{},\"pinned\":{}}}",
+            json_escape(&entry.domain),
+            entry.qtype,
+            entry.ttl.as_secs(),
+            entry.negative,
+            entry.pinned
+        );
+    }
+    out.push_str("]}");
+    out
+}
+
+/// Format the running totals and cache size as a JSON object.
+fn render_stats(totals: &Totals, cache: CacheStats) -> String {
+    let avg_response_ms = if totals.requests > 0 {
+        totals.response_time_us as f64 / totals.requests as f64 / 1000.0
+    } else {
+        0.0
+    };
+    format!(
+        "{{\"requests\":{},\"forwarded\":{},\"cached\":{},\"blocked\":{},\"local\":{},\
+         \"avg_response_ms\":{:.3},\"cache_entries\":{},\"cache_evictions\":{}}}",
+        totals.requests,
+        totals.forwarded,
+        totals.cached,
+        totals.blocked,
+        totals.local,
+        avg_response_ms,
+        cache.entries,
+        cache.evictions
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::DnsCache;
+    use crate::dns::{DnsQuery, DnsResponse, TYPE_A, TYPE_AAAA};
+    use crate::filter::{BlockedResponseStyle, Blocklist};
+    use tokio::io::AsyncReadExt;
+
+    async fn request(addr: SocketAddr, head: &str) -> String {
+        let mut response = String::new();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(head.as_bytes()).await.unwrap();
+        client.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[tokio::test]
+    async fn lists_evicts_and_flushes_the_cache_over_http() {
+        let server = AdminServer::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let cache = DnsCache::new();
+        for (domain, qtype) in [("a.example", TYPE_A), ("b.example", TYPE_AAAA)] {
+            let query = DnsQuery::new(1, domain, qtype);
+            let response = DnsResponse::blocked(&query, BlockedResponseStyle::NullIp, 3600);
+            cache.put(&query, &response.to_bytes());
+        }
+        let resolver = Arc::new(Resolver::new(Blocklist::new()).with_cache(cache));
+        resolver.record_blocked(0.2);
+        server.start(resolver.clone());
+
+        let listed = request(addr, "GET /cache HTTP/1.1\r\n\r\n").await;
+        let evicted = request(addr, "DELETE /cache/A.example./A HTTP/1.1\r\n\r\n").await;
+        let missing = request(addr, "DELETE /cache/a.example/1 HTTP/1.1\r\n\r\n").await;
+        let flushed = request(addr, "DELETE /cache HTTP/1.1\r\n\r\n").await;
+        let stats = request(addr, "GET /stats HTTP/1.1\r\n\r\n").await;
+        let refused = request(addr, "POST /cache HTTP/1.1\r\n\r\n").await;
+
+        assert!(listed.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(listed.contains("Content-Type: application/json\r\n"));
+        assert!(listed.contains("{\"domain\":\"a.example\",\"qtype\":1,\"ttl\":"));
+        assert!(listed.contains("{\"domain\":\"b.example\",\"qtype\":28,\"ttl\":"));
+        assert!(evicted.ends_with("{\"removed\":1}"));
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+        assert!(flushed.ends_with("{\"removed\":1}"));
+        assert_eq!(resolver.cache_len(), 0);
+        assert!(stats.contains("\"requests\":1,"));
+        assert!(stats.contains("\"blocked\":1,"));
+        assert!(refused.starts_with("HTTP/1.1 405 Method Not Allowed\r\n"));
+    }
+}
@@ -0,0 +1,364 @@
+//! Upstream benchmarking (`detour bench-upstreams`, or `detour bench`).
+//!
+//! Sends timed queries to each upstream over UDP and TCP, or over TLS, HTTPS
+//! or QUIC for upstreams registered as such, and reports latency, failure
+//! rate and whether the upstream appears to filter answers. Latency
+//! samples use unique random subdomains so every query misses the upstream's
+//! cache; the filtering check queries the known-good names themselves.
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime};
+
+use tokio::net::UdpSocket;
+
+use crate::dns::{DnsQuery, DnsResponse, RData, TYPE_A};
+use crate::transport::MAX_DNS_PACKET_SIZE;
+#[cfg(feature = "doh-upstream")]
+use crate::transport::doh;
+#[cfg(feature = "doq")]
+use crate::transport::doq;
+use crate::transport::dot;
+use crate::transport::tcp::forward_to_upstream;
+
+/// Names used when no domain file is given; all are expected to resolve.
+const DEFAULT_DOMAINS: &[&str] = &[
+    "example.com",
+    "google.com",
+    "cloudflare.com",
+    "wikipedia.org",
+    "github.com",
+];
+
+/// Per-query timeout.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// An upstream whose first this-many queries all fail is marked dead and skipped.
+const DEAD_AFTER: usize = 3;
+
+/// Configuration for `detour bench-upstreams`.
+pub struct BenchConfig {
+    /// Upstreams to compare
+    pub upstreams: Vec<SocketAddr>,
+    /// Timed queries per upstream and protocol
+    pub samples: usize,
+    /// Known-good domains (empty = built-in list)
+    pub domains: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BenchProtocol {
+    Udp,
+    Tcp,
+    Tls,
+    #[cfg(feature = "doh-upstream")]
+    Https,
+    #[cfg(feature = "doq")]
+    Quic,
+}
+
+impl BenchProtocol {
+    fn as_str(self) -> &'static str {
+        match self {
+            BenchProtocol::Udp => "UDP",
+            BenchProtocol::Tcp => "TCP",
+            BenchProtocol::Tls => "TLS",
+            #[cfg(feature = "doh-upstream")]
+            BenchProtocol::Https => "HTTPS",
+            #[cfg(feature = "doq")]
+            BenchProtocol::Quic => "QUIC",
+        }
+    }
+
+    /// The protocols `upstream` is benchmarked over: its own if it was
+    /// registered as an encrypted upstream, otherwise UDP and TCP.
+    fn for_upstream(upstream: SocketAddr) -> &'static [BenchProtocol] {
+        #[cfg(feature = "doq")]
+        if doq::is_registered(upstream) {
+            return &[BenchProtocol::Quic];
+        }
+        #[cfg(feature = "doh-upstream")]
+        if doh::client::is_registered(upstream) {
+            return &[BenchProtocol::Https];
+        }
+        if dot::is_upstream(upstream) {
+            return &[BenchProtocol::Tls];
+        }
+        &[BenchProtocol::Udp, BenchProtocol::Tcp]
+    }
+}
+
+/// Latency summary for one upstream over one protocol.
+#[derive(Debug, Default, PartialEq)]
+struct LatencySummary {
+    min_ms: f64,
+    avg_ms: f64,
+    median_ms: f64,
+    p95_ms: f64,
+    max_ms: f64,
+    failures: usize,
+    attempts: usize,
+}
+
+impl LatencySummary {
+    fn from_samples(mut latencies: Vec<f64>, attempts: usize) -> Self {
+        let failures = attempts - latencies.len();
+        if latencies.is_empty() {
+            return Self {
+                failures,
+                attempts,
+                ..Self::default()
+            };
+        }
+        latencies.sort_by(f64::total_cmp);
+        Self {
+            min_ms: latencies[0],
+            avg_ms: latencies.iter().sum::<f64>() / latencies.len() as f64,
+            median_ms: percentile(&latencies, 50),
+            p95_ms: percentile(&latencies, 95),
+            max_ms: latencies[latencies.len() - 1],
+            failures,
+            attempts,
+        }
+    }
+
+    fn failure_pct(&self) -> f64 {
+        if self.attempts == 0 {
+            0.0
+        } else {
+            self.failures as f64 / self.attempts as f64 * 100.0
+        }
+    }
+}
+
+/// Nearest-rank percentile of sorted, non-empty samples.
+fn percentile(sorted: &[f64], pct: usize) -> f64 {
+    let rank = (pct * sorted.len()).div_ceil(100).max(1);
+    sorted[rank - 1]
+}
+
+/// Run the benchmark and print a results table.
+pub async fn run(config: BenchConfig) -> io::Result<()> {
+    let domains: Vec<String> = if config.domains.is_empty() {
+        DEFAULT_DOMAINS.iter().map(|d| d.to_string()).collect()
+    } else {
+        config.domains
+    };
+    if config.upstreams.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "no upstreams to benchmark",
+        ));
+    }
+
+    println!(
+        "Benchmarking {} upstreams ({} samples each over UDP and TCP, or their own protocol)...",
+        config.upstreams.len(),
+        config.samples
+    );
+
+    let results = futures::future::join_all(
+        config
+            .upstreams
+            .iter()
+            .map(|&upstream| bench_upstream(upstream, config.samples, &domains)),
+    )
+    .await;
+
+    println!(
+        "{:<24} {:<5} {:>9} {:>9} {:>9} {:>9} {:>9} {:>8}  filtered",
+        "upstream", "proto", "min", "avg", "median", "p95", "max", "fail"
+    );
+    for (upstream, summaries, filtered) in results {
+        for (protocol, summary) in summaries {
+            println!(
+                "{:<24} {:<5} {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>7.2}ms {:>7.1}%  {}",
+                upstream.to_string(),
+                protocol.as_str(),
+                summary.min_ms,
+                summary.avg_ms,
+                summary.median_ms,
+                summary.p95_ms,
+                summary.max_ms,
+                summary.failure_pct(),
+                filtered
+            );
+        }
+    }
+    Ok(())
+}
+
+async fn bench_upstream(
+    upstream: SocketAddr,
+    samples: usize,
+    domains: &[String],
+) -> (SocketAddr, Vec<(BenchProtocol, LatencySummary)>, String) {
+    let protocols = BenchProtocol::for_upstream(upstream);
+    let mut summaries = Vec::with_capacity(protocols.len());
+    for &protocol in protocols {
+        let mut latencies = Vec::with_capacity(samples);
+        let mut attempts = 0;
+        for i in 0..samples {
+            if attempts == DEAD_AFTER && latencies.is_empty() {
+                break;
+            }
+            attempts += 1;
+            let domain = unique_subdomain(&domains[i % domains.len()], i);
+            let start = Instant::now();
+            if timed_query(upstream, protocol, &domain).await.is_some() {
+                latencies.push(start.elapsed().as_secs_f64() * 1000.0);
+            }
+        }
+        // Count skipped samples of a dead upstream as failures.
+        let attempts = if latencies.is_empty() {
+            samples
+        } else {
+            attempts
+        };
+        summaries.push((protocol, LatencySummary::from_samples(latencies, attempts)));
+    }
+
+    let filtered = if summaries.iter().all(|(_, s)| s.failures == s.attempts) {
+        "-".to_string()
+    } else {
+        filter_check(upstream, protocols[0], domains).await
+    };
+    (upstream, summaries, filtered)
+}
+
+/// Query each known-good name and count answers that look filtered.
+async fn filter_check(upstream: SocketAddr, protocol: BenchProtocol, domains: &[String]) -> String {
+    let mut suspicious = 0;
+    let mut answered = 0;
+    for domain in domains {
+        let Some(response) = timed_query(upstream, protocol, domain).await else {
+            continue;
+        };
+        answered += 1;
+        if looks_filtered(&response) {
+            suspicious += 1;
+        }
+    }
+    if suspicious == 0 {
+        "no".to_string()
+    } else {
+        format!("yes ({}/{} names)", suspicious, answered)
+    }
+}
+
+/// A known-good name answered with NXDOMAIN/REFUSED or a sinkhole address.
+fn looks_filtered(response: &DnsResponse) -> bool {
+    if matches!(response.rcode(), 3 | 5) {
+        return true;
+    }
+    response.answers.iter().any(|record| match record.data() {
+        RData::A(addr) => is_sinkhole(IpAddr::V4(addr)),
+        RData::Aaaa(addr) => is_sinkhole(IpAddr::V6(addr)),
+        _ => false,
+    })
+}
+
+fn is_sinkhole(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => v4.is_unspecified() || v4.is_loopback() || v4.is_private(),
+        IpAddr::V6(v6) => v6.is_unspecified() || v6.is_loopback(),
+    }
+}
+
+/// Prefix `domain` with a random-looking label so the upstream cannot answer from cache.
+fn unique_subdomain(domain: &str, sample: usize) -> String {
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos();
+    format!("detour-{:08x}{:04x}.{}", nanos, sample, domain)
+}
+
+/// Send one A query, returning the parsed response if it arrives in time.
+async fn timed_query(
+    upstream: SocketAddr,
+    protocol: BenchProtocol,
+    domain: &str,
+) -> Option<DnsResponse> {
+    let id = (SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        & 0xFFFF) as u16;
+    let query = DnsQuery::new(id, domain, TYPE_A).to_bytes()?;
+
+    let response = tokio::time::timeout(QUERY_TIMEOUT, async {
+        match protocol {
+            BenchProtocol::Udp => udp_query(upstream, &query).await,
+            // The TCP path wraps connections to registered DoT upstreams in TLS.
+            BenchProtocol::Tcp | BenchProtocol::Tls => forward_to_upstream(&query, upstream).await,
+            #[cfg(feature = "doh-upstream")]
+            BenchProtocol::Https => doh::client::query(upstream, &query).await,
+            #[cfg(feature = "doq")]
+            BenchProtocol::Quic => doq::query(upstream, &query).await,
+        }
+    })
+    .await
+    .ok()??;
+
+    let parsed = DnsResponse::parse(&response)?;
+    (parsed.id == id).then_some(parsed)
+}
+
+async fn udp_query(upstream: SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let bind: SocketAddr = if upstream.is_ipv4() {
+        "0.0.0.0:0".parse().unwrap()
+    } else {
+        "[::]:0".parse().unwrap()
+    };
+    let socket = UdpSocket::bind(bind).await.ok()?;
+    socket.connect(upstream).await.ok()?;
+    socket.send(query).await.ok()?;
+    let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+    let len = socket.recv(&mut buf).await.ok()?;
+    Some(buf[..len].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn summary_uses_nearest_rank_percentiles() {
+        let latencies: Vec<f64> = (1..=20).map(|n| n as f64).collect();
+
+        let summary = LatencySummary::from_samples(latencies, 25);
+
+        assert_eq!(summary.min_ms, 1.0);
+        assert_eq!(summary.avg_ms, 10.5);
+        assert_eq!(summary.median_ms, 10.0);
+        assert_eq!(summary.p95_ms, 19.0);
+        assert_eq!(summary.max_ms, 20.0);
+        assert_eq!(summary.failures, 5);
+        assert_eq!(summary.failure_pct(), 20.0);
+    }
+
+    #[test]
+    fn tls_upstreams_are_benchmarked_over_tls_only() {
+        let tls: SocketAddr = "127.0.0.1:45853".parse().unwrap();
+        let plain: SocketAddr = "127.0.0.1:45053".parse().unwrap();
+        let roots = tokio_rustls::rustls::RootCertStore::empty();
+        dot::register_upstream_with_roots(tls, "localhost", roots).unwrap();
+
+        assert_eq!(BenchProtocol::for_upstream(tls), [BenchProtocol::Tls]);
+        assert_eq!(
+            BenchProtocol::for_upstream(plain),
+            [BenchProtocol::Udp, BenchProtocol::Tcp]
+        );
+    }
+
+    #[test]
+    fn unique_subdomain_is_valid_and_distinct() {
+        let first = unique_subdomain("example.com", 0);
+        let second = unique_subdomain("example.com", 1);
+
+        assert!(crate::dns::is_valid_domain(&first));
+        assert!(first.ends_with(".example.com"));
+        assert_ne!(first, second);
+    }
+}
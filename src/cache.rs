@@ -1,89 +1,940 @@
 //! DNS response cache with TTL-based expiration.
+//!
+//! The cache can be saved to and restored from a file so a restart does not
+//! start cold. The file starts with the magic `DTRC`, a u16 format version
+//! and the u64 Unix time it was saved at, then a u32 entry count followed by
+//! entries of (u16 qtype, u16 domain length, domain, u32 remaining TTL in
+//! seconds, u16 response length, response), all big-endian. On load the
+//! time since the save is taken off each remaining TTL.
 
-use rustc_hash::FxHashMap;
+use rustc_hash::{FxHashMap, FxHasher};
+use std::collections::VecDeque;
+use std::hash::Hasher;
+use std::io;
+use std::path::Path;
 use std::sync::RwLock;
-use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
 
-use crate::dns::{DnsQuery, DnsResponse};
+use crate::dns::{DnsQuery, DnsResponse, cap_ttls, set_ttls, strip_opt};
+use crate::filter::SuffixSet;
 
 struct CacheEntry {
     response: Vec<u8>,
     expires_at: Instant,
+    /// Pinned entries are refreshed before expiry and never evicted.
+    pinned: bool,
+    /// Matches the entry's slot in the eviction queue.
+    seq: u64,
+    /// Set on every hit; an entry that was used since it last reached the
+    /// front of the eviction queue gets a second chance instead of eviction.
+    used: AtomicBool,
+    /// Set once a stale hit has asked for a background refresh, until the
+    /// refresh stores a new answer or ends without one.
+    refreshing: AtomicBool,
 }
 
-/// TTL-based DNS cache.
+/// A cache hit from [`DnsCache::lookup`].
+pub enum CacheHit {
+    /// The entry is within its TTL.
+    Fresh(Vec<u8>),
+    /// The entry expired less than the stale TTL ago. `refresh` is true for
+    /// one caller at a time, which should re-query upstream in the background
+    /// and call [`DnsCache::end_refresh`] when done.
+    Stale { response: Vec<u8>, refresh: bool },
+}
+
+/// A cached NXDOMAIN/NODATA response, kept for the SOA's negative TTL.
+struct NegativeEntry {
+    response: Vec<u8>,
+    expires_at: Instant,
+}
+
+/// One shard of the cache.
+#[derive(Default)]
+struct CacheMap {
+    entries: FxHashMap<u16, FxHashMap<String, CacheEntry>>,
+    negatives: FxHashMap<u16, FxHashMap<String, NegativeEntry>>,
+    negative_len: usize,
+    /// Eviction order, oldest first. Slots whose `seq` no longer matches the
+    /// map entry (expired or replaced) are skipped when popped.
+    order: VecDeque<(u16, String, u64)>,
+    len: usize,
+    next_seq: u64,
+}
+
+impl CacheMap {
+    fn get(&self, qtype: u16, domain: &str) -> Option<&CacheEntry> {
+        self.entries.get(&qtype)?.get(domain)
+    }
+
+    fn remove(&mut self, qtype: u16, domain: &str) {
+        if let Some(inner) = self.entries.get_mut(&qtype)
+            && inner.remove(domain).is_some()
+        {
+            self.len -= 1;
+        }
+    }
+
+    fn insert(
+        &mut self,
+        qtype: u16,
+        domain: String,
+        response: Vec<u8>,
+        expires_at: Instant,
+        pinned: bool,
+    ) {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.order.push_back((qtype, domain.clone(), seq));
+        let entry = CacheEntry {
+            response,
+            expires_at,
+            pinned,
+            seq,
+            used: AtomicBool::new(false),
+            refreshing: AtomicBool::new(false),
+        };
+        if self
+            .entries
+            .entry(qtype)
+            .or_default()
+            .insert(domain, entry)
+            .is_none()
+        {
+            self.len += 1;
+        }
+    }
+
+    fn get_negative(&self, qtype: u16, domain: &str) -> Option<&NegativeEntry> {
+        self.negatives.get(&qtype)?.get(domain)
+    }
+
+    fn insert_negative(&mut self, qtype: u16, domain: String, entry: NegativeEntry) {
+        if self
+            .negatives
+            .entry(qtype)
+            .or_default()
+            .insert(domain, entry)
+            .is_none()
+        {
+            self.negative_len += 1;
+        }
+    }
+
+    fn remove_negative(&mut self, qtype: u16, domain: &str) {
+        if let Some(inner) = self.negatives.get_mut(&qtype)
+            && inner.remove(domain).is_some()
+        {
+            self.negative_len -= 1;
+        }
+    }
+
+    fn purge_expired_negatives(&mut self, now: Instant) {
+        for inner in self.negatives.values_mut() {
+            inner.retain(|_, entry| now < entry.expires_at);
+        }
+        self.negative_len = self.negatives.values().map(|inner| inner.len()).sum();
+    }
+
+    /// Evict the least recently used unpinned entry. Returns false if none can be evicted.
+    fn evict_one(&mut self) -> bool {
+        for _ in 0..self.order.len() {
+            let Some((qtype, domain, seq)) = self.order.pop_front() else {
+                return false;
+            };
+            let Some(entry) = self.get(qtype, &domain) else {
+                continue;
+            };
+            if entry.seq != seq {
+                continue;
+            }
+            if entry.pinned || entry.used.swap(false, Ordering::Relaxed) {
+                self.order.push_back((qtype, domain, seq));
+                continue;
+            }
+            self.remove(qtype, &domain);
+            return true;
+        }
+        false
+    }
+
+    /// Drop queue slots for entries that expired or were replaced.
+    fn compact(&mut self) {
+        let entries = &self.entries;
+        self.order.retain(|(qtype, domain, seq)| {
+            entries
+                .get(qtype)
+                .and_then(|inner| inner.get(domain.as_str()))
+                .is_some_and(|entry| entry.seq == *seq)
+        });
+    }
+}
+
+/// TTL of stale answers, served while the entry is refreshed or because no
+/// upstream answered (RFC 8767).
+pub const STALE_ANSWER_TTL: u32 = 30;
+
+/// Whole seconds until `expires_at`, rounded up, for the TTLs of a cache hit.
+fn seconds_left(expires_at: Instant, now: Instant) -> u32 {
+    let left = expires_at.saturating_duration_since(now);
+    u32::try_from(left.as_millis().div_ceil(1000)).unwrap_or(u32::MAX)
+}
+
+/// First bytes of a cache file.
+const CACHE_FILE_MAGIC: &[u8; 4] = b"DTRC";
+
+/// Cache file format written by this version; files in any other are ignored.
+const CACHE_FILE_VERSION: u16 = 1;
+
+/// Entries with fewer seconds than this left are not restored from a cache
+/// file.
+const MIN_RESTORED_TTL: u64 = 5;
+
+/// Seconds since the Unix epoch, for cache file timestamps.
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Shard count used unless configured otherwise.
+pub const DEFAULT_SHARDS: usize = 64;
+
+/// Entries dropped per write lock by [`DnsCache::sweep_expired`].
+const SWEEP_BATCH: usize = 256;
+
+/// Bounded caches use fewer shards so each holds at least this many entries
+/// (LRU order is only kept within a shard).
+const MIN_SHARD_ENTRIES: usize = 256;
+
+/// TTL-based DNS cache with optional LRU eviction.
 ///
 /// Uses a 2-level map (qtype -> domain -> entry) to avoid allocations on lookup.
+/// The maps are split into independently locked shards, chosen by hashing the
+/// domain and qtype, so concurrent lookups rarely contend. When a maximum
+/// entry count is set, each shard holds its share of it and inserting beyond
+/// that evicts the shard's least recently used entry (approximated with a
+/// second-chance queue so hits only need the read lock). Negative answers
+/// live in separate maps and expire after the negative TTL from their SOA
+/// record (RFC 2308).
 pub struct DnsCache {
-    entries: RwLock<FxHashMap<u16, FxHashMap<String, CacheEntry>>>,
+    shards: Box<[RwLock<CacheMap>]>,
+    /// Requested shard count (a power of two); bounded caches may use fewer.
+    shard_count: usize,
     min_ttl: Duration,
     max_ttl: Duration,
+    /// Maximum number of entries (0 = unbounded).
+    max_entries: usize,
+    /// How long past expiry an entry may still be served while it is refreshed.
+    stale_ttl: Duration,
+    /// How long past expiry an entry is kept to answer when no upstream does.
+    serve_stale: Duration,
+    pinned: SuffixSet,
+    /// Entries evicted to make room since the cache was created.
+    evictions: AtomicU64,
+}
+
+/// A cached answer as listed by [`DnsCache::entries`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheEntryInfo {
+    pub domain: String,
+    pub qtype: u16,
+    /// Time left until the entry expires (zero for an expired entry kept
+    /// for stale answers).
+    pub ttl: Duration,
+    /// An NXDOMAIN or NODATA answer.
+    pub negative: bool,
+    pub pinned: bool,
+}
+
+/// Size and eviction counts of a [`DnsCache`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Responses currently cached.
+    pub entries: usize,
+    /// Entries evicted to make room for new ones, since startup.
+    pub evictions: u64,
+}
+
+fn new_shards(count: usize) -> Box<[RwLock<CacheMap>]> {
+    (0..count).map(|_| RwLock::default()).collect()
 }
 
 impl DnsCache {
     pub fn new() -> Self {
         Self {
-            entries: RwLock::new(FxHashMap::default()),
+            shards: new_shards(DEFAULT_SHARDS),
+            shard_count: DEFAULT_SHARDS,
             min_ttl: Duration::from_secs(60),
             max_ttl: Duration::from_secs(86400),
+            max_entries: 0,
+            stale_ttl: Duration::ZERO,
+            serve_stale: Duration::ZERO,
+            pinned: SuffixSet::default(),
+            evictions: AtomicU64::new(0),
         }
     }
 
-    /// Look up a cached response (no allocation on hit or miss).
-    pub fn get(&self, query: &DnsQuery) -> Option<Vec<u8>> {
-        let now = Instant::now();
-        let domain = query.domain.as_str();
+    /// Create a cache holding at most `max` entries.
+    pub fn with_capacity(max: usize) -> Self {
+        Self::new().with_max_entries(max)
+    }
 
-        {
-            let Ok(entries) = self.entries.read() else {
-                return None;
+    /// Limit the cache to `max` entries (0 = unbounded).
+    pub fn with_max_entries(mut self, max: usize) -> Self {
+        self.max_entries = max;
+        self.reshard()
+    }
+
+    /// Split the cache into `count` shards (rounded up to a power of two).
+    pub fn with_shards(mut self, count: usize) -> Self {
+        self.shard_count = count.max(1).next_power_of_two();
+        self.reshard()
+    }
+
+    /// Serve entries up to `stale_ttl` past expiry while they are refreshed (0 = never).
+    pub fn with_stale_ttl(mut self, stale_ttl: Duration) -> Self {
+        self.stale_ttl = stale_ttl;
+        self
+    }
+
+    /// Keep entries up to `max_stale` past expiry for [`DnsCache::get_stale`] (0 = never).
+    pub fn with_serve_stale(mut self, max_stale: Duration) -> Self {
+        self.serve_stale = max_stale;
+        self
+    }
+
+    /// How long past expiry an entry is kept.
+    fn retention(&self) -> Duration {
+        self.stale_ttl.max(self.serve_stale)
+    }
+
+    /// Pin entries for domains matching these suffixes.
+    pub fn with_pinned(mut self, pinned: SuffixSet) -> Self {
+        for shard in self.shards.iter_mut() {
+            if let Ok(map) = shard.get_mut() {
+                for (domain, entry) in map.entries.values_mut().flatten() {
+                    entry.pinned = pinned.matches(domain);
+                }
+            }
+        }
+        self.pinned = pinned;
+        self
+    }
+
+    /// Rebuild the shards if the shard count or capacity calls for a different
+    /// number, moving existing entries to their new shards.
+    fn reshard(mut self) -> Self {
+        let count = if self.max_entries == 0 {
+            self.shard_count
+        } else {
+            let by_size = 1 << (self.max_entries / MIN_SHARD_ENTRIES).max(1).ilog2();
+            self.shard_count.min(by_size)
+        };
+        if count == self.shards.len() {
+            return self;
+        }
+
+        let old = std::mem::replace(&mut self.shards, new_shards(count));
+        for shard in old {
+            let Ok(map) = shard.into_inner() else {
+                continue;
             };
-            if let Some(inner) = entries.get(&query.qtype) {
-                if let Some(entry) = inner.get(domain) {
-                    if now < entry.expires_at {
-                        return query.response_from_cache(&entry.response);
+            for (qtype, inner) in map.entries {
+                for (domain, entry) in inner {
+                    let index = self.shard_index(qtype, &domain);
+                    if let Ok(target) = self.shards[index].get_mut() {
+                        target.insert(
+                            qtype,
+                            domain,
+                            entry.response,
+                            entry.expires_at,
+                            entry.pinned,
+                        );
+                    }
+                }
+            }
+            for (qtype, inner) in map.negatives {
+                for (domain, entry) in inner {
+                    let index = self.shard_index(qtype, &domain);
+                    if let Ok(target) = self.shards[index].get_mut() {
+                        target.insert_negative(qtype, domain, entry);
                     }
                 }
             }
         }
+        self
+    }
 
-        let Ok(mut entries) = self.entries.write() else {
-            return None;
+    fn shard_index(&self, qtype: u16, domain: &str) -> usize {
+        let mut hasher = FxHasher::default();
+        hasher.write(domain.as_bytes());
+        (hasher.finish() ^ u64::from(qtype)) as usize & (self.shards.len() - 1)
+    }
+
+    fn shard(&self, query: &DnsQuery) -> &RwLock<CacheMap> {
+        &self.shards[self.shard_index(query.qtype, &query.domain)]
+    }
+
+    /// Entries each shard may hold (0 = unbounded).
+    fn shard_capacity(&self) -> usize {
+        self.max_entries.div_ceil(self.shards.len())
+    }
+
+    /// Restore a cache written by [`Self::save_to_file`], skipping entries
+    /// with less than [`MIN_RESTORED_TTL`] left once the time since the save
+    /// is taken off.
+    ///
+    /// Files that are truncated, not cache files or in another format
+    /// version are rejected with [`io::ErrorKind::InvalidData`].
+    pub fn load_from_file(path: &Path) -> io::Result<Self> {
+        let data = std::fs::read(path)?;
+        let mut reader = FileReader(&data);
+        if reader.take(CACHE_FILE_MAGIC.len())? != CACHE_FILE_MAGIC {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a cache file",
+            ));
+        }
+        let version = reader.u16()?;
+        if version != CACHE_FILE_VERSION {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported cache file version {}", version),
+            ));
+        }
+        let elapsed = unix_time().saturating_sub(reader.u64()?);
+        let now = Instant::now();
+        let mut cache = Self::new();
+
+        for _ in 0..reader.u32()? {
+            let qtype = reader.u16()?;
+            let domain_len = reader.u16()?;
+            let domain =
+                String::from_utf8(reader.take(domain_len.into())?.to_vec()).map_err(|_| {
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid domain in cache file")
+                })?;
+            let ttl = reader.u32()?;
+            let response_len = reader.u16()?;
+            let response = reader.take(response_len.into())?.to_vec();
+            let ttl = u64::from(ttl).saturating_sub(elapsed);
+            if ttl < MIN_RESTORED_TTL {
+                continue;
+            }
+            let expires_at = now + Duration::from_secs(ttl);
+            let index = cache.shard_index(qtype, &domain);
+            let Ok(map) = cache.shards[index].get_mut() else {
+                continue;
+            };
+            if DnsResponse::is_negative(&response) {
+                map.insert_negative(
+                    qtype,
+                    domain,
+                    NegativeEntry {
+                        response,
+                        expires_at,
+                    },
+                );
+            } else {
+                map.insert(qtype, domain, response, expires_at, false);
+            }
+        }
+
+        Ok(cache)
+    }
+
+    /// Write live entries to `path` (via a temporary file, so a crash never
+    /// leaves a partial cache behind).
+    pub fn save_to_file(&self, path: &Path) -> io::Result<()> {
+        let now = Instant::now();
+        let mut data = CACHE_FILE_MAGIC.to_vec();
+        data.extend_from_slice(&CACHE_FILE_VERSION.to_be_bytes());
+        data.extend_from_slice(&unix_time().to_be_bytes());
+        let count_at = data.len();
+        data.extend_from_slice(&[0; 4]);
+        let mut count: u32 = 0;
+        let mut write = |qtype: u16, domain: &str, response: &[u8], expires_at: Instant| {
+            let ttl = expires_at.saturating_duration_since(now).as_secs();
+            if ttl == 0
+                || domain.len() > usize::from(u16::MAX)
+                || response.len() > usize::from(u16::MAX)
+            {
+                return;
+            }
+            data.extend_from_slice(&qtype.to_be_bytes());
+            data.extend_from_slice(&(domain.len() as u16).to_be_bytes());
+            data.extend_from_slice(domain.as_bytes());
+            data.extend_from_slice(&(ttl.min(u64::from(u32::MAX)) as u32).to_be_bytes());
+            data.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            data.extend_from_slice(response);
+            count += 1;
         };
-        if let Some(inner) = entries.get_mut(&query.qtype) {
-            if let Some(entry) = inner.get(domain) {
-                if now >= entry.expires_at {
-                    inner.remove(domain);
+
+        for shard in &self.shards {
+            let Ok(map) = shard.read() else {
+                continue;
+            };
+            for (&qtype, inner) in &map.entries {
+                for (domain, entry) in inner {
+                    write(qtype, domain, &entry.response, entry.expires_at);
+                }
+            }
+            for (&qtype, inner) in &map.negatives {
+                for (domain, entry) in inner {
+                    write(qtype, domain, &entry.response, entry.expires_at);
+                }
+            }
+        }
+        data[count_at..count_at + 4].copy_from_slice(&count.to_be_bytes());
+
+        let tmp = path.with_extension("tmp");
+        std::fs::write(&tmp, &data)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Look up a fresh cached response (no allocation on hit or miss).
+    pub fn get(&self, query: &DnsQuery) -> Option<Vec<u8>> {
+        match self.lookup(query)? {
+            CacheHit::Fresh(response) => Some(response),
+            CacheHit::Stale { .. } => None,
+        }
+    }
+
+    /// Look up a cached response, including stale ones within the stale TTL.
+    ///
+    /// Record TTLs of fresh hits are capped at the time the entry has left,
+    /// so clients never cache an answer longer than it had left here; stale
+    /// hits get [`STALE_ANSWER_TTL`].
+    pub fn lookup(&self, query: &DnsQuery) -> Option<CacheHit> {
+        let now = Instant::now();
+        let domain = query.domain.as_str();
+        let shard = self.shard(query);
+
+        {
+            let map = shard.read().ok()?;
+            if let Some(entry) = map.get(query.qtype, domain) {
+                if now < entry.expires_at {
+                    entry.used.store(true, Ordering::Relaxed);
+                    let mut response = query.response_from_cache(&entry.response)?;
+                    cap_ttls(&mut response, seconds_left(entry.expires_at, now));
+                    return Some(CacheHit::Fresh(response));
+                }
+                if now < entry.expires_at + self.stale_ttl {
+                    entry.used.store(true, Ordering::Relaxed);
+                    let refresh = !entry.refreshing.swap(true, Ordering::Relaxed);
+                    let mut response = query.response_from_cache(&entry.response)?;
+                    set_ttls(&mut response, STALE_ANSWER_TTL);
+                    return Some(CacheHit::Stale { response, refresh });
                 }
             }
+            if let Some(entry) = map.get_negative(query.qtype, domain)
+                && now < entry.expires_at
+            {
+                let mut response = query.response_from_cache(&entry.response)?;
+                cap_ttls(&mut response, seconds_left(entry.expires_at, now));
+                return Some(CacheHit::Fresh(response));
+            }
+        }
+
+        let mut map = shard.write().ok()?;
+        if map
+            .get(query.qtype, domain)
+            .is_some_and(|entry| now >= entry.expires_at + self.retention())
+        {
+            map.remove(query.qtype, domain);
+        }
+        if map
+            .get_negative(query.qtype, domain)
+            .is_some_and(|entry| now >= entry.expires_at)
+        {
+            map.remove_negative(query.qtype, domain);
         }
         None
     }
 
+    /// Look up a cached response, expired up to the serve-stale limit ago,
+    /// for a query no upstream answered. Its records get
+    /// [`STALE_ANSWER_TTL`] so clients soon ask again.
+    pub fn get_stale(&self, query: &DnsQuery) -> Option<Vec<u8>> {
+        if self.serve_stale.is_zero() {
+            return None;
+        }
+        let map = self.shard(query).read().ok()?;
+        let entry = map.get(query.qtype, &query.domain)?;
+        if Instant::now() >= entry.expires_at + self.serve_stale {
+            return None;
+        }
+        let mut response = query.response_from_cache(&entry.response)?;
+        set_ttls(&mut response, STALE_ANSWER_TTL);
+        Some(response)
+    }
+
+    /// Let the next stale hit on `query`'s entry ask for a refresh again, e.g.
+    /// after a refresh that got no answer to store.
+    pub fn end_refresh(&self, query: &DnsQuery) {
+        if let Ok(map) = self.shard(query).read()
+            && let Some(entry) = map.get(query.qtype, &query.domain)
+        {
+            entry.refreshing.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Move an entry's expiry `ago` into the past.
+    #[cfg(test)]
+    pub(crate) fn expire(&self, query: &DnsQuery, ago: Duration) {
+        if let Ok(mut map) = self.shard(query).write()
+            && let Some(entry) = map
+                .entries
+                .get_mut(&query.qtype)
+                .and_then(|inner| inner.get_mut(query.domain.as_str()))
+        {
+            entry.expires_at = Instant::now() - ago;
+        }
+    }
+
+    /// Move an entry's expiry `by` earlier, as if it had been cached that
+    /// much longer ago.
+    #[cfg(test)]
+    pub(crate) fn age(&self, query: &DnsQuery, by: Duration) {
+        if let Ok(mut map) = self.shard(query).write()
+            && let Some(entry) = map
+                .entries
+                .get_mut(&query.qtype)
+                .and_then(|inner| inner.get_mut(query.domain.as_str()))
+        {
+            entry.expires_at -= by;
+        }
+    }
+
     /// Store a response in the cache (allocates only on insert).
+    ///
+    /// Responses without answers are only cached if they carry an SOA to
+    /// take the negative TTL from. The OPT record is dropped; hits get one
+    /// matching their own query instead. When the answer is a CNAME chain,
+    /// each name it leads to is cached too, with the rest of the chain and
+    /// the same expiry, unless that name already has a live entry.
     pub fn put(&self, query: &DnsQuery, response: &[u8]) {
+        let response = &*strip_opt(response);
+        if DnsResponse::is_negative(response) {
+            if let Some(ttl) = DnsResponse::negative_ttl(response) {
+                self.put_negative(query, response, ttl);
+            }
+            return;
+        }
+
         let ttl = DnsResponse::parse_min_ttl(response, self.min_ttl);
         let ttl = ttl.clamp(self.min_ttl, self.max_ttl);
+        let expires_at = Instant::now() + ttl;
+        self.store(query, response, expires_at, true);
+
+        let targets = DnsResponse::parse(response)
+            .map(|parsed| parsed.cname_target_responses())
+            .unwrap_or_default();
+        for (target, target_response) in targets {
+            let target_query = DnsQuery::new(0, &target, query.qtype);
+            self.store(
+                &target_query,
+                &target_response.to_bytes(),
+                expires_at,
+                false,
+            );
+        }
+    }
+
+    /// Insert the entry for `query`, or update an existing one; with
+    /// `replace` false a live entry is left alone.
+    fn store(&self, query: &DnsQuery, response: &[u8], expires_at: Instant, replace: bool) {
+        let pinned = self.pinned.matches(&query.domain);
+        let capacity = self.shard_capacity();
 
-        let Ok(mut entries) = self.entries.write() else {
+        let Ok(mut map) = self.shard(query).write() else {
             return;
         };
 
-        let inner = entries.entry(query.qtype).or_default();
-        inner.insert(
+        if let Some(entry) = map
+            .entries
+            .get_mut(&query.qtype)
+            .and_then(|inner| inner.get_mut(query.domain.as_str()))
+        {
+            if !replace && Instant::now() < entry.expires_at {
+                return;
+            }
+            entry.response = response.to_vec();
+            entry.expires_at = expires_at;
+            entry.pinned = pinned;
+            entry.used.store(true, Ordering::Relaxed);
+            entry.refreshing.store(false, Ordering::Relaxed);
+            return;
+        }
+
+        if capacity > 0 {
+            while map.len >= capacity && map.evict_one() {
+                self.evictions.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if map.order.len() > 2 * map.len + 1024 {
+            map.compact();
+        }
+
+        map.insert(
+            query.qtype,
+            query.domain.clone(),
+            response.to_vec(),
+            expires_at,
+            pinned,
+        );
+    }
+
+    fn put_negative(&self, query: &DnsQuery, response: &[u8], ttl: Duration) {
+        let now = Instant::now();
+        let expires_at = now + ttl.clamp(self.min_ttl, self.max_ttl);
+        let capacity = self.shard_capacity();
+        let Ok(mut map) = self.shard(query).write() else {
+            return;
+        };
+
+        if capacity > 0 && map.negative_len >= capacity {
+            map.purge_expired_negatives(now);
+            if map.negative_len >= capacity {
+                return;
+            }
+        }
+
+        map.insert_negative(
+            query.qtype,
             query.domain.clone(),
-            CacheEntry {
+            NegativeEntry {
                 response: response.to_vec(),
-                expires_at: Instant::now() + ttl,
+                expires_at,
             },
         );
     }
 
+    /// List pinned entries (qtype, domain) that expire within `within`.
+    pub fn pinned_expiring(&self, within: Duration) -> Vec<(u16, String)> {
+        let deadline = Instant::now() + within;
+        let mut expiring = Vec::new();
+        for shard in &self.shards {
+            let Ok(map) = shard.read() else {
+                continue;
+            };
+            for (&qtype, inner) in &map.entries {
+                expiring.extend(
+                    inner
+                        .iter()
+                        .filter(|(_, entry)| entry.pinned && entry.expires_at <= deadline)
+                        .map(|(domain, _)| (qtype, domain.clone())),
+                );
+            }
+        }
+        expiring
+    }
+
+    /// Every cached answer, including expired ones still kept for stale
+    /// answers, sorted by domain and qtype.
+    pub fn entries(&self) -> Vec<CacheEntryInfo> {
+        let now = Instant::now();
+        let mut entries = Vec::new();
+        for shard in &self.shards {
+            let Ok(map) = shard.read() else {
+                continue;
+            };
+            for (&qtype, inner) in &map.entries {
+                entries.extend(inner.iter().map(|(domain, entry)| CacheEntryInfo {
+                    domain: domain.clone(),
+                    qtype,
+                    ttl: entry.expires_at.saturating_duration_since(now),
+                    negative: false,
+                    pinned: entry.pinned,
+                }));
+            }
+            for (&qtype, inner) in &map.negatives {
+                entries.extend(
+                    inner
+                        .iter()
+                        .filter(|(_, entry)| now < entry.expires_at)
+                        .map(|(domain, entry)| CacheEntryInfo {
+                            domain: domain.clone(),
+                            qtype,
+                            ttl: entry.expires_at - now,
+                            negative: true,
+                            pinned: false,
+                        }),
+                );
+            }
+        }
+        entries.sort_by(|a, b| (&a.domain, a.qtype).cmp(&(&b.domain, b.qtype)));
+        entries
+    }
+
+    /// Drop the cached answer for `domain` and `qtype`, positive or
+    /// negative. Returns whether there was one.
+    pub fn remove(&self, domain: &str, qtype: u16) -> bool {
+        let Ok(mut map) = self.shards[self.shard_index(qtype, domain)].write() else {
+            return false;
+        };
+        let before = map.len + map.negative_len;
+        map.remove(qtype, domain);
+        map.remove_negative(qtype, domain);
+        map.len + map.negative_len < before
+    }
+
+    /// Drop the cached answers of every type for `domain`, returning how
+    /// many there were.
+    pub fn remove_name(&self, domain: &str) -> usize {
+        let mut removed = 0;
+        for shard in &self.shards {
+            let Ok(mut map) = shard.write() else {
+                continue;
+            };
+            let before = map.len + map.negative_len;
+            let qtypes: Vec<u16> = map
+                .entries
+                .keys()
+                .chain(map.negatives.keys())
+                .copied()
+                .collect();
+            for qtype in qtypes {
+                map.remove(qtype, domain);
+                map.remove_negative(qtype, domain);
+            }
+            removed += before - (map.len + map.negative_len);
+        }
+        removed
+    }
+
+    /// Drop every cached answer, pinned ones included, returning how many
+    /// there were.
+    ///
+    /// Shards are emptied one at a time, so queries in flight keep working
+    /// and answers cached meanwhile may survive the flush.
+    pub fn clear(&self) -> usize {
+        let mut dropped = 0;
+        for shard in &self.shards {
+            if let Ok(mut map) = shard.write() {
+                dropped += map.len + map.negative_len;
+                *map = CacheMap::default();
+            }
+        }
+        dropped
+    }
+
+    /// Drop entries past their TTL (and any stale retention), returning how
+    /// many were dropped. Lookups only drop the entry they hit, so names
+    /// never asked for again would otherwise stay cached.
+    ///
+    /// Pinned entries are left for their refresh. Expired entries are found
+    /// under the read lock and dropped in batches of [`SWEEP_BATCH`], so
+    /// queries wait on the write lock only briefly.
+    pub fn sweep_expired(&self) -> usize {
+        let retention = self.retention();
+        let mut dropped = 0;
+        for shard in &self.shards {
+            let now = Instant::now();
+            let expired: Vec<(u16, String)> = {
+                let Ok(map) = shard.read() else {
+                    continue;
+                };
+                let positive = map.entries.iter().flat_map(|(&qtype, inner)| {
+                    inner
+                        .iter()
+                        .filter(|(_, entry)| !entry.pinned && now >= entry.expires_at + retention)
+                        .map(move |(domain, _)| (qtype, domain.clone()))
+                });
+                let negative = map.negatives.iter().flat_map(|(&qtype, inner)| {
+                    inner
+                        .iter()
+                        .filter(|(_, entry)| now >= entry.expires_at)
+                        .map(move |(domain, _)| (qtype, domain.clone()))
+                });
+                positive.chain(negative).collect()
+            };
+            for batch in expired.chunks(SWEEP_BATCH) {
+                let Ok(mut map) = shard.write() else {
+                    break;
+                };
+                let before = map.len + map.negative_len;
+                // Entries may have been refreshed since the read lock was released
+                for (qtype, domain) in batch {
+                    if map
+                        .get(*qtype, domain)
+                        .is_some_and(|entry| now >= entry.expires_at + retention)
+                    {
+                        map.remove(*qtype, domain);
+                    }
+                    if map
+                        .get_negative(*qtype, domain)
+                        .is_some_and(|entry| now >= entry.expires_at)
+                    {
+                        map.remove_negative(*qtype, domain);
+                    }
+                }
+                dropped += before - (map.len + map.negative_len);
+            }
+        }
+        dropped
+    }
+
+    /// Remaining lifetime of a live cached entry, if any.
+    pub fn remaining_ttl(&self, query: &DnsQuery) -> Option<Duration> {
+        let map = self.shard(query).read().ok()?;
+        let entry = map.get(query.qtype, query.domain.as_str())?;
+        entry.expires_at.checked_duration_since(Instant::now())
+    }
+
     pub fn len(&self) -> usize {
-        self.entries
-            .read()
-            .map(|e| e.values().map(|inner| inner.len()).sum())
-            .unwrap_or(0)
+        self.shards
+            .iter()
+            .filter_map(|shard| shard.read().ok())
+            .map(|map| map.len + map.negative_len)
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Current size and evictions so far.
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.len(),
+            evictions: self.evictions.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Cursor over a cache file's bytes.
+struct FileReader<'a>(&'a [u8]);
+
+impl<'a> FileReader<'a> {
+    fn take(&mut self, n: usize) -> io::Result<&'a [u8]> {
+        if self.0.len() < n {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "truncated cache file",
+            ));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> io::Result<u32> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> io::Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        Ok(u64::from_be_bytes(bytes))
     }
 }
 
@@ -92,3 +943,437 @@ impl Default for DnsCache {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{EdnsOpt, RData, TYPE_A, TYPE_CNAME};
+    use crate::filter::BlockedResponseStyle;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    #[test]
+    fn pinned_expiring_lists_only_pinned_entries() {
+        let cache = DnsCache::new().with_pinned(SuffixSet::new(["corp.example"]));
+        let pinned = DnsQuery::new(1, "vpn.corp.example", TYPE_A);
+        let other = DnsQuery::new(2, "example.com", TYPE_A);
+        cache.put(
+            &pinned,
+            &pinned
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+        cache.put(
+            &other,
+            &other
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+
+        let expiring = cache.pinned_expiring(Duration::from_secs(3600));
+
+        assert_eq!(expiring, vec![(TYPE_A, "vpn.corp.example".to_string())]);
+        assert!(cache.pinned_expiring(Duration::ZERO).is_empty());
+    }
+
+    #[test]
+    fn answers_are_cached_without_opt_and_hits_get_their_own() {
+        let cache = DnsCache::new();
+        let mut edns = DnsQuery::new(1, "example.com", TYPE_A);
+        edns.edns_opt = Some(EdnsOpt {
+            udp_payload_size: 4096,
+            dnssec_ok: false,
+            options: Vec::new(),
+        });
+        let plain = DnsQuery::new(2, "example.com", TYPE_A);
+        cache.put(
+            &edns,
+            &edns
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+
+        let plain_hit = DnsResponse::parse(&cache.get(&plain).unwrap()).unwrap();
+        let edns_hit = DnsResponse::parse(&cache.get(&edns).unwrap()).unwrap();
+
+        assert_eq!(plain_hit.edns_opt, None);
+        assert_eq!(edns_hit.edns_opt.unwrap().udp_payload_size, 1232);
+        assert!(cache.remaining_ttl(&plain).unwrap() > Duration::from_secs(60));
+    }
+
+    /// www.example.com CNAME edge.example.net, edge.example.net A 0.0.0.0.
+    fn cname_response(www: &DnsQuery) -> Vec<u8> {
+        let mut response = www.blocked_response(BlockedResponseStyle::NullIp);
+        let mut cname = response.answers[0].clone();
+        cname.rtype = TYPE_CNAME;
+        cname.rdata = b"\x04edge\x07example\x03net\x00".to_vec();
+        response.answers[0].name = "edge.example.net".to_string();
+        response.answers.insert(0, cname);
+        response.to_bytes()
+    }
+
+    #[test]
+    fn cname_targets_are_cached_under_their_own_names() {
+        let cache = DnsCache::new();
+        let www = DnsQuery::new(1, "www.example.com", TYPE_A);
+        let edge = DnsQuery::new(2, "edge.example.net", TYPE_A);
+
+        cache.put(&www, &cname_response(&www));
+        let hit = DnsResponse::parse(&cache.get(&edge).unwrap()).unwrap();
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(hit.id, 2);
+        assert_eq!(hit.questions[0].domain, "edge.example.net");
+        assert_eq!(hit.answers.len(), 1);
+        assert_eq!(hit.answers[0].data(), RData::A(Ipv4Addr::UNSPECIFIED));
+    }
+
+    #[test]
+    fn cname_targets_leave_live_entries_alone() {
+        let cache = DnsCache::new();
+        let www = DnsQuery::new(1, "www.example.com", TYPE_A);
+        let edge = DnsQuery::new(2, "edge.example.net", TYPE_A);
+        let sinkhole = Ipv4Addr::new(10, 0, 0, 53);
+        cache.put(
+            &edge,
+            &edge
+                .blocked_response(BlockedResponseStyle::CustomIp(
+                    sinkhole,
+                    Ipv6Addr::UNSPECIFIED,
+                ))
+                .to_bytes(),
+        );
+
+        cache.put(&www, &cname_response(&www));
+        let hit = DnsResponse::parse(&cache.get(&edge).unwrap()).unwrap();
+
+        assert_eq!(hit.answers[0].data(), RData::A(sinkhole));
+    }
+
+    #[test]
+    fn max_entries_bounds_the_cache() {
+        let cache = DnsCache::with_capacity(3);
+
+        for i in 0..10 {
+            let query = DnsQuery::new(i, &format!("host{}.example", i), TYPE_A);
+            cache.put(
+                &query,
+                &query
+                    .blocked_response(BlockedResponseStyle::NullIp)
+                    .to_bytes(),
+            );
+        }
+
+        assert_eq!(cache.len(), 3);
+        assert!(
+            cache
+                .get(&DnsQuery::new(1, "host9.example", TYPE_A))
+                .is_some()
+        );
+        assert!(
+            cache
+                .get(&DnsQuery::new(1, "host0.example", TYPE_A))
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn eviction_spares_recently_used_entries() {
+        let cache = DnsCache::with_capacity(2);
+        let a = DnsQuery::new(1, "a.example", TYPE_A);
+        let b = DnsQuery::new(2, "b.example", TYPE_A);
+        let c = DnsQuery::new(3, "c.example", TYPE_A);
+        let response = |q: &DnsQuery| q.blocked_response(BlockedResponseStyle::NullIp).to_bytes();
+        cache.put(&a, &response(&a));
+        cache.put(&b, &response(&b));
+
+        assert!(cache.get(&a).is_some());
+        cache.put(&c, &response(&c));
+
+        assert!(cache.get(&a).is_some());
+        assert!(cache.get(&b).is_none());
+        assert!(cache.get(&c).is_some());
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                entries: 2,
+                evictions: 1
+            }
+        );
+    }
+
+    #[test]
+    fn negative_answers_are_cached_for_the_soa_minimum() {
+        let cache = DnsCache::new();
+        let missing = DnsQuery::new(1, "missing.example", TYPE_A);
+        let nxdomain = missing.blocked_response(BlockedResponseStyle::Nxdomain);
+        let servfail = DnsQuery::new(2, "broken.example", TYPE_A);
+
+        cache.put(&missing, &nxdomain.to_bytes());
+        cache.put(&servfail, &servfail.servfail_response().to_bytes());
+
+        let hit = cache
+            .get(&DnsQuery::new(7, "missing.example", TYPE_A))
+            .unwrap();
+        assert_eq!(DnsResponse::parse(&hit).unwrap().rcode(), 3);
+        assert_eq!(hit[..2], [0, 7]);
+        assert!(cache.get(&servfail).is_none());
+        assert_eq!(cache.len(), 1);
+        assert_eq!(
+            DnsResponse::negative_ttl(&nxdomain.to_bytes()),
+            Some(Duration::from_secs(300))
+        );
+    }
+
+    #[test]
+    fn save_and_load_round_trip_live_entries() {
+        let path = std::env::temp_dir().join(format!("detour-cache-{}.bin", std::process::id()));
+        let cache = DnsCache::new();
+        let found = DnsQuery::new(1, "found.example", TYPE_A);
+        let missing = DnsQuery::new(2, "missing.example", TYPE_A);
+        cache.put(
+            &found,
+            &found
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+        cache.put(
+            &missing,
+            &missing
+                .blocked_response(BlockedResponseStyle::Nxdomain)
+                .to_bytes(),
+        );
+
+        cache.save_to_file(&path).unwrap();
+        let restored = DnsCache::load_from_file(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(restored.len(), 2);
+        let hit = restored
+            .get(&DnsQuery::new(9, "found.example", TYPE_A))
+            .unwrap();
+        assert_eq!(hit[..2], [0, 9]);
+        assert!(restored.get(&missing).is_some());
+        assert!(restored.remaining_ttl(&found).unwrap() > Duration::from_secs(250));
+    }
+
+    #[test]
+    fn load_takes_the_time_since_the_save_off_and_rejects_bad_files() {
+        let path =
+            std::env::temp_dir().join(format!("detour-cache-exp-{}.bin", std::process::id()));
+        let mut data = CACHE_FILE_MAGIC.to_vec();
+        data.extend_from_slice(&CACHE_FILE_VERSION.to_be_bytes());
+        data.extend_from_slice(&(unix_time() - 30).to_be_bytes());
+        data.extend_from_slice(&3u32.to_be_bytes());
+        for (domain, ttl) in [
+            ("old.example", 20u32),
+            ("brief.example", 33),
+            ("new.example", 90),
+        ] {
+            let query = DnsQuery::new(1, domain, TYPE_A);
+            let response = query
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes();
+            data.extend_from_slice(&TYPE_A.to_be_bytes());
+            data.extend_from_slice(&(domain.len() as u16).to_be_bytes());
+            data.extend_from_slice(domain.as_bytes());
+            data.extend_from_slice(&ttl.to_be_bytes());
+            data.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            data.extend_from_slice(&response);
+        }
+        let load = |bytes: &[u8]| {
+            std::fs::write(&path, bytes).unwrap();
+            DnsCache::load_from_file(&path)
+        };
+        let mut other_version = data.clone();
+        other_version[4..6].copy_from_slice(&(CACHE_FILE_VERSION + 1).to_be_bytes());
+
+        let restored = load(&data).unwrap();
+        let rejected = [
+            load(&data[..data.len() - 1]),
+            load(&other_version),
+            load(&data[CACHE_FILE_MAGIC.len() + 2 + 8..]),
+        ];
+        let _ = std::fs::remove_file(&path);
+
+        assert_eq!(restored.len(), 1);
+        let ttl = restored
+            .remaining_ttl(&DnsQuery::new(1, "new.example", TYPE_A))
+            .unwrap();
+        assert!(ttl > Duration::from_secs(55) && ttl <= Duration::from_secs(60));
+        for result in rejected {
+            assert_eq!(
+                result.err().map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidData)
+            );
+        }
+    }
+
+    #[test]
+    fn stale_entries_are_served_once_with_a_refresh_request() {
+        let cache = DnsCache::new().with_stale_ttl(Duration::from_secs(5));
+        let query = DnsQuery::new(1, "stale.example", TYPE_A);
+        let response = query
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+        cache.put(&query, &response);
+        cache.expire(&query, Duration::from_secs(1));
+
+        let first = cache.lookup(&query);
+        let second = cache.lookup(&query);
+        cache.end_refresh(&query);
+        let retried = cache.lookup(&query);
+        cache.put(&query, &response);
+
+        assert!(matches!(first, Some(CacheHit::Stale { refresh: true, .. })));
+        assert!(matches!(
+            second,
+            Some(CacheHit::Stale { refresh: false, .. })
+        ));
+        assert!(matches!(
+            retried,
+            Some(CacheHit::Stale { refresh: true, .. })
+        ));
+        assert!(matches!(cache.lookup(&query), Some(CacheHit::Fresh(_))));
+    }
+
+    #[test]
+    fn entries_can_be_listed_removed_and_cleared() {
+        let cache = DnsCache::new();
+        let a = DnsQuery::new(1, "b.example", TYPE_A);
+        let missing = DnsQuery::new(2, "a.example", TYPE_A);
+        cache.put(
+            &a,
+            &DnsResponse::blocked(&a, BlockedResponseStyle::NullIp, 3600).to_bytes(),
+        );
+        cache.put(
+            &missing,
+            &missing
+                .blocked_response(BlockedResponseStyle::Nxdomain)
+                .to_bytes(),
+        );
+
+        let listed = cache.entries();
+        let removed = cache.remove("a.example", TYPE_A);
+        let removed_again = cache.remove("a.example", TYPE_A);
+        let left = cache.len();
+        let cleared = cache.clear();
+
+        assert_eq!(listed.len(), 2);
+        assert_eq!(listed[0].domain, "a.example");
+        assert!(listed[0].negative);
+        assert_eq!(listed[1].domain, "b.example");
+        assert!(listed[1].ttl > Duration::from_secs(3590));
+        assert!(removed);
+        assert!(!removed_again);
+        assert_eq!(left, 1);
+        assert_eq!(cleared, 1);
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn hits_carry_the_ttl_the_entry_has_left() {
+        let cache = DnsCache::new().with_stale_ttl(Duration::from_secs(5));
+        let query = DnsQuery::new(1, "ttl.example", TYPE_A);
+        let served_ttl = |hit: Option<CacheHit>| {
+            let (CacheHit::Fresh(response) | CacheHit::Stale { response, .. }) = hit.unwrap();
+            DnsResponse::parse_min_ttl(&response, Duration::ZERO).as_secs()
+        };
+        cache.put(
+            &query,
+            &DnsResponse::blocked(&query, BlockedResponseStyle::NullIp, 3600).to_bytes(),
+        );
+
+        let fresh = served_ttl(cache.lookup(&query));
+        cache.age(&query, Duration::from_secs(600));
+        let aged = served_ttl(cache.lookup(&query));
+        cache.expire(&query, Duration::from_secs(1));
+        let stale = served_ttl(cache.lookup(&query));
+
+        assert_eq!(fresh, 3600);
+        assert_eq!(aged, 3000);
+        assert_eq!(stale, u64::from(STALE_ANSWER_TTL));
+    }
+
+    #[test]
+    fn expired_entries_are_kept_for_serve_stale() {
+        let cache = DnsCache::new().with_serve_stale(Duration::from_secs(3600));
+        let query = DnsQuery::new(1, "stale.example", TYPE_A);
+        cache.put(
+            &query,
+            &query
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+
+        cache.expire(&query, Duration::from_secs(600));
+        let missed = cache.lookup(&query);
+        let stale = cache.get_stale(&query).unwrap();
+        cache.expire(&query, Duration::from_secs(7200));
+        let too_old = cache.get_stale(&query);
+
+        assert!(missed.is_none());
+        assert_eq!(
+            DnsResponse::parse_min_ttl(&stale, Duration::ZERO),
+            Duration::from_secs(u64::from(STALE_ANSWER_TTL))
+        );
+        assert!(too_old.is_none());
+        assert!(cache.lookup(&query).is_none());
+        assert_eq!(cache.len(), 0);
+    }
+
+    #[test]
+    fn sweep_drops_expired_entries_without_lookups() {
+        let cache = DnsCache::new()
+            .with_stale_ttl(Duration::from_secs(10))
+            .with_pinned(SuffixSet::new(["pinned.example"]));
+        let queries: Vec<_> = ["gone", "stale", "fresh", "pinned"]
+            .iter()
+            .map(|name| DnsQuery::new(1, &format!("{}.example", name), TYPE_A))
+            .collect();
+        for query in &queries {
+            cache.put(
+                query,
+                &query
+                    .blocked_response(BlockedResponseStyle::NullIp)
+                    .to_bytes(),
+            );
+        }
+
+        cache.expire(&queries[0], Duration::from_secs(20));
+        cache.expire(&queries[1], Duration::from_secs(5));
+        cache.expire(&queries[3], Duration::from_secs(20));
+        let dropped = cache.sweep_expired();
+        cache.expire(&queries[1], Duration::from_secs(20));
+        let dropped_later = cache.sweep_expired();
+
+        assert_eq!(dropped, 1);
+        assert_eq!(dropped_later, 1);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.lookup(&queries[2]).is_some());
+        assert_eq!(cache.pinned_expiring(Duration::ZERO).len(), 1);
+    }
+
+    #[test]
+    fn shards_split_the_capacity_and_keep_entries_when_resized() {
+        let cache = DnsCache::with_capacity(4096).with_shards(16);
+        let queries: Vec<_> = (0..10_000)
+            .map(|i| DnsQuery::new(1, &format!("host{}.example", i), TYPE_A))
+            .collect();
+        for query in &queries {
+            cache.put(
+                query,
+                &query
+                    .blocked_response(BlockedResponseStyle::NullIp)
+                    .to_bytes(),
+            );
+        }
+
+        assert_eq!(cache.shards.len(), 16);
+        assert!(cache.len() <= 4096);
+        let before = cache.len();
+        let resharded = cache.with_shards(4);
+        assert_eq!(resharded.shards.len(), 4);
+        assert_eq!(resharded.len(), before);
+        assert!(resharded.get(&queries[9_999]).is_some());
+    }
+}
@@ -0,0 +1,302 @@
+//! Configuration file (`detour --config detour.toml`).
+//!
+//! The file sets the same options as the command line, under the flag names
+//! (`cache-size = 20000` for `--cache-size 20000`); a flag given on the
+//! command line wins over the file. Upstreams may be written in `--upstream`
+//! syntax or as tables naming their protocol, which the command line can only
+//! express through URL schemes; local records likewise as a string or a
+//! table. See `detour.toml` in the repository for a documented example.
+
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+use crate::resolver::{DEFAULT_LOCAL_TTL, LocalRecord};
+
+/// Settings read from a configuration file; unset keys are None.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ConfigFile {
+    /// Bind addresses, one or a list
+    pub bind: Option<BindEntry>,
+    /// Local port to listen on
+    pub port: Option<u16>,
+    /// Upstream DNS servers
+    pub upstreams: Option<Vec<UpstreamEntry>>,
+    /// Print verbose logging
+    pub verbose: Option<bool>,
+    /// Number of worker threads
+    pub workers: Option<usize>,
+    /// Blocklist files used on top of the built-in lists
+    pub blocklists: Option<Vec<String>>,
+    /// Leave out the built-in lists
+    pub no_embedded_lists: Option<bool>,
+    /// Maximum number of cached responses
+    pub cache_size: Option<usize>,
+    /// Serve Prometheus metrics on this port
+    pub metrics_port: Option<u16>,
+    /// Records answered authoritatively instead of forwarded
+    #[serde(default, deserialize_with = "local_records")]
+    pub local_records: Option<Vec<LocalRecord>>,
+}
+
+/// A single bind address or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum BindEntry {
+    /// `bind = "127.0.0.1"`
+    One(String),
+    /// `bind = ["127.0.0.1", "::1"]`
+    Many(Vec<String>),
+}
+
+impl BindEntry {
+    /// The addresses, as repeated `--bind` flags would give them.
+    pub fn into_addresses(self) -> Vec<String> {
+        match self {
+            BindEntry::One(addr) => vec![addr],
+            BindEntry::Many(addrs) => addrs,
+        }
+    }
+}
+
+/// An upstream, in `--upstream` syntax or as a table.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum UpstreamEntry {
+    /// `ip[:port]`, `tls://host[:port]` etc., exactly as for `--upstream`
+    Spec(String),
+    /// `{ address = "dns.example", protocol = "tls", max-qps = 100 }`
+    Table(UpstreamTable),
+}
+
+/// An upstream written as a table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct UpstreamTable {
+    /// `ip[:port]` for plain DNS, `host[:port]` for the encrypted protocols
+    pub address: String,
+    #[serde(default)]
+    pub protocol: UpstreamProtocolName,
+    /// URL path for `https` (default `/dns-query`)
+    pub path: Option<String>,
+    /// Cap on queries per second sent to this upstream
+    pub max_qps: Option<u32>,
+}
+
+/// Protocol of an upstream table.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UpstreamProtocolName {
+    /// Plain DNS over UDP, falling back to TCP
+    #[default]
+    Udp,
+    /// DNS-over-TLS
+    Tls,
+    /// DNS-over-HTTPS
+    Https,
+    /// DNS-over-QUIC
+    Quic,
+}
+
+impl UpstreamEntry {
+    /// The upstream in `--upstream` syntax.
+    pub fn to_spec(&self) -> String {
+        let table = match self {
+            UpstreamEntry::Spec(spec) => return spec.clone(),
+            UpstreamEntry::Table(table) => table,
+        };
+        let scheme = match table.protocol {
+            UpstreamProtocolName::Udp => "",
+            UpstreamProtocolName::Tls => "tls://",
+            UpstreamProtocolName::Https => "https://",
+            UpstreamProtocolName::Quic => "quic://",
+        };
+        let mut spec = format!("{}{}", scheme, table.address);
+        if let Some(path) = &table.path {
+            spec.push_str(path);
+        }
+        if let Some(max_qps) = table.max_qps {
+            spec.push_str(&format!("@maxqps={}", max_qps));
+        }
+        spec
+    }
+}
+
+/// A local record, in `--local-record` syntax or as a table.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum LocalRecordEntry {
+    /// `name [ttl] type value`, exactly as for `--local-record`
+    Spec(String),
+    /// `{ name = "router.home.arpa", type = "A", value = "192.168.1.1" }`
+    Table(LocalRecordTable),
+}
+
+/// A local record written as a table.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LocalRecordTable {
+    name: String,
+    #[serde(rename = "type")]
+    rtype: String,
+    value: String,
+    #[serde(default = "default_local_ttl")]
+    ttl: u32,
+}
+
+fn default_local_ttl() -> u32 {
+    DEFAULT_LOCAL_TTL
+}
+
+fn local_records<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<LocalRecord>>, D::Error> {
+    Vec::<LocalRecordEntry>::deserialize(deserializer)?
+        .into_iter()
+        .map(|entry| match entry {
+            LocalRecordEntry::Spec(spec) => LocalRecord::parse(&spec),
+            LocalRecordEntry::Table(table) => {
+                LocalRecord::new(&table.name, table.ttl, &table.rtype, &table.value)
+            }
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+        .map_err(serde::de::Error::custom)
+}
+
+/// Error reading a configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file could not be read.
+    Io(io::Error),
+    /// The file is not valid TOML or does not match the schema.
+    Parse(String),
+    /// A value is out of range or inconsistent.
+    Invalid(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "cannot read config file: {}", e),
+            ConfigError::Parse(message) => write!(f, "invalid config file: {}", message),
+            ConfigError::Invalid(message) => write!(f, "invalid config file: {}", message),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigFile {
+    /// Read and check a configuration file.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(ConfigError::Io)?;
+        Self::parse(&content)
+    }
+
+    /// Parse a configuration file's contents.
+    pub fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Self =
+            toml::from_str(content).map_err(|e| ConfigError::Parse(e.message().to_string()))?;
+        for upstream in config.upstreams.iter().flatten() {
+            if let UpstreamEntry::Table(table) = upstream
+                && table.path.is_some()
+                && table.protocol != UpstreamProtocolName::Https
+            {
+                return Err(ConfigError::Invalid(format!(
+                    "upstream {}: path is only valid with protocol = \"https\"",
+                    table.address
+                )));
+            }
+        }
+        if let Some(BindEntry::Many(addrs)) = &config.bind
+            && addrs.is_empty()
+        {
+            return Err(ConfigError::Invalid("bind is empty".to_string()));
+        }
+        if config.upstreams.as_ref().is_some_and(Vec::is_empty) {
+            return Err(ConfigError::Invalid("upstreams is empty".to_string()));
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn example_config_parses_and_upstream_tables_become_specs() {
+        let example = ConfigFile::parse(include_str!("../detour.toml")).unwrap();
+        let config = ConfigFile::parse(
+            "port = 5353\n\
+             upstreams = [\n\
+                 \"9.9.9.9\",\n\
+                 { address = \"dns.example\", protocol = \"https\", path = \"/q\", max-qps = 50 },\n\
+             ]\n",
+        )
+        .unwrap();
+
+        let specs: Vec<String> = config
+            .upstreams
+            .unwrap()
+            .iter()
+            .map(UpstreamEntry::to_spec)
+            .collect();
+
+        assert!(example.upstreams.is_some());
+        assert_eq!(config.port, Some(5353));
+        assert_eq!(specs, ["9.9.9.9", "https://dns.example/q@maxqps=50"]);
+        assert!(matches!(
+            ConfigFile::parse("prot = 53"),
+            Err(ConfigError::Parse(_))
+        ));
+        assert!(matches!(
+            ConfigFile::parse("upstreams = [{ address = \"1.1.1.1\", path = \"/q\" }]"),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn local_records_are_strings_or_tables() {
+        let config = ConfigFile::parse(
+            "local-records = [\n\
+                 \"router.home.arpa A 192.168.1.1\",\n\
+                 { name = \"nas.home.arpa\", type = \"AAAA\", value = \"fd00::2\", ttl = 60 },\n\
+             ]\n",
+        )
+        .unwrap();
+
+        let records = config.local_records.unwrap();
+
+        assert_eq!(
+            records,
+            [
+                LocalRecord::new("router.home.arpa", DEFAULT_LOCAL_TTL, "A", "192.168.1.1")
+                    .unwrap(),
+                LocalRecord::new("nas.home.arpa", 60, "AAAA", "fd00::2").unwrap(),
+            ]
+        );
+        assert!(matches!(
+            ConfigFile::parse(
+                "local-records = [{ name = \"nas\", type = \"A\", value = \"fd00::2\" }]"
+            ),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn bind_takes_one_address_or_a_list() {
+        let one = ConfigFile::parse("bind = \"0.0.0.0\"").unwrap();
+        let many = ConfigFile::parse("bind = [\"127.0.0.1\", \"::1\"]").unwrap();
+
+        assert_eq!(one.bind.unwrap().into_addresses(), ["0.0.0.0"]);
+        assert_eq!(many.bind.unwrap().into_addresses(), ["127.0.0.1", "::1"]);
+        assert!(matches!(
+            ConfigFile::parse("bind = []"),
+            Err(ConfigError::Invalid(_))
+        ));
+    }
+}
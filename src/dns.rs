@@ -1,9 +1,52 @@
 //! DNS message parsing and construction.
 
+use std::borrow::Cow;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 use std::time::Duration;
 
+use crate::filter::BlockedResponseStyle;
+
 const HEADER_LEN: usize = 12;
 
+/// Record type A (IPv4 address).
+pub const TYPE_A: u16 = 1;
+/// Record type NS (authoritative name server).
+pub const TYPE_NS: u16 = 2;
+/// Record type CNAME (canonical name).
+pub const TYPE_CNAME: u16 = 5;
+/// Record type SOA (start of authority).
+pub const TYPE_SOA: u16 = 6;
+/// Record type TXT (text strings).
+pub const TYPE_TXT: u16 = 16;
+/// Record type AAAA (IPv6 address).
+pub const TYPE_AAAA: u16 = 28;
+/// Record type OPT (EDNS(0) pseudo-record).
+pub const TYPE_OPT: u16 = 41;
+/// Record type HTTPS (service binding for HTTPS).
+pub const TYPE_HTTPS: u16 = 65;
+/// Class IN (Internet).
+pub const CLASS_IN: u16 = 1;
+/// Class CH (CHAOS), used for server identification queries.
+pub const CLASS_CH: u16 = 3;
+
+/// Default TTL of synthesized answers for blocked domains (`--block-ttl`).
+pub const DEFAULT_BLOCKED_TTL: u32 = 300;
+/// Largest accepted `--block-ttl`, one day.
+pub const MAX_BLOCKED_TTL: u32 = 86400;
+
+/// UDP payload size advertised in the OPT record of locally built answers.
+const EDNS_PAYLOAD_SIZE: u16 = 1232;
+/// DO (DNSSEC OK) bit in the OPT record's TTL field.
+const EDNS_DNSSEC_OK: u32 = 0x8000;
+/// EDNS option code of Client Subnet (RFC 7871).
+pub const OPTION_CLIENT_SUBNET: u16 = 8;
+
+/// Maximum length of a domain name in presentation format.
+const MAX_DOMAIN_LEN: usize = 253;
+/// Maximum length of a single label.
+const MAX_LABEL_LEN: usize = 63;
+
 /// A parsed DNS query.
 #[derive(Debug, Clone)]
 pub struct DnsQuery {
@@ -11,43 +54,169 @@ pub struct DnsQuery {
     pub domain: String,
     pub qtype: u16,
     pub qclass: u16,
+    /// The client's EDNS(0) OPT record, if it sent one.
+    pub edns_opt: Option<EdnsOpt>,
 }
 
-impl DnsQuery {
-    /// Parse a DNS query from raw bytes.
-    /// Domain is normalized to ASCII lowercase in a single pass.
-    pub fn parse(data: &[u8]) -> Option<Self> {
-        if data.len() < HEADER_LEN + 1 {
-            return None;
+/// An EDNS(0) OPT pseudo-record (RFC 6891).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EdnsOpt {
+    /// Largest UDP message the sender can receive
+    pub udp_payload_size: u16,
+    /// The sender wants DNSSEC records (DO bit)
+    pub dnssec_ok: bool,
+    /// EDNS options as (code, data)
+    pub options: Vec<(u16, Vec<u8>)>,
+}
+
+impl EdnsOpt {
+    fn from_record(record: &DnsRecord) -> Option<Self> {
+        let mut options = Vec::new();
+        let mut rest = &record.rdata[..];
+        while !rest.is_empty() {
+            let header = rest.get(..4)?;
+            let code = u16::from_be_bytes([header[0], header[1]]);
+            let len = u16::from_be_bytes([header[2], header[3]]) as usize;
+            options.push((code, rest.get(4..4 + len)?.to_vec()));
+            rest = &rest[4 + len..];
         }
+        Some(Self {
+            udp_payload_size: record.class,
+            dnssec_ok: record.ttl & EDNS_DNSSEC_OK != 0,
+            options,
+        })
+    }
 
-        let id = u16::from_be_bytes([data[0], data[1]]);
+    /// The OPT record of an answer built here for a query carrying this one:
+    /// our own payload size, the DO bit echoed and no options.
+    pub fn reply(&self) -> Self {
+        Self {
+            udp_payload_size: EDNS_PAYLOAD_SIZE,
+            dnssec_ok: self.dnssec_ok,
+            options: Vec::new(),
+        }
+    }
 
-        let mut pos = HEADER_LEN;
-        let mut domain = String::with_capacity(64);
-        let mut first_label = true;
+    fn encode(&self, buf: &mut Vec<u8>) {
+        let flags = if self.dnssec_ok { EDNS_DNSSEC_OK } else { 0 };
+        let rdlength: usize = self.options.iter().map(|(_, data)| 4 + data.len()).sum();
+        buf.push(0); // Root name
+        buf.extend_from_slice(&TYPE_OPT.to_be_bytes());
+        buf.extend_from_slice(&self.udp_payload_size.to_be_bytes());
+        buf.extend_from_slice(&flags.to_be_bytes());
+        buf.extend_from_slice(&(rdlength as u16).to_be_bytes());
+        for (code, data) in &self.options {
+            buf.extend_from_slice(&code.to_be_bytes());
+            buf.extend_from_slice(&(data.len() as u16).to_be_bytes());
+            buf.extend_from_slice(data);
+        }
+    }
+}
 
-        while pos < data.len() {
-            let label_len = data[pos] as usize;
-            pos += 1;
-            if label_len == 0 {
-                break;
+/// An EDNS Client Subnet option (RFC 7871): the network a query came from,
+/// so upstreams can pick answers close to the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EdnsClientSubnet {
+    pub address: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl EdnsClientSubnet {
+    /// The subnet of `address` `prefix_len` bits long (capped at the address
+    /// length), with the host bits cleared. IPv4-mapped IPv6 addresses are
+    /// treated as IPv4.
+    pub fn new(address: IpAddr, prefix_len: u8) -> Self {
+        let address = address.to_canonical();
+        let prefix_len = match address {
+            IpAddr::V4(_) => prefix_len.min(32),
+            IpAddr::V6(_) => prefix_len.min(128),
+        };
+        let address = match address {
+            IpAddr::V4(v4) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V4(Ipv4Addr::from(u32::from(v4) & mask))
             }
-            if pos + label_len > data.len() {
-                return None;
+            IpAddr::V6(v6) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(prefix_len))
+                    .unwrap_or(0);
+                IpAddr::V6(Ipv6Addr::from(u128::from(v6) & mask))
             }
+        };
+        Self {
+            address,
+            prefix_len,
+        }
+    }
 
-            if !first_label {
-                domain.push('.');
-            } else {
-                first_label = false;
-            }
+    /// The option as (code, data): family, source prefix length, scope
+    /// prefix length 0 and only the address bytes the prefix covers.
+    pub fn to_option(&self) -> (u16, Vec<u8>) {
+        let (family, octets): (u16, Vec<u8>) = match self.address {
+            IpAddr::V4(v4) => (1, v4.octets().to_vec()),
+            IpAddr::V6(v6) => (2, v6.octets().to_vec()),
+        };
+        let mut data = Vec::with_capacity(4 + octets.len());
+        data.extend_from_slice(&family.to_be_bytes());
+        data.push(self.prefix_len);
+        data.push(0); // Scope prefix length, set by the server
+        data.extend_from_slice(&octets[..usize::from(self.prefix_len).div_ceil(8)]);
+        (OPTION_CLIENT_SUBNET, data)
+    }
+}
 
-            for &b in &data[pos..pos + label_len] {
-                domain.push((b as char).to_ascii_lowercase());
-            }
-            pos += label_len;
+impl DnsQuery {
+    /// Create a recursive IN-class query for a domain.
+    pub fn new(id: u16, domain: &str, qtype: u16) -> Self {
+        Self {
+            id,
+            domain: domain.to_ascii_lowercase(),
+            qtype,
+            qclass: CLASS_IN,
+            edns_opt: None,
         }
+    }
+
+    /// Encode the query to wire format bytes (recursion desired), with its
+    /// OPT record if it has one.
+    ///
+    /// Returns `None` if the domain is not a valid DNS name; `.` is the root.
+    pub fn to_bytes(&self) -> Option<Vec<u8>> {
+        let domain = match self.domain.as_str() {
+            "." => "",
+            domain if is_valid_domain(domain) => domain,
+            _ => return None,
+        };
+
+        let mut data = Vec::with_capacity(HEADER_LEN + domain.len() + 6);
+        data.extend_from_slice(&self.id.to_be_bytes());
+        data.extend_from_slice(&[0x01, 0x00]); // Flags: standard query, RD
+        data.extend_from_slice(&[0x00, 0x01]); // QDCOUNT
+        data.extend_from_slice(&[0x00, 0x00]); // ANCOUNT
+        data.extend_from_slice(&[0x00, 0x00]); // NSCOUNT
+        data.extend_from_slice(&(self.edns_opt.is_some() as u16).to_be_bytes()); // ARCOUNT
+        encode_domain(&mut data, domain);
+        data.extend_from_slice(&self.qtype.to_be_bytes());
+        data.extend_from_slice(&self.qclass.to_be_bytes());
+        if let Some(opt) = &self.edns_opt {
+            opt.encode(&mut data);
+        }
+        Some(data)
+    }
+
+    /// Parse a DNS query from raw bytes.
+    /// Domain is normalized to ASCII lowercase in a single pass; compressed
+    /// names are followed (see [`read_name`]). An OPT record in the
+    /// additional section is kept in `edns_opt`; a malformed one is ignored.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN + 1 {
+            return None;
+        }
+
+        let id = u16::from_be_bytes([data[0], data[1]]);
+        let (domain, pos) = read_name(data, HEADER_LEN)?;
 
         if domain.is_empty() || pos + 4 > data.len() {
             return None;
@@ -55,28 +224,63 @@ impl DnsQuery {
 
         let qtype = u16::from_be_bytes([data[pos], data[pos + 1]]);
         let qclass = u16::from_be_bytes([data[pos + 2], data[pos + 3]]);
+        let ancount = u16::from_be_bytes([data[6], data[7]]) as usize;
+        let nscount = u16::from_be_bytes([data[8], data[9]]) as usize;
+        let edns_opt = read_opt(data, pos + 4, ancount + nscount);
 
         Some(Self {
             id,
             domain,
             qtype,
             qclass,
+            edns_opt,
         })
     }
 
-    /// Create a blocked response (returns 0.0.0.0).
-    pub fn blocked_response(&self) -> DnsResponse {
-        DnsResponse::blocked(self)
+    /// Whether `other` asks the same question: name (ignoring case), type
+    /// and class.
+    pub fn same_question(&self, other: &DnsQuery) -> bool {
+        self.qtype == other.qtype
+            && self.qclass == other.qclass
+            && self.domain.eq_ignore_ascii_case(&other.domain)
+    }
+
+    /// Create a blocked response in the given style, with the default TTL.
+    pub fn blocked_response(&self, style: BlockedResponseStyle) -> DnsResponse {
+        DnsResponse::blocked(self, style, DEFAULT_BLOCKED_TTL)
+    }
+
+    /// Create an NXDOMAIN response echoing the question.
+    pub fn nxdomain_response(&self) -> DnsResponse {
+        DnsResponse::nxdomain(self)
+    }
+
+    /// Create a REFUSED response echoing the question.
+    pub fn refused_response(&self) -> DnsResponse {
+        DnsResponse::refused(self)
+    }
+
+    /// Create a SERVFAIL response echoing the question.
+    pub fn servfail_response(&self) -> DnsResponse {
+        DnsResponse::servfail(self)
     }
 
     /// Create a response from cached data, updating the transaction ID.
+    ///
+    /// Cached responses carry no OPT record (see [`strip_opt`]); one is
+    /// added if this query has EDNS.
     pub fn response_from_cache(&self, cached: &[u8]) -> Option<Vec<u8>> {
-        if cached.len() < 2 {
+        if cached.len() < HEADER_LEN {
             return None;
         }
         let mut response = cached.to_vec();
         response[0] = (self.id >> 8) as u8;
         response[1] = (self.id & 0xFF) as u8;
+        if let Some(opt) = &self.edns_opt {
+            let arcount = u16::from_be_bytes([response[10], response[11]]) + 1;
+            response[10..12].copy_from_slice(&arcount.to_be_bytes());
+            opt.reply().encode(&mut response);
+        }
         Some(response)
     }
 }
@@ -88,6 +292,10 @@ pub struct DnsResponse {
     pub flags: u16,
     pub questions: Vec<DnsQuestion>,
     pub answers: Vec<DnsRecord>,
+    /// Authority section (e.g. the SOA of a negative answer).
+    pub authority: Vec<DnsRecord>,
+    /// OPT record, the only additional record kept.
+    pub edns_opt: Option<EdnsOpt>,
 }
 
 /// A DNS question section entry.
@@ -99,7 +307,7 @@ pub struct DnsQuestion {
 }
 
 /// A DNS resource record.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DnsRecord {
     pub name: String,
     pub rtype: u16,
@@ -108,9 +316,88 @@ pub struct DnsRecord {
     pub rdata: Vec<u8>,
 }
 
+/// Typed record data decoded from a [`DnsRecord`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Cname(String),
+    /// Any record type without a dedicated decoder (raw RDATA).
+    Other {
+        rtype: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl fmt::Display for RData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RData::A(addr) => write!(f, "A {}", addr),
+            RData::Aaaa(addr) => write!(f, "AAAA {}", addr),
+            RData::Cname(name) => write!(f, "CNAME {}", name),
+            RData::Other { rtype, data } => write!(f, "TYPE{} ({} bytes)", rtype, data.len()),
+        }
+    }
+}
+
+impl DnsRecord {
+    /// Decode the record data into its typed form.
+    pub fn data(&self) -> RData {
+        match (self.rtype, self.rdata.len()) {
+            (TYPE_A, 4) => {
+                let octets: [u8; 4] = self.rdata[..].try_into().unwrap_or_default();
+                RData::A(Ipv4Addr::from(octets))
+            }
+            (TYPE_AAAA, 16) => {
+                let octets: [u8; 16] = self.rdata[..].try_into().unwrap_or_default();
+                RData::Aaaa(Ipv6Addr::from(octets))
+            }
+            (TYPE_CNAME, _) => match read_name(&self.rdata, 0) {
+                Some((name, _)) => RData::Cname(name),
+                None => RData::Other {
+                    rtype: self.rtype,
+                    data: self.rdata.clone(),
+                },
+            },
+            _ => RData::Other {
+                rtype: self.rtype,
+                data: self.rdata.clone(),
+            },
+        }
+    }
+}
+
 impl DnsResponse {
-    /// Create a blocked response (0.0.0.0) for a query.
-    pub fn blocked(query: &DnsQuery) -> Self {
+    /// Create a response for a blocked query in the given style, cacheable
+    /// by clients for `ttl` seconds.
+    ///
+    /// Address styles answer A queries with the IPv4 address and AAAA
+    /// queries with the IPv6 one; any other type gets NODATA with an SOA, so
+    /// clients cache the empty answer instead of retrying. REFUSED only
+    /// echoes the question.
+    pub fn blocked(query: &DnsQuery, style: BlockedResponseStyle, ttl: u32) -> Self {
+        let (ipv4, ipv6) = match style {
+            BlockedResponseStyle::Nxdomain => {
+                let mut response = Self::nxdomain(query);
+                response.authority.push(blocked_soa(&query.domain, ttl));
+                return response;
+            }
+            BlockedResponseStyle::Refused => return Self::refused(query),
+            BlockedResponseStyle::NullIp => (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED),
+            BlockedResponseStyle::CustomIp(ipv4, ipv6) => (ipv4, ipv6),
+        };
+        let mut answers = Vec::new();
+        let mut authority = Vec::new();
+        match query.qtype {
+            TYPE_A => answers.push(blocked_record(query, TYPE_A, ipv4.octets().to_vec(), ttl)),
+            TYPE_AAAA => answers.push(blocked_record(
+                query,
+                TYPE_AAAA,
+                ipv6.octets().to_vec(),
+                ttl,
+            )),
+            _ => authority.push(blocked_soa(&query.domain, ttl)),
+        }
         Self {
             id: query.id,
             flags: 0x8180, // Standard response, recursion available, no error
@@ -119,14 +406,242 @@ impl DnsResponse {
                 qtype: query.qtype,
                 qclass: query.qclass,
             }],
+            answers,
+            authority,
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Create an NXDOMAIN response (no answers) for a query.
+    pub fn nxdomain(query: &DnsQuery) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8183, // Standard response, recursion available, NXDOMAIN
+            questions: vec![DnsQuestion {
+                domain: query.domain.clone(),
+                qtype: query.qtype,
+                qclass: query.qclass,
+            }],
+            answers: Vec::new(),
+            authority: Vec::new(),
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Create a REFUSED response (no answers) for a query.
+    pub fn refused(query: &DnsQuery) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8185, // Standard response, recursion available, REFUSED
+            questions: vec![DnsQuestion {
+                domain: query.domain.clone(),
+                qtype: query.qtype,
+                qclass: query.qclass,
+            }],
+            answers: Vec::new(),
+            authority: Vec::new(),
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Create a SERVFAIL response (no answers) for a query.
+    pub fn servfail(query: &DnsQuery) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8182, // Standard response, recursion available, SERVFAIL
+            questions: vec![DnsQuestion {
+                domain: query.domain.clone(),
+                qtype: query.qtype,
+                qclass: query.qclass,
+            }],
+            answers: Vec::new(),
+            authority: Vec::new(),
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Create a CHAOS-class TXT answer (e.g. for `version.bind`).
+    pub fn chaos_txt(query: &DnsQuery, text: &str) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8580, // Authoritative response, recursion available, no error
+            questions: vec![DnsQuestion {
+                domain: query.domain.clone(),
+                qtype: query.qtype,
+                qclass: query.qclass,
+            }],
             answers: vec![DnsRecord {
                 name: query.domain.clone(),
-                rtype: 1, // A record
-                class: 1, // IN
-                ttl: 300,
-                rdata: vec![0, 0, 0, 0], // 0.0.0.0
+                rtype: TYPE_TXT,
+                class: CLASS_CH,
+                ttl: 0,
+                rdata: encode_txt(text),
+            }],
+            authority: Vec::new(),
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Create an authoritative answer (AA set) carrying `answers`.
+    pub fn authoritative(query: &DnsQuery, answers: Vec<DnsRecord>) -> Self {
+        Self {
+            id: query.id,
+            flags: 0x8580, // Authoritative response, recursion available, no error
+            questions: vec![DnsQuestion {
+                domain: query.domain.clone(),
+                qtype: query.qtype,
+                qclass: query.qclass,
             }],
+            answers,
+            authority: Vec::new(),
+            edns_opt: query.edns_opt.as_ref().map(EdnsOpt::reply),
+        }
+    }
+
+    /// Parse a response from wire format (header, questions, answers, authority
+    /// and the OPT record).
+    ///
+    /// Compressed names are expanded, including those inside CNAME RDATA,
+    /// so the resulting records are self-contained.
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        if data.len() < HEADER_LEN {
+            return None;
+        }
+
+        let id = u16::from_be_bytes([data[0], data[1]]);
+        let flags = u16::from_be_bytes([data[2], data[3]]);
+        let qdcount = u16::from_be_bytes([data[4], data[5]]) as usize;
+        let ancount = u16::from_be_bytes([data[6], data[7]]) as usize;
+        let nscount = u16::from_be_bytes([data[8], data[9]]) as usize;
+
+        let mut pos = HEADER_LEN;
+        let mut questions = Vec::with_capacity(qdcount);
+        for _ in 0..qdcount {
+            let (domain, next) = read_name(data, pos)?;
+            let fixed = data.get(next..next + 4)?;
+            questions.push(DnsQuestion {
+                domain,
+                qtype: u16::from_be_bytes([fixed[0], fixed[1]]),
+                qclass: u16::from_be_bytes([fixed[2], fixed[3]]),
+            });
+            pos = next + 4;
+        }
+
+        let mut answers = Vec::with_capacity(ancount);
+        for _ in 0..ancount {
+            let (record, next) = read_record(data, pos)?;
+            answers.push(record);
+            pos = next;
+        }
+
+        let mut authority = Vec::with_capacity(nscount);
+        for _ in 0..nscount {
+            let (record, next) = read_record(data, pos)?;
+            authority.push(record);
+            pos = next;
+        }
+
+        Some(Self {
+            id,
+            flags,
+            questions,
+            answers,
+            authority,
+            edns_opt: read_opt(data, pos, 0),
+        })
+    }
+
+    /// Response code from the header flags (0 = NOERROR, 3 = NXDOMAIN, ...).
+    pub fn rcode(&self) -> u8 {
+        (self.flags & 0x000F) as u8
+    }
+
+    /// Whether a wire-format response carries no answers (NXDOMAIN or NODATA).
+    pub fn is_negative(response: &[u8]) -> bool {
+        response.len() >= HEADER_LEN && response[6] == 0 && response[7] == 0
+    }
+
+    /// Negative caching TTL of an NXDOMAIN/NODATA response (RFC 2308).
+    ///
+    /// The lower of the authority SOA's own TTL and its MINIMUM field; None if
+    /// the response has answers, another rcode, or no SOA.
+    pub fn negative_ttl(response: &[u8]) -> Option<Duration> {
+        let parsed = Self::parse(response)?;
+        if !parsed.answers.is_empty() || !matches!(parsed.rcode(), 0 | 3) {
+            return None;
+        }
+        let soa = parsed.authority.iter().find(|r| r.rtype == TYPE_SOA)?;
+        // MINIMUM is the last field and is never compressed.
+        let minimum = soa.rdata.get(soa.rdata.len().checked_sub(4)?..)?;
+        let minimum = u32::from_be_bytes(minimum.try_into().ok()?);
+        Some(Duration::from_secs(u64::from(soa.ttl.min(minimum))))
+    }
+
+    /// CNAME targets of a wire-format response, in chain order from the
+    /// question's name; empty if it does not parse or has no CNAME.
+    pub fn parse_cname_chain(response: &[u8]) -> Vec<String> {
+        Self::parse(response).map_or_else(Vec::new, |parsed| parsed.cname_chain())
+    }
+
+    /// CNAME targets in the answer section, in chain order from the
+    /// question's name.
+    ///
+    /// Links are followed by owner name rather than record order, and the
+    /// chain is at most as long as the answer section, so a CNAME loop ends.
+    pub fn cname_chain(&self) -> Vec<String> {
+        let mut chain: Vec<String> = Vec::new();
+        let Some(question) = self.questions.first() else {
+            return chain;
+        };
+        while chain.len() < self.answers.len() {
+            let name = chain.last().unwrap_or(&question.domain);
+            let target = self
+                .answers
+                .iter()
+                .filter(|record| record.rtype == TYPE_CNAME && record.name == *name)
+                .find_map(|record| match record.data() {
+                    RData::Cname(target) => Some(target),
+                    _ => None,
+                });
+            match target {
+                Some(target) => chain.push(target),
+                None => break,
+            }
         }
+        chain
+    }
+
+    /// A response for each name the question's CNAME chain leads to,
+    /// answering it with the records owned by that name and the names after
+    /// it, so the rest of the chain can be cached under its own names.
+    pub fn cname_target_responses(&self) -> Vec<(String, DnsResponse)> {
+        let Some(question) = self.questions.first() else {
+            return Vec::new();
+        };
+        let chain = self.cname_chain();
+        (0..chain.len())
+            .map(|i| {
+                let rest = &chain[i..];
+                let response = DnsResponse {
+                    id: self.id,
+                    flags: self.flags,
+                    questions: vec![DnsQuestion {
+                        domain: chain[i].clone(),
+                        qtype: question.qtype,
+                        qclass: question.qclass,
+                    }],
+                    answers: self
+                        .answers
+                        .iter()
+                        .filter(|record| rest.contains(&record.name))
+                        .cloned()
+                        .collect(),
+                    authority: Vec::new(),
+                    edns_opt: None,
+                };
+                (chain[i].clone(), response)
+            })
+            .collect()
     }
 
     /// Encode the response to wire format bytes.
@@ -138,23 +653,23 @@ impl DnsResponse {
         data.extend_from_slice(&self.flags.to_be_bytes());
         data.extend_from_slice(&(self.questions.len() as u16).to_be_bytes());
         data.extend_from_slice(&(self.answers.len() as u16).to_be_bytes());
-        data.extend_from_slice(&[0x00, 0x00]); // NSCOUNT
-        data.extend_from_slice(&[0x00, 0x00]); // ARCOUNT
+        data.extend_from_slice(&(self.authority.len() as u16).to_be_bytes());
+        data.extend_from_slice(&(self.edns_opt.is_some() as u16).to_be_bytes()); // ARCOUNT
 
         // Questions
         for q in &self.questions {
-            Self::encode_domain(&mut data, &q.domain);
+            encode_domain(&mut data, &q.domain);
             data.extend_from_slice(&q.qtype.to_be_bytes());
             data.extend_from_slice(&q.qclass.to_be_bytes());
         }
 
-        // Answers
-        for a in &self.answers {
+        // Answers, then authority
+        for a in self.answers.iter().chain(&self.authority) {
             // Use compression pointer if this is the first question's domain
             if !self.questions.is_empty() && a.name == self.questions[0].domain {
                 data.extend_from_slice(&[0xC0, 0x0C]); // Pointer to offset 12
             } else {
-                Self::encode_domain(&mut data, &a.name);
+                encode_domain(&mut data, &a.name);
             }
             data.extend_from_slice(&a.rtype.to_be_bytes());
             data.extend_from_slice(&a.class.to_be_bytes());
@@ -163,18 +678,18 @@ impl DnsResponse {
             data.extend_from_slice(&a.rdata);
         }
 
-        data
-    }
-
-    fn encode_domain(buf: &mut Vec<u8>, domain: &str) {
-        for label in domain.split('.') {
-            buf.push(label.len() as u8);
-            buf.extend_from_slice(label.as_bytes());
+        if let Some(opt) = &self.edns_opt {
+            opt.encode(&mut data);
         }
-        buf.push(0);
+        data
     }
 
-    /// Parse TTL from a response, returning the minimum TTL across all records.
+    /// Parse TTL from a response, returning the minimum TTL across its
+    /// records.
+    ///
+    /// Only answer records count when there are any, so NS or SOA records
+    /// in the authority section cannot cut a positive answer short; OPT
+    /// records never count.
     pub fn parse_min_ttl(response: &[u8], default: Duration) -> Duration {
         if response.len() < HEADER_LEN {
             return default;
@@ -183,7 +698,11 @@ impl DnsResponse {
         let ancount = u16::from_be_bytes([response[6], response[7]]) as usize;
         let nscount = u16::from_be_bytes([response[8], response[9]]) as usize;
         let arcount = u16::from_be_bytes([response[10], response[11]]) as usize;
-        let total_rrs = ancount + nscount + arcount;
+        let total_rrs = if ancount > 0 {
+            ancount
+        } else {
+            nscount + arcount
+        };
 
         if total_rrs == 0 {
             return default;
@@ -231,13 +750,17 @@ impl DnsResponse {
                 break;
             }
 
-            let ttl = u32::from_be_bytes([
-                response[pos + 4],
-                response[pos + 5],
-                response[pos + 6],
-                response[pos + 7],
-            ]);
-            min_ttl = min_ttl.min(ttl);
+            // An OPT record's TTL field holds EDNS flags, not a TTL
+            let rtype = u16::from_be_bytes([response[pos], response[pos + 1]]);
+            if rtype != TYPE_OPT {
+                let ttl = u32::from_be_bytes([
+                    response[pos + 4],
+                    response[pos + 5],
+                    response[pos + 6],
+                    response[pos + 7],
+                ]);
+                min_ttl = min_ttl.min(ttl);
+            }
 
             let rdlength = u16::from_be_bytes([response[pos + 8], response[pos + 9]]) as usize;
             pos += 10 + rdlength;
@@ -250,3 +773,739 @@ impl DnsResponse {
         }
     }
 }
+
+pub(crate) fn encode_domain(buf: &mut Vec<u8>, domain: &str) {
+    if !domain.is_empty() {
+        for label in domain.split('.') {
+            buf.push(label.len() as u8);
+            buf.extend_from_slice(label.as_bytes());
+        }
+    }
+    buf.push(0);
+}
+
+/// Encode text as TXT RDATA: a sequence of length-prefixed strings of up to 255 bytes.
+pub(crate) fn encode_txt(text: &str) -> Vec<u8> {
+    let bytes = text.as_bytes();
+    if bytes.is_empty() {
+        return vec![0];
+    }
+    let mut rdata = Vec::with_capacity(bytes.len() + bytes.len() / 255 + 1);
+    for chunk in bytes.chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+    rdata
+}
+
+/// A query type given by number or by name (`A`, `AAAA`, ...).
+pub fn parse_qtype(value: &str) -> Option<u16> {
+    if let Ok(qtype) = value.parse() {
+        return Some(qtype);
+    }
+    match value.to_ascii_uppercase().as_str() {
+        "A" => Some(TYPE_A),
+        "NS" => Some(TYPE_NS),
+        "CNAME" => Some(TYPE_CNAME),
+        "SOA" => Some(TYPE_SOA),
+        "TXT" => Some(TYPE_TXT),
+        "AAAA" => Some(TYPE_AAAA),
+        "HTTPS" => Some(TYPE_HTTPS),
+        _ => None,
+    }
+}
+
+/// Check that a domain can be encoded as a DNS name (label and total length limits).
+pub fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty()
+        && domain.len() <= MAX_DOMAIN_LEN
+        && domain
+            .split('.')
+            .all(|label| !label.is_empty() && label.len() <= MAX_LABEL_LEN)
+}
+
+/// Read a resource record starting at `pos`, returning it and the offset after it.
+///
+/// CNAME RDATA is re-encoded uncompressed so the record is self-contained.
+fn read_record(data: &[u8], pos: usize) -> Option<(DnsRecord, usize)> {
+    let (name, next) = read_name(data, pos)?;
+    let fixed = data.get(next..next + 10)?;
+    let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+    let class = u16::from_be_bytes([fixed[2], fixed[3]]);
+    let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+    let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    let rdata_start = next + 10;
+    let raw = data.get(rdata_start..rdata_start + rdlength)?;
+
+    let rdata = if rtype == TYPE_CNAME {
+        let (target, _) = read_name(data, rdata_start)?;
+        let mut buf = Vec::with_capacity(target.len() + 2);
+        encode_domain(&mut buf, &target);
+        buf
+    } else {
+        raw.to_vec()
+    };
+
+    let record = DnsRecord {
+        name,
+        rtype,
+        class,
+        ttl,
+        rdata,
+    };
+    Some((record, rdata_start + rdlength))
+}
+
+/// Find the OPT record in the additional section, which follows `skip`
+/// records starting at `pos`.
+fn read_opt(data: &[u8], mut pos: usize, skip: usize) -> Option<EdnsOpt> {
+    let arcount = u16::from_be_bytes([data[10], data[11]]) as usize;
+    if arcount == 0 {
+        return None;
+    }
+    for index in 0..skip + arcount {
+        let (record, next) = read_record(data, pos)?;
+        if index >= skip && record.rtype == TYPE_OPT {
+            return EdnsOpt::from_record(&record);
+        }
+        pos = next;
+    }
+    None
+}
+
+/// Remove the OPT record from a message, for storing answers independently
+/// of the EDNS of the query that fetched them.
+///
+/// Only a trailing OPT record is removed (where it always is in practice),
+/// so no compression pointer can refer past the cut.
+pub fn strip_opt(message: &[u8]) -> Cow<'_, [u8]> {
+    if message.len() < HEADER_LEN || message[10..12] == [0, 0] {
+        return Cow::Borrowed(message);
+    }
+    let count = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]) as usize;
+    let records = count(6) + count(8) + count(10);
+    let mut pos = HEADER_LEN;
+    for _ in 0..count(4) {
+        let Some((_, next)) = read_name(message, pos) else {
+            return Cow::Borrowed(message);
+        };
+        pos = next + 4;
+    }
+    let mut last = None;
+    for _ in 0..records {
+        let Some((record, next)) = read_record(message, pos) else {
+            return Cow::Borrowed(message);
+        };
+        last = Some((pos, record.rtype));
+        pos = next;
+    }
+    match last {
+        Some((start, TYPE_OPT)) => {
+            let mut stripped = message[..start].to_vec();
+            let arcount = (count(10) - 1) as u16;
+            stripped[10..12].copy_from_slice(&arcount.to_be_bytes());
+            Cow::Owned(stripped)
+        }
+        _ => Cow::Borrowed(message),
+    }
+}
+
+/// Add a Client Subnet option to a query, replacing any the client sent.
+///
+/// A query without EDNS gets an OPT record; one with EDNS keeps its payload
+/// size, DO bit and other options. Returns `None` if the query can't be
+/// parsed or its OPT record isn't the last record.
+pub fn set_client_subnet(query: &[u8], subnet: &EdnsClientSubnet) -> Option<Vec<u8>> {
+    let parsed = DnsQuery::parse(query)?;
+    let stripped = strip_opt(query);
+    let mut opt = match parsed.edns_opt {
+        Some(_) if stripped.len() == query.len() => return None,
+        Some(opt) => opt,
+        None => EdnsOpt {
+            udp_payload_size: EDNS_PAYLOAD_SIZE,
+            dnssec_ok: false,
+            options: Vec::new(),
+        },
+    };
+    opt.options
+        .retain(|(code, _)| *code != OPTION_CLIENT_SUBNET);
+    opt.options.push(subnet.to_option());
+    let mut message = stripped.into_owned();
+    let arcount = u16::from_be_bytes([message[10], message[11]]) + 1;
+    message[10..12].copy_from_slice(&arcount.to_be_bytes());
+    opt.encode(&mut message);
+    Some(message)
+}
+
+/// Set the TTL of every record in a message, in place. The OPT record is
+/// left alone, as its TTL field holds EDNS flags; a malformed record stops
+/// the rewrite.
+pub fn set_ttls(message: &mut [u8], ttl: u32) {
+    rewrite_ttls(message, |_| ttl);
+}
+
+/// Lower every record TTL above `max` to `max`, in place, skipping OPT like
+/// [`set_ttls`].
+pub fn cap_ttls(message: &mut [u8], max: u32) {
+    rewrite_ttls(message, |ttl| ttl.min(max));
+}
+
+/// Replace the TTL of every record but OPT with `rewrite(ttl)`.
+fn rewrite_ttls(message: &mut [u8], rewrite: impl Fn(u32) -> u32) {
+    if message.len() < HEADER_LEN {
+        return;
+    }
+    let count = |at: usize| u16::from_be_bytes([message[at], message[at + 1]]) as usize;
+    let questions = count(4);
+    let records = count(6) + count(8) + count(10);
+    let mut pos = HEADER_LEN;
+    for _ in 0..questions {
+        let Some((_, next)) = read_name(message, pos) else {
+            return;
+        };
+        pos = next + 4;
+    }
+    for _ in 0..records {
+        let Some((_, next)) = read_name(message, pos) else {
+            return;
+        };
+        let Some(fixed) = message.get_mut(next..next + 10) else {
+            return;
+        };
+        if u16::from_be_bytes([fixed[0], fixed[1]]) != TYPE_OPT {
+            let ttl = u32::from_be_bytes([fixed[4], fixed[5], fixed[6], fixed[7]]);
+            fixed[4..8].copy_from_slice(&rewrite(ttl).to_be_bytes());
+        }
+        pos = next + 10 + u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+    }
+}
+
+/// An answer record for a blocked query.
+fn blocked_record(query: &DnsQuery, rtype: u16, rdata: Vec<u8>, ttl: u32) -> DnsRecord {
+    DnsRecord {
+        name: query.domain.clone(),
+        rtype,
+        class: CLASS_IN,
+        ttl,
+        rdata,
+    }
+}
+
+/// SOA placed in the authority section of blocked NXDOMAIN and NODATA answers so
+/// clients can cache the negative answer for `ttl` seconds.
+fn blocked_soa(domain: &str, ttl: u32) -> DnsRecord {
+    let mut rdata = Vec::with_capacity(64);
+    encode_domain(&mut rdata, "detour.invalid"); // MNAME
+    encode_domain(&mut rdata, "blocked.detour.invalid"); // RNAME
+    for value in [1, 3600, 600, 86400, ttl] {
+        // SERIAL, REFRESH, RETRY, EXPIRE, MINIMUM
+        rdata.extend_from_slice(&u32::to_be_bytes(value));
+    }
+    DnsRecord {
+        name: domain.to_string(),
+        rtype: TYPE_SOA,
+        class: CLASS_IN,
+        ttl,
+        rdata,
+    }
+}
+
+/// Read a possibly compressed name starting at `pos`.
+///
+/// Returns the lowercase name and the offset just past the name at its
+/// original location. Compression pointers must point before the labels
+/// already read, which rules out loops. No allocation beyond the name itself.
+fn read_name(data: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut name = String::with_capacity(64);
+    let mut end = None;
+    // Start of the run of labels being read; pointer targets strictly decrease.
+    let mut segment_start = pos;
+
+    loop {
+        let len = *data.get(pos)? as usize;
+        if len & 0xC0 == 0xC0 {
+            let target = ((len & 0x3F) << 8) | *data.get(pos + 1)? as usize;
+            if target >= segment_start {
+                return None;
+            }
+            end.get_or_insert(pos + 2);
+            pos = target;
+            segment_start = target;
+            continue;
+        }
+        if len & 0xC0 != 0 {
+            return None;
+        }
+
+        pos += 1;
+        if len == 0 {
+            break;
+        }
+
+        let label = data.get(pos..pos + len)?;
+        if !name.is_empty() {
+            name.push('.');
+        }
+        for &b in label {
+            name.push((b as char).to_ascii_lowercase());
+        }
+        if name.len() > MAX_DOMAIN_LEN {
+            return None;
+        }
+        pos += len;
+    }
+
+    Some((name, end.unwrap_or(pos)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_to_bytes_round_trips_through_parse() {
+        let query = DnsQuery::new(0xBEEF, "Example.COM", TYPE_AAAA);
+
+        let parsed = DnsQuery::parse(&query.to_bytes().unwrap()).unwrap();
+
+        assert_eq!(parsed.id, 0xBEEF);
+        assert_eq!(parsed.domain, "example.com");
+        assert_eq!(parsed.qtype, TYPE_AAAA);
+        assert_eq!(parsed.qclass, CLASS_IN);
+    }
+
+    #[test]
+    fn query_to_bytes_rejects_invalid_names() {
+        let long_label = "a".repeat(64);
+
+        assert!(DnsQuery::new(1, "", TYPE_A).to_bytes().is_none());
+        assert!(DnsQuery::new(1, "a..b", TYPE_A).to_bytes().is_none());
+        assert!(DnsQuery::new(1, &long_label, TYPE_A).to_bytes().is_none());
+    }
+
+    #[test]
+    fn root_query_encodes_the_empty_name() {
+        let data = DnsQuery::new(1, ".", TYPE_NS).to_bytes().unwrap();
+
+        assert_eq!(data[HEADER_LEN..], [0, 0, 2, 0, 1]);
+    }
+
+    #[test]
+    fn response_parse_expands_compressed_cname() {
+        let query = DnsQuery::new(7, "www.example.com", TYPE_A);
+        let mut data = query.to_bytes().unwrap();
+        data[2] = 0x81;
+        data[3] = 0x80;
+        data[7] = 2; // ANCOUNT
+        // www.example.com CNAME example.com (pointer to offset 16)
+        data.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x05, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x02]);
+        data.extend_from_slice(&[0xC0, 0x10]);
+        // example.com A 93.184.216.34
+        data.extend_from_slice(&[0xC0, 0x10, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x04]);
+        data.extend_from_slice(&[93, 184, 216, 34]);
+
+        let response = DnsResponse::parse(&data).unwrap();
+
+        assert_eq!(response.rcode(), 0);
+        assert_eq!(response.questions[0].domain, "www.example.com");
+        assert_eq!(
+            response.answers[0].data(),
+            RData::Cname("example.com".to_string())
+        );
+        assert_eq!(response.answers[1].name, "example.com");
+        assert_eq!(
+            response.answers[1].data(),
+            RData::A(Ipv4Addr::new(93, 184, 216, 34))
+        );
+    }
+
+    #[test]
+    fn response_parse_rejects_forward_pointers() {
+        let mut data = DnsQuery::new(7, "example.com", TYPE_A).to_bytes().unwrap();
+        data[7] = 1;
+        data.extend_from_slice(&[0xC0, 0xFF, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x04]);
+        data.extend_from_slice(&[1, 2, 3, 4]);
+
+        assert!(DnsResponse::parse(&data).is_none());
+    }
+
+    #[test]
+    fn read_name_follows_pointers_into_earlier_names() {
+        // A second question "www" + pointer to the first question's "example.com".
+        let mut data = DnsQuery::new(9, "example.com", TYPE_A).to_bytes().unwrap();
+        let second = data.len();
+        data.extend_from_slice(&[3, b'w', b'w', b'w', 0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+
+        let (name, end) = read_name(&data, second).unwrap();
+
+        assert_eq!(name, "www.example.com");
+        assert_eq!(end, second + 6);
+        assert_eq!(DnsQuery::parse(&data).unwrap().domain, "example.com");
+    }
+
+    #[test]
+    fn query_parse_rejects_pointer_loops() {
+        let mut data = DnsQuery::new(9, "example.com", TYPE_A).to_bytes().unwrap();
+        data.truncate(HEADER_LEN);
+        // "example" followed by a pointer back to itself.
+        data.extend_from_slice(&[7, b'e', b'x', b'a', b'm', b'p', b'l', b'e', 0xC0, 0x0C]);
+        data.extend_from_slice(&[0x00, 0x01, 0x00, 0x01]);
+
+        assert!(DnsQuery::parse(&data).is_none());
+    }
+
+    fn cname(name: &str, target: &str) -> DnsRecord {
+        let mut rdata = Vec::new();
+        encode_domain(&mut rdata, target);
+        DnsRecord {
+            name: name.to_string(),
+            rtype: TYPE_CNAME,
+            class: 1,
+            ttl: 60,
+            rdata,
+        }
+    }
+
+    #[test]
+    fn cname_chain_is_followed_from_the_question() {
+        let mut response = DnsQuery::new(7, "www.example.com", TYPE_A).nxdomain_response();
+        response.flags = 0x8180;
+        response.answers = vec![
+            cname("cdn.example.net", "edge.example.net"),
+            cname("www.example.com", "cdn.example.net"),
+            DnsRecord {
+                name: "edge.example.net".to_string(),
+                rtype: TYPE_A,
+                class: 1,
+                ttl: 60,
+                rdata: vec![10, 0, 0, 1],
+            },
+        ];
+
+        let chain = DnsResponse::parse_cname_chain(&response.to_bytes());
+        let targets = response.cname_target_responses();
+        response
+            .answers
+            .push(cname("edge.example.net", "www.example.com"));
+        let looped = response.cname_chain();
+
+        assert_eq!(chain, ["cdn.example.net", "edge.example.net"]);
+        assert_eq!(targets[0].0, "cdn.example.net");
+        assert_eq!(targets[0].1.questions[0].domain, "cdn.example.net");
+        assert_eq!(targets[0].1.answers.len(), 2);
+        assert_eq!(targets[1].0, "edge.example.net");
+        assert_eq!(
+            targets[1].1.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert!(looped.len() <= response.answers.len());
+        assert!(DnsResponse::parse_cname_chain(&[0; 4]).is_empty());
+    }
+
+    #[test]
+    fn blocked_response_styles() {
+        let query = DnsQuery::new(5, "ads.example.com", TYPE_A);
+
+        let nxdomain = DnsResponse::parse(
+            &query
+                .blocked_response(BlockedResponseStyle::Nxdomain)
+                .to_bytes(),
+        )
+        .unwrap();
+        let refused = DnsResponse::parse(
+            &query
+                .blocked_response(BlockedResponseStyle::Refused)
+                .to_bytes(),
+        )
+        .unwrap();
+        let sinkhole = DnsResponse::parse(
+            &query
+                .blocked_response(BlockedResponseStyle::CustomIp(
+                    Ipv4Addr::new(10, 0, 0, 53),
+                    Ipv6Addr::UNSPECIFIED,
+                ))
+                .to_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(nxdomain.rcode(), 3);
+        assert!(nxdomain.answers.is_empty());
+        assert_eq!(nxdomain.authority.len(), 1);
+        assert_eq!(nxdomain.authority[0].rtype, TYPE_SOA);
+        assert_eq!(refused.rcode(), 5);
+        assert!(refused.answers.is_empty());
+        assert!(refused.authority.is_empty());
+        assert_eq!(refused.questions[0].domain, "ads.example.com");
+        assert_eq!(refused.questions[0].qtype, TYPE_A);
+        assert_eq!(sinkhole.rcode(), 0);
+        assert_eq!(
+            sinkhole.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 53))
+        );
+    }
+
+    #[test]
+    fn nxdomain_responses_parse_with_another_dns_implementation() {
+        let query = DnsQuery::new(5, "ads.example.com", TYPE_A);
+        let bytes = query
+            .blocked_response(BlockedResponseStyle::Nxdomain)
+            .to_bytes();
+
+        let packet = simple_dns::Packet::parse(&bytes).unwrap();
+
+        assert_eq!(packet.id(), 5);
+        assert_eq!(packet.rcode(), simple_dns::RCODE::NameError);
+        assert_eq!(packet.questions[0].qname.to_string(), "ads.example.com");
+        assert!(packet.answers.is_empty());
+        assert_eq!(packet.name_servers.len(), 1);
+        assert!(matches!(
+            packet.name_servers[0].rdata,
+            simple_dns::rdata::RData::SOA(_)
+        ));
+    }
+
+    #[test]
+    fn blocked_responses_follow_the_query_type() {
+        let style =
+            BlockedResponseStyle::CustomIp(Ipv4Addr::new(10, 0, 0, 53), Ipv6Addr::UNSPECIFIED);
+        let blocked = |qtype| {
+            let query = DnsQuery::new(5, "ads.example.com", qtype);
+            DnsResponse::parse(&query.blocked_response(style).to_bytes()).unwrap()
+        };
+
+        let aaaa = blocked(TYPE_AAAA);
+        let txt = blocked(TYPE_TXT);
+
+        assert_eq!(aaaa.rcode(), 0);
+        assert_eq!(aaaa.answers[0].data(), RData::Aaaa(Ipv6Addr::UNSPECIFIED));
+        assert_eq!(txt.rcode(), 0);
+        assert!(txt.answers.is_empty());
+        assert_eq!(txt.authority[0].rtype, TYPE_SOA);
+    }
+
+    #[test]
+    fn blocked_txt_and_https_queries_get_nodata_on_the_wire() {
+        let styles = [
+            BlockedResponseStyle::NullIp,
+            BlockedResponseStyle::CustomIp(Ipv4Addr::new(10, 0, 0, 53), Ipv6Addr::LOCALHOST),
+        ];
+
+        for qtype in [TYPE_TXT, TYPE_HTTPS] {
+            for style in styles {
+                let bytes = DnsQuery::new(5, "ads.example.com", qtype)
+                    .blocked_response(style)
+                    .to_bytes();
+                let packet = simple_dns::Packet::parse(&bytes).unwrap();
+
+                // NOERROR, ANCOUNT 0, NSCOUNT 1.
+                assert_eq!(bytes[3] & 0x0f, 0, "{} {}", qtype, style);
+                assert_eq!(bytes[6..8], [0, 0], "{} {}", qtype, style);
+                assert_eq!(bytes[8..10], [0, 1], "{} {}", qtype, style);
+                assert_eq!(packet.rcode(), simple_dns::RCODE::NoError);
+                assert!(packet.answers.is_empty());
+                assert!(matches!(
+                    packet.name_servers[0].rdata,
+                    simple_dns::rdata::RData::SOA(_)
+                ));
+            }
+        }
+    }
+
+    #[test]
+    fn null_blocked_aaaa_queries_get_an_aaaa_record() {
+        let query = DnsQuery::new(5, "ads.example.com", TYPE_AAAA);
+
+        let response = DnsResponse::parse(
+            &query
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rtype, TYPE_AAAA);
+        assert_eq!(response.answers[0].rdata, [0; 16]);
+    }
+
+    #[test]
+    fn sinkhole_answers_carry_records_of_the_query_family() {
+        let ipv6: Ipv6Addr = "fd00::53".parse().unwrap();
+        let style = BlockedResponseStyle::CustomIp(Ipv4Addr::new(192, 168, 1, 5), ipv6);
+        // Answer name (a pointer), then type, class, TTL and RDLENGTH.
+        let answer = HEADER_LEN + "ads.example.com".len() + 2 + 4;
+        let blocked = |qtype| {
+            DnsQuery::new(5, "ads.example.com", qtype)
+                .blocked_response(style)
+                .to_bytes()
+        };
+
+        let a = blocked(TYPE_A);
+        let aaaa = blocked(TYPE_AAAA);
+
+        assert_eq!(a[answer + 2..answer + 4], TYPE_A.to_be_bytes());
+        assert_eq!(a[answer + 10..answer + 12], 4u16.to_be_bytes());
+        assert_eq!(a[answer + 12..], [192, 168, 1, 5]);
+        assert_eq!(aaaa[answer + 2..answer + 4], TYPE_AAAA.to_be_bytes());
+        assert_eq!(aaaa[answer + 10..answer + 12], 16u16.to_be_bytes());
+        assert_eq!(aaaa[answer + 12..], ipv6.octets());
+    }
+
+    #[test]
+    fn set_ttls_rewrites_every_record_but_opt() {
+        let mut query = DnsQuery::new(6, "example.com", TYPE_TXT);
+        query.edns_opt = Some(EdnsOpt {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            options: Vec::new(),
+        });
+        let mut nodata = query
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+
+        set_ttls(&mut nodata, 30);
+
+        let parsed = DnsResponse::parse(&nodata).unwrap();
+        assert_eq!(parsed.authority[0].ttl, 30);
+        assert!(parsed.edns_opt.unwrap().dnssec_ok);
+        assert_eq!(
+            DnsResponse::parse_min_ttl(&nodata, Duration::ZERO),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn cap_ttls_only_lowers_longer_ttls() {
+        let query = DnsQuery::new(7, "example.com", TYPE_A);
+        let mut long = DnsResponse::blocked(&query, BlockedResponseStyle::NullIp, 3600).to_bytes();
+        let mut short = DnsResponse::blocked(&query, BlockedResponseStyle::NullIp, 60).to_bytes();
+
+        cap_ttls(&mut long, 300);
+        cap_ttls(&mut short, 300);
+
+        assert_eq!(DnsResponse::parse(&long).unwrap().answers[0].ttl, 300);
+        assert_eq!(DnsResponse::parse(&short).unwrap().answers[0].ttl, 60);
+    }
+
+    #[test]
+    fn min_ttl_of_positive_answers_ignores_opt_and_authority_records() {
+        let query = DnsQuery::new(12, "example.com", TYPE_A);
+        let mut response = query.blocked_response(BlockedResponseStyle::NullIp);
+        response.answers[0].ttl = 3600;
+        response.authority.push(DnsRecord {
+            name: "example.com".to_string(),
+            rtype: TYPE_NS,
+            class: CLASS_IN,
+            ttl: 60,
+            rdata: b"\x02ns\x07example\x03com\x00".to_vec(),
+        });
+        response.edns_opt = Some(EdnsOpt {
+            udp_payload_size: 1232,
+            dnssec_ok: false,
+            options: Vec::new(),
+        });
+        let bytes = response.to_bytes();
+        response.answers.clear();
+        let referral = response.to_bytes();
+
+        assert_eq!(
+            DnsResponse::parse_min_ttl(&bytes, Duration::from_secs(60)),
+            Duration::from_secs(3600)
+        );
+        assert_eq!(
+            DnsResponse::parse_min_ttl(&referral, Duration::ZERO),
+            Duration::from_secs(60)
+        );
+    }
+
+    #[test]
+    fn query_types_parse_by_number_or_name() {
+        assert_eq!(parse_qtype("28"), Some(TYPE_AAAA));
+        assert_eq!(parse_qtype("aaaa"), Some(TYPE_AAAA));
+        assert_eq!(parse_qtype("MX2"), None);
+    }
+
+    #[test]
+    fn edns_opt_is_parsed_echoed_and_strippable() {
+        let mut query = DnsQuery::new(6, "example.com", TYPE_A);
+        query.edns_opt = Some(EdnsOpt {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            options: vec![(10, vec![1, 2, 3, 4, 5, 6, 7, 8])],
+        });
+
+        let parsed = DnsQuery::parse(&query.to_bytes().unwrap()).unwrap();
+        let blocked = parsed
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+        let stripped = strip_opt(&blocked);
+
+        assert_eq!(parsed.edns_opt, query.edns_opt);
+        assert_eq!(
+            DnsResponse::parse(&blocked).unwrap().edns_opt,
+            Some(EdnsOpt {
+                udp_payload_size: EDNS_PAYLOAD_SIZE,
+                dnssec_ok: true,
+                options: Vec::new(),
+            })
+        );
+        assert_eq!(
+            DnsResponse::parse_min_ttl(&blocked, Duration::ZERO),
+            Duration::from_secs(u64::from(DEFAULT_BLOCKED_TTL))
+        );
+        assert_eq!(DnsResponse::parse(&stripped).unwrap().edns_opt, None);
+        assert_eq!(&stripped[10..12], &[0, 0]);
+        assert_eq!(stripped.len(), blocked.len() - 11);
+    }
+
+    #[test]
+    fn client_subnet_is_truncated_and_replaces_the_clients_own() {
+        let v4 = EdnsClientSubnet::new("192.0.2.77".parse().unwrap(), 20);
+        let v6 = EdnsClientSubnet::new("2001:db8:abcd:12::1".parse().unwrap(), 56);
+        let mapped = EdnsClientSubnet::new("::ffff:198.51.100.9".parse().unwrap(), 64);
+        let mut query = DnsQuery::new(9, "example.com", TYPE_A);
+        query.edns_opt = Some(EdnsOpt {
+            udp_payload_size: 4096,
+            dnssec_ok: true,
+            options: vec![(10, vec![1; 8]), v6.to_option()],
+        });
+
+        let plain = set_client_subnet(
+            &DnsQuery::new(9, "example.com", TYPE_A).to_bytes().unwrap(),
+            &v4,
+        )
+        .unwrap();
+        let replaced = set_client_subnet(&query.to_bytes().unwrap(), &v4).unwrap();
+
+        assert_eq!(
+            v4.to_option(),
+            (OPTION_CLIENT_SUBNET, vec![0, 1, 20, 0, 192, 0, 0])
+        );
+        assert_eq!(
+            v6.to_option(),
+            (
+                OPTION_CLIENT_SUBNET,
+                vec![0, 2, 56, 0, 0x20, 0x01, 0x0d, 0xb8, 0xab, 0xcd, 0x00]
+            )
+        );
+        assert_eq!(
+            mapped.to_option(),
+            (OPTION_CLIENT_SUBNET, vec![0, 1, 32, 0, 198, 51, 100, 9])
+        );
+        assert_eq!(
+            DnsQuery::parse(&plain).unwrap().edns_opt,
+            Some(EdnsOpt {
+                udp_payload_size: EDNS_PAYLOAD_SIZE,
+                dnssec_ok: false,
+                options: vec![v4.to_option()],
+            })
+        );
+        assert_eq!(
+            DnsQuery::parse(&replaced).unwrap().edns_opt,
+            Some(EdnsOpt {
+                udp_payload_size: 4096,
+                dnssec_ok: true,
+                options: vec![(10, vec![1; 8]), v4.to_option()],
+            })
+        );
+    }
+}
@@ -1,21 +1,118 @@
 //! Blocklist for ad/tracking domains.
 //!
-//! Loads domains from embedded lists or a custom file path.
+//! Loads domains from embedded lists or a custom file path. Lists may be
+//! plain (one domain per line) or in hosts-file format (`0.0.0.0 domain`);
+//! the format is detected per line by whether it starts with an IP literal.
+//! A listed domain blocks itself and all its subdomains; wildcard patterns
+//! are described on [`Blocklist`]. Adblock Plus `||domain^` rules and
+//! `@@||domain^` exceptions may be mixed into any list, and files starting
+//! with an `[Adblock Plus` header are read as Adblock Plus filters only (see
+//! [`Blocklist::from_adblock_format`]). Allowlisted domains are never
+//! blocked; an allowlist entry only covers that exact domain, not its
+//! subdomains.
 
-use rustc_hash::FxHashSet;
+use regex::RegexSet;
+use rustc_hash::{FxHashMap, FxHashSet};
+use std::net::IpAddr;
 
-/// Embedded blocklists loaded at compile time.
+use super::domains::DomainSet;
+use crate::dns::is_valid_domain;
+
+/// Embedded blocklists loaded at compile time, each behind its `list-*` feature.
 const EMBEDDED_LISTS: &[&str] = &[
+    #[cfg(feature = "list-adaway")]
     include_str!("lists/Adaway.txt"),
+    #[cfg(feature = "list-adguard")]
     include_str!("lists/AdguardDNS.txt"),
+    #[cfg(feature = "list-easylist")]
     include_str!("lists/Easylist.txt"),
+    #[cfg(feature = "list-easyprivacy")]
     include_str!("lists/Easyprivacy.txt"),
+    #[cfg(feature = "list-phishing")]
     include_str!("lists/Phishing_army_blocklist_extended.txt"),
 ];
 
 /// A set of blocked domains for efficient lookup.
+///
+/// Entries match as follows:
+/// - `example.com` blocks `example.com` and every subdomain of it.
+/// - `*.example.com` blocks every subdomain of `example.com` but not
+///   `example.com` itself, unless [`Blocklist::with_wildcard_apex`] is set.
+/// - A `*` label elsewhere, as in `ads.*.cdn.net`, matches exactly one
+///   label: `ads.eu.cdn.net` is blocked with its subdomains, while
+///   `ads.cdn.net` and `ads.a.b.cdn.net` are not. Combined with a leading
+///   `*.`, only strict subdomains of the matches are blocked.
+/// - `||example.com^` blocks like `example.com`, and an `@@||example.com^`
+///   exception exempts `example.com` and its subdomains, whatever blocks them.
+/// - Regex rules ([`Blocklist::with_regex_rules`]) block every name they
+///   match, e.g. `^[a-z0-9]+\.metrics\.vendor\.com$`.
+/// - Allowlist entries exempt exactly the listed domain.
+///
+/// Plain entries and leading-`*.` patterns are each one hash lookup per label
+/// of the queried name, whether the domains are in a hash set or packed
+/// with [`Blocklist::with_compact_storage`]. Patterns with inner `*` labels are kept apart, keyed
+/// by the labels after their last `*`, so they cost nothing while none are
+/// loaded. Regex rules are only tried once every lookup has missed.
+/// Entries with a `*` inside a label, with nothing after the last
+/// `*` label or with characters no hostname has, element hiding rules and
+/// Adblock Plus rules for URL paths are ignored.
 pub struct Blocklist {
-    domains: FxHashSet<String>,
+    domains: DomainSet,
+    /// Suffixes of `*.suffix` patterns, matching strict subdomains only.
+    wildcard_patterns: FxHashSet<String>,
+    /// Patterns with inner `*` labels, by the fixed suffix after the last one.
+    label_patterns: FxHashMap<String, Vec<LabelPattern>>,
+    /// `*.suffix` patterns block `suffix` itself too.
+    wildcard_apex: bool,
+    /// Domains of `@@||domain^` exceptions, covering their subdomains too.
+    exceptions: FxHashSet<String>,
+    /// Regex rules, tried when no domain or pattern matches.
+    regexes: Option<RegexSet>,
+    allowlist: FxHashSet<String>,
+}
+
+/// The part of a pattern such as `*.ads.*.cdn.net` before its fixed suffix.
+#[derive(PartialEq, Eq)]
+struct LabelPattern {
+    /// Labels in order, `*` matching any one label (`ads`, `*`)
+    labels: Vec<String>,
+    /// Whether the pattern started with `*.`
+    subdomains_only: bool,
+}
+
+/// A name a [`Blocklist`] blocks, and the kind of rule that blocked it.
+struct Match<'a, 'b> {
+    /// The listed domain, `*.` pattern suffix or name a pattern or regex matched
+    name: &'a str,
+    rule: Rule<'a, 'b>,
+}
+
+enum Rule<'a, 'b> {
+    Domain,
+    Wildcard,
+    /// A pattern with inner `*` labels, with its fixed suffix
+    Labels(&'b LabelPattern, &'a str),
+    Regex,
+}
+
+impl LabelPattern {
+    /// Where the name matched by this pattern starts in `domain`, given that
+    /// the pattern's suffix starts at `suffix_start`.
+    fn match_start(&self, domain: &str, suffix_start: usize, apex: bool) -> Option<usize> {
+        let mut start = suffix_start;
+        for label in self.labels.iter().rev() {
+            if start == 0 {
+                return None;
+            }
+            let end = start - 1;
+            let label_start = domain[..end].rfind('.').map_or(0, |dot| dot + 1);
+            if label != "*" && *label != domain[label_start..end] {
+                return None;
+            }
+            start = label_start;
+        }
+        (start > 0 || !self.subdomains_only || apex).then_some(start)
+    }
 }
 
 impl Blocklist {
@@ -24,33 +121,345 @@ impl Blocklist {
         Self::from_lists(EMBEDDED_LISTS.iter().copied())
     }
 
+    /// Create a blocklist with no entries.
+    pub fn empty() -> Self {
+        Self::from_lists(std::iter::empty())
+    }
+
     /// Create a blocklist from a custom file path (replaces embedded lists).
+    ///
+    /// Adblock Plus filter lists are detected by their `[Adblock Plus` header.
     pub fn from_file(path: &str) -> std::io::Result<Self> {
         let content = std::fs::read_to_string(path)?;
-        Ok(Self::from_lists(std::iter::once(content.as_str())))
+        Ok(Self::from_content(&content))
+    }
+
+    /// Create a blocklist from Adblock Plus filters.
+    ///
+    /// `||domain^` rules block the domain and its subdomains, and
+    /// `@@||domain^` exceptions exempt the domain and its subdomains. Element
+    /// hiding, URL patterns and rules scoped by `$` options other than
+    /// `$important` do not map to whole domains and are skipped, as are plain
+    /// domain lines, which Adblock Plus reads as URL substrings.
+    pub fn from_adblock_format(content: &str) -> Self {
+        let mut blocklist = Self::empty();
+        for line in list_entries(content) {
+            blocklist.add_adblock_rule(line);
+        }
+        blocklist
+    }
+
+    /// Create a blocklist from several files (see [`Self::from_file`]),
+    /// merged with [`Self::merged`].
+    pub fn from_files(paths: &[&str]) -> std::io::Result<Self> {
+        let lists = paths
+            .iter()
+            .map(|path| Self::from_file(path))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Self::merged(lists))
+    }
+
+    fn from_content(content: &str) -> Self {
+        if is_adblock_format(content) {
+            Self::from_adblock_format(content)
+        } else {
+            Self::from_lists(std::iter::once(content))
+        }
+    }
+
+    /// Create a blocklist from a hosts file (`<ip> <domain>...` per line).
+    ///
+    /// The IP column is ignored, so `0.0.0.0` and `127.0.0.1` sinkhole lists
+    /// both work. Plain domain lines are accepted too.
+    pub fn from_hosts_file(path: &str) -> std::io::Result<Self> {
+        Self::from_file(path)
+    }
+
+    /// Create a blocklist from a custom file, exempting the domains listed in `allow_path`.
+    pub fn from_file_with_allowlist(block_path: &str, allow_path: &str) -> std::io::Result<Self> {
+        let mut blocklist = Self::from_file(block_path)?;
+        let allowlist = std::fs::read_to_string(allow_path)?;
+        for domain in list_entries(&allowlist) {
+            blocklist.add_allowlist_entry(domain);
+        }
+        Ok(blocklist)
+    }
+
+    /// Replace the blocked domains with those in `path`, keeping the allowlist.
+    ///
+    /// The file is fully parsed before the swap, so on error the current
+    /// domains are left untouched. Adblock Plus exceptions are part of the
+    /// list and are replaced along with the domains.
+    pub fn reload_from_file(&mut self, path: &str) -> std::io::Result<()> {
+        let content = std::fs::read_to_string(path)?;
+        let fresh = Self::from_content(&content);
+        self.domains = match self.domains.is_packed() {
+            true => fresh.domains.pack(),
+            false => fresh.domains,
+        };
+        self.wildcard_patterns = fresh.wildcard_patterns;
+        self.label_patterns = fresh.label_patterns;
+        self.exceptions = fresh.exceptions;
+        Ok(())
     }
 
     fn from_lists<'a>(lists: impl Iterator<Item = &'a str>) -> Self {
-        let domains = lists
-            .flat_map(|list| list.lines())
-            .filter_map(|line| {
-                let line = line.trim();
-                if line.is_empty() || line.starts_with('#') || line.starts_with('!') {
-                    return None;
+        let mut blocklist = Self {
+            domains: DomainSet::default(),
+            wildcard_patterns: FxHashSet::default(),
+            label_patterns: FxHashMap::default(),
+            wildcard_apex: false,
+            exceptions: FxHashSet::default(),
+            regexes: None,
+            allowlist: FxHashSet::default(),
+        };
+        for line in lists.flat_map(list_entries) {
+            if blocklist.add_adblock_rule(line) || is_element_hiding(line) {
+                continue;
+            }
+            for entry in line_domains(line) {
+                blocklist.add_entry(&entry.trim_end_matches('.').to_ascii_lowercase());
+            }
+        }
+        blocklist
+    }
+
+    /// Add a `||domain^` rule or `@@||domain^` exception, returning whether
+    /// the line was in Adblock Plus syntax (even if it was not usable).
+    fn add_adblock_rule(&mut self, line: &str) -> bool {
+        if let Some(exception) = line.strip_prefix("@@") {
+            if let Some(domain) = adblock_domain(exception) {
+                self.exceptions.insert(domain);
+            }
+        } else if line.starts_with("||") {
+            if let Some(domain) = adblock_domain(line) {
+                self.domains.insert(domain);
+            }
+        } else {
+            return false;
+        }
+        true
+    }
+
+    /// Add a lowercase domain or wildcard pattern.
+    fn add_entry(&mut self, entry: &str) {
+        let hostname = entry
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.' | b'*'));
+        if entry.is_empty() || !hostname {
+            return;
+        }
+        let (subdomains_only, rest) = match entry.strip_prefix("*.") {
+            Some(rest) => (true, rest),
+            None => (false, entry),
+        };
+        let Some(last_star) = rest.rfind('*') else {
+            if subdomains_only {
+                self.wildcard_patterns.insert(rest.to_string());
+            } else {
+                self.domains.insert(rest.to_string());
+            }
+            return;
+        };
+        let (head, suffix) = rest.split_at(last_star + 1);
+        let Some(suffix) = suffix.strip_prefix('.') else {
+            return;
+        };
+        let labels: Vec<String> = head.split('.').map(str::to_string).collect();
+        if suffix.is_empty() || labels.iter().any(|l| l.contains('*') && l != "*") {
+            return;
+        }
+        self.label_patterns
+            .entry(suffix.to_string())
+            .or_default()
+            .push(LabelPattern {
+                labels,
+                subdomains_only,
+            });
+    }
+
+    /// Let `*.domain` patterns block `domain` itself as well as its subdomains.
+    pub fn with_wildcard_apex(mut self, enabled: bool) -> Self {
+        self.wildcard_apex = enabled;
+        self
+    }
+
+    /// Pack the plain domains into one buffer instead of a hash set of strings.
+    ///
+    /// Large lists take less than half the memory, with lookups as fast. Adding entries afterwards, e.g. with [`Blocklist::merge`],
+    /// unpacks the domains into a hash set again.
+    pub fn with_compact_storage(mut self, enabled: bool) -> Self {
+        self.domains = match enabled {
+            true => self.domains.pack(),
+            false => DomainSet::Hash(self.domains.into_hash()),
+        };
+        self
+    }
+
+    /// Merge several lists into one; entries found in more than one are kept once.
+    ///
+    /// Regex rules and allowlists of the lists are not carried over.
+    pub fn merged(lists: impl IntoIterator<Item = Blocklist>) -> Self {
+        let mut merged = Self::empty();
+        for list in lists {
+            merged.merge(list);
+        }
+        merged
+    }
+
+    /// Add the entries and exceptions of `other`, which is consumed.
+    ///
+    /// Its regex rules and allowlist are not carried over.
+    pub fn merge(&mut self, other: Blocklist) {
+        if self.is_empty() && self.exceptions.is_empty() {
+            self.domains = other.domains;
+            self.wildcard_patterns = other.wildcard_patterns;
+            self.label_patterns = other.label_patterns;
+            self.exceptions = other.exceptions;
+            return;
+        }
+        self.domains.extend(other.domains);
+        self.wildcard_patterns.extend(other.wildcard_patterns);
+        for (suffix, patterns) in other.label_patterns {
+            let merged = self.label_patterns.entry(suffix).or_default();
+            for pattern in patterns {
+                if !merged.contains(&pattern) {
+                    merged.push(pattern);
                 }
-                Some(line.to_ascii_lowercase())
-            })
-            .collect();
+            }
+        }
+        self.exceptions.extend(other.exceptions);
+    }
 
-        Self { domains }
+    /// Also block names matching these regex rules, one pattern per line
+    /// (blank lines and `#` comments skipped).
+    ///
+    /// Patterns are matched against the lowercase name without a trailing
+    /// dot and are not anchored, so `^` and `$` are needed to match a whole
+    /// name. Every invalid pattern is reported with its line number.
+    pub fn with_regex_rules(mut self, rules: &str) -> Result<Self, String> {
+        let mut patterns = Vec::new();
+        let mut errors = Vec::new();
+        for (index, line) in rules.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match regex::Regex::new(line) {
+                Ok(_) => patterns.push(line),
+                Err(e) => errors.push(format!("line {}: {}", index + 1, e)),
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors.join("\n"));
+        }
+        self.regexes = match patterns.is_empty() {
+            true => None,
+            false => Some(RegexSet::new(patterns).map_err(|e| e.to_string())?),
+        };
+        Ok(self)
+    }
+
+    /// Never block exactly this domain (its subdomains are unaffected).
+    pub fn add_allowlist_entry(&mut self, domain: &str) {
+        self.allowlist
+            .insert(domain.trim().trim_end_matches('.').to_ascii_lowercase());
     }
 
     /// Check if a domain should be blocked (hot path, assumes already lowercase ASCII).
     #[inline]
     pub fn is_blocked(&self, domain: &str) -> bool {
+        self.matched_entry(domain).is_some()
+    }
+
+    /// Return the blocklist entry that blocks a domain (the domain itself or an ancestor).
+    ///
+    /// For a `*.suffix` pattern the matched suffix is returned, for a
+    /// pattern with inner `*` labels the name it matched, and for a regex
+    /// rule the whole domain.
+    #[inline]
+    pub fn matched_entry<'a>(&self, domain: &'a str) -> Option<&'a str> {
+        self.find_match(domain).map(|found| found.name)
+    }
+
+    /// Return the rule that blocks a domain as it would be written in a list:
+    /// the listed domain (the name itself or an ancestor), the `*` pattern
+    /// or the regex.
+    pub fn explain_block(&self, domain: &str) -> Option<String> {
+        let found = self.find_match(domain)?;
+        Some(match found.rule {
+            Rule::Domain => found.name.to_string(),
+            Rule::Wildcard => format!("*.{}", found.name),
+            Rule::Labels(pattern, suffix) => {
+                let prefix = if pattern.subdomains_only { "*." } else { "" };
+                format!("{}{}.{}", prefix, pattern.labels.join("."), suffix)
+            }
+            Rule::Regex => {
+                let regexes = self.regexes.as_ref()?;
+                let index = regexes.matches(domain).into_iter().next()?;
+                regexes.patterns()[index].clone()
+            }
+        })
+    }
+
+    #[inline]
+    fn find_match<'a>(&self, domain: &'a str) -> Option<Match<'a, '_>> {
+        if !self.allowlist.is_empty() && self.allowlist.contains(domain) {
+            return None;
+        }
+        if !self.exceptions.is_empty() && self.is_excepted(domain) {
+            return None;
+        }
         let mut current = domain;
         loop {
             if self.domains.contains(current) {
+                return Some(Match {
+                    name: current,
+                    rule: Rule::Domain,
+                });
+            }
+            if (current.len() < domain.len() || self.wildcard_apex)
+                && !self.wildcard_patterns.is_empty()
+                && self.wildcard_patterns.contains(current)
+            {
+                return Some(Match {
+                    name: current,
+                    rule: Rule::Wildcard,
+                });
+            }
+            if !self.label_patterns.is_empty()
+                && let Some(patterns) = self.label_patterns.get(current)
+            {
+                let suffix_start = domain.len() - current.len();
+                if let Some((pattern, start)) = patterns.iter().find_map(|p| {
+                    p.match_start(domain, suffix_start, self.wildcard_apex)
+                        .map(|start| (p, start))
+                }) {
+                    return Some(Match {
+                        name: &domain[start..],
+                        rule: Rule::Labels(pattern, current),
+                    });
+                }
+            }
+            match current.find('.') {
+                Some(pos) => current = &current[pos + 1..],
+                None => break,
+            }
+        }
+        match &self.regexes {
+            Some(regexes) if regexes.is_match(domain) => Some(Match {
+                name: domain,
+                rule: Rule::Regex,
+            }),
+            _ => None,
+        }
+    }
+
+    /// Whether an Adblock Plus exception covers `domain` or an ancestor of it.
+    fn is_excepted(&self, domain: &str) -> bool {
+        let mut current = domain;
+        loop {
+            if self.exceptions.contains(current) {
                 return true;
             }
             match current.find('.') {
@@ -60,10 +469,78 @@ impl Blocklist {
         }
     }
 
-    /// Returns the number of domains in the blocklist.
+    /// Returns the number of domains, wildcard patterns and regex rules in the blocklist.
     pub fn len(&self) -> usize {
-        self.domains.len()
+        let label_patterns: usize = self.label_patterns.values().map(Vec::len).sum();
+        let regexes = self.regexes.as_ref().map_or(0, RegexSet::len);
+        self.domains.len() + self.wildcard_patterns.len() + label_patterns + regexes
+    }
+
+    /// Returns true if the blocklist contains no domains, patterns or rules.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Whether a list starts with an `[Adblock Plus ...]` header (after any blank lines).
+fn is_adblock_format(content: &str) -> bool {
+    content
+        .lines()
+        .map(str::trim)
+        .find(|line| !line.is_empty())
+        .is_some_and(|line| line.starts_with("[Adblock Plus"))
+}
+
+/// Domain of a `||domain^` rule, or None for rules that are not domain-wide.
+fn adblock_domain(rule: &str) -> Option<String> {
+    let (domain, options) = rule.strip_prefix("||")?.split_once('^')?;
+    if !matches!(options, "" | "|" | "$important") {
+        return None;
     }
+    let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+    let plain = domain
+        .bytes()
+        .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'));
+    (plain && is_valid_domain(&domain)).then_some(domain)
+}
+
+/// Whether a line is an element hiding rule (`example.com##.banner`,
+/// `#@#`, `#?#`), as opposed to a `#` comment after whitespace.
+fn is_element_hiding(line: &str) -> bool {
+    line.find('#')
+        .is_some_and(|pos| !line[..pos].ends_with(char::is_whitespace))
+}
+
+/// Non-empty lines of a list, skipping `#` and `!` comments.
+fn list_entries(list: &str) -> impl Iterator<Item = &str> {
+    list.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('!'))
+}
+
+/// Hostnames that hosts files map to loopback and that must never be blocked.
+const HOSTS_LOCAL_NAMES: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// Domains on a list line: the line itself for plain lists, or the names
+/// after the IP column for hosts-file lines (inline `#` comments dropped).
+fn line_domains(line: &str) -> impl Iterator<Item = &str> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace().peekable();
+    if fields
+        .peek()
+        .is_some_and(|field| field.parse::<IpAddr>().is_ok())
+    {
+        fields.next();
+    }
+    fields.filter(|name| !HOSTS_LOCAL_NAMES.contains(&name.to_ascii_lowercase().as_str()))
 }
 
 impl Default for Blocklist {
@@ -80,9 +557,10 @@ mod tests {
     fn new_parses_domains() {
         let blocklist = Blocklist::new();
 
-        assert!(blocklist.len() > 0);
+        assert_eq!(blocklist.is_empty(), EMBEDDED_LISTS.is_empty());
     }
 
+    #[cfg(any(feature = "list-adguard", feature = "list-easylist"))]
     #[test]
     fn is_blocked_exact_match() {
         let blocklist = Blocklist::new();
@@ -90,6 +568,7 @@ mod tests {
         assert!(blocklist.is_blocked("doubleclick.com"));
     }
 
+    #[cfg(any(feature = "list-adguard", feature = "list-easylist"))]
     #[test]
     fn is_blocked_subdomain_match() {
         let blocklist = Blocklist::new();
@@ -98,6 +577,7 @@ mod tests {
         assert!(blocklist.is_blocked("tracker.ads.doubleclick.com"));
     }
 
+    #[cfg(any(feature = "list-adguard", feature = "list-easylist"))]
     #[test]
     fn is_blocked_case_insensitive() {
         let blocklist = Blocklist::new();
@@ -122,4 +602,302 @@ mod tests {
 
         assert!(!blocklist.is_blocked(""));
     }
+
+    #[cfg(any(feature = "list-adguard", feature = "list-easylist"))]
+    #[test]
+    fn allowlist_overrides_only_exact_domains() {
+        let mut blocklist = Blocklist::new();
+        blocklist.add_allowlist_entry("Ads.DoubleClick.com.");
+        blocklist.add_allowlist_entry("com");
+
+        assert!(!blocklist.is_blocked("ads.doubleclick.com"));
+        assert!(blocklist.is_blocked("doubleclick.com"));
+        assert!(blocklist.is_blocked("tracker.ads.doubleclick.com"));
+    }
+
+    #[test]
+    fn hosts_file_lines_are_parsed_alongside_plain_domains() {
+        let list = "# hosts\n\
+            127.0.0.1 localhost\n\
+            ::1 ip6-localhost ip6-loopback\n\
+            0.0.0.0 Ads.Example.com tracker.example.net # inline\n\
+            127.0.0.1\tmetrics.example.org\n\
+            plain.example.io\n";
+
+        let blocklist = Blocklist::from_lists(std::iter::once(list));
+
+        assert_eq!(blocklist.len(), 4);
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("tracker.example.net"));
+        assert!(blocklist.is_blocked("metrics.example.org"));
+        assert!(blocklist.is_blocked("plain.example.io"));
+        assert!(!blocklist.is_blocked("localhost"));
+    }
+
+    #[test]
+    fn wildcard_patterns_block_subdomains_but_not_the_apex() {
+        let blocklist = Blocklist::from_lists(std::iter::once("*.evil.com\nplain.org\n"));
+
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocklist.is_blocked("a.evil.com"));
+        assert!(blocklist.is_blocked("b.a.evil.com"));
+        assert!(!blocklist.is_blocked("evil.com"));
+        assert!(!blocklist.is_blocked("notevil.com"));
+        assert!(blocklist.is_blocked("plain.org"));
+        assert_eq!(blocklist.matched_entry("a.evil.com"), Some("evil.com"));
+    }
+
+    #[test]
+    fn inner_wildcards_match_one_label() {
+        let list = "ads.*.cdn.net\n*.t.*.example\nbad*.org\nads.*\n";
+
+        let blocklist = Blocklist::from_lists(std::iter::once(list));
+        let apex = Blocklist::from_lists(std::iter::once("*.evil.com\n*.t.*.example\n"))
+            .with_wildcard_apex(true);
+
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocklist.is_blocked("ads.eu.cdn.net"));
+        assert!(blocklist.is_blocked("x.ads.eu.cdn.net"));
+        assert!(!blocklist.is_blocked("ads.cdn.net"));
+        assert!(!blocklist.is_blocked("ads.a.b.cdn.net"));
+        assert!(!blocklist.is_blocked("cdn.ads.eu.net"));
+        assert_eq!(
+            blocklist.matched_entry("x.ads.eu.cdn.net"),
+            Some("ads.eu.cdn.net")
+        );
+        assert!(blocklist.is_blocked("a.t.b.example"));
+        assert!(!blocklist.is_blocked("t.b.example"));
+        assert!(!blocklist.is_blocked("bad1.org"));
+        assert!(apex.is_blocked("evil.com"));
+        assert!(apex.is_blocked("t.b.example"));
+    }
+
+    #[test]
+    fn adblock_rules_block_domains_and_exceptions_allow_them() {
+        let list = "[Adblock Plus 2.0]\n\
+            ! Title: test\n\
+            ||Ads.Example.com^\n\
+            ||tracker.example.net^$important\n\
+            @@||safe.ads.example.com^\n\
+            ||scoped.example.org^$third-party\n\
+            ||cdn.example.org/ads/*\n\
+            example.com##.banner\n\
+            /banner/*\n";
+
+        let blocklist = Blocklist::from_adblock_format(list);
+
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("x.ads.example.com"));
+        assert!(blocklist.is_blocked("tracker.example.net"));
+        assert!(!blocklist.is_blocked("safe.ads.example.com"));
+        assert!(!blocklist.is_blocked("scoped.example.org"));
+        assert!(!blocklist.is_blocked("cdn.example.org"));
+    }
+
+    #[test]
+    fn adblock_rules_mix_into_plain_lists() {
+        // Excerpt in the style of the AdGuard DNS filter, which has no header.
+        let list = "! Title: AdGuard DNS filter\n\
+            ! Homepage: https://github.com/AdguardTeam/AdGuardSDNSFilter\n\
+            ||0-02.net^\n\
+            ||doubleclick.net^\n\
+            ||ad.doubleclick.net^$third-party\n\
+            @@||pagead2.googlesyndication.com^|\n\
+            @@||www.doubleclick.net^\n\
+            ||googlesyndication.com^\n\
+            ||example.org/ads/banner.js\n\
+            example.org##.ad-slot\n\
+            example.org#@#.sponsored\n\
+            /adserver/*\n\
+            0.0.0.0 hosts.example.com # inline comment\n\
+            plain.example.com\n";
+
+        let blocklist = Blocklist::from_lists(std::iter::once(list));
+
+        assert_eq!(blocklist.len(), 5);
+        assert!(blocklist.is_blocked("0-02.net"));
+        assert!(blocklist.is_blocked("ad.doubleclick.net"));
+        assert!(blocklist.is_blocked("adservice.googlesyndication.com"));
+        assert!(blocklist.is_blocked("hosts.example.com"));
+        assert!(blocklist.is_blocked("plain.example.com"));
+        assert!(!blocklist.is_blocked("www.doubleclick.net"));
+        assert!(!blocklist.is_blocked("x.pagead2.googlesyndication.com"));
+        assert!(!blocklist.is_blocked("example.org"));
+    }
+
+    #[test]
+    fn regex_rules_block_names_the_lookups_miss() {
+        let rules = "# per-customer metrics hosts\n\
+            ^[a-z0-9]+\\.metrics\\.vendor\\.com$\n\
+            \n\
+            ^[a-z0-9]{4}\\.collect\\.vendor\\.net$\n";
+        let mut blocklist = Blocklist::from_lists(std::iter::once("ads.example.com"))
+            .with_regex_rules(rules)
+            .unwrap();
+        blocklist.add_allowlist_entry("ok11.metrics.vendor.com");
+
+        let invalid = Blocklist::from_lists(std::iter::empty())
+            .with_regex_rules("^fine$\n^(unclosed\n[z-a]\n")
+            .err()
+            .unwrap();
+
+        assert_eq!(blocklist.len(), 3);
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert_eq!(
+            blocklist.matched_entry("a1b2c3.metrics.vendor.com"),
+            Some("a1b2c3.metrics.vendor.com")
+        );
+        assert!(blocklist.is_blocked("x9y8.collect.vendor.net"));
+        assert!(!blocklist.is_blocked("x9y8z.collect.vendor.net"));
+        assert!(!blocklist.is_blocked("metrics.vendor.com"));
+        assert!(!blocklist.is_blocked("ok11.metrics.vendor.com"));
+        assert!(invalid.starts_with("line 2: "));
+        assert!(invalid.contains("\nline 3: "));
+    }
+
+    #[test]
+    fn explain_block_names_the_rule_as_listed() {
+        let list = "tracker.net\n*.evil.com\n*.ads.*.cdn.net\n";
+        let blocklist = Blocklist::from_lists(std::iter::once(list))
+            .with_regex_rules("^[a-z0-9]+\\.metrics\\.vendor\\.com$\n")
+            .unwrap();
+
+        assert_eq!(
+            blocklist.explain_block("tracker.net").as_deref(),
+            Some("tracker.net")
+        );
+        assert_eq!(
+            blocklist.explain_block("a.b.tracker.net").as_deref(),
+            Some("tracker.net")
+        );
+        assert_eq!(
+            blocklist.explain_block("a.evil.com").as_deref(),
+            Some("*.evil.com")
+        );
+        assert_eq!(
+            blocklist.explain_block("x.ads.eu.cdn.net").as_deref(),
+            Some("*.ads.*.cdn.net")
+        );
+        assert_eq!(
+            blocklist.explain_block("a1.metrics.vendor.com").as_deref(),
+            Some("^[a-z0-9]+\\.metrics\\.vendor\\.com$")
+        );
+        assert_eq!(blocklist.explain_block("evil.com"), None);
+    }
+
+    #[test]
+    fn merged_lists_keep_shared_entries_once() {
+        let first = Blocklist::from_lists(std::iter::once(
+            "ads.example.com\n*.cdn.example.net\nads.*.example.org\n",
+        ));
+        let second = Blocklist::from_lists(std::iter::once(
+            "ads.example.com\ntracker.example.com\nads.*.example.org\n@@||ok.tracker.example.com^\n",
+        ));
+
+        let merged = Blocklist::merged([first, second]);
+
+        assert_eq!(merged.len(), 4);
+        assert!(merged.is_blocked("ads.example.com"));
+        assert!(merged.is_blocked("x.cdn.example.net"));
+        assert!(merged.is_blocked("ads.eu.example.org"));
+        assert!(merged.is_blocked("tracker.example.com"));
+        assert!(!merged.is_blocked("ok.tracker.example.com"));
+    }
+
+    #[test]
+    fn compact_storage_matches_like_the_hash_set() {
+        let list = "ads.example.com\ntracker.net\n*.cdn.example.org\n";
+        let blocklist = Blocklist::from_lists(std::iter::once(list)).with_compact_storage(true);
+
+        let mut merged = Blocklist::from_lists(std::iter::once(list)).with_compact_storage(true);
+        merged.merge(Blocklist::from_lists(std::iter::once("late.example.com\n")));
+
+        assert_eq!(blocklist.len(), 3);
+        assert_eq!(
+            blocklist.matched_entry("a.b.ads.example.com"),
+            Some("ads.example.com")
+        );
+        assert!(blocklist.is_blocked("tracker.net"));
+        assert!(blocklist.is_blocked("x.cdn.example.org"));
+        assert!(!blocklist.is_blocked("example.com"));
+        assert!(!blocklist.is_blocked("net"));
+        assert_eq!(merged.len(), 4);
+        assert!(merged.is_blocked("ads.example.com"));
+        assert!(merged.is_blocked("late.example.com"));
+    }
+
+    #[test]
+    fn compact_storage_of_a_single_domain_answers_misses() {
+        let blocklist =
+            Blocklist::from_lists(std::iter::once("ads.example.com\n")).with_compact_storage(true);
+
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(!blocklist.is_blocked("ok.example.com"));
+        assert!(!blocklist.is_blocked("example.com"));
+    }
+
+    #[test]
+    fn from_file_detects_adblock_lists() {
+        let path = std::env::temp_dir().join(format!("detour-adblock-{}.txt", std::process::id()));
+        std::fs::write(&path, "\n[Adblock Plus 2.0]\n||ads.example.com^\n").unwrap();
+
+        let blocklist = Blocklist::from_file(path.to_str().unwrap()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(blocklist.len(), 1);
+        assert!(blocklist.is_blocked("ads.example.com"));
+    }
+
+    #[test]
+    fn from_files_merges_every_list() {
+        let dir = std::env::temp_dir();
+        let plain = dir.join(format!("detour-plain-{}.txt", std::process::id()));
+        let adblock = dir.join(format!("detour-abp-{}.txt", std::process::id()));
+        std::fs::write(&plain, "ads.example.com\nshared.example\n").unwrap();
+        std::fs::write(
+            &adblock,
+            "[Adblock Plus 2.0]\n||shared.example^\n||t.example^\n",
+        )
+        .unwrap();
+
+        let blocklist =
+            Blocklist::from_files(&[plain.to_str().unwrap(), adblock.to_str().unwrap()]).unwrap();
+        let missing = Blocklist::from_files(&[plain.to_str().unwrap(), "/nonexistent/list.txt"]);
+        std::fs::remove_file(&plain).unwrap();
+        std::fs::remove_file(&adblock).unwrap();
+
+        assert_eq!(blocklist.len(), 3);
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(blocklist.is_blocked("a.t.example"));
+        assert!(missing.is_err());
+    }
+
+    #[test]
+    fn reload_from_file_swaps_domains_and_keeps_the_allowlist() {
+        let path =
+            std::env::temp_dir().join(format!("detour-blocklist-{}.txt", std::process::id()));
+        std::fs::write(&path, "ads.example.com\nkept.example.com\n").unwrap();
+        let mut blocklist = Blocklist::new();
+        blocklist.add_allowlist_entry("kept.example.com");
+
+        blocklist.reload_from_file(path.to_str().unwrap()).unwrap();
+        let missing = blocklist.reload_from_file("/nonexistent/detour-blocklist.txt");
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(missing.is_err());
+        assert_eq!(blocklist.len(), 2);
+        assert!(blocklist.is_blocked("ads.example.com"));
+        assert!(!blocklist.is_blocked("kept.example.com"));
+        assert!(!blocklist.is_blocked("doubleclick.com"));
+    }
+
+    #[cfg(feature = "list-adaway")]
+    #[test]
+    fn embedded_hosts_lists_contribute_bare_domains() {
+        let blocklist = Blocklist::new();
+
+        assert!(blocklist.is_blocked("analytics.163.com"));
+        assert!(!blocklist.is_blocked("localhost"));
+    }
 }
@@ -0,0 +1,152 @@
+//! Storage for the plain domains of a blocklist.
+//!
+//! Lists are parsed into a hash set, which takes insertions cheaply but keeps
+//! every domain in a heap allocation of its own behind a 24-byte `String`.
+//! A set can be packed instead: the domains are laid end to end in one
+//! buffer, each after a length byte, and found through an open-addressing
+//! table of 4-byte offsets into it. A lookup is still one hash and, on a hit,
+//! one comparison, while a domain costs its own bytes plus a few more.
+
+use rustc_hash::{FxBuildHasher, FxHashSet};
+use std::hash::BuildHasher;
+
+/// Domains held in a hash set, or packed into one buffer.
+pub(super) enum DomainSet {
+    Hash(FxHashSet<String>),
+    Packed(PackedDomains),
+}
+
+impl Default for DomainSet {
+    fn default() -> Self {
+        Self::Hash(FxHashSet::default())
+    }
+}
+
+impl DomainSet {
+    #[inline]
+    pub(super) fn contains(&self, domain: &str) -> bool {
+        match self {
+            Self::Hash(domains) => domains.contains(domain),
+            Self::Packed(domains) => domains.contains(domain),
+        }
+    }
+
+    pub(super) fn len(&self) -> usize {
+        match self {
+            Self::Hash(domains) => domains.len(),
+            Self::Packed(domains) => domains.len,
+        }
+    }
+
+    pub(super) fn is_packed(&self) -> bool {
+        matches!(self, Self::Packed(_))
+    }
+
+    /// Add a domain, unpacking a packed set into a hash set first.
+    pub(super) fn insert(&mut self, domain: String) {
+        self.hash_mut().insert(domain);
+    }
+
+    /// Add the domains of `other`, unpacking a packed set first.
+    pub(super) fn extend(&mut self, other: DomainSet) {
+        self.hash_mut().extend(other.into_hash());
+    }
+
+    /// Pack the domains into one buffer (a no-op if already packed).
+    pub(super) fn pack(self) -> Self {
+        match self {
+            Self::Hash(domains) => Self::Packed(PackedDomains::new(domains)),
+            packed => packed,
+        }
+    }
+
+    /// The domains as a hash set.
+    pub(super) fn into_hash(self) -> FxHashSet<String> {
+        match self {
+            Self::Hash(domains) => domains,
+            Self::Packed(domains) => domains.iter().map(str::to_string).collect(),
+        }
+    }
+
+    fn hash_mut(&mut self) -> &mut FxHashSet<String> {
+        if let Self::Packed(_) = self {
+            *self = Self::Hash(std::mem::take(self).into_hash());
+        }
+        match self {
+            Self::Hash(domains) => domains,
+            Self::Packed(_) => unreachable!("unpacked above"),
+        }
+    }
+}
+
+/// Marks a free slot in [`PackedDomains::slots`].
+const EMPTY: u32 = u32::MAX;
+
+/// Domains laid end to end, found through a linear-probing table.
+pub(super) struct PackedDomains {
+    /// Each domain as a length byte followed by its bytes.
+    bytes: Vec<u8>,
+    /// Offsets into `bytes`, or [`EMPTY`]; the length is a power of two,
+    /// at least half again the number of domains and always more than it, so
+    /// a miss reaches a free slot.
+    slots: Vec<u32>,
+    len: usize,
+}
+
+impl PackedDomains {
+    /// Pack `domains`; names longer than 255 bytes, which no DNS name is,
+    /// are dropped.
+    fn new(domains: FxHashSet<String>) -> Self {
+        let domains: Vec<String> = domains.into_iter().filter(|d| d.len() <= 255).collect();
+        let total: usize = domains.iter().map(|d| d.len() + 1).sum();
+        let mut packed = Self {
+            bytes: Vec::with_capacity(total),
+            slots: vec![EMPTY; (domains.len() + domains.len() / 2 + 1).next_power_of_two()],
+            len: domains.len(),
+        };
+        for domain in domains {
+            let offset = u32::try_from(packed.bytes.len())
+                .ok()
+                .filter(|&offset| offset != EMPTY)
+                .expect("packed domains fit in 4 GiB");
+            packed.bytes.push(domain.len() as u8);
+            packed.bytes.extend_from_slice(domain.as_bytes());
+            let mut slot = packed.first_slot(&domain);
+            while packed.slots[slot] != EMPTY {
+                slot = (slot + 1) & (packed.slots.len() - 1);
+            }
+            packed.slots[slot] = offset;
+        }
+        packed
+    }
+
+    #[inline]
+    fn first_slot(&self, domain: &str) -> usize {
+        FxBuildHasher.hash_one(domain) as usize & (self.slots.len() - 1)
+    }
+
+    #[inline]
+    fn get(&self, offset: u32) -> &[u8] {
+        let start = offset as usize + 1;
+        &self.bytes[start..start + self.bytes[offset as usize] as usize]
+    }
+
+    #[inline]
+    fn contains(&self, domain: &str) -> bool {
+        let mut slot = self.first_slot(domain);
+        loop {
+            match self.slots[slot] {
+                EMPTY => return false,
+                offset if self.get(offset) == domain.as_bytes() => return true,
+                _ => slot = (slot + 1) & (self.slots.len() - 1),
+            }
+        }
+    }
+
+    fn iter(&self) -> impl Iterator<Item = &str> {
+        self.slots
+            .iter()
+            .filter(|&&offset| offset != EMPTY)
+            .map(|&offset| std::str::from_utf8(self.get(offset)).expect("domains are ASCII"))
+    }
+}
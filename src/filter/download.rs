@@ -0,0 +1,415 @@
+//! Blocklists downloaded from URLs (`--blocklist-url`).
+//!
+//! Each list is kept on disk in a cache directory and loaded from there like
+//! any other list file, so a failed download at startup falls back to the
+//! last copy. Downloads send the copy's `ETag` and `Last-Modified` back as
+//! `If-None-Match` and `If-Modified-Since`; a `304 Not Modified` answer
+//! leaves it alone. HTTP and HTTPS URLs are accepted, HTTPS servers are
+//! verified against the system certificate store, and redirects are
+//! followed up to [`MAX_REDIRECTS`] times.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Empty, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http1;
+use hyper::{Request, Response, StatusCode, header};
+use hyper_util::rt::TokioIo;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, ClientConfig};
+
+use crate::transport::dot::system_roots;
+
+/// How long one download may take, redirects included.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Redirects followed before a download fails.
+pub const MAX_REDIRECTS: usize = 5;
+
+/// Largest list accepted; bigger answers fail the download.
+const MAX_LIST_BYTES: usize = 256 * 1024 * 1024;
+
+/// Result of a successful [`RemoteList::refresh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Refresh {
+    /// A new copy was downloaded.
+    Updated,
+    /// The server reported the list unchanged since the last copy.
+    NotModified,
+}
+
+/// A blocklist URL and its copy on disk.
+pub struct RemoteList {
+    url: String,
+    path: PathBuf,
+    /// For `https://` URLs
+    connector: Option<TlsConnector>,
+}
+
+/// Validators of the copy on disk, sent back on the next download.
+#[derive(Default)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Parts of an `http://` or `https://` URL.
+struct Url<'a> {
+    https: bool,
+    /// Host as written, with brackets for IPv6
+    host: &'a str,
+    port: u16,
+    /// Path and query, `/` when empty
+    path: &'a str,
+}
+
+impl RemoteList {
+    /// Track `url`, keeping its copy in `cache_dir`.
+    pub fn new(url: &str, cache_dir: &Path) -> io::Result<Self> {
+        let parts = parse_url(url).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid blocklist URL: {}", url),
+            )
+        })?;
+        let connector = match parts.https {
+            true => Some(tls_connector()?),
+            false => None,
+        };
+        Ok(Self {
+            url: url.to_string(),
+            path: cache_dir.join(file_name(url)),
+            connector,
+        })
+    }
+
+    /// The list's URL.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Where the downloaded copy is kept (it may not exist yet).
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Download the list if it changed since the copy on disk.
+    pub async fn refresh(&self) -> io::Result<Refresh> {
+        tokio::time::timeout(DOWNLOAD_TIMEOUT, self.download())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "download timed out"))?
+    }
+
+    async fn download(&self) -> io::Result<Refresh> {
+        let validators_path = self.validators_path();
+        let validators = match self.path.exists() {
+            true => Validators::read(&validators_path),
+            false => Validators::default(),
+        };
+        let mut url = self.url.clone();
+        for _ in 0..=MAX_REDIRECTS {
+            let response = self.get(&url, &validators).await?;
+            let status = response.status();
+            if status == StatusCode::NOT_MODIFIED {
+                return Ok(Refresh::NotModified);
+            }
+            if status.is_redirection() {
+                url = redirect_target(&url, &response)?;
+                continue;
+            }
+            if !status.is_success() {
+                return Err(io::Error::other(format!("HTTP {}", status)));
+            }
+            let fresh = Validators {
+                etag: header_value(&response, header::ETAG),
+                last_modified: header_value(&response, header::LAST_MODIFIED),
+            };
+            let body = Limited::new(response.into_body(), MAX_LIST_BYTES)
+                .collect()
+                .await
+                .map_err(io::Error::other)?
+                .to_bytes();
+            let path = self.path.clone();
+            tokio::task::spawn_blocking(move || {
+                save(&path, &body)?;
+                fresh.write(&validators_path)
+            })
+            .await
+            .map_err(io::Error::other)??;
+            return Ok(Refresh::Updated);
+        }
+        Err(io::Error::other(format!(
+            "more than {} redirects",
+            MAX_REDIRECTS
+        )))
+    }
+
+    /// Send one GET, conditional on `validators`.
+    async fn get(&self, url: &str, validators: &Validators) -> io::Result<Response<Incoming>> {
+        let invalid =
+            || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid URL: {}", url));
+        let parts = parse_url(url).ok_or_else(invalid)?;
+        let mut request = Request::get(parts.path)
+            .header(header::HOST, parts.authority())
+            .header(
+                header::USER_AGENT,
+                concat!("detour/", env!("CARGO_PKG_VERSION")),
+            );
+        if let Some(etag) = &validators.etag {
+            request = request.header(header::IF_NONE_MATCH, etag);
+        }
+        if let Some(last_modified) = &validators.last_modified {
+            request = request.header(header::IF_MODIFIED_SINCE, last_modified);
+        }
+        let request = request.body(Empty::new()).map_err(|_| invalid())?;
+
+        let host = parts.host.trim_start_matches('[').trim_end_matches(']');
+        let stream = TcpStream::connect((host, parts.port)).await?;
+        if !parts.https {
+            return send(stream, request).await;
+        }
+        // A redirect may lead from http:// to https://.
+        let connector = match &self.connector {
+            Some(connector) => connector.clone(),
+            None => tls_connector()?,
+        };
+        let server_name = ServerName::try_from(host.to_string()).map_err(|_| invalid())?;
+        let stream = connector.connect(server_name, stream).await?;
+        send(stream, request).await
+    }
+
+    fn validators_path(&self) -> PathBuf {
+        self.path.with_extension("validators")
+    }
+}
+
+/// TLS connector verifying servers against the system certificate store.
+fn tls_connector() -> io::Result<TlsConnector> {
+    let mut config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .map_err(io::Error::other)?
+            .with_root_certificates(system_roots()?)
+            .with_no_client_auth();
+    config.alpn_protocols = vec![b"http/1.1".to_vec()];
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+impl Validators {
+    /// Read validators saved by [`Validators::write`]; missing ones are None.
+    fn read(path: &Path) -> Self {
+        let mut validators = Self::default();
+        let content = std::fs::read_to_string(path).unwrap_or_default();
+        for line in content.lines() {
+            match line.split_once(": ") {
+                Some(("etag", value)) => validators.etag = Some(value.to_string()),
+                Some(("last-modified", value)) => {
+                    validators.last_modified = Some(value.to_string())
+                }
+                _ => {}
+            }
+        }
+        validators
+    }
+
+    fn write(&self, path: &Path) -> io::Result<()> {
+        let mut content = String::new();
+        if let Some(etag) = &self.etag {
+            content.push_str(&format!("etag: {}\n", etag));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            content.push_str(&format!("last-modified: {}\n", last_modified));
+        }
+        save(path, content.as_bytes())
+    }
+}
+
+impl Url<'_> {
+    /// `host[:port]` for the Host header, leaving out the default port.
+    fn authority(&self) -> String {
+        match (self.https, self.port) {
+            (true, 443) | (false, 80) => self.host.to_string(),
+            _ => format!("{}:{}", self.host, self.port),
+        }
+    }
+}
+
+/// Send `request` over a fresh HTTP/1.1 connection on `stream`.
+async fn send<S>(stream: S, request: Request<Empty<Bytes>>) -> io::Result<Response<Incoming>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (mut sender, connection) = http1::handshake(TokioIo::new(stream))
+        .await
+        .map_err(io::Error::other)?;
+    tokio::spawn(connection);
+    sender.send_request(request).await.map_err(io::Error::other)
+}
+
+/// The URL a redirect points to; relative locations are resolved against `url`.
+fn redirect_target(url: &str, response: &Response<Incoming>) -> io::Result<String> {
+    let location = header_value(response, header::LOCATION)
+        .ok_or_else(|| io::Error::other("redirect without a Location"))?;
+    if parse_url(&location).is_some() {
+        return Ok(location);
+    }
+    let base = parse_url(url).expect("URL parsed before the request");
+    match location.starts_with('/') {
+        true => Ok(format!(
+            "{}://{}{}",
+            if base.https { "https" } else { "http" },
+            base.authority(),
+            location
+        )),
+        false => Err(io::Error::other(format!(
+            "unsupported redirect: {}",
+            location
+        ))),
+    }
+}
+
+fn header_value(response: &Response<Incoming>, name: header::HeaderName) -> Option<String> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+/// Write `content` to `path` through a temporary file, so readers never see
+/// a partial copy.
+fn save(path: &Path, content: &[u8]) -> io::Result<()> {
+    let mut partial = path.as_os_str().to_owned();
+    partial.push(".partial");
+    std::fs::write(&partial, content)?;
+    std::fs::rename(&partial, path)
+}
+
+/// Split an `http://` or `https://` URL, or None if it is neither.
+fn parse_url(url: &str) -> Option<Url<'_>> {
+    let (https, rest) = match url.split_once("://")? {
+        ("https", rest) => (true, rest),
+        ("http", rest) => (false, rest),
+        _ => return None,
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let default_port = if https { 443 } else { 80 };
+    let (host, port) = match authority.rsplit_once(':') {
+        Some((host, port)) if !port.contains(']') => (host, port.parse().ok()?),
+        _ => (authority, default_port),
+    };
+    (!host.is_empty()).then_some(Url {
+        https,
+        host,
+        port,
+        path,
+    })
+}
+
+/// File name for the copy of `url`: the URL without its scheme, with
+/// anything but ASCII letters, digits, `.` and `-` replaced by `_`.
+fn file_name(url: &str) -> String {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    let mut name: String = rest
+        .chars()
+        .take(200)
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '.' | '-' => c,
+            _ => '_',
+        })
+        .collect();
+    name.push_str(".list");
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Serve `responses` in order, one per connection, returning the address
+    /// and a channel carrying each request's head.
+    async fn server(
+        responses: Vec<String>,
+    ) -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (requests, received) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                while !head.ends_with(b"\r\n\r\n") {
+                    head.push(stream.read_u8().await.unwrap());
+                }
+                requests.send(String::from_utf8(head).unwrap()).unwrap();
+                stream.write_all(response.as_bytes()).await.unwrap();
+                stream.shutdown().await.unwrap();
+            }
+        });
+        (format!("http://{}", addr), received)
+    }
+
+    #[tokio::test]
+    async fn downloads_follow_redirects_and_revalidate_the_copy() {
+        let dir = std::env::temp_dir().join(format!("detour-download-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (base, mut requests) = server(vec![
+            "HTTP/1.1 302 Found\r\nLocation: /lists/ads.txt\r\nContent-Length: 0\r\n\r\n".into(),
+            "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 16\r\n\r\nads.example.com\n".into(),
+            "HTTP/1.1 304 Not Modified\r\n\r\n".into(),
+            "HTTP/1.1 500 Internal Server Error\r\nContent-Length: 0\r\n\r\n".into(),
+        ])
+        .await;
+        let list = RemoteList::new(&format!("{}/ads", base), &dir).unwrap();
+
+        let first = list.refresh().await.unwrap();
+        let copy = std::fs::read_to_string(list.path()).unwrap();
+        let second = list.refresh().await.unwrap();
+        let failed = list.refresh().await;
+        let mut heads = Vec::new();
+        while let Ok(head) = requests.try_recv() {
+            heads.push(head);
+        }
+        let kept = std::fs::read_to_string(list.path()).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(first, Refresh::Updated);
+        assert_eq!(copy, "ads.example.com\n");
+        assert_eq!(second, Refresh::NotModified);
+        assert!(failed.is_err());
+        assert_eq!(kept, copy);
+        assert!(heads[1].starts_with("GET /lists/ads.txt HTTP/1.1\r\n"));
+        assert!(!heads[1].contains("if-none-match"));
+        assert!(heads[2].contains("if-none-match: \"v1\"\r\n"));
+    }
+
+    #[test]
+    fn urls_split_into_host_port_and_path() {
+        let url = parse_url("https://big.oisd.nl/domainswild").unwrap();
+        let ipv6 = parse_url("http://[::1]:8080").unwrap();
+
+        assert_eq!(
+            (url.https, url.host, url.port, url.path),
+            (true, "big.oisd.nl", 443, "/domainswild")
+        );
+        assert_eq!(url.authority(), "big.oisd.nl");
+        assert_eq!((ipv6.host, ipv6.port, ipv6.path), ("[::1]", 8080, "/"));
+        assert_eq!(ipv6.authority(), "[::1]:8080");
+        assert!(parse_url("ftp://example.com/list").is_none());
+        assert_eq!(
+            file_name("https://big.oisd.nl/domainswild?v=2"),
+            "big.oisd.nl_domainswild_v_2.list"
+        );
+    }
+}
@@ -4,19 +4,172 @@
 //! a blocklist of known ad/tracking domains.
 
 mod blocklist;
+mod domains;
+#[cfg(feature = "blocklist-url")]
+pub mod download;
+mod suffix;
 
 pub use blocklist::Blocklist;
+pub use suffix::SuffixSet;
 
-use crate::dns::DnsQuery;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use crate::dns::{DnsQuery, DnsResponse};
+
+/// How queries for blocked domains are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BlockedResponseStyle {
+    /// NXDOMAIN with an SOA in the authority section, so clients cache the negative answer.
+    Nxdomain,
+    /// REFUSED with no answers, signalling a policy refusal rather than a fake answer.
+    Refused,
+    /// An A record pointing to 0.0.0.0 (`::` for AAAA, NODATA for other types).
+    #[default]
+    NullIp,
+    /// A and AAAA records pointing to sinkhole addresses (NODATA for other types).
+    CustomIp(Ipv4Addr, Ipv6Addr),
+}
+
+impl FromStr for BlockedResponseStyle {
+    type Err = String;
+
+    /// Parse `nxdomain`, `refused`, `null` (0.0.0.0) or sinkhole addresses:
+    /// an IPv4 address, an IPv6 one, or both separated by a comma. A family
+    /// without an address gets the unspecified one.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid blocked response style: {}", s);
+        match s.to_ascii_lowercase().as_str() {
+            "nxdomain" => Ok(Self::Nxdomain),
+            "refused" => Ok(Self::Refused),
+            "null" | "nullip" | "0.0.0.0" => Ok(Self::NullIp),
+            other => {
+                let (mut ipv4, mut ipv6) = (Ipv4Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED);
+                for addr in other.split(',') {
+                    match addr.trim().parse().map_err(|_| invalid())? {
+                        IpAddr::V4(addr) => ipv4 = addr,
+                        IpAddr::V6(addr) => ipv6 = addr,
+                    }
+                }
+                Ok(Self::CustomIp(ipv4, ipv6))
+            }
+        }
+    }
+}
+
+impl fmt::Display for BlockedResponseStyle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Nxdomain => write!(f, "nxdomain"),
+            Self::Refused => write!(f, "refused"),
+            Self::NullIp => write!(f, "null"),
+            Self::CustomIp(ipv4, ipv6) if ipv6.is_unspecified() => write!(f, "{}", ipv4),
+            Self::CustomIp(ipv4, ipv6) if ipv4.is_unspecified() => write!(f, "{}", ipv6),
+            Self::CustomIp(ipv4, ipv6) => write!(f, "{},{}", ipv4, ipv6),
+        }
+    }
+}
 
 /// Check if a DNS query should be blocked and return an appropriate response.
 ///
 /// Returns `Some(response)` if the query should be blocked, `None` if it should
-/// be forwarded to upstream.
-pub fn filter_query(blocklist: &Blocklist, query: &DnsQuery) -> Option<Vec<u8>> {
+/// be forwarded to upstream. The response's records carry `ttl`.
+pub fn filter_query(
+    blocklist: &Blocklist,
+    query: &DnsQuery,
+    style: BlockedResponseStyle,
+    ttl: u32,
+) -> Option<Vec<u8>> {
     if blocklist.is_blocked(&query.domain) {
-        Some(query.blocked_response().to_bytes())
+        Some(DnsResponse::blocked(query, style, ttl).to_bytes())
     } else {
         None
     }
 }
+
+/// Check an upstream response for CNAME cloaking: a name that is not blocked
+/// but is an alias, somewhere along its CNAME chain, of one that is.
+///
+/// Returns an NXDOMAIN answer to send instead, with the response's ID and
+/// question and an SOA carrying `ttl`, or `None` if no name in the chain is
+/// blocked.
+pub fn check_cname_cloaking(blocklist: &Blocklist, response: &[u8], ttl: u32) -> Option<Vec<u8>> {
+    if blocklist.is_empty() {
+        return None;
+    }
+    let chain = DnsResponse::parse_cname_chain(response);
+    if !chain.iter().any(|name| blocklist.is_blocked(name)) {
+        return None;
+    }
+    let query = DnsQuery::parse(response)?;
+    Some(DnsResponse::blocked(&query, BlockedResponseStyle::Nxdomain, ttl).to_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::DEFAULT_BLOCKED_TTL;
+
+    #[test]
+    fn blocked_response_style_parses() {
+        assert_eq!("NXDOMAIN".parse(), Ok(BlockedResponseStyle::Nxdomain));
+        assert_eq!("null".parse(), Ok(BlockedResponseStyle::NullIp));
+        assert_eq!(
+            "10.0.0.53".parse(),
+            Ok(BlockedResponseStyle::CustomIp(
+                Ipv4Addr::new(10, 0, 0, 53),
+                Ipv6Addr::UNSPECIFIED
+            ))
+        );
+        assert_eq!(
+            "10.0.0.53, fd00::53".parse(),
+            Ok(BlockedResponseStyle::CustomIp(
+                Ipv4Addr::new(10, 0, 0, 53),
+                "fd00::53".parse().unwrap()
+            ))
+        );
+        assert!("sinkhole".parse::<BlockedResponseStyle>().is_err());
+        assert!(
+            "10.0.0.53,sinkhole"
+                .parse::<BlockedResponseStyle>()
+                .is_err()
+        );
+    }
+
+    /// `metrics.example.com CNAME tracker.evil.com`, then an A record for the tracker.
+    fn cloaked_response() -> Vec<u8> {
+        let query = DnsQuery::new(42, "metrics.example.com", crate::dns::TYPE_A);
+        let mut response = query.blocked_response(BlockedResponseStyle::NullIp);
+        let mut cname = response.answers[0].clone();
+        cname.rtype = crate::dns::TYPE_CNAME;
+        cname.rdata = b"\x07tracker\x04evil\x03com\x00".to_vec();
+        response.answers[0].name = "tracker.evil.com".to_string();
+        response.answers.insert(0, cname);
+        response.to_bytes()
+    }
+
+    #[test]
+    fn cname_cloaked_trackers_are_answered_with_nxdomain() {
+        let blocking = Blocklist::from_adblock_format("||tracker.evil.com^");
+        let other = Blocklist::from_adblock_format("||ads.example.net^");
+
+        let blocked =
+            check_cname_cloaking(&blocking, &cloaked_response(), DEFAULT_BLOCKED_TTL).unwrap();
+        let blocked = DnsResponse::parse(&blocked).unwrap();
+
+        assert_eq!(blocked.id, 42);
+        assert_eq!(blocked.rcode(), 3);
+        assert!(blocked.answers.is_empty());
+        assert_eq!(blocked.questions[0].domain, "metrics.example.com");
+        assert!(check_cname_cloaking(&other, &cloaked_response(), DEFAULT_BLOCKED_TTL).is_none());
+        assert!(
+            check_cname_cloaking(
+                &Blocklist::empty(),
+                &cloaked_response(),
+                DEFAULT_BLOCKED_TTL
+            )
+            .is_none()
+        );
+    }
+}
@@ -0,0 +1,71 @@
+//! Domain suffix matching.
+//!
+//! Matches a domain against a set of suffixes on label boundaries, so
+//! `example.com` matches `example.com` and `a.example.com` but not
+//! `badexample.com`.
+
+use rustc_hash::FxHashSet;
+
+/// A set of domain suffixes.
+#[derive(Default)]
+pub struct SuffixSet {
+    suffixes: FxHashSet<String>,
+}
+
+impl SuffixSet {
+    /// Create a set from domain suffixes (normalized to lowercase, trailing dot removed).
+    pub fn new<S: AsRef<str>>(suffixes: impl IntoIterator<Item = S>) -> Self {
+        let suffixes = suffixes
+            .into_iter()
+            .map(|s| s.as_ref().trim().trim_end_matches('.').to_ascii_lowercase())
+            .filter(|s| !s.is_empty())
+            .collect();
+        Self { suffixes }
+    }
+
+    /// Check if the domain or any of its parents is in the set (assumes lowercase input).
+    #[inline]
+    pub fn matches(&self, domain: &str) -> bool {
+        if self.suffixes.is_empty() {
+            return false;
+        }
+        let mut current = domain;
+        loop {
+            if self.suffixes.contains(current) {
+                return true;
+            }
+            match current.find('.') {
+                Some(pos) => current = &current[pos + 1..],
+                None => return false,
+            }
+        }
+    }
+
+    /// Returns true if the set contains no suffixes.
+    pub fn is_empty(&self) -> bool {
+        self.suffixes.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_on_label_boundaries() {
+        let set = SuffixSet::new(["Example.com."]);
+
+        assert!(set.matches("example.com"));
+        assert!(set.matches("a.b.example.com"));
+        assert!(!set.matches("badexample.com"));
+        assert!(!set.matches("com"));
+    }
+
+    #[test]
+    fn empty_set_matches_nothing() {
+        let set = SuffixSet::default();
+
+        assert!(set.is_empty());
+        assert!(!set.matches("example.com"));
+    }
+}
@@ -1,22 +1,41 @@
 //! Detour - A performance focused DNS proxy.
 //!
 //! A minimal, single-threaded DNS proxy that supports:
-//! - UDP and TCP transports
+//! - UDP, TCP, DNS-over-TLS and DNS-over-HTTPS transports
 //! - Response caching with TTL-based expiration
 //! - Domain blocklist filtering
 //! - Upstream racing (queries multiple servers, uses first response)
 //!
 //! # Architecture
 //!
-//! - [`transport`] - UDP and TCP network handlers
+//! - [`transport`] - UDP, TCP, DNS-over-TLS and DNS-over-HTTPS network handlers
 //! - [`resolver`] - Query processing logic (block/cache/forward decisions)
+//! - [`upstream`] - Upstream specs and per-upstream rate limits
 //! - [`cache`] - TTL-aware DNS response cache
 //! - [`filter`] - Domain blocklist matching
 //! - [`dns`] - DNS message parsing and construction
+//! - [`logging`] - Log backends (stdout, journald, syslog)
+//! - [`proxy`] - Proxy configuration and startup
+//! - [`shutdown`] - Graceful shutdown and connection draining
+//! - [`config`] - TOML configuration file (`--config`)
+//! - [`tail`] - Live query event stream (`detour tail`)
+//! - [`statsd`] - Metrics push to a statsd collector
+//! - [`bench`] - Upstream latency/filtering comparison
+//! - [`admin`] - HTTP admin API for the cache
 
+pub mod admin;
+pub mod bench;
 pub mod cache;
+pub mod config;
 pub mod dns;
 pub mod filter;
+pub mod logging;
+pub mod proxy;
 pub mod resolver;
+pub mod shutdown;
 pub mod stats;
+pub mod statsd;
+#[cfg(unix)]
+pub mod tail;
 pub mod transport;
+pub mod upstream;
@@ -0,0 +1,634 @@
+//! Log output backends (`--log-target`, `--log-file`, `--log-format`).
+//!
+//! Query events, stats and errors are emitted as [`LogEvent`]s carrying a
+//! syslog priority and optional structured fields. The stdout and file
+//! backends write one line per event, either the familiar `[timestamp]
+//! message` text or a JSON object for log aggregators; the journald and
+//! syslog backends send one datagram per event so severity and fields
+//! survive, which lets `journalctl -p warning -u detour` show only real
+//! problems.
+//!
+//! If the journald or syslog socket cannot be opened, or off Unix where
+//! there is none, output falls back to stdout.
+//!
+//! Query events are also published on a bounded broadcast channel for
+//! `detour tail` subscribers; a subscriber that falls behind is dropped
+//! rather than slowing down query handling.
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write as _};
+use std::net::{IpAddr, SocketAddr};
+#[cfg(unix)]
+use std::os::fd::AsFd;
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+#[cfg(unix)]
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+use tokio::sync::broadcast;
+
+const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+const SYSLOG_SOCKET: &str = "/dev/log";
+#[cfg(unix)]
+const IDENTIFIER: &str = "detour";
+
+/// Syslog facility `daemon`.
+#[cfg(unix)]
+const FACILITY_DAEMON: u8 = 3;
+
+/// Query events buffered per subscriber before it is considered lagging.
+const STREAM_CAPACITY: usize = 1024;
+
+/// Where log events are written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogTarget {
+    /// Plain lines on stdout (errors on stderr)
+    #[default]
+    Stdout,
+    /// The systemd journal, via its native protocol
+    Journald,
+    /// RFC 5424 syslog over /dev/log, facility `daemon`
+    Syslog,
+}
+
+/// How events are written to stdout or a log file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum LogFormat {
+    /// `[timestamp] message` lines
+    #[default]
+    Text,
+    /// One JSON object per line, with the event's fields as keys
+    Json,
+}
+
+/// Syslog severity of an event.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Priority {
+    Err = 3,
+    Warning = 4,
+    Notice = 5,
+    Info = 6,
+    Debug = 7,
+}
+
+impl Priority {
+    fn as_str(self) -> &'static str {
+        match self {
+            Priority::Err => "error",
+            Priority::Warning => "warn",
+            Priority::Notice => "notice",
+            Priority::Info => "info",
+            Priority::Debug => "debug",
+        }
+    }
+}
+
+/// A single log event.
+pub struct LogEvent<'a> {
+    pub priority: Priority,
+    pub message: fmt::Arguments<'a>,
+    pub protocol: Option<&'a str>,
+    pub domain: Option<&'a str>,
+    pub action: Option<&'a str>,
+    pub latency_ms: Option<f64>,
+    /// Upstream that answered a forwarded query, and its latency
+    pub upstream: Option<(SocketAddr, f64)>,
+}
+
+impl<'a> LogEvent<'a> {
+    pub fn new(priority: Priority, message: fmt::Arguments<'a>) -> Self {
+        Self {
+            priority,
+            message,
+            protocol: None,
+            domain: None,
+            action: None,
+            latency_ms: None,
+            upstream: None,
+        }
+    }
+
+    pub fn with_protocol(mut self, protocol: &'a str) -> Self {
+        self.protocol = Some(protocol);
+        self
+    }
+
+    pub fn with_domain(mut self, domain: &'a str) -> Self {
+        self.domain = Some(domain);
+        self
+    }
+
+    pub fn with_action(mut self, action: &'a str) -> Self {
+        self.action = Some(action);
+        self
+    }
+
+    pub fn with_latency(mut self, latency_ms: f64) -> Self {
+        self.latency_ms = Some(latency_ms);
+        self
+    }
+
+    pub fn with_upstream(mut self, upstream: (SocketAddr, f64)) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+}
+
+enum Sink {
+    Stdout(LogFormat),
+    File(Mutex<File>, LogFormat),
+    #[cfg(unix)]
+    Journald(UnixDatagram),
+    #[cfg(unix)]
+    Syslog(UnixDatagram),
+}
+
+static SINK: OnceLock<Sink> = OnceLock::new();
+
+/// Select the log backend. Only the first call has any effect.
+///
+/// With a `file`, events are appended to it instead of going to `target`.
+/// Falls back to stdout (with a warning on stderr) if the target socket is
+/// unavailable; a file that cannot be opened is an error.
+pub fn init(target: LogTarget, format: LogFormat, file: Option<&Path>) -> io::Result<()> {
+    let sink = match (target, file) {
+        (_, Some(path)) => {
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| {
+                    io::Error::new(
+                        e.kind(),
+                        format!("Cannot open log file {}: {}", path.display(), e),
+                    )
+                })?;
+            Sink::File(Mutex::new(file), format)
+        }
+        (LogTarget::Stdout, None) => Sink::Stdout(format),
+        #[cfg(unix)]
+        (LogTarget::Journald, None) => match connect(JOURNALD_SOCKET) {
+            Ok(socket) => Sink::Journald(socket),
+            Err(e) => fallback("journald", JOURNALD_SOCKET, e, format),
+        },
+        #[cfg(unix)]
+        (LogTarget::Syslog, None) => match connect(SYSLOG_SOCKET) {
+            Ok(socket) => Sink::Syslog(socket),
+            Err(e) => fallback("syslog", SYSLOG_SOCKET, e, format),
+        },
+        #[cfg(not(unix))]
+        (LogTarget::Journald, None) => fallback("journald", JOURNALD_SOCKET, unsupported(), format),
+        #[cfg(not(unix))]
+        (LogTarget::Syslog, None) => fallback("syslog", SYSLOG_SOCKET, unsupported(), format),
+    };
+    let _ = SINK.set(sink);
+    Ok(())
+}
+
+/// Check if stdout is connected to the systemd journal, as it is for a
+/// service without `StandardOutput=` redirection.
+#[cfg(unix)]
+pub fn stdout_is_journal() -> bool {
+    let Ok(stream) = std::env::var("JOURNAL_STREAM") else {
+        return false;
+    };
+    let Ok(stdout) = io::stdout().as_fd().try_clone_to_owned() else {
+        return false;
+    };
+    match File::from(stdout).metadata() {
+        Ok(metadata) => stream == format!("{}:{}", metadata.dev(), metadata.ino()),
+        Err(_) => false,
+    }
+}
+
+#[cfg(not(unix))]
+pub fn stdout_is_journal() -> bool {
+    false
+}
+
+#[cfg(unix)]
+fn connect(path: &str) -> io::Result<UnixDatagram> {
+    let socket = UnixDatagram::unbound()?;
+    socket.connect(path)?;
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+#[cfg(not(unix))]
+fn unsupported() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        "Unix sockets are only available on Unix",
+    )
+}
+
+fn fallback(name: &str, path: &str, error: io::Error, format: LogFormat) -> Sink {
+    eprintln!("Cannot log to {} ({}: {}), using stdout", name, path, error);
+    Sink::Stdout(format)
+}
+
+/// Write an event to the configured backend.
+pub fn emit(event: &LogEvent) {
+    match SINK.get_or_init(|| Sink::Stdout(LogFormat::Text)) {
+        Sink::Stdout(format) => {
+            let line = format_line(event, *format);
+            match event.priority {
+                Priority::Err => eprintln!("{}", line),
+                _ => println!("{}", line),
+            }
+        }
+        Sink::File(file, format) => {
+            let mut line = format_line(event, *format);
+            line.push('\n');
+            if let Ok(mut file) = file.lock() {
+                let _ = file.write_all(line.as_bytes());
+            }
+        }
+        #[cfg(unix)]
+        Sink::Journald(socket) => {
+            let _ = socket.send(&journald_payload(event));
+        }
+        #[cfg(unix)]
+        Sink::Syslog(socket) => {
+            let _ = socket.send(syslog_payload(event).as_bytes());
+        }
+    }
+}
+
+pub fn error(message: fmt::Arguments) {
+    emit(&LogEvent::new(Priority::Err, message));
+}
+
+pub fn warn(message: fmt::Arguments) {
+    emit(&LogEvent::new(Priority::Warning, message));
+}
+
+pub fn info(message: fmt::Arguments) {
+    emit(&LogEvent::new(Priority::Info, message));
+}
+
+/// A query event as streamed to `detour tail` subscribers.
+#[derive(Debug, Clone)]
+pub struct QueryEvent {
+    pub client: IpAddr,
+    pub domain: String,
+    pub action: String,
+    /// The event formatted like a verbose-mode line.
+    pub line: String,
+}
+
+static STREAM: OnceLock<broadcast::Sender<Arc<QueryEvent>>> = OnceLock::new();
+
+fn stream() -> &'static broadcast::Sender<Arc<QueryEvent>> {
+    STREAM.get_or_init(|| broadcast::channel(STREAM_CAPACITY).0)
+}
+
+/// Subscribe to the live query event stream.
+pub fn subscribe() -> broadcast::Receiver<Arc<QueryEvent>> {
+    stream().subscribe()
+}
+
+/// Check if anyone is subscribed, so events are only built when needed.
+#[inline]
+pub fn has_subscribers() -> bool {
+    STREAM.get().is_some_and(|s| s.receiver_count() > 0)
+}
+
+/// Publish a query event to subscribers; never blocks.
+pub fn publish(event: QueryEvent) {
+    let _ = stream().send(Arc::new(event));
+}
+
+fn format_line(event: &LogEvent, format: LogFormat) -> String {
+    match format {
+        LogFormat::Text => text_line(event),
+        LogFormat::Json => json_line(event),
+    }
+}
+
+/// Format an event as a `[timestamp] message` line, tagging errors and warnings.
+fn text_line(event: &LogEvent) -> String {
+    let level = match event.priority {
+        Priority::Err => "[ERROR] ",
+        Priority::Warning => "[WARN] ",
+        _ => "",
+    };
+    format!("[{}] {}{}", timestamp(), level, event.message)
+}
+
+/// Format an event as a single-line JSON object; fields the event does not
+/// carry are left out.
+fn json_line(event: &LogEvent) -> String {
+    let mut line = format!(
+        "{{\"timestamp\":\"{}\",\"level\":\"{}\"",
+        rfc3339_timestamp(),
+        event.priority.as_str()
+    );
+    if let Some(protocol) = event.protocol {
+        line.push_str(&format!(",\"protocol\":\"{}\"", json_escape(protocol)));
+    }
+    if let Some(domain) = event.domain {
+        line.push_str(&format!(",\"domain\":\"{}\"", json_escape(domain)));
+    }
+    if let Some(action) = event.action {
+        line.push_str(&format!(",\"action\":\"{}\"", json_escape(action)));
+    }
+    if let Some(latency) = event.latency_ms {
+        line.push_str(&format!(",\"total_ms\":{:.3}", latency));
+    }
+    if let Some((from, upstream_ms)) = event.upstream {
+        line.push_str(&format!(
+            ",\"upstream_ms\":{:.3},\"upstream_addr\":\"{}\"",
+            upstream_ms, from
+        ));
+    }
+    line.push_str(&format!(
+        ",\"message\":\"{}\"}}",
+        json_escape(&event.message.to_string())
+    ));
+    line
+}
+
+/// Escape a string for use inside a JSON string literal.
+pub(crate) fn json_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                escaped.push_str(&format!("\\u{:04x}", c as u32));
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Encode an event in the journald native protocol.
+#[cfg(unix)]
+fn journald_payload(event: &LogEvent) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(256);
+    journald_field(&mut buf, "PRIORITY", &(event.priority as u8).to_string());
+    journald_field(&mut buf, "SYSLOG_IDENTIFIER", IDENTIFIER);
+    journald_field(&mut buf, "MESSAGE", &event.message.to_string());
+    if let Some(protocol) = event.protocol {
+        journald_field(&mut buf, "DETOUR_PROTOCOL", protocol);
+    }
+    if let Some(domain) = event.domain {
+        journald_field(&mut buf, "DETOUR_DOMAIN", domain);
+    }
+    if let Some(action) = event.action {
+        journald_field(&mut buf, "DETOUR_ACTION", action);
+    }
+    if let Some(latency) = event.latency_ms {
+        journald_field(&mut buf, "DETOUR_LATENCY_MS", &format!("{:.3}", latency));
+    }
+    if let Some((from, upstream_ms)) = event.upstream {
+        journald_field(&mut buf, "DETOUR_UPSTREAM", &from.to_string());
+        journald_field(
+            &mut buf,
+            "DETOUR_UPSTREAM_MS",
+            &format!("{:.3}", upstream_ms),
+        );
+    }
+    buf
+}
+
+/// Append one field; values containing newlines use the length-prefixed form.
+#[cfg(unix)]
+fn journald_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    if value.contains('\n') {
+        buf.push(b'\n');
+        buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_bytes());
+    buf.push(b'\n');
+}
+
+/// Format an event as an RFC 5424 syslog message.
+#[cfg(unix)]
+fn syslog_payload(event: &LogEvent) -> String {
+    let pri = FACILITY_DAEMON * 8 + event.priority as u8;
+    let mut params = String::new();
+    if let Some(domain) = event.domain {
+        params.push_str(&format!(" domain=\"{}\"", sd_escape(domain)));
+    }
+    if let Some(action) = event.action {
+        params.push_str(&format!(" action=\"{}\"", sd_escape(action)));
+    }
+    if let Some(latency) = event.latency_ms {
+        params.push_str(&format!(" latency_ms=\"{:.3}\"", latency));
+    }
+    let structured = if params.is_empty() {
+        "-".to_string()
+    } else {
+        format!("[detour@32473{}]", params)
+    };
+    format!(
+        "<{}>1 - - {} {} - {} {}",
+        pri,
+        IDENTIFIER,
+        std::process::id(),
+        structured,
+        event.message
+    )
+}
+
+/// Escape `"`, `\` and `]` in a structured-data parameter value.
+#[cfg(unix)]
+fn sd_escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '"' | '\\' | ']') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Current UTC time as `YYYY-MM-DD HH:MM:SS`.
+pub fn timestamp() -> String {
+    utc_now(' ')
+}
+
+/// Current UTC time as RFC 3339 (`YYYY-MM-DDTHH:MM:SSZ`).
+fn rfc3339_timestamp() -> String {
+    let mut now = utc_now('T');
+    now.push('Z');
+    now
+}
+
+/// Current UTC date and time, separated by `separator`.
+fn utc_now(separator: char) -> String {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let total_secs = now.as_secs();
+
+    // Days since epoch
+    let days = total_secs / 86400;
+
+    // Calculate year, month, day from days since 1970-01-01
+    let (year, month, day) = days_to_ymd(days);
+
+    // Time of day
+    let day_secs = total_secs % 86400;
+    let hours = day_secs / 3600;
+    let mins = (day_secs % 3600) / 60;
+    let secs = day_secs % 60;
+
+    format!(
+        "{:04}-{:02}-{:02}{}{:02}:{:02}:{:02}",
+        year, month, day, separator, hours, mins, secs
+    )
+}
+
+fn days_to_ymd(days: u64) -> (u64, u64, u64) {
+    // Days since 1970-01-01
+    let mut remaining = days as i64;
+    let mut year = 1970i64;
+
+    loop {
+        let days_in_year = if is_leap_year(year) { 366 } else { 365 };
+        if remaining < days_in_year {
+            break;
+        }
+        remaining -= days_in_year;
+        year += 1;
+    }
+
+    let leap = is_leap_year(year);
+    let days_in_months: [i64; 12] = [
+        31,
+        if leap { 29 } else { 28 },
+        31,
+        30,
+        31,
+        30,
+        31,
+        31,
+        30,
+        31,
+        30,
+        31,
+    ];
+
+    let mut month = 1;
+    for days_in_month in days_in_months {
+        if remaining < days_in_month {
+            break;
+        }
+        remaining -= days_in_month;
+        month += 1;
+    }
+
+    (year as u64, month, remaining as u64 + 1)
+}
+
+fn is_leap_year(year: i64) -> bool {
+    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn journald_payload_carries_priority_and_fields() {
+        let event = LogEvent::new(Priority::Warning, format_args!("slow query"))
+            .with_domain("example.com")
+            .with_action("FORWARDED")
+            .with_latency(12.5);
+
+        let payload = String::from_utf8(journald_payload(&event)).unwrap();
+
+        assert_eq!(
+            payload,
+            "PRIORITY=4\nSYSLOG_IDENTIFIER=detour\nMESSAGE=slow query\n\
+             DETOUR_DOMAIN=example.com\nDETOUR_ACTION=FORWARDED\nDETOUR_LATENCY_MS=12.500\n"
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn journald_multiline_values_are_length_prefixed() {
+        let mut buf = Vec::new();
+
+        journald_field(&mut buf, "MESSAGE", "a\nb");
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&3u64.to_le_bytes());
+        expected.extend_from_slice(b"a\nb\n");
+        assert_eq!(buf, expected);
+    }
+
+    #[test]
+    fn json_line_carries_query_fields() {
+        let from: SocketAddr = "1.1.1.1:53".parse().unwrap();
+        let event = LogEvent::new(Priority::Info, format_args!("say \"hi\"\n"))
+            .with_protocol("UDP")
+            .with_domain("example.com")
+            .with_action("FORWARDED")
+            .with_latency(12.5)
+            .with_upstream((from, 11.25));
+
+        let line = json_line(&event);
+
+        let (timestamp, rest) = line.split_once("\",\"level\"").unwrap();
+        assert!(timestamp.starts_with("{\"timestamp\":\""));
+        assert!(timestamp.ends_with('Z'));
+        assert_eq!(
+            rest,
+            ":\"info\",\"protocol\":\"UDP\",\"domain\":\"example.com\",\
+             \"action\":\"FORWARDED\",\"total_ms\":12.500,\"upstream_ms\":11.250,\
+             \"upstream_addr\":\"1.1.1.1:53\",\"message\":\"say \\\"hi\\\"\\n\"}"
+        );
+    }
+
+    #[test]
+    fn json_line_leaves_out_missing_fields() {
+        let event = LogEvent::new(Priority::Err, format_args!("UDP recv error"));
+
+        let line = json_line(&event);
+
+        let (_, rest) = line.split_once("\",\"level\"").unwrap();
+        assert_eq!(rest, ":\"error\",\"message\":\"UDP recv error\"}");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn syslog_payload_is_rfc5424() {
+        let event = LogEvent::new(Priority::Err, format_args!("UDP recv error"));
+        let query = LogEvent::new(Priority::Info, format_args!("q"))
+            .with_domain("a\"b.example")
+            .with_action("BLOCKED");
+
+        let plain = syslog_payload(&event);
+        let structured = syslog_payload(&query);
+
+        let pid = std::process::id();
+        assert_eq!(
+            plain,
+            format!("<27>1 - - detour {} - - UDP recv error", pid)
+        );
+        assert_eq!(
+            structured,
+            format!(
+                "<30>1 - - detour {} - [detour@32473 domain=\"a\\\"b.example\" action=\"BLOCKED\"] q",
+                pid
+            )
+        );
+    }
+}
@@ -3,17 +3,25 @@
 //! Forwards DNS queries to an upstream server with optional ad-blocking.
 //! Supports both UDP and TCP transports.
 
-mod cache;
-mod dns;
-mod filter;
-mod proxy;
-mod resolver;
-mod stats;
-mod transport;
-
-use clap::{Parser, Subcommand};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, Subcommand};
+use detour::config::ConfigFile;
+use detour::dns::{DEFAULT_BLOCKED_TTL, DnsQuery, MAX_BLOCKED_TTL, TYPE_NS};
+use detour::filter::BlockedResponseStyle;
+use detour::logging::{self, LogFormat, LogTarget};
+use detour::resolver::{ForwardRule, LocalRecord, QnameMinimization};
+#[cfg(unix)]
+use detour::tail;
+use detour::transport::MAX_DNS_PACKET_SIZE;
+use detour::transport::dot;
+use detour::transport::tcp::DEFAULT_MAX_CLIENTS;
+use detour::upstream::{self, UpstreamLimits, UpstreamProtocol, UpstreamSpec, UpstreamStrategy};
+use detour::{bench, cache, proxy, resolver};
 use std::io;
-use std::net::SocketAddr;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
 
 #[derive(Parser)]
 #[command(name = "detour")]
@@ -22,15 +30,19 @@ struct Args {
     #[command(subcommand)]
     command: Option<Command>,
 
+    /// Read settings from this TOML file; flags given on the command line override it
+    #[arg(short, long, value_name = "FILE")]
+    config: Option<PathBuf>,
+
     /// Local port to listen on
     #[arg(short, long, default_value = "53")]
     port: u16,
 
-    /// Bind address
-    #[arg(short, long, default_value = "127.0.0.1")]
-    bind: String,
+    /// Bind address; repeat to listen on several (DoT, DoH and metrics ports use the first)
+    #[arg(short, long, value_name = "ADDR", default_values_t = ["127.0.0.1".to_string()])]
+    bind: Vec<String>,
 
-    /// Upstream DNS servers (host:port), races all and uses first response
+    /// Upstream DNS servers (ip[:port], tls://host[:port], https://host[:port][/path] or quic://host[:port], optionally @maxqps=N), races all and uses first response
     #[arg(short, long, default_values_t = [
         "1.1.1.1:53".to_string(),
         "1.0.0.1:53".to_string(),
@@ -47,9 +59,314 @@ struct Args {
     #[arg(short, long)]
     workers: Option<usize>,
 
-    /// Path to custom blocklist file (replaces built-in lists)
+    /// Blocklist file to use on top of the built-in lists (repeatable)
     #[arg(short = 'l', long)]
-    blocklist: Option<String>,
+    blocklist: Vec<String>,
+
+    /// Leave out the built-in lists, blocking only what --blocklist and --blocklist-url list
+    #[arg(long)]
+    no_embedded_lists: bool,
+
+    /// File of domains that are never blocked, one per line (exact matches only)
+    #[arg(long, value_name = "FILE")]
+    allowlist: Option<String>,
+
+    /// Download a blocklist from this http(s) URL and refresh it periodically (repeatable); used on top of the built-in lists like --blocklist
+    #[arg(long, value_name = "URL")]
+    blocklist_url: Vec<String>,
+
+    /// Directory downloaded blocklists are kept in, used when a download fails
+    #[arg(
+        long,
+        value_name = "DIR",
+        default_value = "/var/cache/detour/blocklists"
+    )]
+    blocklist_cache_dir: PathBuf,
+
+    /// Download --blocklist-url lists again this often (e.g. 12h); unchanged lists are not transferred again
+    #[arg(long, value_name = "DURATION", default_value = "24h", value_parser = parse_duration)]
+    blocklist_refresh: Duration,
+
+    /// File of regex rules, one per line; names matching any are blocked (e.g. ^[a-z0-9]+\.metrics\.vendor\.com$)
+    #[arg(long, value_name = "FILE")]
+    blocklist_regex: Option<String>,
+
+    /// Let `*.domain` blocklist entries block the domain itself, not just its subdomains
+    #[arg(long)]
+    wildcard_apex: bool,
+
+    /// Pack blocklist domains into one buffer, using less than half the memory for large lists
+    #[arg(long)]
+    compact_blocklist: bool,
+
+    /// Log every resolution stage for this domain and its subdomains (repeatable)
+    #[arg(long = "trace-domain", value_name = "DOMAIN")]
+    trace_domains: Vec<String>,
+
+    /// Log queries slower than this, independent of --verbose (e.g. 250ms, 1s; a bare number is milliseconds)
+    #[arg(long, visible_alias = "slow-query-ms", value_name = "DURATION", value_parser = parse_duration)]
+    slow_query_threshold: Option<Duration>,
+
+    /// Forward single-label names (e.g. `printer`) upstream instead of answering NXDOMAIN
+    #[arg(long)]
+    forward_unqualified: bool,
+
+    /// Send queries for a domain and its subdomains to their own upstreams, uncached (repeatable, e.g. corp.example=10.0.0.53)
+    #[arg(long = "forward", value_name = "DOMAIN=IP[:PORT][,...]", value_parser = ForwardRule::parse)]
+    forward_rules: Vec<ForwardRule>,
+
+    /// File of forwarding rules (one DOMAIN=IP[:PORT][,...] per line); --forward rules take precedence
+    #[arg(long, value_name = "PATH")]
+    forward_file: Option<String>,
+
+    /// Answer queries for this name authoritatively instead of forwarding them, with no records for types it lacks (repeatable, e.g. "router.home.arpa A 192.168.1.1"; A, AAAA, CNAME and TXT, TTL 300s unless given before the type)
+    #[arg(long = "local-record", value_name = "NAME [TTL] TYPE VALUE", value_parser = LocalRecord::parse)]
+    local_records: Vec<LocalRecord>,
+
+    /// Never log queries for this domain or its subdomains (repeatable)
+    #[arg(long, value_name = "DOMAIN")]
+    log_exclude: Vec<String>,
+
+    /// File of domain suffixes (one per line) that are never logged
+    #[arg(long, value_name = "PATH")]
+    log_exclude_file: Option<String>,
+
+    /// Compare answers from all racing upstreams and warn when they disagree
+    #[arg(long)]
+    detect_divergence: bool,
+
+    /// With --detect-divergence, check one in every N forwarded queries
+    #[arg(long, value_name = "N", default_value = "10")]
+    divergence_sample: u64,
+
+    /// Text returned for CHAOS TXT version.bind/id.server queries [default: detour/<version>]
+    #[arg(long, value_name = "TEXT")]
+    chaos_id: Option<String>,
+
+    /// Refuse CHAOS identification queries instead of answering them
+    #[arg(long, conflicts_with = "chaos_id")]
+    no_chaos: bool,
+
+    /// Keep this domain and its subdomains cached and refreshed before expiry (repeatable)
+    #[arg(long = "pin-domain", value_name = "DOMAIN")]
+    pin_domains: Vec<String>,
+
+    /// Push metrics to this statsd collector (host:port) every stats interval
+    #[arg(long, value_name = "ADDR")]
+    statsd: Option<SocketAddr>,
+
+    /// Prefix for statsd metric names
+    #[arg(long, value_name = "PREFIX", default_value = "detour.")]
+    statsd_prefix: String,
+
+    /// Where query events, stats and errors are logged
+    #[arg(long, value_enum, default_value_t = LogTarget::Stdout)]
+    log_target: LogTarget,
+
+    /// Format of log lines on stdout or in the log file
+    #[arg(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Append log lines to this file instead of writing them to the log target
+    #[arg(long, value_name = "PATH", conflicts_with = "log_target")]
+    log_file: Option<PathBuf>,
+
+    /// Log to syslog, same as --log-target syslog; without either, a service whose stdout is the journal logs to journald
+    #[arg(long, conflicts_with_all = ["log_target", "log_file"])]
+    log_syslog: bool,
+
+    /// Largest UDP DNS message accepted from clients and upstreams, in bytes
+    #[arg(long, value_name = "BYTES", default_value_t = MAX_DNS_PACKET_SIZE as u16)]
+    #[arg(value_parser = clap::value_parser!(u16).range(512..))]
+    max_udp_size: u16,
+
+    /// UDP sockets sharing the listen address through SO_REUSEPORT, each read by its own task (Unix only); --rate-limit-pps applies across all of them
+    #[arg(long, value_name = "N", default_value = "1")]
+    udp_sockets: NonZeroUsize,
+
+    /// Unix socket streaming live query events to `detour tail`
+    #[cfg(unix)]
+    #[arg(long, value_name = "PATH", default_value = tail::DEFAULT_SOCKET)]
+    tail_socket: PathBuf,
+
+    /// Do not open the `detour tail` socket
+    #[cfg(unix)]
+    #[arg(long)]
+    no_tail_socket: bool,
+
+    /// Race every query to all upstreams, or try them one at a time in the order given
+    #[arg(long, value_enum, default_value_t = UpstreamStrategy::Race)]
+    upstream_strategy: UpstreamStrategy,
+
+    /// Race only the first N upstreams, adding the rest if none answers within --failover-timeout [default: all]
+    #[arg(long, value_name = "N")]
+    race: Option<NonZeroUsize>,
+
+    /// Race the upstreams that have been answering fastest first, so with --race the fastest make up the raced set (queries raced over TCP; UDP keeps the configured order)
+    #[arg(long)]
+    rank_upstreams: bool,
+
+    /// With --upstream-strategy failover, try the next upstream after waiting this long for one (also how long --race waits)
+    #[arg(long, value_name = "DURATION", default_value = "1s", value_parser = parse_duration)]
+    failover_timeout: Duration,
+
+    /// Give up on an upstream that has not answered a query sent over TCP within this time; clients get SERVFAIL once every upstream has failed
+    #[arg(long, visible_alias = "upstream-timeout-ms", value_name = "DURATION", default_value = "5s", value_parser = parse_duration)]
+    upstream_timeout: Duration,
+
+    /// Retry an upstream whose TCP connection fails or closes before answering up to N times, waiting 100ms, then 200ms, ... (within --upstream-timeout)
+    #[arg(long, value_name = "N", default_value_t = 0)]
+    upstream_retries: u32,
+
+    /// Tell upstreams which network each query came from (EDNS Client Subnet), sending the client's address cut to its first N bits (at most 32 for IPv4 clients); cached answers keep no subnet
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u8).range(..=128))]
+    ecs_prefix_len: Option<u8>,
+
+    /// Answer SERVFAIL when no upstream responds to a UDP query within this time
+    #[arg(long, value_name = "DURATION", default_value = "3s", value_parser = parse_duration)]
+    query_timeout: Duration,
+
+    /// Drop UDP queries from a client IP beyond this many per second, with bursts of up to a second's worth, counted across all --udp-sockets (0 = unlimited)
+    #[arg(long, value_name = "N", default_value = "0")]
+    rate_limit_pps: u32,
+
+    /// Answer for blocked domains: `null` (0.0.0.0, :: for AAAA), `nxdomain`, `refused`, or sinkhole addresses (IPv4, IPv6 or both, comma-separated); other query types get an empty answer
+    #[arg(long, visible_alias = "block-mode", value_name = "STYLE", default_value_t = BlockedResponseStyle::NullIp)]
+    block_response: BlockedResponseStyle,
+
+    /// Answer blocked A queries with this address, e.g. a page explaining the block [default: 0.0.0.0]
+    #[arg(long, value_name = "ADDR", conflicts_with = "block_response")]
+    block_ipv4: Option<Ipv4Addr>,
+
+    /// Answer blocked AAAA queries with this address [default: ::]
+    #[arg(long, value_name = "ADDR", conflicts_with = "block_response")]
+    block_ipv6: Option<Ipv6Addr>,
+
+    /// How long clients may cache answers to blocked queries, in seconds (at most a day)
+    #[arg(long, value_name = "SECONDS", default_value_t = DEFAULT_BLOCKED_TTL)]
+    #[arg(value_parser = clap::value_parser!(u32).range(..=i64::from(MAX_BLOCKED_TTL)))]
+    block_ttl: u32,
+
+    /// Answer NXDOMAIN when upstreams resolve a public name to a private, loopback or link-local address (DNS rebinding); .local, .internal and similar names are exempt
+    #[arg(long)]
+    rebinding_protection: bool,
+
+    /// Ask upstreams about each parent of a name (com, then example.com) before the full name, and answer NXDOMAIN without sending it when a parent does not exist (RFC 7816)
+    #[arg(long)]
+    qname_minimize: bool,
+
+    /// Maximum number of cached responses; least recently used are evicted (0 = unbounded)
+    #[arg(long, value_name = "ENTRIES", default_value = "100000")]
+    cache_size: usize,
+
+    /// Close TCP client connections that stay idle for this long
+    #[arg(long, value_name = "DURATION", default_value = "10s", value_parser = parse_duration)]
+    tcp_idle_timeout: Duration,
+
+    /// Maximum concurrent TCP client connections; further connections are closed
+    #[arg(long, value_name = "COUNT", default_value_t = DEFAULT_MAX_CLIENTS)]
+    max_tcp_clients: usize,
+
+    /// Send TCP keepalive probes on client connections idle for this long, so NAT devices keep them open [default: off]
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    tcp_keepalive_idle: Option<Duration>,
+
+    /// Time between TCP keepalive probes once they have started
+    #[arg(long, value_name = "DURATION", default_value = "15s", value_parser = parse_duration, requires = "tcp_keepalive_idle")]
+    tcp_keepalive_interval: Duration,
+
+    /// On SIGINT or SIGTERM, wait this long for queries and connections in flight before exiting
+    #[arg(long, value_name = "DURATION", default_value = "5s", value_parser = parse_duration)]
+    shutdown_timeout: Duration,
+
+    /// Number of cache shards (rounded up to a power of two); more shards reduce lock contention
+    #[arg(long, value_name = "COUNT", default_value_t = cache::DEFAULT_SHARDS)]
+    cache_shards: usize,
+
+    /// Answer from expired cache entries for this long past their TTL while refreshing them in the background (0 = off)
+    #[arg(long, value_name = "DURATION", default_value = "0s", value_parser = parse_duration)]
+    stale_ttl: Duration,
+
+    /// Drop expired cache entries this often, so names never asked for again do not stay cached (0 = only when looked up again)
+    #[arg(long, value_name = "DURATION", default_value = "5m", value_parser = parse_duration)]
+    cache_sweep_interval: Duration,
+
+    /// When no upstream answers, answer from cache entries that expired up to this long ago, with a 30s TTL (RFC 8767; 0 = off, e.g. 24h)
+    #[arg(long, value_name = "DURATION", default_value = "0s", value_parser = parse_duration)]
+    serve_stale: Duration,
+
+    /// PEM certificate chain for DNS-over-TLS; enables the DoT listener together with --tls-key
+    #[arg(long, value_name = "PATH", requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM private key for DNS-over-TLS
+    #[arg(long, value_name = "PATH", requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// Port for DNS-over-TLS on the bind address
+    #[arg(long, default_value_t = dot::DEFAULT_PORT)]
+    dot_port: u16,
+
+    /// Save the cache to this file periodically and restore it on startup
+    #[arg(long, value_name = "PATH")]
+    cache_file: Option<PathBuf>,
+
+    /// Serve DNS-over-HTTPS (RFC 8484) on this address, e.g. 127.0.0.1:8053; uses HTTPS when --doh-cert/--doh-key or --tls-cert/--tls-key are set
+    #[arg(long, value_name = "ADDR", group = "doh")]
+    doh_listen: Option<SocketAddr>,
+
+    /// Serve DNS-over-HTTPS on this port of the bind address (alternative to --doh-listen)
+    #[arg(long, value_name = "PORT", group = "doh")]
+    doh_port: Option<u16>,
+
+    /// PEM certificate chain for DNS-over-HTTPS, if it should differ from --tls-cert
+    #[arg(long, value_name = "PATH", requires = "doh", requires = "doh_key")]
+    doh_cert: Option<PathBuf>,
+
+    /// PEM private key for DNS-over-HTTPS
+    #[arg(long, value_name = "PATH", requires = "doh_cert")]
+    doh_key: Option<PathBuf>,
+
+    /// Count queries per domain and log the 10 most queried with each stats line
+    #[arg(long)]
+    track_domains: bool,
+
+    /// Reload --blocklist/--allowlist/--blocklist-regex when they change, checking this often (e.g. 30s); SIGHUP always reloads
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    reload_interval: Option<Duration>,
+
+    /// Serve Prometheus metrics at /metrics on this port of the bind address
+    #[arg(long, value_name = "PORT")]
+    metrics_port: Option<u16>,
+
+    /// Serve the JSON admin API (cache listing and flushing, stats) on this port of 127.0.0.1; it has no authentication
+    #[arg(long, value_name = "PORT", group = "admin")]
+    admin_port: Option<u16>,
+
+    /// Serve the JSON admin API on this address instead of loopback, e.g. 10.0.0.2:8053 (alternative to --admin-port)
+    #[arg(long, value_name = "ADDR", group = "admin")]
+    admin_listen: Option<SocketAddr>,
+
+    /// Probe every upstream this often and leave out those failing --health-check-failures probes in a row
+    #[arg(long, value_name = "DURATION", value_parser = parse_duration)]
+    health_check_interval: Option<Duration>,
+
+    /// Name the health probes query (type NS); `.` is the root
+    #[arg(long, value_name = "NAME", default_value = ".", value_parser = parse_probe_name)]
+    health_check_name: String,
+
+    /// Consecutive failed health probes before an upstream is left out
+    #[arg(long, value_name = "COUNT", default_value_t = upstream::DEFAULT_HEALTH_FAILURES)]
+    #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+    health_check_failures: u32,
+
+    /// Leave an upstream out for --circuit-breaker-backoff after this many forwarded queries in a row go unanswered (0 = never)
+    #[arg(long, value_name = "COUNT", default_value_t = resolver::DEFAULT_CIRCUIT_FAILURES)]
+    circuit_breaker_failures: u32,
+
+    /// How long an upstream tripped by --circuit-breaker-failures sits out before a trial query
+    #[arg(long, value_name = "DURATION", default_value = "30s", value_parser = parse_duration)]
+    circuit_breaker_backoff: Duration,
 }
 
 #[derive(Subcommand)]
@@ -58,27 +375,180 @@ enum Command {
     Install,
     /// Uninstall the systemd service
     Uninstall,
-}
+    /// Compare latency, failure rate and filtering of the configured upstreams
+    #[command(visible_alias = "bench")]
+    BenchUpstreams {
+        /// Timed queries per upstream and protocol
+        #[arg(
+            long,
+            visible_alias = "bench-count",
+            value_name = "N",
+            default_value = "20"
+        )]
+        samples: usize,
 
-fn main() -> io::Result<()> {
-    let args = Args::parse();
+        /// File of known-good domains (one per line) to query
+        #[arg(long, value_name = "PATH")]
+        domains: Option<String>,
+
+        /// Additional upstreams to include (host[:port])
+        #[arg(value_name = "UPSTREAM")]
+        extra: Vec<String>,
+    },
+    /// Check whether the configured blocklists block a domain, and by which rule
+    TestBlocklist {
+        /// Domain to check
+        domain: String,
+    },
+    /// Stream live query events from the running instance (Unix only)
+    #[cfg(unix)]
+    Tail {
+        /// Only show this domain and its subdomains
+        #[arg(long, value_name = "SUFFIX")]
+        domain: Option<String>,
+
+        /// Only show queries from this client IP
+        #[arg(long, value_name = "IP")]
+        client: Option<IpAddr>,
+
+        /// Only show this action (blocked, cached, local, forwarded)
+        #[arg(long)]
+        action: Option<String>,
+    },
+}
 
-    if let Some(cmd) = args.command {
-        return match cmd {
-            Command::Install => install_service(),
-            Command::Uninstall => uninstall_service(),
-        };
+/// Fill in the settings from a config file that were not given on the command line.
+fn apply_config_file(args: &mut Args, matches: &ArgMatches, file: ConfigFile) {
+    let unset = |id: &str| matches.value_source(id) != Some(ValueSource::CommandLine);
+    if let Some(bind) = file.bind.filter(|_| unset("bind")) {
+        args.bind = bind.into_addresses();
+    }
+    if let Some(port) = file.port.filter(|_| unset("port")) {
+        args.port = port;
+    }
+    if let Some(upstreams) = file.upstreams.filter(|_| unset("upstream")) {
+        args.upstream = upstreams.iter().map(|entry| entry.to_spec()).collect();
+    }
+    if let Some(verbose) = file.verbose.filter(|_| unset("verbose")) {
+        args.verbose = verbose;
+    }
+    if let Some(workers) = file.workers.filter(|_| unset("workers")) {
+        args.workers = Some(workers);
+    }
+    if let Some(blocklists) = file.blocklists.filter(|_| unset("blocklist")) {
+        args.blocklist = blocklists;
+    }
+    if let Some(no_embedded_lists) = file
+        .no_embedded_lists
+        .filter(|_| unset("no_embedded_lists"))
+    {
+        args.no_embedded_lists = no_embedded_lists;
+    }
+    if let Some(cache_size) = file.cache_size.filter(|_| unset("cache_size")) {
+        args.cache_size = cache_size;
     }
+    if let Some(metrics_port) = file.metrics_port.filter(|_| unset("metrics_port")) {
+        args.metrics_port = Some(metrics_port);
+    }
+    if let Some(records) = file.local_records.filter(|_| unset("local_records")) {
+        args.local_records = records;
+    }
+}
 
-    let bind_addr: SocketAddr = format!("{}:{}", args.bind, args.port)
-        .parse()
-        .expect("invalid bind address");
+fn main() -> io::Result<()> {
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    if let Some(path) = &args.config {
+        let file = ConfigFile::load(path).map_err(io::Error::other)?;
+        apply_config_file(&mut args, &matches, file);
+    }
 
-    let upstreams: Vec<SocketAddr> = args
+    let upstream_specs: Vec<UpstreamSpec> = args
         .upstream
         .iter()
-        .map(|s| s.parse().expect("invalid upstream address"))
+        .map(|s| UpstreamSpec::parse(s).expect("invalid upstream"))
+        .collect();
+    let mut upstreams: Vec<SocketAddr> = upstream_specs.iter().map(|spec| spec.addr).collect();
+    let upstream_protocols: Vec<UpstreamProtocol> = upstream_specs
+        .iter()
+        .map(|spec| spec.protocol.clone())
         .collect();
+    let quic_upstreams: Vec<(SocketAddr, String)> = upstream_specs
+        .iter()
+        .filter_map(|spec| Some((spec.addr, spec.quic_server_name.clone()?)))
+        .collect();
+
+    let mut test_domain = None;
+    if let Some(cmd) = args.command.take() {
+        match cmd {
+            Command::Install => return install_service(),
+            Command::Uninstall => return uninstall_service(),
+            Command::BenchUpstreams {
+                samples,
+                domains,
+                extra,
+            } => {
+                upstreams.extend(
+                    extra
+                        .iter()
+                        .map(|s| upstream::parse_addr(s).expect("invalid upstream address")),
+                );
+                let domains = match domains {
+                    Some(path) => proxy::read_domain_list(&path)?,
+                    None => Vec::new(),
+                };
+                let config = bench::BenchConfig {
+                    upstreams,
+                    samples: samples.max(1),
+                    domains,
+                };
+                // Encrypted upstreams are benchmarked over their own protocol.
+                return tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(async {
+                        proxy::register_upstreams(
+                            &config.upstreams,
+                            &upstream_protocols,
+                            &quic_upstreams,
+                        )?;
+                        bench::run(config).await
+                    });
+            }
+            #[cfg(unix)]
+            Command::Tail {
+                domain,
+                client,
+                action,
+            } => {
+                let filter = tail::TailFilter {
+                    domain,
+                    client,
+                    action,
+                };
+                return tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?
+                    .block_on(tail::run_client(&args.tail_socket, &filter));
+            }
+            // Checked against the full proxy configuration, once it is built.
+            Command::TestBlocklist { domain } => test_domain = Some(domain),
+        }
+    }
+
+    let bind_addrs: Vec<SocketAddr> = args
+        .bind
+        .iter()
+        .map(|bind| {
+            let ip: IpAddr = bind
+                .trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse()
+                .expect("invalid bind address");
+            SocketAddr::new(ip, args.port)
+        })
+        .collect();
+    let bind_addr = bind_addrs[0];
 
     let workers = args.workers.unwrap_or_else(|| {
         let cores = std::thread::available_parallelism()
@@ -87,14 +557,136 @@ fn main() -> io::Result<()> {
         cores * 2
     });
 
+    let log_target = if args.log_syslog {
+        LogTarget::Syslog
+    } else if matches.value_source("log_target") != Some(ValueSource::CommandLine)
+        && args.log_file.is_none()
+        && logging::stdout_is_journal()
+    {
+        LogTarget::Journald
+    } else {
+        args.log_target
+    };
+    let doh = doh_config(&args, bind_addr);
+    let admin_addr = admin_addr(&args);
+
     let config = proxy::ProxyConfig {
-        bind_addr,
+        bind_addrs,
         upstreams,
         verbose: args.verbose,
         workers,
-        blocklist_path: args.blocklist,
+        blocklist_paths: args.blocklist,
+        no_embedded_lists: args.no_embedded_lists,
+        allowlist_path: args.allowlist,
+        blocklist_regex_path: args.blocklist_regex,
+        blocklist_urls: (!args.blocklist_url.is_empty()).then_some(proxy::BlocklistUrlConfig {
+            urls: args.blocklist_url,
+            cache_dir: args.blocklist_cache_dir,
+            refresh_interval: args.blocklist_refresh,
+        }),
+        wildcard_apex: args.wildcard_apex,
+        compact_blocklist: args.compact_blocklist,
+        trace_domains: args.trace_domains,
+        slow_query_threshold: args.slow_query_threshold,
+        forward_unqualified: args.forward_unqualified,
+        forward_rules: args.forward_rules,
+        local_records: args.local_records,
+        local_records_file: args
+            .config
+            .clone()
+            .filter(|_| matches.value_source("local_records") != Some(ValueSource::CommandLine)),
+        forward_file: args.forward_file,
+        log_exclude: args.log_exclude,
+        log_exclude_file: args.log_exclude_file,
+        divergence_sample: args.detect_divergence.then_some(args.divergence_sample),
+        chaos_id: if args.no_chaos {
+            None
+        } else {
+            Some(
+                args.chaos_id
+                    .unwrap_or_else(|| resolver::DEFAULT_CHAOS_ID.to_string()),
+            )
+        },
+        pin_domains: args.pin_domains,
+        statsd: args.statsd,
+        statsd_prefix: args.statsd_prefix,
+        log_target,
+        log_format: args.log_format,
+        log_file: args.log_file,
+        max_udp_size: usize::from(args.max_udp_size),
+        udp_sockets: args.udp_sockets.get(),
+        #[cfg(unix)]
+        tail_socket: (!args.no_tail_socket).then_some(args.tail_socket),
+        #[cfg(not(unix))]
+        tail_socket: None,
+        upstream_limits: UpstreamLimits::new(&upstream_specs),
+        upstream_protocols,
+        quic_upstreams,
+        upstream_strategy: args.upstream_strategy,
+        race: args.race.map(NonZeroUsize::get),
+        rank_upstreams: args.rank_upstreams,
+        failover_timeout: args.failover_timeout,
+        upstream_timeout: args.upstream_timeout,
+        upstream_retries: args.upstream_retries,
+        ecs_prefix_len: args.ecs_prefix_len,
+        query_timeout: args.query_timeout,
+        rate_limit_pps: args.rate_limit_pps,
+        blocked_response: match (args.block_ipv4, args.block_ipv6) {
+            (None, None) => args.block_response,
+            (ipv4, ipv6) => BlockedResponseStyle::CustomIp(
+                ipv4.unwrap_or(Ipv4Addr::UNSPECIFIED),
+                ipv6.unwrap_or(Ipv6Addr::UNSPECIFIED),
+            ),
+        },
+        blocked_ttl: args.block_ttl,
+        rebinding_protection: args.rebinding_protection,
+        qname_minimization: if args.qname_minimize {
+            QnameMinimization::Relaxed
+        } else {
+            QnameMinimization::Off
+        },
+        cache_size: args.cache_size,
+        tcp_idle_timeout: args.tcp_idle_timeout,
+        max_tcp_clients: args.max_tcp_clients,
+        tcp_keepalive_idle: args.tcp_keepalive_idle,
+        tcp_keepalive_interval: args.tcp_keepalive_interval,
+        shutdown_timeout: args.shutdown_timeout,
+        dot: args
+            .tls_cert
+            .zip(args.tls_key)
+            .map(|(cert, key)| proxy::DotConfig {
+                addr: SocketAddr::new(bind_addr.ip(), args.dot_port),
+                cert,
+                key,
+            }),
+        cache_shards: args.cache_shards,
+        stale_ttl: args.stale_ttl,
+        cache_sweep_interval: args.cache_sweep_interval,
+        serve_stale: args.serve_stale,
+        cache_file: args.cache_file,
+        doh,
+        track_domains: args.track_domains,
+        reload_interval: args.reload_interval,
+        metrics_addr: args
+            .metrics_port
+            .map(|port| SocketAddr::new(bind_addr.ip(), port)),
+        admin_addr,
+        circuit_breaker_failures: args.circuit_breaker_failures,
+        circuit_breaker_backoff: args.circuit_breaker_backoff,
+        health_check: args
+            .health_check_interval
+            .filter(|interval| !interval.is_zero())
+            .map(|interval| proxy::HealthCheckConfig {
+                interval,
+                probe_name: args.health_check_name,
+                failures: args.health_check_failures,
+            }),
     };
 
+    if let Some(domain) = test_domain {
+        return proxy::test_blocklist(&config, &domain);
+    }
+
     tokio::runtime::Builder::new_multi_thread()
         .worker_threads(config.workers)
         .enable_all()
@@ -102,8 +694,53 @@ fn main() -> io::Result<()> {
         .block_on(proxy::run(config))
 }
 
+/// The DNS-over-HTTPS listener, if --doh-listen or --doh-port is given.
+fn doh_config(args: &Args, bind_addr: SocketAddr) -> Option<proxy::DohConfig> {
+    let addr = args.doh_listen.or(args
+        .doh_port
+        .map(|port| SocketAddr::new(bind_addr.ip(), port)))?;
+    Some(proxy::DohConfig {
+        addr,
+        tls: args.doh_cert.clone().zip(args.doh_key.clone()),
+    })
+}
+
+/// The admin API address: --admin-listen, or --admin-port on loopback.
+fn admin_addr(args: &Args) -> Option<SocketAddr> {
+    args.admin_listen.or(args
+        .admin_port
+        .map(|port| SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)))
+}
+
+/// Parse a duration such as `250ms`, `2s`, `1m` or `24h` (bare numbers are milliseconds).
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let s = s.trim();
+    let (value, unit) = match s.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(pos) => s.split_at(pos),
+        None => (s, "ms"),
+    };
+    let value: f64 = value
+        .parse()
+        .map_err(|_| format!("invalid duration: {}", s))?;
+    let secs = match unit {
+        "ms" => value / 1000.0,
+        "s" => value,
+        "m" => value * 60.0,
+        "h" => value * 3600.0,
+        _ => return Err(format!("invalid duration unit: {}", unit)),
+    };
+    Ok(Duration::from_secs_f64(secs))
+}
+
 const SERVICE_FILE: &str = include_str!("../detour.service");
 
+fn parse_probe_name(s: &str) -> Result<String, String> {
+    match DnsQuery::new(0, s, TYPE_NS).to_bytes() {
+        Some(_) => Ok(s.to_ascii_lowercase()),
+        None => Err(format!("invalid domain name: {}", s)),
+    }
+}
+
 fn install_service() -> io::Result<()> {
     use std::process::Command;
 
@@ -146,3 +783,55 @@ fn uninstall_service() -> io::Result<()> {
     println!("Uninstalled.");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn doh_port_and_cert_serve_https_on_the_bind_address() {
+        let bind_addr = "127.0.0.2:53".parse().unwrap();
+        let args = Args::try_parse_from([
+            "detour",
+            "--doh-port",
+            "8443",
+            "--doh-cert",
+            "doh.pem",
+            "--doh-key",
+            "doh.key",
+        ])
+        .unwrap();
+        let plain = Args::try_parse_from(["detour", "--doh-listen", "127.0.0.1:8053"]).unwrap();
+        let unused_cert = Args::try_parse_from(["detour", "--doh-cert", "doh.pem"]);
+
+        let doh = doh_config(&args, bind_addr).unwrap();
+        let plain = doh_config(&plain, bind_addr).unwrap();
+
+        assert_eq!(doh.addr, "127.0.0.2:8443".parse().unwrap());
+        assert_eq!(
+            doh.tls,
+            Some((PathBuf::from("doh.pem"), PathBuf::from("doh.key")))
+        );
+        assert_eq!(plain.addr, "127.0.0.1:8053".parse().unwrap());
+        assert_eq!(plain.tls, None);
+        assert!(unused_cert.is_err());
+    }
+
+    #[test]
+    fn admin_api_stays_on_loopback_unless_given_an_address() {
+        let port =
+            Args::try_parse_from(["detour", "--bind", "0.0.0.0", "--admin-port", "8053"]).unwrap();
+        let listen = Args::try_parse_from(["detour", "--admin-listen", "10.0.0.2:8053"]).unwrap();
+        let both = Args::try_parse_from([
+            "detour",
+            "--admin-port",
+            "8053",
+            "--admin-listen",
+            "10.0.0.2:8053",
+        ]);
+
+        assert_eq!(admin_addr(&port), Some("127.0.0.1:8053".parse().unwrap()));
+        assert_eq!(admin_addr(&listen), Some("10.0.0.2:8053".parse().unwrap()));
+        assert!(both.is_err());
+    }
+}
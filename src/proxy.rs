@@ -3,55 +3,592 @@
 //! Binds transports and runs the proxy server.
 
 use std::io;
-use std::net::SocketAddr;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
-use crate::filter::Blocklist;
-use crate::resolver::Resolver;
-use crate::transport::{tcp::TcpTransport, udp::UdpTransport};
+use crate::admin::AdminServer;
+use crate::cache::DnsCache;
+#[cfg(unix)]
+use crate::config::ConfigFile;
+use crate::dns::{DnsQuery, TYPE_A, TYPE_AAAA, TYPE_NS};
+#[cfg(feature = "blocklist-url")]
+use crate::filter::download::{Refresh, RemoteList};
+use crate::filter::{BlockedResponseStyle, Blocklist, SuffixSet};
+use crate::logging::{self, LogFormat, LogTarget};
+use crate::resolver::{
+    ForwardRule, ForwardRules, LocalRecord, LocalRecords, QnameMinimization, Resolver,
+    UpstreamCircuitBreaker, UpstreamRanker,
+};
+use crate::shutdown::ShutdownSignal;
+use crate::stats::prometheus::{self, StatsServer};
+use crate::statsd::StatsdSink;
+#[cfg(unix)]
+use crate::tail;
+#[cfg(feature = "doh-upstream")]
+use crate::transport::doh::client::DohClient;
+use crate::transport::doh::{self, DohTransport};
+#[cfg(feature = "doq")]
+use crate::transport::doq;
+use crate::transport::dot::{self, DotTransport};
+use crate::transport::tcp::{self, TcpTransport};
+use crate::transport::{MAX_DNS_PACKET_SIZE, udp::UdpTransport};
+use crate::upstream::{UpstreamHealth, UpstreamLimits, UpstreamProtocol, UpstreamStrategy};
 
 /// Configuration for the DNS proxy.
 pub struct ProxyConfig {
-    /// Local address to bind (e.g., 127.0.0.1:5353)
-    pub bind_addr: SocketAddr,
-    /// Upstream DNS server addresses (races all, uses first response)
+    /// Local addresses to bind (e.g., 127.0.0.1:5353), each served over UDP and TCP
+    pub bind_addrs: Vec<SocketAddr>,
+    /// Upstream DNS server addresses (raced or tried in order, see `upstream_strategy`)
     pub upstreams: Vec<SocketAddr>,
     /// Enable verbose logging (domain, blocked status, timing)
     pub verbose: bool,
     /// Number of worker threads
     pub workers: usize,
     /// Custom blocklist file path (None = use embedded lists)
-    pub blocklist_path: Option<String>,
+    pub blocklist_paths: Vec<String>,
+    /// Leave the embedded lists out, so only the files and URLs block
+    pub no_embedded_lists: bool,
+    /// File of exact domains that are never blocked
+    pub allowlist_path: Option<String>,
+    /// File of regex rules blocking the names they match
+    pub blocklist_regex_path: Option<String>,
+    /// Blocklists downloaded from URLs (None = off)
+    pub blocklist_urls: Option<BlocklistUrlConfig>,
+    /// `*.domain` blocklist entries block the domain itself too
+    pub wildcard_apex: bool,
+    /// Keep blocklist domains packed in one buffer rather than a hash set
+    pub compact_blocklist: bool,
+    /// Domain suffixes whose queries are traced at every stage
+    pub trace_domains: Vec<String>,
+    /// Log queries whose total handling time exceeds this (None = disabled)
+    pub slow_query_threshold: Option<Duration>,
+    /// Forward single-label names upstream instead of answering NXDOMAIN
+    pub forward_unqualified: bool,
+    /// Domain suffixes sent to their own upstreams instead of the global ones
+    pub forward_rules: Vec<ForwardRule>,
+    /// File with one forwarding rule per line, overridden by `forward_rules`
+    pub forward_file: Option<String>,
+    /// Records answered authoritatively instead of forwarded
+    pub local_records: Vec<LocalRecord>,
+    /// Config file whose `local-records` are re-read on SIGHUP (None = the
+    /// records only change on restart)
+    pub local_records_file: Option<PathBuf>,
+    /// Domain suffixes that are never logged
+    pub log_exclude: Vec<String>,
+    /// File with one domain suffix per line that is never logged
+    pub log_exclude_file: Option<String>,
+    /// Compare racing upstream answers for one in every N forwarded queries (None = off)
+    pub divergence_sample: Option<u64>,
+    /// Text for CHAOS `version.bind`/`id.server` queries (None = REFUSED)
+    pub chaos_id: Option<String>,
+    /// Domain suffixes whose cache entries are kept warm and never evicted
+    pub pin_domains: Vec<String>,
+    /// statsd collector receiving metrics every stats interval (None = off)
+    pub statsd: Option<SocketAddr>,
+    /// Prefix for statsd metric names
+    pub statsd_prefix: String,
+    /// Log backend for query events, stats and errors
+    pub log_target: LogTarget,
+    /// Line format for stdout and `log_file`
+    pub log_format: LogFormat,
+    /// File that log events are appended to instead of `log_target` (None = off)
+    pub log_file: Option<PathBuf>,
+    /// Receive buffer size for UDP messages
+    pub max_udp_size: usize,
+    /// Client UDP sockets bound with SO_REUSEPORT, one loop each (1 = a plain socket)
+    pub udp_sockets: usize,
+    /// Unix socket for `detour tail` subscribers (None = disabled; Unix only)
+    pub tail_socket: Option<PathBuf>,
+    /// Per-upstream outbound rate limits
+    pub upstream_limits: UpstreamLimits,
+    /// How queries are spread over the upstreams
+    pub upstream_strategy: UpstreamStrategy,
+    /// Race only the first N upstreams, adding the rest after `failover_timeout` (None = all)
+    pub race: Option<usize>,
+    /// Race the upstreams that have been answering fastest first, over TCP
+    pub rank_upstreams: bool,
+    /// With failover, how long to wait for one upstream before trying the next
+    pub failover_timeout: Duration,
+    /// How long each upstream raced over TCP may take to answer
+    pub upstream_timeout: Duration,
+    /// Retries of a failed TCP exchange with an upstream, within its timeout
+    pub upstream_retries: u32,
+    /// Send upstreams the client's subnet, cut to this many bits (EDNS Client Subnet)
+    pub ecs_prefix_len: Option<u8>,
+    /// Unanswered queries in a row that open an upstream's circuit (0 = off)
+    pub circuit_breaker_failures: u32,
+    /// How long an open circuit keeps its upstream out
+    pub circuit_breaker_backoff: Duration,
+    /// Protocol of each upstream in `upstreams`, in the same order
+    pub upstream_protocols: Vec<UpstreamProtocol>,
+    /// Upstreams in `upstreams` spoken to over DNS-over-QUIC, with their TLS server names
+    pub quic_upstreams: Vec<(SocketAddr, String)>,
+    /// How long a forwarded UDP query waits for an upstream before SERVFAIL
+    pub query_timeout: Duration,
+    /// UDP queries allowed per second from each client IP (0 = unlimited)
+    pub rate_limit_pps: u32,
+    /// How queries for blocked domains are answered
+    pub blocked_response: BlockedResponseStyle,
+    /// TTL, in seconds, of answers to blocked queries
+    pub blocked_ttl: u32,
+    /// Replace upstream answers giving public names local addresses with NXDOMAIN
+    pub rebinding_protection: bool,
+    /// Ask about the ancestors of forwarded names first (RFC 7816)
+    pub qname_minimization: QnameMinimization,
+    /// Maximum number of cached responses (0 = unbounded)
+    pub cache_size: usize,
+    /// Close TCP connections idle for this long
+    pub tcp_idle_timeout: Duration,
+    /// Maximum concurrent TCP client connections (DNS-over-TLS has its own cap)
+    pub max_tcp_clients: usize,
+    /// Start TCP keepalive probes on client connections idle this long (None = off)
+    pub tcp_keepalive_idle: Option<Duration>,
+    /// Time between TCP keepalive probes
+    pub tcp_keepalive_interval: Duration,
+    /// How long queries and connections in flight may finish on shutdown
+    pub shutdown_timeout: Duration,
+    /// Serve DNS-over-TLS as well (None = off)
+    pub dot: Option<DotConfig>,
+    /// Number of independently locked cache shards
+    pub cache_shards: usize,
+    /// Serve expired cache entries for this long while refreshing them (zero = off)
+    pub stale_ttl: Duration,
+    /// When no upstream answers, answer from entries expired up to this long ago (0 = off)
+    pub serve_stale: Duration,
+    /// Drop expired cache entries this often (zero = only when looked up)
+    pub cache_sweep_interval: Duration,
+    /// File the cache is restored from on startup and flushed to periodically (None = off)
+    pub cache_file: Option<PathBuf>,
+    /// Serve DNS-over-HTTPS as well (None = off)
+    pub doh: Option<DohConfig>,
+    /// Count queries per domain and log the most queried with each stats line
+    pub track_domains: bool,
+    /// Check the blocklist, allowlist and regex rule files for changes this often (None = SIGHUP only)
+    pub reload_interval: Option<Duration>,
+    /// Serve Prometheus metrics on this address (None = off)
+    pub metrics_addr: Option<SocketAddr>,
+    /// Serve the admin API on this address (None = off)
+    pub admin_addr: Option<SocketAddr>,
+    /// Probe the upstreams and leave unhealthy ones out (None = off)
+    pub health_check: Option<HealthCheckConfig>,
+}
+
+/// Upstream health probe settings.
+pub struct HealthCheckConfig {
+    /// How often every upstream is probed
+    pub interval: Duration,
+    /// Name queried (type NS) by each probe; `.` is the root
+    pub probe_name: String,
+    /// Consecutive failed probes before an upstream is left out
+    pub failures: u32,
+}
+
+/// Downloaded blocklist settings.
+pub struct BlocklistUrlConfig {
+    /// `http://` or `https://` URLs of the lists
+    pub urls: Vec<String>,
+    /// Directory the downloaded copies are kept in
+    pub cache_dir: PathBuf,
+    /// How often the lists are downloaded again
+    pub refresh_interval: Duration,
+}
+
+/// DNS-over-TLS listener settings.
+pub struct DotConfig {
+    /// Address to accept TLS connections on (usually port 853)
+    pub addr: SocketAddr,
+    /// PEM certificate chain
+    pub cert: PathBuf,
+    /// PEM private key
+    pub key: PathBuf,
+}
+
+/// DNS-over-HTTPS listener settings.
+pub struct DohConfig {
+    /// Address to accept HTTP connections on
+    pub addr: SocketAddr,
+    /// PEM certificate chain and private key; without them the DoT ones are
+    /// used, or plain HTTP if DoT is off
+    pub tls: Option<(PathBuf, PathBuf)>,
+}
+
+/// How often pinned cache entries are checked for refresh.
+const PIN_REFRESH_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Pinned entries expiring within this window are refreshed.
+const PIN_REFRESH_LEAD: Duration = Duration::from_secs(10);
+
+/// How often the cache is flushed to the cache file.
+const CACHE_FLUSH_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How long a health probe waits for its answer.
+const PROBE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// First retry delay after a failed blocklist download; doubles per failure
+/// up to the refresh interval.
+#[cfg(feature = "blocklist-url")]
+const DOWNLOAD_RETRY: Duration = Duration::from_secs(60);
+
+/// Read a file with one domain per line, skipping blank lines and `#` comments.
+pub fn read_domain_list(path: &str) -> io::Result<Vec<String>> {
+    let content = std::fs::read_to_string(path)?;
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
 }
 
 /// Run the DNS proxy with the given configuration.
 ///
-/// Starts UDP and TCP transports on the bind address and forwards
-/// all queries to the upstream server. Runs indefinitely.
-pub async fn run(config: ProxyConfig) -> io::Result<()> {
-    let blocklist = match &config.blocklist_path {
-        Some(path) => Blocklist::from_file(path)?,
-        None => Blocklist::new(),
+/// Starts UDP and TCP transports on every bind address and forwards
+/// all queries to the upstream server. Runs until SIGINT or SIGTERM, then
+/// stops taking queries and waits up to the shutdown timeout for those in
+/// flight.
+pub async fn run(mut config: ProxyConfig) -> io::Result<()> {
+    logging::init(
+        config.log_target,
+        config.log_format,
+        config.log_file.as_deref(),
+    )?;
+    #[cfg(feature = "blocklist-url")]
+    let remote_lists = match &config.blocklist_urls {
+        Some(urls) => download_blocklists(urls).await?,
+        None => Vec::new(),
+    };
+    #[cfg(not(feature = "blocklist-url"))]
+    if config.blocklist_urls.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "blocklist downloads are not compiled in (enable the `blocklist-url` feature)",
+        ));
+    }
+    #[cfg(feature = "blocklist-url")]
+    let lists = BlocklistFiles::new(
+        &config,
+        remote_lists
+            .iter()
+            .map(|(list, _)| (list.url().to_string(), list.path().to_path_buf()))
+            .collect(),
+    );
+    #[cfg(not(feature = "blocklist-url"))]
+    let lists = BlocklistFiles::new(&config, Vec::new());
+    let sources = lists.sources()?;
+    if sources.len() > 1 {
+        for (name, list) in &sources {
+            println!("Blocklist {}: {} entries", name, list.len());
+        }
+    }
+    let blocklist = lists.build(sources)?;
+    let mut log_exclude = config.log_exclude.clone();
+    if let Some(path) = &config.log_exclude_file {
+        log_exclude.extend(read_domain_list(path)?);
+    }
+    let mut forward_rules = Vec::new();
+    if let Some(path) = &config.forward_file {
+        for line in read_domain_list(path)? {
+            let rule = ForwardRule::parse(&line).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, e))
+            })?;
+            forward_rules.push(rule);
+        }
+    }
+    forward_rules.extend(config.forward_rules.iter().cloned());
+
+    let cache = match &config.cache_file {
+        Some(path) => load_cache(path),
+        None => DnsCache::new(),
     };
-    let resolver = Arc::new(Resolver::new(blocklist));
+
+    let resolver = Arc::new(
+        Resolver::new(blocklist)
+            .with_cache(cache)
+            .with_trace_domains(SuffixSet::new(&config.trace_domains))
+            .with_slow_query_threshold(config.slow_query_threshold)
+            .with_forward_unqualified(config.forward_unqualified)
+            .with_forward_rules(ForwardRules::new(forward_rules.iter().cloned()))
+            .with_local_records(LocalRecords::new(config.local_records.iter().cloned()))
+            .with_log_exclusions(SuffixSet::new(&log_exclude))
+            .with_divergence_detection(config.divergence_sample)
+            .with_chaos_id(config.chaos_id.clone())
+            .with_pinned_domains(SuffixSet::new(&config.pin_domains))
+            .with_upstream_limits(config.upstream_limits)
+            .with_upstream_strategy(config.upstream_strategy, config.failover_timeout)
+            .with_race_limit(config.race)
+            .with_upstream_timeout(config.upstream_timeout)
+            .with_upstream_retries(config.upstream_retries)
+            .with_ecs_prefix_len(config.ecs_prefix_len)
+            .with_upstream_stats(config.upstreams.len())
+            .with_upstream_health(match &config.health_check {
+                Some(health_check) => UpstreamHealth::new(&config.upstreams, health_check.failures),
+                None => UpstreamHealth::default(),
+            })
+            .with_upstream_ranker(if config.rank_upstreams {
+                UpstreamRanker::new(&config.upstreams)
+            } else {
+                UpstreamRanker::default()
+            })
+            .with_circuit_breaker(match config.circuit_breaker_failures {
+                0 => UpstreamCircuitBreaker::default(),
+                failures => UpstreamCircuitBreaker::new(
+                    &config.upstreams,
+                    failures,
+                    config.circuit_breaker_backoff,
+                ),
+            })
+            .with_blocked_response(config.blocked_response)
+            .with_blocked_ttl(config.blocked_ttl)
+            .with_rebinding_protection(config.rebinding_protection)
+            .with_qname_minimization(config.qname_minimization)
+            .with_cache_shards(config.cache_shards)
+            .with_cache_size(config.cache_size)
+            .with_stale_ttl(config.stale_ttl)
+            .with_serve_stale(config.serve_stale)
+            .with_domain_tracking(config.track_domains),
+    );
 
     println!(
-        "DNS proxy listening on {} ({} domains blocked, {} workers)",
-        config.bind_addr,
+        "DNS proxy blocking {} domains with {} workers",
         resolver.blocked_count(),
         config.workers
     );
-    let upstream_strs: Vec<_> = config.upstreams.iter().map(|a| a.to_string()).collect();
+    register_upstreams(
+        &config.upstreams,
+        &config.upstream_protocols,
+        &config.quic_upstreams,
+    )?;
+    let upstream_strs: Vec<_> = config
+        .upstreams
+        .iter()
+        .zip(&config.upstream_protocols)
+        .map(
+            |(addr, protocol)| match config.quic_upstreams.iter().find(|(quic, _)| quic == addr) {
+                Some((_, server_name)) => format!("quic://{} ({})", server_name, addr),
+                None => match protocol {
+                    UpstreamProtocol::Tls { sni } => format!("tls://{} ({})", sni, addr),
+                    UpstreamProtocol::Https { url } => format!("{} ({})", url, addr),
+                    UpstreamProtocol::Plain => addr.to_string(),
+                },
+            },
+        )
+        .collect();
     println!("Racing upstreams: {}", upstream_strs.join(", "));
+    if !config.trace_domains.is_empty() {
+        println!("Tracing domains: {}", config.trace_domains.join(", "));
+    }
+    if !config.pin_domains.is_empty() {
+        println!("Pinned domains: {}", config.pin_domains.join(", "));
+    }
+    if !forward_rules.is_empty() {
+        let rule_strs: Vec<_> = forward_rules
+            .iter()
+            .map(|rule| {
+                let upstream_strs: Vec<_> = rule.upstreams.iter().map(|a| a.to_string()).collect();
+                format!("{} -> {}", rule.suffix, upstream_strs.join(","))
+            })
+            .collect();
+        println!("Forwarding rules: {}", rule_strs.join(", "));
+    }
+    if !config.local_records.is_empty() {
+        println!("Local records: {}", config.local_records.len());
+    }
 
-    let udp = UdpTransport::bind(config.bind_addr, config.upstreams.len()).await?;
-    let tcp = TcpTransport::bind(config.bind_addr).await?;
+    #[cfg(unix)]
+    if let Some(path) = &config.tail_socket
+        && let Err(e) = tail::serve(path)
+    {
+        logging::warn(format_args!(
+            "Query stream disabled, cannot listen on {}: {}",
+            path.display(),
+            e
+        ));
+    }
 
-    udp.start(config.upstreams.clone(), resolver.clone(), config.verbose);
-    tcp.start(config.upstreams, resolver.clone(), config.verbose);
+    // Upstreams past the race limit are asked over TCP, so they need no socket.
+    let udp_upstreams = match (config.upstream_strategy, config.race) {
+        (UpstreamStrategy::Race, Some(race)) => race.min(config.upstreams.len()),
+        _ => config.upstreams.len(),
+    };
+    let shutdown = ShutdownSignal::new();
+    let mut listeners = Vec::with_capacity(config.bind_addrs.len());
+    for &addr in &config.bind_addrs {
+        let udp = if config.udp_sockets > 1 {
+            UdpTransport::bind_reuseport(addr, config.udp_sockets, udp_upstreams).await?
+        } else {
+            UdpTransport::bind(addr, udp_upstreams).await?
+        };
+        let udp = udp
+            .with_shutdown(shutdown.handle())
+            .with_max_packet_size(config.max_udp_size)
+            .with_query_timeout(config.query_timeout)
+            .with_client_rate_limit(config.rate_limit_pps);
+        let mut tcp = TcpTransport::bind(addr)
+            .await?
+            .with_shutdown(shutdown.handle())
+            .with_idle_timeout(config.tcp_idle_timeout)
+            .with_max_clients(config.max_tcp_clients);
+        if let Some(idle) = config.tcp_keepalive_idle {
+            tcp = tcp.with_keepalive(
+                idle,
+                config.tcp_keepalive_interval,
+                tcp::DEFAULT_KEEPALIVE_RETRIES,
+            );
+        }
+        if udp.socket_count() > 1 {
+            println!(
+                "DNS proxy listening on {} (UDP: {} sockets with SO_REUSEPORT)",
+                addr,
+                udp.socket_count()
+            );
+        } else {
+            println!("DNS proxy listening on {}", addr);
+        }
+        listeners.push((udp, tcp));
+    }
+    let tls = match &config.dot {
+        Some(dot_config) => Some(dot::load_tls_config(&dot_config.cert, &dot_config.key)?),
+        None => None,
+    };
+    let dot = match (&config.dot, &tls) {
+        (Some(dot_config), Some(tls)) => {
+            let mut transport = DotTransport::bind(dot_config.addr, tls.clone())
+                .await?
+                .with_shutdown(shutdown.handle())
+                .with_idle_timeout(config.tcp_idle_timeout)
+                .with_max_clients(config.max_tcp_clients);
+            if let Some(idle) = config.tcp_keepalive_idle {
+                transport = transport.with_keepalive(
+                    idle,
+                    config.tcp_keepalive_interval,
+                    tcp::DEFAULT_KEEPALIVE_RETRIES,
+                );
+            }
+            println!("DNS-over-TLS listening on {}", dot_config.addr);
+            Some(transport)
+        }
+        _ => None,
+    };
+    let doh = match &config.doh {
+        Some(doh_config) => {
+            let tls = match &doh_config.tls {
+                Some((cert, key)) => Some(dot::load_tls_config(cert, key)?),
+                None => tls,
+            };
+            let mut transport = DohTransport::bind(doh_config.addr)
+                .await?
+                .with_shutdown(shutdown.handle())
+                .with_idle_timeout(config.tcp_idle_timeout)
+                .with_max_clients(config.max_tcp_clients);
+            let scheme = match tls {
+                Some(tls) => {
+                    transport = transport.with_tls(tls);
+                    "https"
+                }
+                None => "http",
+            };
+            println!(
+                "DNS-over-HTTPS listening on {}://{}{}",
+                scheme,
+                doh_config.addr,
+                doh::PATH
+            );
+            Some(transport)
+        }
+        None => None,
+    };
+
+    if let Some(addr) = config.metrics_addr {
+        let server = StatsServer::bind(addr).await?;
+        println!(
+            "Prometheus metrics listening on http://{}{}",
+            addr,
+            prometheus::PATH
+        );
+        server.start(resolver.clone());
+    }
+
+    if let Some(addr) = config.admin_addr {
+        let server = AdminServer::bind(addr).await?;
+        println!("Admin API listening on http://{}", addr);
+        server.start(resolver.clone());
+    }
+
+    if let Some(health_check) = config.health_check.take() {
+        tokio::spawn(check_upstream_health(
+            resolver.clone(),
+            config.upstreams.clone(),
+            health_check,
+        ));
+    }
+
+    if let Some(path) = config.cache_file.clone() {
+        tokio::spawn(flush_cache(resolver.clone(), path));
+    }
+    if !config.cache_sweep_interval.is_zero() {
+        tokio::spawn(sweep_cache(
+            resolver.clone(),
+            config.cache_sweep_interval,
+            config.verbose,
+        ));
+    }
+
+    #[cfg(unix)]
+    tokio::spawn(reload_on_sighup(
+        resolver.clone(),
+        lists.clone(),
+        config.local_records_file.clone(),
+    ));
+    #[cfg(unix)]
+    tokio::spawn(flush_cache_on_sigusr1(resolver.clone()));
+    #[cfg(feature = "blocklist-url")]
+    if let Some(urls) = &config.blocklist_urls {
+        for (list, downloaded) in remote_lists {
+            tokio::spawn(refresh_blocklist(
+                resolver.clone(),
+                lists.clone(),
+                list,
+                urls.refresh_interval,
+                downloaded,
+            ));
+        }
+    }
+    if let Some(interval) = config.reload_interval.filter(|i| !i.is_zero()) {
+        tokio::spawn(reload_on_change(resolver.clone(), lists, interval));
+    }
+
+    if !config.pin_domains.is_empty() {
+        tokio::spawn(refresh_pinned(
+            resolver.clone(),
+            config.pin_domains.clone(),
+            config.upstreams.clone(),
+        ));
+    }
+
+    for (udp, tcp) in listeners {
+        udp.start(config.upstreams.clone(), resolver.clone(), config.verbose);
+        tcp.start(config.upstreams.clone(), resolver.clone(), config.verbose);
+    }
+    if let Some(dot) = dot {
+        dot.start(config.upstreams.clone(), resolver.clone(), config.verbose);
+    }
+    if let Some(doh) = doh {
+        doh.start(config.upstreams.clone(), resolver.clone(), config.verbose);
+    }
+    let cache_file = config
+        .cache_file
+        .clone()
+        .map(|path| (resolver.clone(), path));
+
+    let statsd = match config.statsd {
+        Some(target) => Some(StatsdSink::new(target, &config.statsd_prefix)?),
+        None => None,
+    };
 
     // Print stats every minute
+    let upstreams = config.upstreams.clone();
     tokio::spawn(async move {
         let mut interval = tokio::time::interval(Duration::from_secs(60));
         interval.tick().await; // Skip first immediate tick
@@ -64,21 +601,601 @@ pub async fn run(config: ProxyConfig) -> io::Result<()> {
             } else {
                 0.0
             };
-            println!(
-                "[stats] cache={} requests={} forwarded={} cached={} blocked={} cache_hit={:.1}% avg_response={:.2}ms",
+            logging::info(format_args!(
+                "[stats] cache={} requests={} forwarded={} cached={} negatives={} blocked={} local={} unqualified={} failed={} timeouts={} stale={} spoofed={} mismatched={} rebinding_blocked={} fallbacks={} coalesced={} tcp_rejected={} rate_limited={} cache_hit={:.1}% avg_response={:.2}ms p50={:.2}ms p95={:.2}ms p99={:.2}ms",
                 cache_len,
                 stats.requests,
                 stats.forwarded,
                 stats.cached,
+                stats.negatives,
                 stats.blocked,
+                stats.local,
+                stats.unqualified,
+                stats.failed,
+                stats.timeouts,
+                stats.stale,
+                stats.spoofed,
+                stats.mismatched,
+                stats.rebinding_blocked,
+                stats.fallbacks,
+                stats.coalesced,
+                stats.tcp_rejected,
+                stats.rate_limited,
                 cache_hit_pct,
-                stats.avg_response_ms
-            );
+                stats.avg_response_ms,
+                stats.p50_ms,
+                stats.p95_ms,
+                stats.p99_ms
+            ));
+            if let Some(statsd) = &statsd {
+                statsd.emit(&stats, cache_len);
+            }
+            if stats.upstreams.iter().any(|&(wins, _)| wins > 0) {
+                let wins: Vec<_> = upstreams
+                    .iter()
+                    .zip(&stats.upstreams)
+                    .map(|(upstream, (wins, avg_ms))| {
+                        format!("{}={}/{:.2}ms", upstream, wins, avg_ms)
+                    })
+                    .collect();
+                logging::info(format_args!("[stats] upstream_wins {}", wins.join(" ")));
+            }
+            let unhealthy = resolver.upstream_health().unhealthy();
+            if !unhealthy.is_empty() {
+                let unhealthy: Vec<_> = unhealthy.iter().map(|a| a.to_string()).collect();
+                logging::info(format_args!("[stats] unhealthy {}", unhealthy.join(" ")));
+            }
+            if !stats.throttled.is_empty() {
+                let throttled: Vec<_> = stats
+                    .throttled
+                    .iter()
+                    .map(|(upstream, count)| format!("{}={}", upstream, count))
+                    .collect();
+                logging::info(format_args!("[stats] throttled {}", throttled.join(" ")));
+            }
+            if !stats.top_domains.is_empty() {
+                let top_domains: Vec<_> = stats
+                    .top_domains
+                    .iter()
+                    .map(|(domain, count)| format!("{}={}", domain, count))
+                    .collect();
+                logging::info(format_args!(
+                    "[stats] top_domains {}",
+                    top_domains.join(" ")
+                ));
+            }
+            if !stats.divergences.is_empty() {
+                let divergences: Vec<_> = stats
+                    .divergences
+                    .iter()
+                    .map(|(upstream, count)| format!("{}={}", upstream, count))
+                    .collect();
+                logging::info(format_args!(
+                    "[stats] divergences {}",
+                    divergences.join(" ")
+                ));
+            }
         }
     });
 
-    // Keep running forever
-    std::future::pending::<()>().await;
+    wait_for_termination().await;
+    logging::info(format_args!(
+        "Shutting down, waiting up to {:?} for queries in flight",
+        config.shutdown_timeout
+    ));
+    if !shutdown.drain(config.shutdown_timeout).await {
+        logging::warn(format_args!(
+            "Shutdown timeout reached with queries still in flight"
+        ));
+    }
+    if let Some((resolver, path)) = cache_file
+        && let Err(e) = resolver.save_cache(&path)
+    {
+        logging::warn(format_args!(
+            "Failed to save cache to {}: {}",
+            path.display(),
+            e
+        ));
+    }
+
+    Ok(())
+}
+
+/// Wait for SIGINT (Ctrl-C) or, on Unix, SIGTERM.
+async fn wait_for_termination() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => logging::warn(format_args!("SIGTERM handling disabled: {}", e)),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        logging::warn(format_args!("Ctrl-C handling disabled: {}", e));
+        std::future::pending::<()>().await;
+    }
+}
+
+/// Blocklist, allowlist and regex rule files the blocklist is (re)built from.
+#[derive(Clone)]
+struct BlocklistFiles {
+    /// Include the embedded lists
+    embedded: bool,
+    blocklists: Vec<String>,
+    /// URLs and copies of downloaded lists; missing copies have never been downloaded
+    downloaded: Vec<(String, PathBuf)>,
+    allowlist: Option<String>,
+    regex: Option<String>,
+    wildcard_apex: bool,
+    /// Pack the domains to save memory
+    compact: bool,
+}
+
+impl BlocklistFiles {
+    fn new(config: &ProxyConfig, downloaded: Vec<(String, PathBuf)>) -> Self {
+        Self {
+            embedded: !config.no_embedded_lists,
+            blocklists: config.blocklist_paths.clone(),
+            downloaded,
+            allowlist: config.allowlist_path.clone(),
+            regex: config.blocklist_regex_path.clone(),
+            wildcard_apex: config.wildcard_apex,
+            compact: config.compact_blocklist,
+        }
+    }
+
+    /// Build the blocklist from all sources with its regex rules and allowlist applied.
+    fn load(&self) -> io::Result<Blocklist> {
+        self.build(self.sources()?)
+    }
+
+    /// Load each source list on its own, named for reporting.
+    fn sources(&self) -> io::Result<Vec<(String, Blocklist)>> {
+        let mut sources = Vec::new();
+        if self.embedded {
+            sources.push(("embedded lists".to_string(), Blocklist::new()));
+        }
+        for path in &self.blocklists {
+            sources.push((path.clone(), Blocklist::from_file(path)?));
+        }
+        for (url, path) in &self.downloaded {
+            match Blocklist::from_file(&path.to_string_lossy()) {
+                Ok(list) => sources.push((url.clone(), list)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sources)
+    }
+
+    /// Merge loaded sources and apply the regex rules and allowlist.
+    fn build(&self, sources: Vec<(String, Blocklist)>) -> io::Result<Blocklist> {
+        let mut blocklist = Blocklist::merged(sources.into_iter().map(|(_, list)| list))
+            .with_wildcard_apex(self.wildcard_apex)
+            .with_compact_storage(self.compact);
+        if let Some(path) = &self.regex {
+            blocklist = blocklist
+                .with_regex_rules(&std::fs::read_to_string(path)?)
+                .map_err(|e| {
+                    io::Error::new(io::ErrorKind::InvalidData, format!("{}:\n{}", path, e))
+                })?;
+        }
+        if let Some(path) = &self.allowlist {
+            for domain in read_domain_list(path)? {
+                blocklist.add_allowlist_entry(&domain);
+            }
+        }
+        Ok(blocklist)
+    }
 
+    /// Latest modification time of the configured files (None when there are none).
+    fn modified(&self) -> Option<SystemTime> {
+        [&self.allowlist, &self.regex]
+            .into_iter()
+            .flatten()
+            .chain(&self.blocklists)
+            .map(Path::new)
+            .chain(self.downloaded.iter().map(|(_, path)| path.as_path()))
+            .filter_map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+/// Load the blocklist `config` describes and print whether `domain` is
+/// blocked, and by which rule (`detour test-blocklist`).
+///
+/// Downloaded lists are read from their last copies; nothing is fetched.
+pub fn test_blocklist(config: &ProxyConfig, domain: &str) -> io::Result<()> {
+    #[cfg(feature = "blocklist-url")]
+    let downloaded = match &config.blocklist_urls {
+        Some(urls) => urls
+            .urls
+            .iter()
+            .map(|url| {
+                RemoteList::new(url, &urls.cache_dir)
+                    .map(|list| (url.clone(), list.path().to_path_buf()))
+            })
+            .collect::<io::Result<_>>()?,
+        None => Vec::new(),
+    };
+    #[cfg(not(feature = "blocklist-url"))]
+    let downloaded = Vec::new();
+    let blocklist = BlocklistFiles::new(config, downloaded).load()?;
+
+    let domain = domain.trim().trim_end_matches('.').to_ascii_lowercase();
+    match blocklist.explain_block(&domain) {
+        Some(rule) if rule == domain => println!("{} is blocked: it is listed", domain),
+        Some(rule) if blocklist.matched_entry(&domain) == Some(rule.as_str()) => {
+            println!("{} is blocked: its ancestor {} is listed", domain, rule)
+        }
+        Some(rule) => println!("{} is blocked: it matches {}", domain, rule),
+        None => println!("{} is not blocked", domain),
+    }
     Ok(())
 }
+
+/// Rebuild the blocklist off the runtime and swap it into the resolver.
+///
+/// On error the current blocklist stays in place.
+async fn reload_blocklist(resolver: &Resolver, lists: &BlocklistFiles) {
+    let loader = lists.clone();
+    match tokio::task::spawn_blocking(move || loader.load()).await {
+        Ok(Ok(blocklist)) => {
+            let previous = resolver.blocked_count();
+            let count = blocklist.len();
+            resolver.replace_blocklist(blocklist);
+            logging::info(format_args!(
+                "Reloaded blocklist ({} domains blocked, was {})",
+                count, previous
+            ));
+        }
+        Ok(Err(e)) => logging::warn(format_args!(
+            "Blocklist reload failed, keeping the current list: {}",
+            e
+        )),
+        Err(e) => logging::warn(format_args!("Blocklist reload failed: {}", e)),
+    }
+}
+
+/// Re-read the local records from the config file at `path` and swap them
+/// into the resolver.
+///
+/// On error the current records stay in place.
+#[cfg(unix)]
+fn reload_local_records(resolver: &Resolver, path: &Path) {
+    match ConfigFile::load(path) {
+        Ok(file) => {
+            let records = LocalRecords::new(file.local_records.unwrap_or_default());
+            let changed = resolver.replace_local_records(records);
+            logging::info(format_args!(
+                "Local records reloaded ({} names changed)",
+                changed
+            ));
+        }
+        Err(e) => logging::error(format_args!(
+            "Local records reload failed, keeping the current ones: {}",
+            e
+        )),
+    }
+}
+
+/// Reload the blocklist, and the local records if they come from a config
+/// file, whenever the process receives SIGHUP.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    resolver: Arc<Resolver>,
+    lists: BlocklistFiles,
+    local_records_file: Option<PathBuf>,
+) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            logging::warn(format_args!("SIGHUP reload disabled: {}", e));
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        reload_blocklist(&resolver, &lists).await;
+        if let Some(path) = &local_records_file {
+            reload_local_records(&resolver, path);
+        }
+    }
+}
+
+/// Empty the cache whenever the process receives SIGUSR1.
+#[cfg(unix)]
+async fn flush_cache_on_sigusr1(resolver: Arc<Resolver>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            logging::warn(format_args!("SIGUSR1 cache flush disabled: {}", e));
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        let dropped = resolver.clear_cache();
+        logging::info(format_args!(
+            "Cache flushed on SIGUSR1 ({} entries dropped)",
+            dropped
+        ));
+    }
+}
+
+/// Reload the blocklist when its files change, checking every `interval`.
+async fn reload_on_change(resolver: Arc<Resolver>, lists: BlocklistFiles, interval: Duration) {
+    let mut last_modified = lists.modified();
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await; // Just loaded
+    loop {
+        interval.tick().await;
+        let modified = lists.modified();
+        if modified != last_modified {
+            last_modified = modified;
+            reload_blocklist(&resolver, &lists).await;
+        }
+    }
+}
+
+/// Download the blocklists at startup, returning each with whether its
+/// download succeeded; failed ones fall back to their last copy.
+#[cfg(feature = "blocklist-url")]
+async fn download_blocklists(config: &BlocklistUrlConfig) -> io::Result<Vec<(RemoteList, bool)>> {
+    std::fs::create_dir_all(&config.cache_dir)?;
+    let lists = config
+        .urls
+        .iter()
+        .map(|url| RemoteList::new(url, &config.cache_dir))
+        .collect::<io::Result<Vec<_>>>()?;
+    let results = futures::future::join_all(lists.iter().map(RemoteList::refresh)).await;
+    let mut downloaded = Vec::new();
+    for (list, result) in lists.into_iter().zip(results) {
+        match &result {
+            Ok(Refresh::Updated) => println!("Downloaded blocklist {}", list.url()),
+            Ok(Refresh::NotModified) => println!("Blocklist {} is up to date", list.url()),
+            Err(e) if list.path().exists() => logging::warn(format_args!(
+                "Downloading blocklist {} failed, using the last copy: {}",
+                list.url(),
+                e
+            )),
+            Err(e) => logging::warn(format_args!(
+                "Downloading blocklist {} failed and there is no earlier copy: {}",
+                list.url(),
+                e
+            )),
+        }
+        downloaded.push((list, result.is_ok()));
+    }
+    Ok(downloaded)
+}
+
+/// Download a blocklist again every `interval`, reloading the blocklist when
+/// it changed. Failed downloads are retried sooner, backing off from
+/// [`DOWNLOAD_RETRY`] up to `interval`.
+#[cfg(feature = "blocklist-url")]
+async fn refresh_blocklist(
+    resolver: Arc<Resolver>,
+    lists: BlocklistFiles,
+    list: RemoteList,
+    interval: Duration,
+    downloaded: bool,
+) {
+    let mut retry = DOWNLOAD_RETRY.min(interval);
+    let mut wait = if downloaded { interval } else { retry };
+    loop {
+        tokio::time::sleep(wait).await;
+        match list.refresh().await {
+            Ok(refresh) => {
+                if refresh == Refresh::Updated {
+                    reload_blocklist(&resolver, &lists).await;
+                }
+                retry = DOWNLOAD_RETRY.min(interval);
+                wait = interval;
+            }
+            Err(e) => {
+                logging::warn(format_args!(
+                    "Downloading blocklist {} failed, retrying in {}s: {}",
+                    list.url(),
+                    retry.as_secs(),
+                    e
+                ));
+                wait = retry;
+                retry = (retry * 2).min(interval);
+            }
+        }
+    }
+}
+
+/// Restore the cache from `path`, starting empty if it is missing or unreadable.
+fn load_cache(path: &Path) -> DnsCache {
+    match DnsCache::load_from_file(path) {
+        Ok(cache) => {
+            println!(
+                "Restored {} cached responses from {}",
+                cache.len(),
+                path.display()
+            );
+            cache
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => DnsCache::new(),
+        Err(e) => {
+            logging::warn(format_args!(
+                "Ignoring cache file {}: {}",
+                path.display(),
+                e
+            ));
+            DnsCache::new()
+        }
+    }
+}
+
+/// Write the cache to `path` every [`CACHE_FLUSH_INTERVAL`].
+async fn flush_cache(resolver: Arc<Resolver>, path: PathBuf) {
+    let mut interval = tokio::time::interval(CACHE_FLUSH_INTERVAL);
+    interval.tick().await; // Nothing new to save yet
+    loop {
+        interval.tick().await;
+        let resolver = resolver.clone();
+        let target = path.clone();
+        let result = tokio::task::spawn_blocking(move || resolver.save_cache(&target)).await;
+        if let Ok(Err(e)) = result {
+            logging::warn(format_args!(
+                "Failed to save cache to {}: {}",
+                path.display(),
+                e
+            ));
+        }
+    }
+}
+
+/// Drop expired cache entries every `interval`.
+async fn sweep_cache(resolver: Arc<Resolver>, interval: Duration, verbose: bool) {
+    let mut interval = tokio::time::interval(interval);
+    interval.tick().await; // Skip first immediate tick
+    loop {
+        interval.tick().await;
+        let dropped = resolver.sweep_cache();
+        if verbose && dropped > 0 {
+            logging::info(format_args!(
+                "Cache sweep dropped {} expired entries",
+                dropped
+            ));
+        }
+    }
+}
+
+/// Warm the cache for pinned domains, then refresh their entries before they expire.
+async fn refresh_pinned(resolver: Arc<Resolver>, domains: Vec<String>, upstreams: Vec<SocketAddr>) {
+    for domain in &domains {
+        for qtype in [TYPE_A, TYPE_AAAA] {
+            if let Err(e) = resolver.resolve(domain, qtype, &upstreams).await {
+                logging::warn(format_args!(
+                    "Failed to warm pinned domain {}: {}",
+                    domain, e
+                ));
+            }
+        }
+    }
+
+    let mut interval = tokio::time::interval(PIN_REFRESH_INTERVAL);
+    loop {
+        interval.tick().await;
+        resolver.refresh_pinned(PIN_REFRESH_LEAD, &upstreams).await;
+    }
+}
+
+/// Probe every upstream each interval, logging health changes.
+async fn check_upstream_health(
+    resolver: Arc<Resolver>,
+    upstreams: Vec<SocketAddr>,
+    health_check: HealthCheckConfig,
+) {
+    let mut interval = tokio::time::interval(health_check.interval);
+    loop {
+        interval.tick().await;
+        let probes = upstreams.iter().map(|&upstream| {
+            let probe_name = &health_check.probe_name;
+            async move { (upstream, probe(upstream, probe_name).await) }
+        });
+        for (upstream, ok) in futures::future::join_all(probes).await {
+            match resolver.upstream_health().record_probe(upstream, ok) {
+                Some(true) => logging::info(format_args!("Upstream {} is healthy again", upstream)),
+                Some(false) => logging::warn(format_args!(
+                    "Upstream {} failed {} health probes in a row, leaving it out",
+                    upstream, health_check.failures
+                )),
+                None => {}
+            }
+        }
+    }
+}
+
+/// Register the `tls://`, `https://` and `quic://` upstreams with the
+/// clients that speak their protocol; `protocols` is indexed like `upstreams`.
+pub fn register_upstreams(
+    upstreams: &[SocketAddr],
+    protocols: &[UpstreamProtocol],
+    quic_upstreams: &[(SocketAddr, String)],
+) -> io::Result<()> {
+    #[cfg(feature = "doq")]
+    for (addr, server_name) in quic_upstreams {
+        doq::register(*addr, server_name)?;
+    }
+    #[cfg(not(feature = "doq"))]
+    let _ = quic_upstreams;
+    for (addr, protocol) in upstreams.iter().zip(protocols) {
+        match protocol {
+            UpstreamProtocol::Plain => {}
+            UpstreamProtocol::Tls { sni } => dot::register_upstream(*addr, sni)?,
+            #[cfg(feature = "doh-upstream")]
+            UpstreamProtocol::Https { url } => {
+                doh::client::register(DohClient::new(*addr, url, dot::system_roots()?)?)
+            }
+            #[cfg(not(feature = "doh-upstream"))]
+            UpstreamProtocol::Https { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// Send one health probe, returning whether the upstream answered usefully.
+async fn probe(upstream: SocketAddr, probe_name: &str) -> bool {
+    let id = rand::random();
+    let Some(query) = DnsQuery::new(id, probe_name, TYPE_NS).to_bytes() else {
+        return false;
+    };
+    #[cfg(feature = "doq")]
+    if doq::is_registered(upstream) {
+        return doq::query(upstream, &query)
+            .await
+            .is_some_and(|response| probe_answered(&response, id));
+    }
+    #[cfg(feature = "doh-upstream")]
+    if doh::client::is_registered(upstream) {
+        return doh::client::query(upstream, &query)
+            .await
+            .is_some_and(|response| probe_answered(&response, id));
+    }
+    if dot::is_upstream(upstream) {
+        return tokio::time::timeout(PROBE_TIMEOUT, tcp::forward_to_upstream(&query, upstream))
+            .await
+            .ok()
+            .flatten()
+            .is_some_and(|response| probe_answered(&response, id));
+    }
+
+    let local: SocketAddr = match upstream {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let Ok(socket) = tokio::net::UdpSocket::bind(local).await else {
+        return false;
+    };
+    if socket.connect(upstream).await.is_err() || socket.send(&query).await.is_err() {
+        return false;
+    }
+    let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+    match tokio::time::timeout(PROBE_TIMEOUT, socket.recv(&mut buf)).await {
+        Ok(Ok(len)) => probe_answered(&buf[..len], id),
+        _ => false,
+    }
+}
+
+/// A probe succeeds on any answer to it other than SERVFAIL or REFUSED.
+fn probe_answered(response: &[u8], id: u16) -> bool {
+    response.len() >= 12
+        && response[..2] == id.to_be_bytes()
+        && response[2] & 0x80 != 0
+        && !matches!(response[3] & 0x0F, 2 | 5)
+}
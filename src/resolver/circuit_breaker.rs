@@ -0,0 +1,229 @@
+//! Circuit breaker for unreliable upstreams.
+//!
+//! An upstream that fails `threshold` queries in a row has its circuit
+//! opened: it sits out of queries for the backoff period. Once that has
+//! passed the circuit is half-open, and the next query may go to it as a
+//! trial; an answer closes the circuit, another failure (or no outcome
+//! within a further backoff) keeps it open. If every circuit is open, all
+//! upstreams are used anyway so queries are not refused outright.
+
+use rustc_hash::FxHashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// Consecutive failures that open a circuit by default.
+pub const DEFAULT_CIRCUIT_FAILURES: u32 = 5;
+
+/// How long an open circuit keeps its upstream out of queries by default.
+pub const DEFAULT_CIRCUIT_BACKOFF: Duration = Duration::from_secs(30);
+
+/// The state of one upstream's circuit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Queries go to the upstream.
+    Closed,
+    /// The upstream sits out of queries until `until`.
+    Open { until: Instant },
+    /// The backoff has passed; the next query is a trial.
+    HalfOpen,
+}
+
+/// Per-upstream circuits, driven by the outcome of forwarded queries.
+pub struct UpstreamCircuitBreaker {
+    circuits: FxHashMap<SocketAddr, Circuit>,
+    threshold: u32,
+    backoff: Duration,
+    /// Reference point of the `open_until` timestamps.
+    epoch: Instant,
+    /// Circuits currently open or half-open.
+    open: AtomicUsize,
+}
+
+impl Default for UpstreamCircuitBreaker {
+    /// A breaker with no circuits, which never excludes an upstream.
+    fn default() -> Self {
+        Self::new(&[], DEFAULT_CIRCUIT_FAILURES, DEFAULT_CIRCUIT_BACKOFF)
+    }
+}
+
+#[derive(Default)]
+struct Circuit {
+    /// Consecutive failures; at or above the threshold the circuit is open.
+    failures: AtomicU32,
+    /// Milliseconds after the epoch until which an open circuit excludes
+    /// its upstream.
+    open_until: AtomicU64,
+}
+
+impl UpstreamCircuitBreaker {
+    /// Open an upstream's circuit for `backoff` after `threshold` failures
+    /// in a row (minimum 1).
+    pub fn new(upstreams: &[SocketAddr], threshold: u32, backoff: Duration) -> Self {
+        Self {
+            circuits: upstreams
+                .iter()
+                .map(|&addr| (addr, Circuit::default()))
+                .collect(),
+            threshold: threshold.max(1),
+            backoff,
+            epoch: Instant::now(),
+            open: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.circuits.is_empty()
+    }
+
+    /// Consecutive failures that open a circuit.
+    pub fn threshold(&self) -> u32 {
+        self.threshold
+    }
+
+    /// How long an open circuit excludes its upstream.
+    pub fn backoff(&self) -> Duration {
+        self.backoff
+    }
+
+    /// Milliseconds since the epoch.
+    fn now_ms(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    /// The state of `upstream`'s circuit; unknown upstreams are always closed.
+    pub fn state(&self, upstream: SocketAddr) -> CircuitState {
+        let Some(circuit) = self.circuits.get(&upstream) else {
+            return CircuitState::Closed;
+        };
+        if circuit.failures.load(Ordering::Relaxed) < self.threshold {
+            return CircuitState::Closed;
+        }
+        let until = circuit.open_until.load(Ordering::Relaxed);
+        if until > self.now_ms() {
+            CircuitState::Open {
+                until: self.epoch + Duration::from_millis(until),
+            }
+        } else {
+            CircuitState::HalfOpen
+        }
+    }
+
+    /// Check if a query may go to `upstream`: its circuit is closed, it is
+    /// half-open and this query is the trial, or every circuit is open.
+    #[inline]
+    pub fn allow(&self, upstream: SocketAddr) -> bool {
+        let Some(circuit) = self.circuits.get(&upstream) else {
+            return true;
+        };
+        if circuit.failures.load(Ordering::Relaxed) < self.threshold
+            || self.open.load(Ordering::Relaxed) >= self.circuits.len()
+        {
+            return true;
+        }
+        // Half-open: the first query to get here re-arms the backoff and is
+        // the trial, so a trial that never reports back only delays the next.
+        let until = circuit.open_until.load(Ordering::Relaxed);
+        let now = self.now_ms();
+        now >= until
+            && circuit
+                .open_until
+                .compare_exchange(
+                    until,
+                    now + self.backoff.as_millis() as u64,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_ok()
+    }
+
+    /// Record an answer from `upstream`, returning the new state if its
+    /// circuit closed.
+    pub fn record_success(&self, upstream: SocketAddr) -> Option<CircuitState> {
+        let circuit = self.circuits.get(&upstream)?;
+        if circuit.failures.swap(0, Ordering::Relaxed) >= self.threshold {
+            self.open.fetch_sub(1, Ordering::Relaxed);
+            return Some(CircuitState::Closed);
+        }
+        None
+    }
+
+    /// Record a query `upstream` failed to answer, returning the new state
+    /// if its circuit opened.
+    ///
+    /// Failures once the circuit is open change nothing: a failed trial has
+    /// already re-armed the backoff in [`Self::allow`].
+    pub fn record_failure(&self, upstream: SocketAddr) -> Option<CircuitState> {
+        let circuit = self.circuits.get(&upstream)?;
+        let failures = circuit.failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures != self.threshold {
+            return None;
+        }
+        self.open.fetch_add(1, Ordering::Relaxed);
+        let until = self.now_ms() + self.backoff.as_millis() as u64;
+        circuit.open_until.store(until, Ordering::Relaxed);
+        Some(self.state(upstream))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn circuits_open_after_the_threshold_and_close_on_success() {
+        let (first, second) = (
+            "10.0.0.1:53".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+        );
+        let breaker = UpstreamCircuitBreaker::new(&[first, second], 2, Duration::from_secs(30));
+
+        let transitions = [
+            breaker.record_failure(first),
+            breaker.record_success(first),
+            breaker.record_failure(first),
+            breaker.record_failure(first),
+        ];
+        let excluded = !breaker.allow(first);
+        breaker.record_failure(second);
+        breaker.record_failure(second);
+        let all_open_allowed = breaker.allow(first) && breaker.allow(second);
+        let closed = breaker.record_success(first);
+
+        assert_eq!(transitions[..3], [None, None, None]);
+        assert!(matches!(transitions[3], Some(CircuitState::Open { .. })));
+        assert!(excluded);
+        assert!(all_open_allowed);
+        assert_eq!(closed, Some(CircuitState::Closed));
+        assert_eq!(breaker.state(first), CircuitState::Closed);
+        assert!(matches!(breaker.state(second), CircuitState::Open { .. }));
+        assert!(!breaker.allow(second));
+        assert!(breaker.allow("10.0.0.3:53".parse().unwrap()));
+    }
+
+    #[test]
+    fn half_open_circuits_allow_one_trial() {
+        let (flaky, steady) = (
+            "10.0.0.1:53".parse().unwrap(),
+            "10.0.0.2:53".parse().unwrap(),
+        );
+        let breaker = UpstreamCircuitBreaker::new(&[flaky, steady], 1, Duration::from_millis(50));
+
+        breaker.record_failure(flaky);
+        let open = breaker.allow(flaky);
+        std::thread::sleep(Duration::from_millis(60));
+        let state = breaker.state(flaky);
+        let trial = breaker.allow(flaky);
+        let second = breaker.allow(flaky);
+        let failed_trial = breaker.record_failure(flaky);
+        let closed = breaker.record_success(flaky);
+
+        assert!(!open);
+        assert_eq!(state, CircuitState::HalfOpen);
+        assert!(trial);
+        assert!(!second);
+        assert_eq!(failed_trial, None);
+        assert_eq!(closed, Some(CircuitState::Closed));
+        assert!(breaker.allow(flaky));
+    }
+}
@@ -0,0 +1,106 @@
+//! Coalescing of identical forwards in flight.
+//!
+//! When a query is forwarded while another for the same name and type is
+//! still waiting on the upstreams, it waits for that answer instead of
+//! sending its own. The first query holds an [`InFlight`] guard; completing it
+//! fans the answer out to every waiter, and dropping it (the forward failed
+//! or was abandoned) releases them without one.
+
+use rustc_hash::FxHashMap;
+use std::sync::{Arc, Mutex, PoisonError};
+use tokio::sync::broadcast;
+
+type Key = (u16, String);
+
+/// Forwards in flight by question, each with the channel its answer goes out on.
+#[derive(Default)]
+pub(crate) struct InFlightQueries {
+    queries: Mutex<FxHashMap<Key, broadcast::Sender<Vec<u8>>>>,
+}
+
+impl InFlightQueries {
+    /// Start forwarding `(qtype, domain)`, or wait for the forward already in flight.
+    pub(crate) fn join(self: &Arc<Self>, qtype: u16, domain: &str) -> Result<InFlight, Waiter> {
+        let key = (qtype, domain.to_string());
+        let mut queries = self.queries.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(sender) = queries.get(&key) {
+            return Err(Waiter(sender.subscribe()));
+        }
+        queries.insert(key.clone(), broadcast::channel(1).0);
+        Ok(InFlight {
+            queries: self.clone(),
+            key: Some(key),
+        })
+    }
+
+    fn finish(&self, key: &Key, response: Option<&[u8]>) {
+        let sender = self
+            .queries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(key);
+        if let (Some(sender), Some(response)) = (sender, response) {
+            let _ = sender.send(response.to_vec());
+        }
+    }
+}
+
+/// A forward other queries for the same question may be waiting on.
+pub struct InFlight {
+    queries: Arc<InFlightQueries>,
+    key: Option<Key>,
+}
+
+impl InFlight {
+    /// Hand the upstream answer to every query waiting on this forward.
+    pub fn complete(mut self, response: &[u8]) {
+        if let Some(key) = self.key.take() {
+            self.queries.finish(&key, Some(response));
+        }
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            self.queries.finish(&key, None);
+        }
+    }
+}
+
+/// A query waiting on an identical forward in flight.
+pub struct Waiter(broadcast::Receiver<Vec<u8>>);
+
+impl Waiter {
+    /// Wait for the forward's answer, rewritten to transaction ID `id`.
+    ///
+    /// Returns None if the forward failed.
+    pub async fn response(mut self, id: u16) -> Option<Vec<u8>> {
+        let mut response = self.0.recv().await.ok()?;
+        response[..2].copy_from_slice(&id.to_be_bytes());
+        Some(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn waiters_get_the_answer_and_are_released_when_the_forward_is_dropped() {
+        let queries = Arc::new(InFlightQueries::default());
+        let first = queries.join(1, "example.com").ok().unwrap();
+        let waiter = queries.join(1, "example.com").err().unwrap();
+        let other_type = queries.join(28, "example.com");
+
+        first.complete(&[0, 1, 0x81, 0x80]);
+        let answered = waiter.response(7).await;
+        let second = queries.join(1, "example.com").ok().unwrap();
+        let released = queries.join(1, "example.com").err().unwrap();
+        drop(second);
+
+        assert!(other_type.is_ok());
+        assert_eq!(answered, Some(vec![0, 7, 0x81, 0x80]));
+        assert_eq!(released.response(7).await, None);
+    }
+}
@@ -0,0 +1,172 @@
+//! Upstream answer divergence detection.
+//!
+//! When racing, every upstream answers the same question. If the losing
+//! answers disagree materially with the winner (different rcode, or no
+//! address in common), one of the resolvers may be hijacked or poisoned.
+//! CDN rotation is tolerated: any overlap in the address sets counts as
+//! agreement.
+
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::dns::{DnsResponse, RData};
+
+/// Samples forwarded queries for divergence checks.
+pub struct DivergenceDetector {
+    sample_every: u64,
+    counter: AtomicU64,
+}
+
+impl DivergenceDetector {
+    /// Check one in every `sample_every` forwarded queries (minimum 1 = all).
+    pub fn new(sample_every: u64) -> Self {
+        Self {
+            sample_every: sample_every.max(1),
+            counter: AtomicU64::new(0),
+        }
+    }
+
+    /// Returns true if the next forwarded query should be checked.
+    #[inline]
+    pub fn should_sample(&self) -> bool {
+        self.counter
+            .fetch_add(1, Ordering::Relaxed)
+            .is_multiple_of(self.sample_every)
+    }
+}
+
+/// How two upstream answers for the same question disagree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Divergence {
+    /// The response codes differ (e.g. NOERROR vs NXDOMAIN).
+    Rcode { winner: u8, other: u8 },
+    /// Both returned addresses, but none in common.
+    DisjointAnswers {
+        winner: Vec<IpAddr>,
+        other: Vec<IpAddr>,
+    },
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Divergence::Rcode { winner, other } => {
+                write!(
+                    f,
+                    "reason=rcode winner_rcode={} other_rcode={}",
+                    winner, other
+                )
+            }
+            Divergence::DisjointAnswers { winner, other } => write!(
+                f,
+                "reason=disjoint_answers winner_answers={:?} other_answers={:?}",
+                winner, other
+            ),
+        }
+    }
+}
+
+/// Compare the winning response with another upstream's response.
+///
+/// Returns `None` if they agree or either response cannot be parsed.
+pub fn compare(winner: &[u8], other: &[u8]) -> Option<Divergence> {
+    let winner = DnsResponse::parse(winner)?;
+    let other = DnsResponse::parse(other)?;
+
+    if winner.rcode() != other.rcode() {
+        return Some(Divergence::Rcode {
+            winner: winner.rcode(),
+            other: other.rcode(),
+        });
+    }
+
+    let winner_addrs = addresses(&winner);
+    let other_addrs = addresses(&other);
+    if winner_addrs.is_empty() || other_addrs.is_empty() {
+        return None;
+    }
+    if winner_addrs.iter().any(|addr| other_addrs.contains(addr)) {
+        return None;
+    }
+    Some(Divergence::DisjointAnswers {
+        winner: winner_addrs,
+        other: other_addrs,
+    })
+}
+
+fn addresses(response: &DnsResponse) -> Vec<IpAddr> {
+    response
+        .answers
+        .iter()
+        .filter_map(|record| match record.data() {
+            RData::A(addr) => Some(IpAddr::V4(addr)),
+            RData::Aaaa(addr) => Some(IpAddr::V6(addr)),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, TYPE_A};
+
+    fn response(rcode: u8, addrs: &[[u8; 4]]) -> Vec<u8> {
+        let mut data = DnsQuery::new(1, "example.com", TYPE_A).to_bytes().unwrap();
+        data[2] = 0x81;
+        data[3] = 0x80 | rcode;
+        data[7] = addrs.len() as u8;
+        for addr in addrs {
+            data.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01, 0, 0, 0, 60, 0x00, 0x04]);
+            data.extend_from_slice(addr);
+        }
+        data
+    }
+
+    #[test]
+    fn overlapping_answers_agree() {
+        let winner = response(0, &[[1, 1, 1, 1], [2, 2, 2, 2]]);
+        let other = response(0, &[[2, 2, 2, 2], [3, 3, 3, 3]]);
+
+        assert_eq!(compare(&winner, &other), None);
+    }
+
+    #[test]
+    fn disjoint_answers_diverge() {
+        let winner = response(0, &[[10, 0, 0, 1]]);
+        let other = response(0, &[[93, 184, 216, 34]]);
+
+        let divergence = compare(&winner, &other);
+
+        assert!(matches!(
+            divergence,
+            Some(Divergence::DisjointAnswers { .. })
+        ));
+    }
+
+    #[test]
+    fn rcode_mismatch_diverges() {
+        let winner = response(0, &[[10, 0, 0, 1]]);
+        let other = response(3, &[]);
+
+        let divergence = compare(&winner, &other);
+
+        assert_eq!(
+            divergence,
+            Some(Divergence::Rcode {
+                winner: 0,
+                other: 3
+            })
+        );
+    }
+
+    #[test]
+    fn sampling_checks_one_in_n() {
+        let detector = DivergenceDetector::new(3);
+
+        let sampled: Vec<bool> = (0..6).map(|_| detector.should_sample()).collect();
+
+        assert_eq!(sampled, vec![true, false, false, true, false, false]);
+    }
+}
@@ -0,0 +1,127 @@
+//! Conditional forwarding (split DNS).
+//!
+//! Queries under a domain suffix can go to their own upstreams, e.g. an
+//! internal zone to the resolver serving it, while everything else goes to
+//! the global upstreams. The longest matching suffix wins. Matched queries
+//! bypass the cache, so internal answers are never refreshed through the
+//! global upstreams or written to the cache file.
+
+use rustc_hash::FxHashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use crate::upstream::parse_addr;
+
+/// Upstreams for the domains under one suffix.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ForwardRule {
+    /// Lowercase suffix without trailing dot, e.g. `corp.example`
+    pub suffix: String,
+    /// Upstreams for matching queries, raced or tried in order like the global ones
+    pub upstreams: Vec<SocketAddr>,
+}
+
+impl ForwardRule {
+    /// Parse `suffix=addr[,addr...]`, where each `addr` is accepted by [`parse_addr`].
+    ///
+    /// The suffix may be written as a pattern, `*.corp.example`, which
+    /// matches the same names as `corp.example`.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let (suffix, addrs) = spec
+            .split_once('=')
+            .ok_or_else(|| format!("invalid forwarding rule (expected suffix=addr): {}", spec))?;
+        let suffix = suffix.trim();
+        let suffix = suffix
+            .strip_prefix("*.")
+            .unwrap_or(suffix)
+            .trim_end_matches('.')
+            .to_ascii_lowercase();
+        if suffix.is_empty() {
+            return Err(format!("forwarding rule has no domain suffix: {}", spec));
+        }
+        if suffix.contains('*') {
+            return Err(format!(
+                "forwarding rule has a wildcard past `*.`: {}",
+                spec
+            ));
+        }
+        let upstreams = addrs
+            .split(',')
+            .map(|addr| parse_addr(addr.trim()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { suffix, upstreams })
+    }
+}
+
+/// Forwarding rules by suffix.
+#[derive(Default)]
+pub struct ForwardRules {
+    rules: FxHashMap<String, Arc<ForwardRule>>,
+}
+
+impl ForwardRules {
+    /// Build the table; a later rule for the same suffix replaces an earlier one.
+    pub fn new(rules: impl IntoIterator<Item = ForwardRule>) -> Self {
+        let rules = rules
+            .into_iter()
+            .map(|rule| (rule.suffix.clone(), Arc::new(rule)))
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The rule with the longest suffix matching `domain` (assumes lowercase input).
+    #[inline]
+    pub fn matching(&self, domain: &str) -> Option<&Arc<ForwardRule>> {
+        if self.rules.is_empty() {
+            return None;
+        }
+        let mut current = domain;
+        loop {
+            if let Some(rule) = self.rules.get(current) {
+                return Some(rule);
+            }
+            current = &current[current.find('.')? + 1..];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_suffix_wins() {
+        let rules = ForwardRules::new([
+            ForwardRule::parse("Example.=10.0.0.53").unwrap(),
+            ForwardRule::parse("corp.example=10.0.0.54:5353, 10.0.0.55").unwrap(),
+        ]);
+
+        let corp = rules.matching("db.corp.example").unwrap();
+
+        assert_eq!(corp.suffix, "corp.example");
+        assert_eq!(
+            corp.upstreams,
+            vec![
+                "10.0.0.54:5353".parse().unwrap(),
+                "10.0.0.55:53".parse().unwrap()
+            ]
+        );
+        assert_eq!(rules.matching("example").unwrap().suffix, "example");
+        assert_eq!(rules.matching("www.example").unwrap().suffix, "example");
+        assert!(rules.matching("notexample").is_none());
+        assert!(ForwardRule::parse("corp.example").is_err());
+        assert!(ForwardRule::parse("=10.0.0.53").is_err());
+        assert_eq!(
+            ForwardRule::parse("*.Corp.Example=10.0.0.54")
+                .unwrap()
+                .suffix,
+            "corp.example"
+        );
+        assert!(ForwardRule::parse("db.*.example=10.0.0.54").is_err());
+        assert!(ForwardRule::parse("corp.example=dns.corp").is_err());
+    }
+}
@@ -0,0 +1,258 @@
+//! Local authoritative records.
+//!
+//! Names on a home network (`router.home.arpa`) can be answered here instead
+//! of upstream. A query for a name with local records gets every record of
+//! the queried type in an authoritative (AA) answer. A name with a CNAME
+//! answers it for any type, followed by whatever the local records give its
+//! target; a name with neither gets an empty answer (NODATA) rather than
+//! being forwarded.
+
+use rustc_hash::FxHashMap;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use crate::dns::{
+    CLASS_IN, DnsRecord, RData, TYPE_A, TYPE_AAAA, TYPE_CNAME, TYPE_TXT, encode_domain, encode_txt,
+    is_valid_domain, parse_qtype,
+};
+
+/// TTL of local records that do not give one.
+pub const DEFAULT_LOCAL_TTL: u32 = 300;
+
+/// Most CNAMEs followed through the local records for one answer.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// One record answered locally.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalRecord {
+    /// Lowercase name without trailing dot, e.g. `router.home.arpa`
+    pub name: String,
+    pub ttl: u32,
+    pub rtype: u16,
+    /// Record data in wire format
+    pub rdata: Vec<u8>,
+}
+
+impl LocalRecord {
+    /// Build a record from its type and value as written in a zone file.
+    ///
+    /// A, AAAA, CNAME and TXT values are accepted; TXT values are taken
+    /// verbatim, without quotes.
+    pub fn new(name: &str, ttl: u32, rtype: &str, value: &str) -> Result<Self, String> {
+        let name = name.trim_end_matches('.').to_ascii_lowercase();
+        if !is_valid_domain(&name) {
+            return Err(format!("invalid local record name: {}", name));
+        }
+        let invalid = || {
+            format!(
+                "invalid {} value for local record {}: {}",
+                rtype, name, value
+            )
+        };
+        let rtype = parse_qtype(rtype).ok_or_else(|| format!("unknown record type: {}", rtype))?;
+        let rdata = match rtype {
+            TYPE_A => value
+                .parse::<Ipv4Addr>()
+                .map_err(|_| invalid())?
+                .octets()
+                .to_vec(),
+            TYPE_AAAA => value
+                .parse::<Ipv6Addr>()
+                .map_err(|_| invalid())?
+                .octets()
+                .to_vec(),
+            TYPE_CNAME => {
+                let target = value.trim_end_matches('.').to_ascii_lowercase();
+                if !is_valid_domain(&target) {
+                    return Err(invalid());
+                }
+                let mut rdata = Vec::with_capacity(target.len() + 2);
+                encode_domain(&mut rdata, &target);
+                rdata
+            }
+            TYPE_TXT => encode_txt(value),
+            _ => return Err(format!("local records cannot have type {}", rtype)),
+        };
+        Ok(Self {
+            name,
+            ttl,
+            rtype,
+            rdata,
+        })
+    }
+
+    /// Parse `name [ttl] type value`, e.g. `router.home.arpa A 192.168.1.1`.
+    /// The TTL defaults to [`DEFAULT_LOCAL_TTL`].
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let expected = || {
+            format!(
+                "invalid local record (expected name [ttl] type value): {}",
+                spec
+            )
+        };
+        let (name, rest) = spec
+            .trim()
+            .split_once(char::is_whitespace)
+            .ok_or_else(expected)?;
+        let (field, rest) = rest
+            .trim_start()
+            .split_once(char::is_whitespace)
+            .ok_or_else(expected)?;
+        let (ttl, rtype, value) = match field.parse() {
+            Ok(ttl) => {
+                let (rtype, value) = rest
+                    .trim_start()
+                    .split_once(char::is_whitespace)
+                    .ok_or_else(expected)?;
+                (ttl, rtype, value)
+            }
+            Err(_) => (DEFAULT_LOCAL_TTL, field, rest),
+        };
+        Self::new(name, ttl, rtype, value.trim())
+    }
+}
+
+/// Local records by name.
+#[derive(Default)]
+pub struct LocalRecords {
+    records: FxHashMap<String, Vec<DnsRecord>>,
+}
+
+impl LocalRecords {
+    pub fn new(records: impl IntoIterator<Item = LocalRecord>) -> Self {
+        let mut by_name: FxHashMap<String, Vec<DnsRecord>> = FxHashMap::default();
+        for record in records {
+            by_name
+                .entry(record.name.clone())
+                .or_default()
+                .push(DnsRecord {
+                    name: record.name,
+                    rtype: record.rtype,
+                    class: CLASS_IN,
+                    ttl: record.ttl,
+                    rdata: record.rdata,
+                });
+        }
+        Self { records: by_name }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    /// Whether `domain` has local records (assumes lowercase input).
+    pub fn contains(&self, domain: &str) -> bool {
+        self.records.contains_key(domain)
+    }
+
+    /// The answer for `domain` and `qtype` if the name has local records
+    /// (assumes lowercase input): its records of that type, or its CNAME
+    /// followed by the target's answer while the target is local too.
+    /// Empty if the name has neither.
+    #[inline]
+    pub fn answer(&self, domain: &str, qtype: u16) -> Option<Vec<DnsRecord>> {
+        let mut records = self.records.get(domain)?;
+        let mut answer = Vec::new();
+        for _ in 0..MAX_CNAME_CHAIN {
+            let matching = answer.len();
+            answer.extend(
+                records
+                    .iter()
+                    .filter(|record| record.rtype == qtype)
+                    .cloned(),
+            );
+            if answer.len() > matching {
+                break;
+            }
+            let Some(alias) = records.iter().find(|record| record.rtype == TYPE_CNAME) else {
+                break;
+            };
+            answer.push(alias.clone());
+            let RData::Cname(target) = alias.data() else {
+                break;
+            };
+            match self.records.get(&target) {
+                Some(target_records) => records = target_records,
+                None => break,
+            }
+        }
+        Some(answer)
+    }
+
+    /// Names whose records differ in `other`, including names only one of
+    /// the two defines.
+    pub fn changed_names(&self, other: &LocalRecords) -> Vec<String> {
+        let mut changed: Vec<String> = self
+            .records
+            .iter()
+            .filter(|&(name, records)| other.records.get(name) != Some(records))
+            .map(|(name, _)| name.clone())
+            .collect();
+        changed.extend(
+            other
+                .records
+                .keys()
+                .filter(|name| !self.records.contains_key(*name))
+                .cloned(),
+        );
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_parse_from_zone_file_syntax_and_match_name_and_type() {
+        let records = LocalRecords::new([
+            LocalRecord::parse("Router.Home.Arpa. A 192.168.1.1").unwrap(),
+            LocalRecord::parse("router.home.arpa 60 A 192.168.1.2").unwrap(),
+            LocalRecord::parse("nas.home.arpa CNAME router.home.arpa").unwrap(),
+            LocalRecord::parse("router.home.arpa TXT v=spf1 -all").unwrap(),
+        ]);
+
+        let addresses = records.answer("router.home.arpa", TYPE_A).unwrap();
+        let txt = records.answer("router.home.arpa", TYPE_TXT).unwrap();
+        let alias = records.answer("nas.home.arpa", TYPE_CNAME).unwrap();
+
+        assert_eq!(addresses.len(), 2);
+        assert_eq!(addresses[0].rdata, [192, 168, 1, 1]);
+        assert_eq!(addresses[0].ttl, DEFAULT_LOCAL_TTL);
+        assert_eq!(addresses[1].ttl, 60);
+        assert_eq!(txt[0].rdata, b"\x0bv=spf1 -all");
+        assert_eq!(alias[0].rdata, b"\x06router\x04home\x04arpa\x00");
+        assert_eq!(
+            records.answer("router.home.arpa", TYPE_AAAA),
+            Some(Vec::new())
+        );
+        assert!(records.answer("home.arpa", TYPE_A).is_none());
+        assert!(LocalRecord::parse("router.home.arpa A").is_err());
+        assert!(LocalRecord::parse("router.home.arpa A 192.168.1").is_err());
+        assert!(LocalRecord::parse("router.home.arpa MX 10 mail").is_err());
+        assert!(LocalRecord::new("host.home.arpa", 300, "AAAA", "fd00::1").is_ok());
+    }
+
+    #[test]
+    fn cnames_answer_any_type_and_are_followed_through_local_records() {
+        let records = LocalRecords::new([
+            LocalRecord::parse("nas.home.arpa CNAME storage.home.arpa").unwrap(),
+            LocalRecord::parse("storage.home.arpa CNAME box.home.arpa").unwrap(),
+            LocalRecord::parse("box.home.arpa A 192.168.1.5").unwrap(),
+            LocalRecord::parse("ext.home.arpa CNAME example.com").unwrap(),
+            LocalRecord::parse("loop.home.arpa CNAME loop.home.arpa").unwrap(),
+        ]);
+
+        let chain = records.answer("nas.home.arpa", TYPE_A).unwrap();
+        let no_aaaa = records.answer("nas.home.arpa", TYPE_AAAA).unwrap();
+        let external = records.answer("ext.home.arpa", TYPE_A).unwrap();
+        let looped = records.answer("loop.home.arpa", TYPE_A).unwrap();
+
+        let types: Vec<u16> = chain.iter().map(|record| record.rtype).collect();
+        assert_eq!(types, [TYPE_CNAME, TYPE_CNAME, TYPE_A]);
+        assert_eq!(chain[2].rdata, [192, 168, 1, 5]);
+        assert_eq!(no_aaaa.len(), 2);
+        assert_eq!(external.len(), 1);
+        assert_eq!(external[0].rtype, TYPE_CNAME);
+        assert_eq!(looped.len(), MAX_CNAME_CHAIN);
+    }
+}
@@ -0,0 +1,1729 @@
+//! DNS query resolution logic.
+//!
+//! Handles the core query processing pipeline:
+//! 1. Filter (block ads/trackers)
+//! 2. Cache lookup
+//! 3. Decide whether to forward or return cached/blocked response
+//!
+//! Transports handle the actual I/O, resolver handles decisions.
+
+mod circuit_breaker;
+mod coalesce;
+mod divergence;
+mod forwarding;
+mod local_records;
+mod qname_min;
+mod ranking;
+mod rebinding;
+
+pub use circuit_breaker::{CircuitState, DEFAULT_CIRCUIT_FAILURES, UpstreamCircuitBreaker};
+pub use coalesce::{InFlight, Waiter};
+
+use coalesce::InFlightQueries;
+pub use divergence::{Divergence, DivergenceDetector};
+pub use forwarding::{ForwardRule, ForwardRules};
+pub use local_records::{DEFAULT_LOCAL_TTL, LocalRecord, LocalRecords};
+pub use qname_min::QnameMinimization;
+use qname_min::ZoneCuts;
+pub use ranking::{LATENCY_SMOOTHING, UpstreamRanker};
+
+use std::borrow::Cow;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::atomic::{AtomicU16, AtomicU64, Ordering};
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use std::time::{Duration, Instant};
+
+use crate::cache::{CacheEntryInfo, CacheHit, CacheStats, DnsCache};
+use crate::dns::{
+    CLASS_CH, DEFAULT_BLOCKED_TTL, DnsQuery, DnsResponse, EdnsClientSubnet, RData, TYPE_TXT,
+    set_client_subnet,
+};
+use crate::filter::{
+    BlockedResponseStyle, Blocklist, SuffixSet, check_cname_cloaking, filter_query,
+};
+use crate::logging;
+use crate::stats::{Stats, StatsSnapshot};
+use crate::transport::tcp::query_upstreams;
+use crate::transport::trace;
+use crate::upstream::{
+    DEFAULT_FAILOVER_TIMEOUT, DEFAULT_UPSTREAM_TIMEOUT, UpstreamHealth, UpstreamLimits,
+    UpstreamStrategy,
+};
+
+/// Default answer to CHAOS identification queries.
+pub const DEFAULT_CHAOS_ID: &str = concat!("detour/", env!("CARGO_PKG_VERSION"));
+
+/// CHAOS-class names answered with the server identity.
+const CHAOS_NAMES: &[&str] = &[
+    "version.bind",
+    "version.server",
+    "id.server",
+    "hostname.bind",
+];
+
+/// Transaction ID counter for queries built by [`Resolver::resolve`].
+static NEXT_QUERY_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Action to take for a DNS query.
+pub enum QueryAction {
+    /// Query is blocked, return this response immediately.
+    Blocked {
+        response: Vec<u8>,
+        domain: String,
+        qtype: u16,
+    },
+    /// Query was found in cache, return this response immediately.
+    Cached {
+        response: Vec<u8>,
+        domain: String,
+        qtype: u16,
+    },
+    /// Query was answered from an expired cache entry within the stale TTL.
+    ///
+    /// Return the response immediately, then refresh the entry in the
+    /// background with [`Resolver::refresh`]. Only one stale hit at a time
+    /// gets this action; others while it runs are plain [`QueryAction::Cached`].
+    CachedStale {
+        response: Vec<u8>,
+        domain: String,
+        qtype: u16,
+    },
+    /// Query was answered locally (e.g. NXDOMAIN for an unqualified name).
+    Local {
+        response: Vec<u8>,
+        domain: String,
+        qtype: u16,
+    },
+    /// Query should be forwarded to upstream.
+    ///
+    /// `traced` is set when the domain matches a `--trace-domain` suffix, so
+    /// transports can emit per-stage trace lines without re-matching. `rule`
+    /// is the forwarding rule whose upstreams replace the global ones.
+    /// Identical queries arriving meanwhile wait on `in_flight`, which must be
+    /// completed with the upstream answer.
+    Forward {
+        domain: String,
+        qtype: u16,
+        traced: bool,
+        rule: Option<Arc<ForwardRule>>,
+        in_flight: InFlight,
+    },
+    /// An identical query is already being forwarded; `answer` yields its
+    /// response, or None if that forward failed.
+    Coalesced {
+        domain: String,
+        qtype: u16,
+        traced: bool,
+        answer: Waiter,
+    },
+    /// Query could not be parsed.
+    Invalid,
+}
+
+/// Error returned by [`Resolver::resolve`] and [`Resolver::process_response`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResolveError {
+    /// The domain is not a valid DNS name.
+    InvalidName,
+    /// The domain is on the blocklist.
+    Blocked,
+    /// No upstream returned a response.
+    NoResponse,
+    /// A response was received but could not be parsed.
+    Malformed,
+    /// The response carried a non-zero RCODE (e.g. 3 = NXDOMAIN).
+    Rcode(u8),
+    /// The response answered a different question than the one asked.
+    QuestionMismatch,
+}
+
+impl fmt::Display for ResolveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolveError::InvalidName => write!(f, "invalid domain name"),
+            ResolveError::Blocked => write!(f, "domain is blocked"),
+            ResolveError::NoResponse => write!(f, "no upstream responded"),
+            ResolveError::Malformed => write!(f, "malformed response"),
+            ResolveError::Rcode(rcode) => write!(f, "upstream returned rcode {}", rcode),
+            ResolveError::QuestionMismatch => write!(f, "upstream answered another question"),
+        }
+    }
+}
+
+impl std::error::Error for ResolveError {}
+
+/// Resolver handles DNS query processing decisions.
+///
+/// Contains all shared logic between transports: filtering, caching decisions,
+/// upstream selection, etc. Transports call this to decide what to do with queries.
+pub struct Resolver {
+    /// Swapped wholesale on reload, so queries see either the old or the new list.
+    blocklist: Arc<RwLock<Blocklist>>,
+    cache: DnsCache,
+    stats: Stats,
+    trace_domains: SuffixSet,
+    /// Slow-query logging threshold in microseconds (0 = disabled).
+    slow_query_threshold_us: AtomicU64,
+    forward_unqualified: bool,
+    log_exclude: Arc<SuffixSet>,
+    divergence: Option<DivergenceDetector>,
+    chaos_id: Option<String>,
+    upstream_limits: UpstreamLimits,
+    upstream_health: UpstreamHealth,
+    circuit_breaker: UpstreamCircuitBreaker,
+    upstream_ranker: UpstreamRanker,
+    upstream_strategy: UpstreamStrategy,
+    /// Upstreams raced at first; the rest are a fallback tier (None = all).
+    race_limit: Option<usize>,
+    failover_timeout: Duration,
+    upstream_timeout: Duration,
+    /// Retries of a failed TCP exchange with one upstream.
+    upstream_retries: u32,
+    /// Prefix length of the client subnet sent upstream (None = no ECS).
+    ecs_prefix_len: Option<u8>,
+    blocked_style: BlockedResponseStyle,
+    blocked_ttl: u32,
+    rebinding_protection: bool,
+    qname_minimization: QnameMinimization,
+    zone_cuts: ZoneCuts,
+    forward_rules: ForwardRules,
+    local_records: RwLock<LocalRecords>,
+    in_flight: Arc<InFlightQueries>,
+}
+
+impl Resolver {
+    /// Create a new resolver with the given blocklist.
+    pub fn new(blocklist: Blocklist) -> Self {
+        Self {
+            blocklist: Arc::new(RwLock::new(blocklist)),
+            cache: DnsCache::new(),
+            stats: Stats::new(),
+            trace_domains: SuffixSet::default(),
+            slow_query_threshold_us: AtomicU64::new(0),
+            forward_unqualified: false,
+            log_exclude: Arc::default(),
+            divergence: None,
+            chaos_id: Some(DEFAULT_CHAOS_ID.to_string()),
+            upstream_limits: UpstreamLimits::default(),
+            upstream_health: UpstreamHealth::default(),
+            circuit_breaker: UpstreamCircuitBreaker::default(),
+            upstream_ranker: UpstreamRanker::default(),
+            upstream_strategy: UpstreamStrategy::default(),
+            race_limit: None,
+            failover_timeout: DEFAULT_FAILOVER_TIMEOUT,
+            upstream_timeout: DEFAULT_UPSTREAM_TIMEOUT,
+            upstream_retries: 0,
+            ecs_prefix_len: None,
+            blocked_style: BlockedResponseStyle::default(),
+            blocked_ttl: DEFAULT_BLOCKED_TTL,
+            rebinding_protection: false,
+            qname_minimization: QnameMinimization::Off,
+            zone_cuts: ZoneCuts::default(),
+            forward_rules: ForwardRules::default(),
+            local_records: RwLock::default(),
+            in_flight: Arc::default(),
+        }
+    }
+
+    /// Text returned for CHAOS `version.bind`-style queries (None = always REFUSED).
+    pub fn with_chaos_id(mut self, chaos_id: Option<String>) -> Self {
+        self.chaos_id = chaos_id;
+        self
+    }
+
+    /// Track wins and latency for `count` upstreams, indexed like the upstream list.
+    pub fn with_upstream_stats(mut self, count: usize) -> Self {
+        self.stats = std::mem::take(&mut self.stats).with_upstreams(count);
+        self
+    }
+
+    /// Count queries per domain, reported as the top domains in each stats snapshot.
+    pub fn with_domain_tracking(mut self, enabled: bool) -> Self {
+        self.stats = std::mem::take(&mut self.stats).with_domain_tracking(enabled);
+        self
+    }
+
+    /// How queries for blocked domains are answered.
+    pub fn with_blocked_response(mut self, style: BlockedResponseStyle) -> Self {
+        self.blocked_style = style;
+        self
+    }
+
+    /// TTL, in seconds, of the records in answers to blocked queries.
+    pub fn with_blocked_ttl(mut self, ttl: u32) -> Self {
+        self.blocked_ttl = ttl;
+        self
+    }
+
+    /// Answer NXDOMAIN instead of passing on upstream answers that give a
+    /// public name a private or loopback address.
+    pub fn with_rebinding_protection(mut self, enabled: bool) -> Self {
+        self.rebinding_protection = enabled;
+        self
+    }
+
+    /// Ask upstream about each ancestor of a name before forwarding it (RFC 7816).
+    pub fn with_qname_minimization(mut self, mode: QnameMinimization) -> Self {
+        self.qname_minimization = mode;
+        self
+    }
+
+    /// Whether and how names are minimized before forwarding (RFC 7816).
+    pub fn qname_minimization(&self) -> QnameMinimization {
+        self.qname_minimization
+    }
+
+    /// Send queries under these suffixes to their own upstreams, bypassing the cache.
+    pub fn with_forward_rules(mut self, rules: ForwardRules) -> Self {
+        self.forward_rules = rules;
+        self
+    }
+
+    /// Answer queries matching these records authoritatively, without
+    /// asking upstream.
+    pub fn with_local_records(mut self, records: LocalRecords) -> Self {
+        self.local_records = RwLock::new(records);
+        self
+    }
+
+    /// Cap outbound queries to individual upstreams.
+    pub fn with_upstream_limits(mut self, limits: UpstreamLimits) -> Self {
+        self.upstream_limits = limits;
+        self
+    }
+
+    /// How queries are spread over the upstreams; failover waits `failover_timeout`
+    /// for each upstream before trying the next.
+    pub fn with_upstream_strategy(
+        mut self,
+        strategy: UpstreamStrategy,
+        failover_timeout: Duration,
+    ) -> Self {
+        self.upstream_strategy = strategy;
+        self.failover_timeout = failover_timeout;
+        self
+    }
+
+    /// Race only the first `limit` upstreams, adding the rest if none of them
+    /// answers within the failover timeout (None = race all).
+    pub fn with_race_limit(mut self, limit: Option<usize>) -> Self {
+        self.race_limit = limit;
+        self
+    }
+
+    /// Give up on a raced upstream that has not answered over TCP within `timeout`.
+    pub fn with_upstream_timeout(mut self, timeout: Duration) -> Self {
+        self.upstream_timeout = timeout;
+        self
+    }
+
+    /// Retry a TCP exchange with an upstream that failed (connection
+    /// refused or reset, closed before answering) up to `retries` times,
+    /// with exponential backoff, within the upstream timeout.
+    pub fn with_upstream_retries(mut self, retries: u32) -> Self {
+        self.upstream_retries = retries;
+        self
+    }
+
+    /// Send forwarded queries an EDNS Client Subnet option (RFC 7871) with
+    /// the client's address cut to `prefix_len` bits.
+    pub fn with_ecs_prefix_len(mut self, prefix_len: Option<u8>) -> Self {
+        self.ecs_prefix_len = prefix_len;
+        self
+    }
+
+    /// How queries for blocked domains are answered.
+    pub fn blocked_style(&self) -> BlockedResponseStyle {
+        self.blocked_style
+    }
+
+    /// How queries are spread over the upstreams.
+    pub fn upstream_strategy(&self) -> UpstreamStrategy {
+        self.upstream_strategy
+    }
+
+    /// How many upstreams are raced before falling back to the rest (None = all).
+    pub fn race_limit(&self) -> Option<usize> {
+        self.race_limit
+    }
+
+    /// How long failover waits for one upstream before trying the next, and
+    /// a limited race waits before adding the fallback tier.
+    pub fn failover_timeout(&self) -> Duration {
+        self.failover_timeout
+    }
+
+    /// How long a raced upstream may take to answer over TCP.
+    pub fn upstream_timeout(&self) -> Duration {
+        self.upstream_timeout
+    }
+
+    /// How many times a failed TCP exchange with an upstream is retried.
+    pub fn upstream_retries(&self) -> u32 {
+        self.upstream_retries
+    }
+
+    /// Prefix length of the client subnet sent upstream, if ECS is on.
+    pub fn ecs_prefix_len(&self) -> Option<u8> {
+        self.ecs_prefix_len
+    }
+
+    /// The query to send upstream for `client`: with its subnet attached if
+    /// ECS is on, otherwise as the client sent it.
+    pub fn forwarded_query<'a>(&self, query: &'a [u8], client: IpAddr) -> Cow<'a, [u8]> {
+        let Some(prefix_len) = self.ecs_prefix_len else {
+            return Cow::Borrowed(query);
+        };
+        match set_client_subnet(query, &EdnsClientSubnet::new(client, prefix_len)) {
+            Some(query) => Cow::Owned(query),
+            None => Cow::Borrowed(query),
+        }
+    }
+
+    /// Leave upstreams failing their health probes out of queries.
+    pub fn with_upstream_health(mut self, health: UpstreamHealth) -> Self {
+        self.upstream_health = health;
+        self
+    }
+
+    /// Health of the upstreams, for the probes to update.
+    pub fn upstream_health(&self) -> &UpstreamHealth {
+        &self.upstream_health
+    }
+
+    /// Leave upstreams that keep failing queries out for a while.
+    pub fn with_circuit_breaker(mut self, breaker: UpstreamCircuitBreaker) -> Self {
+        self.circuit_breaker = breaker;
+        self
+    }
+
+    /// Race the upstreams that have been answering fastest first.
+    pub fn with_upstream_ranker(mut self, ranker: UpstreamRanker) -> Self {
+        self.upstream_ranker = ranker;
+        self
+    }
+
+    /// `upstreams` in the order they are raced: as given, or by latency if
+    /// ranking is on.
+    pub fn rank_upstreams<'a>(&self, upstreams: &'a [SocketAddr]) -> Cow<'a, [SocketAddr]> {
+        self.upstream_ranker.rank(upstreams)
+    }
+
+    /// Record that `upstream` answered a forwarded query, closing its circuit.
+    pub fn record_upstream_success(&self, upstream: SocketAddr) {
+        if self.circuit_breaker.record_success(upstream).is_some() {
+            logging::info(format_args!("Upstream {} is answering again", upstream));
+        }
+    }
+
+    /// Record that `upstream` failed to answer a forwarded query in time,
+    /// opening its circuit after too many failures in a row.
+    pub fn record_upstream_failure(&self, upstream: SocketAddr) {
+        if self.circuit_breaker.record_failure(upstream).is_some() {
+            logging::warn(format_args!(
+                "Upstream {} failed {} queries in a row, leaving it out for {:?}",
+                upstream,
+                self.circuit_breaker.threshold(),
+                self.circuit_breaker.backoff()
+            ));
+        }
+    }
+
+    /// Check whether `upstream` may take part in this query's race: it is
+    /// healthy, its circuit is not open and it is within its rate limit
+    /// (consumes its budget).
+    #[inline]
+    pub fn upstream_allowed(&self, upstream: SocketAddr) -> bool {
+        if !self.upstream_health.allow(upstream) || !self.circuit_breaker.allow(upstream) {
+            return false;
+        }
+        if self.upstream_limits.allow(upstream) {
+            return true;
+        }
+        self.stats.record_throttled(upstream);
+        false
+    }
+
+    /// The upstreams that are healthy, not shut out by their circuit and
+    /// within their rate limit for this query.
+    pub fn allowed_upstreams(&self, upstreams: &[SocketAddr]) -> Vec<SocketAddr> {
+        if self.upstream_limits.is_empty()
+            && self.upstream_health.is_empty()
+            && self.circuit_breaker.is_empty()
+        {
+            return upstreams.to_vec();
+        }
+        upstreams
+            .iter()
+            .copied()
+            .filter(|&upstream| self.upstream_allowed(upstream))
+            .collect()
+    }
+
+    /// Build a SERVFAIL answer for a raw query (e.g. when every upstream is throttled).
+    pub fn servfail(&self, query: &[u8]) -> Option<Vec<u8>> {
+        DnsQuery::parse(query).map(|q| q.servfail_response().to_bytes())
+    }
+
+    /// Use a pre-populated cache (e.g. one restored from disk).
+    ///
+    /// Call before the other cache settings, which apply to the cache in place.
+    pub fn with_cache(mut self, cache: DnsCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Limit the cache to `max` entries, evicting least recently used ones (0 = unbounded).
+    pub fn with_cache_size(mut self, max: usize) -> Self {
+        self.cache = self.cache.with_max_entries(max);
+        self
+    }
+
+    /// Split the cache into `count` independently locked shards.
+    pub fn with_cache_shards(mut self, count: usize) -> Self {
+        self.cache = self.cache.with_shards(count);
+        self
+    }
+
+    /// Serve expired entries for up to `stale_ttl` while they are refreshed (0 = never).
+    pub fn with_stale_ttl(mut self, stale_ttl: Duration) -> Self {
+        self.cache = self.cache.with_stale_ttl(stale_ttl);
+        self
+    }
+
+    /// Answer queries no upstream answered from entries expired up to
+    /// `max_stale` ago (0 = never; see [`Self::stale_answer`]).
+    pub fn with_serve_stale(mut self, max_stale: Duration) -> Self {
+        self.cache = self.cache.with_serve_stale(max_stale);
+        self
+    }
+
+    /// Pin cache entries for domains matching these suffixes (see [`Self::refresh_pinned`]).
+    pub fn with_pinned_domains(mut self, pinned: SuffixSet) -> Self {
+        self.cache = self.cache.with_pinned(pinned);
+        self
+    }
+
+    /// Compare racing upstream answers for one in every `sample_every` forwarded queries.
+    pub fn with_divergence_detection(mut self, sample_every: Option<u64>) -> Self {
+        self.divergence = sample_every.map(DivergenceDetector::new);
+        self
+    }
+
+    /// Returns true if this forwarded query should have its losing answers compared.
+    #[inline]
+    pub fn should_check_divergence(&self) -> bool {
+        self.divergence.as_ref().is_some_and(|d| d.should_sample())
+    }
+
+    /// Compare a losing upstream's answer with the winner's and record any divergence.
+    ///
+    /// Both upstreams are counted: a hijacked resolver disagrees with every
+    /// other one, so its count stands out in the per-upstream stats.
+    pub fn check_divergence(
+        &self,
+        winner: SocketAddr,
+        winner_response: &[u8],
+        other: SocketAddr,
+        other_response: &[u8],
+    ) -> Option<Divergence> {
+        let divergence = divergence::compare(winner_response, other_response)?;
+        self.stats.record_divergence(winner);
+        self.stats.record_divergence(other);
+        Some(divergence)
+    }
+
+    /// Never log queries for domains matching these suffixes (they are still resolved).
+    pub fn with_log_exclusions(mut self, log_exclude: SuffixSet) -> Self {
+        self.log_exclude = Arc::new(log_exclude);
+        self
+    }
+
+    /// Domains excluded from logging, for transports to hand to their `QueryLogger`.
+    pub fn log_exclusions(&self) -> Arc<SuffixSet> {
+        self.log_exclude.clone()
+    }
+
+    /// Forward single-label names upstream instead of answering NXDOMAIN locally.
+    pub fn with_forward_unqualified(mut self, forward_unqualified: bool) -> Self {
+        self.forward_unqualified = forward_unqualified;
+        self
+    }
+
+    /// Emit `[trace]` logs at every stage for domains matching these suffixes.
+    pub fn with_trace_domains(mut self, trace_domains: SuffixSet) -> Self {
+        self.trace_domains = trace_domains;
+        self
+    }
+
+    /// Log queries whose total handling time exceeds `threshold`, regardless of verbosity.
+    pub fn with_slow_query_threshold(self, threshold: Option<Duration>) -> Self {
+        self.set_slow_query_threshold(threshold);
+        self
+    }
+
+    /// Change the slow-query threshold at runtime (`None` disables slow-query logging).
+    pub fn set_slow_query_threshold(&self, threshold: Option<Duration>) {
+        let us = threshold.map_or(0, |t| t.as_micros().max(1) as u64);
+        self.slow_query_threshold_us.store(us, Ordering::Relaxed);
+    }
+
+    /// Check whether a query that took `elapsed_ms` in total should be logged as slow.
+    #[inline]
+    pub fn is_slow(&self, elapsed_ms: f64) -> bool {
+        let threshold_us = self.slow_query_threshold_us.load(Ordering::Relaxed);
+        threshold_us > 0 && elapsed_ms * 1000.0 > threshold_us as f64
+    }
+
+    /// Process a DNS query and decide what action to take.
+    ///
+    /// This is the main entry point for transports. Call this with the raw
+    /// DNS query (without TCP length prefix) to get the action to take.
+    pub fn process_query(&self, data: &[u8]) -> QueryAction {
+        let Some(query) = DnsQuery::parse(data) else {
+            return QueryAction::Invalid;
+        };
+
+        let domain = query.domain.clone();
+        if !self.log_exclude.matches(&domain) {
+            self.stats.record_domain(&domain);
+        }
+        let traced = self.is_traced(&domain);
+        if traced {
+            trace(
+                &domain,
+                format_args!(
+                    "parsed id={} qtype={} qclass={}",
+                    query.id, query.qtype, query.qclass
+                ),
+            );
+        }
+
+        // CHAOS-class queries are never forwarded: identify ourselves or refuse
+        if query.qclass == CLASS_CH {
+            let response = match &self.chaos_id {
+                Some(id) if query.qtype == TYPE_TXT && CHAOS_NAMES.contains(&domain.as_str()) => {
+                    DnsResponse::chaos_txt(&query, id)
+                }
+                _ => query.refused_response(),
+            };
+            return QueryAction::Local {
+                response: response.to_bytes(),
+                domain,
+                qtype: query.qtype,
+            };
+        }
+
+        // Single-label names (`printer`, `wpad`) only leak internal hostnames
+        // upstream, unless local records answer them
+        if !self.forward_unqualified
+            && !domain.contains('.')
+            && domain != "localhost"
+            && !self.local_records().contains(&domain)
+        {
+            self.stats.record_unqualified();
+            if traced {
+                trace(
+                    &domain,
+                    format_args!("unqualified name, answering NXDOMAIN"),
+                );
+            }
+            return QueryAction::Local {
+                response: query.nxdomain_response().to_bytes(),
+                domain,
+                qtype: query.qtype,
+            };
+        }
+
+        // Step 1: Check blocklist
+        let blocklist = self.blocklist();
+        if let Some(blocked_response) =
+            filter_query(&blocklist, &query, self.blocked_style, self.blocked_ttl)
+        {
+            if traced {
+                let rule = blocklist.matched_entry(&domain).unwrap_or_default();
+                trace(&domain, format_args!("blocklist: blocked by rule {}", rule));
+            }
+            return QueryAction::Blocked {
+                response: blocked_response,
+                domain,
+                qtype: query.qtype,
+            };
+        }
+        drop(blocklist);
+        if traced {
+            trace(&domain, format_args!("blocklist: not blocked"));
+        }
+
+        // Local records take precedence over forwarding rules and the cache
+        if let Some(records) = self.local_records().answer(&domain, query.qtype) {
+            if traced {
+                trace(
+                    &domain,
+                    format_args!("local record, answering authoritatively"),
+                );
+            }
+            return QueryAction::Local {
+                response: DnsResponse::authoritative(&query, records).to_bytes(),
+                domain,
+                qtype: query.qtype,
+            };
+        }
+
+        if let Some(rule) = self.forward_rules.matching(&domain) {
+            if traced {
+                let upstream_strs: Vec<_> = rule.upstreams.iter().map(|a| a.to_string()).collect();
+                trace(
+                    &domain,
+                    format_args!(
+                        "forwarding rule {} matched, bypassing cache for {}",
+                        rule.suffix,
+                        upstream_strs.join(", ")
+                    ),
+                );
+            }
+            return self.forward(domain, query.qtype, traced, Some(rule.clone()));
+        }
+
+        // Step 2: Check cache
+        match self.cache.lookup(&query) {
+            Some(CacheHit::Fresh(cached_response)) => {
+                if traced {
+                    let remaining = self.cache.remaining_ttl(&query).unwrap_or_default();
+                    trace(
+                        &domain,
+                        format_args!("cache: hit, {}s remaining", remaining.as_secs()),
+                    );
+                }
+                if DnsResponse::is_negative(&cached_response) {
+                    self.stats.record_negative();
+                }
+                return QueryAction::Cached {
+                    response: cached_response,
+                    domain,
+                    qtype: query.qtype,
+                };
+            }
+            Some(CacheHit::Stale { response, refresh }) => {
+                if traced {
+                    let action = if refresh {
+                        "refreshing"
+                    } else {
+                        "refresh in progress"
+                    };
+                    trace(&domain, format_args!("cache: stale hit, {}", action));
+                }
+                let qtype = query.qtype;
+                return if refresh {
+                    QueryAction::CachedStale {
+                        response,
+                        domain,
+                        qtype,
+                    }
+                } else {
+                    QueryAction::Cached {
+                        response,
+                        domain,
+                        qtype,
+                    }
+                };
+            }
+            None => (),
+        }
+        if traced {
+            trace(&domain, format_args!("cache: miss"));
+        }
+
+        // Step 3: Forward to upstream
+        self.forward(domain, query.qtype, traced, None)
+    }
+
+    /// Forward a query, or wait for an identical one already in flight.
+    fn forward(
+        &self,
+        domain: String,
+        qtype: u16,
+        traced: bool,
+        rule: Option<Arc<ForwardRule>>,
+    ) -> QueryAction {
+        match self.in_flight.join(qtype, &domain) {
+            Ok(in_flight) => QueryAction::Forward {
+                domain,
+                qtype,
+                traced,
+                rule,
+                in_flight,
+            },
+            Err(answer) => {
+                self.stats.record_coalesced();
+                if traced {
+                    trace(&domain, format_args!("coalesced with a forward in flight"));
+                }
+                QueryAction::Coalesced {
+                    domain,
+                    qtype,
+                    traced,
+                    answer,
+                }
+            }
+        }
+    }
+
+    /// Describe an upstream response for trace output: rcode, answers and
+    /// how long it is now cached for. Call after [`Self::process_response`].
+    pub fn describe_response(&self, response: &[u8]) -> String {
+        let Some(parsed) = DnsResponse::parse(response) else {
+            return "unparseable response".to_string();
+        };
+        let answers: Vec<String> = parsed
+            .answers
+            .iter()
+            .map(|a| a.data().to_string())
+            .collect();
+        let cached = DnsQuery::parse(response)
+            .and_then(|query| self.cache.remaining_ttl(&query))
+            .map(|ttl| format!("cached for {}s", ttl.as_secs()))
+            .unwrap_or_else(|| "not cached".to_string());
+        format!(
+            "rcode={} answers=[{}] {}",
+            parsed.rcode(),
+            answers.join(", "),
+            cached
+        )
+    }
+
+    /// Resolve a domain through the full pipeline without any client socket.
+    ///
+    /// Builds a query, applies the blocklist and cache exactly like the proxy
+    /// path, races `upstreams` over TCP on a miss and caches the result. A
+    /// stale hit is refreshed first and only answered as is if that fails.
+    /// Returns the decoded answer records.
+    pub async fn resolve(
+        &self,
+        domain: &str,
+        qtype: u16,
+        upstreams: &[SocketAddr],
+    ) -> Result<Vec<RData>, ResolveError> {
+        let start_time = Instant::now();
+        let id = NEXT_QUERY_ID.fetch_add(1, Ordering::Relaxed);
+        let query = DnsQuery::new(id, domain.trim_end_matches('.'), qtype);
+        let data = query.to_bytes().ok_or(ResolveError::InvalidName)?;
+
+        let response = match self.process_query(&data) {
+            QueryAction::Invalid => return Err(ResolveError::InvalidName),
+            QueryAction::Blocked { .. } => {
+                self.record_blocked(start_time.elapsed().as_secs_f64() * 1000.0);
+                return Err(ResolveError::Blocked);
+            }
+            QueryAction::Cached { response, .. } => {
+                self.record_cached(start_time.elapsed().as_secs_f64() * 1000.0);
+                response
+            }
+            QueryAction::CachedStale { response, .. } => {
+                self.record_cached(start_time.elapsed().as_secs_f64() * 1000.0);
+                self.refresh(&query.domain, qtype, upstreams)
+                    .await
+                    .unwrap_or(response)
+            }
+            QueryAction::Local { response, .. } => {
+                self.record_local(start_time.elapsed().as_secs_f64() * 1000.0);
+                response
+            }
+            QueryAction::Forward {
+                rule, in_flight, ..
+            } => {
+                let upstreams = match &rule {
+                    Some(rule) => self.allowed_upstreams(&rule.upstreams),
+                    None => self.allowed_upstreams(upstreams),
+                };
+                let Some((response, ..)) = query_upstreams(&data, &upstreams, self).await else {
+                    self.record_failed();
+                    return Err(ResolveError::NoResponse);
+                };
+                let response = match self.process_response(&query, &response) {
+                    Ok(replaced) => replaced.unwrap_or(response),
+                    Err(e) => {
+                        self.record_failed();
+                        return Err(e);
+                    }
+                };
+                in_flight.complete(&response);
+                self.record_forwarded(start_time.elapsed().as_secs_f64() * 1000.0);
+                response
+            }
+            QueryAction::Coalesced { answer, .. } => {
+                let Some(response) = answer.response(id).await else {
+                    self.record_failed();
+                    return Err(ResolveError::NoResponse);
+                };
+                self.record_forwarded(start_time.elapsed().as_secs_f64() * 1000.0);
+                response
+            }
+        };
+
+        let parsed = DnsResponse::parse(&response).ok_or(ResolveError::Malformed)?;
+        match parsed.rcode() {
+            0 => Ok(parsed.answers.iter().map(|a| a.data()).collect()),
+            rcode => Err(ResolveError::Rcode(rcode)),
+        }
+    }
+
+    /// Re-resolve pinned cache entries that expire within `lead`, bypassing the cache.
+    ///
+    /// Returns the number of entries refreshed.
+    pub async fn refresh_pinned(&self, lead: Duration, upstreams: &[SocketAddr]) -> usize {
+        let mut refreshed = 0;
+        for (qtype, domain) in self.cache.pinned_expiring(lead) {
+            if self.refresh(&domain, qtype, upstreams).await.is_some() {
+                refreshed += 1;
+            }
+        }
+        refreshed
+    }
+
+    /// Re-resolve `domain` upstream and replace its cache entry, bypassing the cache.
+    ///
+    /// Returns the new answer, or `None` if no upstream gave an acceptable
+    /// one. Either way a later stale hit on the entry may refresh it again.
+    pub async fn refresh(
+        &self,
+        domain: &str,
+        qtype: u16,
+        upstreams: &[SocketAddr],
+    ) -> Option<Vec<u8>> {
+        let id = NEXT_QUERY_ID.fetch_add(1, Ordering::Relaxed);
+        let query = DnsQuery::new(id, domain, qtype);
+        let data = query.to_bytes()?;
+        let upstreams = self.allowed_upstreams(upstreams);
+        let answer = match query_upstreams(&data, &upstreams, self).await {
+            Some((response, ..)) => self
+                .process_response(&query, &response)
+                .ok()
+                .map(|replaced| replaced.unwrap_or(response)),
+            None => None,
+        };
+        self.cache.end_refresh(&query);
+        answer
+    }
+
+    /// Called when we receive a response from upstream to `question`.
+    ///
+    /// Caches the response, unless a forwarding rule matches. A response
+    /// whose question section differs from `question` is counted and
+    /// rejected with [`ResolveError::QuestionMismatch`]: it must be neither
+    /// cached nor sent, or an upstream could answer one name with records
+    /// for another. Responses without a readable question are passed
+    /// through uncached. If the answer's CNAME chain leads to a blocked domain (see
+    /// [`check_cname_cloaking`]), or rebinding protection is on and the
+    /// answer gives a public name a local address, it is not cached and the
+    /// NXDOMAIN answer to send instead is returned. Names under a forwarding
+    /// rule are exempt from rebinding protection, as their upstreams are
+    /// usually local resolvers.
+    pub fn process_response(
+        &self,
+        question: &DnsQuery,
+        response: &[u8],
+    ) -> Result<Option<Vec<u8>>, ResolveError> {
+        let Some(query) = DnsQuery::parse(response) else {
+            return Ok(None);
+        };
+        if !query.same_question(question) {
+            self.stats.record_mismatched();
+            return Err(ResolveError::QuestionMismatch);
+        }
+        if let Some(blocked) = check_cname_cloaking(&self.blocklist(), response, self.blocked_ttl) {
+            self.trace_response(&query.domain, "CNAME chain leads to a blocked domain");
+            return Ok(Some(blocked));
+        }
+        let forwarded_by_rule = self.forward_rules.matching(&query.domain).is_some();
+        if self.rebinding_protection
+            && !forwarded_by_rule
+            && rebinding::is_rebinding(&query.domain, response)
+        {
+            self.stats.record_rebinding_blocked();
+            self.trace_response(
+                &query.domain,
+                "answer points a public name at a local address",
+            );
+            let nxdomain =
+                DnsResponse::blocked(&query, BlockedResponseStyle::Nxdomain, self.blocked_ttl);
+            return Ok(Some(nxdomain.to_bytes()));
+        }
+        if !forwarded_by_rule {
+            self.cache.put(&query, response);
+        }
+        Ok(None)
+    }
+
+    /// Minimise a query about to be forwarded to `upstreams`: ask them about
+    /// each ancestor of its name not yet known to exist (see [`qname_min`]).
+    ///
+    /// Returns the NXDOMAIN answer to send instead of forwarding if an
+    /// ancestor does not exist, None to forward the query as usual.
+    pub async fn minimize_qname(&self, query: &[u8], upstreams: &[SocketAddr]) -> Option<Vec<u8>> {
+        if self.qname_minimization == QnameMinimization::Off {
+            return None;
+        }
+        let query = DnsQuery::parse(query)?;
+        let missing = qname_min::probe(&query.domain, &self.zone_cuts, |probe| async move {
+            query_upstreams(&probe, upstreams, self)
+                .await
+                .map(|(response, ..)| response)
+        })
+        .await?;
+        if self.is_traced(&query.domain) {
+            trace(
+                &query.domain,
+                format_args!(
+                    "qname minimisation: {} does not exist, answering NXDOMAIN",
+                    missing
+                ),
+            );
+        }
+        Some(query.nxdomain_response().to_bytes())
+    }
+
+    /// Trace an upstream answer that was replaced with NXDOMAIN.
+    fn trace_response(&self, domain: &str, reason: &str) {
+        if self.is_traced(domain) {
+            trace(domain, format_args!("{}, answering NXDOMAIN", reason));
+        }
+    }
+
+    /// An expired cache entry to answer a query no upstream answered, if
+    /// serve-stale allows one (RFC 8767); counted in the stats.
+    pub fn stale_answer(&self, query: &DnsQuery) -> Option<Vec<u8>> {
+        let response = self.cache.get_stale(query)?;
+        self.stats.record_stale();
+        if self.is_traced(&query.domain) {
+            trace(
+                &query.domain,
+                format_args!("no upstream answered, serving a stale cache entry"),
+            );
+        }
+        Some(response)
+    }
+
+    /// Check if a domain matches `--trace-domain` and is not excluded from logging.
+    fn is_traced(&self, domain: &str) -> bool {
+        self.trace_domains.matches(domain) && !self.log_exclude.matches(domain)
+    }
+
+    /// Returns the number of domains in the blocklist.
+    pub fn blocked_count(&self) -> usize {
+        self.blocklist().len()
+    }
+
+    /// Replace the blocklist; queries in flight finish against the old one.
+    pub fn replace_blocklist(&self, blocklist: Blocklist) {
+        *self
+            .blocklist
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = blocklist;
+    }
+
+    fn blocklist(&self) -> RwLockReadGuard<'_, Blocklist> {
+        self.blocklist
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the local records, dropping the cached answers of every name
+    /// whose records changed. Returns the number of changed names.
+    pub fn replace_local_records(&self, records: LocalRecords) -> usize {
+        let mut current = self
+            .local_records
+            .write()
+            .unwrap_or_else(PoisonError::into_inner);
+        let changed = current.changed_names(&records);
+        *current = records;
+        drop(current);
+        for name in &changed {
+            self.cache.remove_name(name);
+        }
+        changed.len()
+    }
+
+    fn local_records(&self) -> RwLockReadGuard<'_, LocalRecords> {
+        self.local_records
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Write the cache to `path` (see [`DnsCache::save_to_file`]).
+    pub fn save_cache(&self, path: &Path) -> io::Result<()> {
+        self.cache.save_to_file(path)
+    }
+
+    /// Returns the number of entries in the cache.
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    /// Cache size and how many entries were evicted to make room.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Every cached response, sorted by name and type.
+    pub fn cache_entries(&self) -> Vec<CacheEntryInfo> {
+        self.cache.entries()
+    }
+
+    /// Evict the cached response for `domain` and `qtype`, returning whether
+    /// there was one.
+    pub fn remove_cached(&self, domain: &str, qtype: u16) -> bool {
+        self.cache.remove(domain, qtype)
+    }
+
+    /// Evict every cached response, returning how many there were.
+    pub fn clear_cache(&self) -> usize {
+        self.cache.clear()
+    }
+
+    /// Evict expired cached responses, returning how many there were.
+    pub fn sweep_cache(&self) -> usize {
+        self.cache.sweep_expired()
+    }
+
+    /// Record a forwarded request with response time.
+    pub fn record_forwarded(&self, response_time_ms: f64) {
+        self.stats.record_forwarded(response_time_ms);
+    }
+
+    /// Record a cached response with response time.
+    pub fn record_cached(&self, response_time_ms: f64) {
+        self.stats.record_cached(response_time_ms);
+    }
+
+    /// Record a blocked request with response time.
+    pub fn record_blocked(&self, response_time_ms: f64) {
+        self.stats.record_blocked(response_time_ms);
+    }
+
+    /// Record a locally answered request with response time.
+    pub fn record_local(&self, response_time_ms: f64) {
+        self.stats.record_local(response_time_ms);
+    }
+
+    /// Record a forwarded query that no upstream answered.
+    pub fn record_failed(&self) {
+        self.stats.record_failed();
+    }
+
+    /// Record a forwarded query that timed out waiting for an upstream.
+    pub fn record_timeout(&self) {
+        self.stats.record_timeout();
+    }
+
+    /// Record a TCP connection closed because too many clients were connected.
+    pub fn record_tcp_rejected(&self) {
+        self.stats.record_tcp_rejected();
+    }
+
+    /// Record a UDP query dropped because its client was over the rate limit.
+    pub fn record_rate_limited(&self) {
+        self.stats.record_rate_limited();
+    }
+
+    /// Record an upstream response that arrived from an unexpected address.
+    pub fn record_spoofed(&self) {
+        self.stats.record_spoofed();
+    }
+
+    /// Record that the upstream at `idx` answered first, after `latency_ms`.
+    pub fn record_upstream_win(&self, idx: usize, latency_ms: f64) {
+        self.stats.record_upstream_win(idx, latency_ms);
+        self.upstream_ranker.record_win(idx, latency_ms);
+    }
+
+    /// Record failover moving on to the next upstream, or a limited race
+    /// adding its fallback tier.
+    pub fn record_fallback(&self) {
+        self.stats.record_fallback();
+    }
+
+    /// Adjust the number of queries awaiting an upstream answer by `delta`.
+    pub fn add_pending(&self, delta: isize) {
+        self.stats.add_pending(delta);
+    }
+
+    /// The live stats, e.g. for a metrics endpoint to read.
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Get a snapshot of current stats and reset counters.
+    pub fn stats_snapshot_and_reset(&self) -> StatsSnapshot {
+        self.stats.snapshot_and_reset()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{TYPE_A, TYPE_AAAA, TYPE_CNAME};
+    use std::net::{Ipv4Addr, Ipv6Addr};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    /// Spawn a TCP upstream that answers every query with a single A record.
+    async fn mock_upstream(addr: Ipv4Addr) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut len = [0u8; 2];
+                stream.read_exact(&mut len).await.unwrap();
+                let mut query = vec![0u8; u16::from_be_bytes(len) as usize];
+                stream.read_exact(&mut query).await.unwrap();
+
+                let mut response = query;
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+                response.extend_from_slice(&[0, 0, 1, 44, 0x00, 0x04]);
+                response.extend_from_slice(&addr.octets());
+
+                let len = (response.len() as u16).to_be_bytes();
+                stream.write_all(&len).await.unwrap();
+                stream.write_all(&response).await.unwrap();
+            }
+        });
+        local
+    }
+
+    #[test]
+    fn process_query_flags_traced_domains() {
+        let resolver =
+            Resolver::new(Blocklist::new()).with_trace_domains(SuffixSet::new(["example.com"]));
+        let traced = DnsQuery::new(1, "www.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let untraced = DnsQuery::new(2, "example.org", TYPE_A).to_bytes().unwrap();
+
+        let traced_action = resolver.process_query(&traced);
+        let untraced_action = resolver.process_query(&untraced);
+
+        assert!(matches!(
+            traced_action,
+            QueryAction::Forward { traced: true, .. }
+        ));
+        assert!(matches!(
+            untraced_action,
+            QueryAction::Forward { traced: false, .. }
+        ));
+    }
+
+    #[test]
+    fn log_excluded_domains_are_left_out_of_top_domains() {
+        let resolver = Resolver::new(Blocklist::new())
+            .with_domain_tracking(true)
+            .with_log_exclusions(SuffixSet::new(["private.example.com"]));
+        let excluded = DnsQuery::new(1, "a.private.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let sibling = DnsQuery::new(2, "public.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        resolver.process_query(&excluded);
+        resolver.process_query(&sibling);
+
+        let top = resolver.stats().top_domains(10);
+        assert_eq!(top.len(), 1);
+        assert_eq!(top[0].0, "public.example.com");
+    }
+
+    #[test]
+    fn process_query_never_traces_log_excluded_domains() {
+        let resolver = Resolver::new(Blocklist::new())
+            .with_trace_domains(SuffixSet::new(["example.com"]))
+            .with_log_exclusions(SuffixSet::new(["private.example.com"]));
+        let excluded = DnsQuery::new(1, "a.private.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let sibling = DnsQuery::new(2, "public.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        let excluded_action = resolver.process_query(&excluded);
+        let sibling_action = resolver.process_query(&sibling);
+
+        assert!(matches!(
+            excluded_action,
+            QueryAction::Forward { traced: false, .. }
+        ));
+        assert!(matches!(
+            sibling_action,
+            QueryAction::Forward { traced: true, .. }
+        ));
+        assert!(resolver.log_exclusions().matches("a.private.example.com"));
+    }
+
+    fn chaos_query(domain: &str, qtype: u16) -> Vec<u8> {
+        let mut query = DnsQuery::new(0x4242, domain, qtype);
+        query.qclass = CLASS_CH;
+        query.to_bytes().unwrap()
+    }
+
+    fn local_response(action: QueryAction) -> DnsResponse {
+        let QueryAction::Local { response, .. } = action else {
+            panic!("expected local answer");
+        };
+        DnsResponse::parse(&response).unwrap()
+    }
+
+    #[test]
+    fn process_query_answers_chaos_version_bind() {
+        let resolver = Resolver::new(Blocklist::new());
+
+        let response =
+            local_response(resolver.process_query(&chaos_query("version.bind", TYPE_TXT)));
+
+        assert_eq!(response.id, 0x4242);
+        assert_eq!(response.rcode(), 0);
+        assert_eq!(response.questions[0].qclass, CLASS_CH);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].rtype, TYPE_TXT);
+        assert_eq!(response.answers[0].class, CLASS_CH);
+        assert_eq!(
+            response.answers[0].rdata[0] as usize,
+            DEFAULT_CHAOS_ID.len()
+        );
+        assert_eq!(&response.answers[0].rdata[1..], DEFAULT_CHAOS_ID.as_bytes());
+    }
+
+    #[test]
+    fn local_records_are_answered_authoritatively() {
+        let resolver = Resolver::new(Blocklist::empty()).with_local_records(LocalRecords::new([
+            LocalRecord::parse("router.home.arpa 3600 A 192.168.1.1").unwrap(),
+        ]));
+        let query = DnsQuery::new(8, "router.home.arpa", TYPE_A);
+
+        let response = local_response(resolver.process_query(&query.to_bytes().unwrap()));
+        let other_type = local_response(
+            resolver.process_query(
+                &DnsQuery::new(8, "router.home.arpa", TYPE_AAAA)
+                    .to_bytes()
+                    .unwrap(),
+            ),
+        );
+
+        assert_eq!(response.id, 8);
+        assert_eq!(response.flags & 0x0400, 0x0400);
+        assert_eq!(response.rcode(), 0);
+        assert_eq!(response.answers.len(), 1);
+        assert_eq!(response.answers[0].ttl, 3600);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(192, 168, 1, 1))
+        );
+        assert_eq!(other_type.flags & 0x0400, 0x0400);
+        assert_eq!(other_type.rcode(), 0);
+        assert!(other_type.answers.is_empty());
+    }
+
+    #[test]
+    fn replacing_local_records_adds_changes_and_removes_names() {
+        let resolver = Resolver::new(Blocklist::empty()).with_local_records(LocalRecords::new([
+            LocalRecord::parse("router.home.arpa A 192.168.1.1").unwrap(),
+            LocalRecord::parse("old.home.arpa A 192.168.1.2").unwrap(),
+            LocalRecord::parse("same.home.arpa A 192.168.1.3").unwrap(),
+        ]));
+        let query = |id, name| DnsQuery::new(id, name, TYPE_A).to_bytes().unwrap();
+        let forwarded = DnsQuery::new(1, "nas.home.arpa", TYPE_A);
+        let forwarded_answer = forwarded
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+        resolver
+            .process_response(&forwarded, &forwarded_answer)
+            .unwrap();
+
+        let changed = resolver.replace_local_records(LocalRecords::new([
+            LocalRecord::parse("router.home.arpa A 192.168.1.10").unwrap(),
+            LocalRecord::parse("nas.home.arpa A 192.168.1.20").unwrap(),
+            LocalRecord::parse("same.home.arpa A 192.168.1.3").unwrap(),
+        ]));
+        let router = local_response(resolver.process_query(&query(2, "router.home.arpa")));
+        let nas = local_response(resolver.process_query(&query(3, "nas.home.arpa")));
+        let removed = resolver.process_query(&query(4, "old.home.arpa"));
+
+        assert_eq!(changed, 3);
+        assert_eq!(resolver.cache_len(), 0);
+        assert_eq!(
+            router.answers[0].data(),
+            RData::A(Ipv4Addr::new(192, 168, 1, 10))
+        );
+        assert_eq!(
+            nas.answers[0].data(),
+            RData::A(Ipv4Addr::new(192, 168, 1, 20))
+        );
+        assert!(matches!(removed, QueryAction::Forward { .. }));
+    }
+
+    #[test]
+    fn process_query_uses_custom_chaos_id() {
+        let resolver = Resolver::new(Blocklist::new()).with_chaos_id(Some("edge-1".to_string()));
+
+        let response = local_response(resolver.process_query(&chaos_query("id.server", TYPE_TXT)));
+
+        assert_eq!(response.answers[0].rdata, b"\x06edge-1".to_vec());
+    }
+
+    #[test]
+    fn process_query_refuses_other_chaos_queries() {
+        let enabled = Resolver::new(Blocklist::new());
+        let disabled = Resolver::new(Blocklist::new()).with_chaos_id(None);
+
+        let other_name =
+            local_response(enabled.process_query(&chaos_query("authors.bind", TYPE_TXT)));
+        let other_type =
+            local_response(enabled.process_query(&chaos_query("version.bind", TYPE_A)));
+        let disabled =
+            local_response(disabled.process_query(&chaos_query("version.bind", TYPE_TXT)));
+
+        for response in [other_name, other_type, disabled] {
+            assert_eq!(response.rcode(), 5);
+            assert!(response.answers.is_empty());
+        }
+    }
+
+    #[test]
+    fn process_query_answers_unqualified_names_locally() {
+        let resolver = Resolver::new(Blocklist::new());
+        let query = DnsQuery::new(9, "printer", TYPE_A).to_bytes().unwrap();
+
+        let response = local_response(resolver.process_query(&query));
+
+        assert_eq!(response.id, 9);
+        assert_eq!(response.rcode(), 3);
+        assert!(response.answers.is_empty());
+        assert_eq!(resolver.stats_snapshot_and_reset().unqualified, 1);
+    }
+
+    #[test]
+    fn local_records_answer_unqualified_names() {
+        let resolver = Resolver::new(Blocklist::new()).with_local_records(LocalRecords::new([
+            LocalRecord::parse("printer A 192.168.1.9").unwrap(),
+        ]));
+        let defined = DnsQuery::new(1, "printer", TYPE_A).to_bytes().unwrap();
+        let undefined = DnsQuery::new(2, "wpad", TYPE_A).to_bytes().unwrap();
+
+        let defined = local_response(resolver.process_query(&defined));
+        let undefined = local_response(resolver.process_query(&undefined));
+
+        assert_eq!(defined.rcode(), 0);
+        assert_eq!(
+            defined.answers[0].data(),
+            RData::A(Ipv4Addr::new(192, 168, 1, 9))
+        );
+        assert_eq!(undefined.rcode(), 3);
+        assert_eq!(resolver.stats_snapshot_and_reset().unqualified, 1);
+    }
+
+    #[test]
+    fn process_query_forwards_localhost_and_opted_in_unqualified_names() {
+        let strict = Resolver::new(Blocklist::new());
+        let permissive = Resolver::new(Blocklist::new()).with_forward_unqualified(true);
+        let localhost = DnsQuery::new(1, "localhost", TYPE_A).to_bytes().unwrap();
+        let printer = DnsQuery::new(2, "printer", TYPE_A).to_bytes().unwrap();
+
+        assert!(matches!(
+            strict.process_query(&localhost),
+            QueryAction::Forward { .. }
+        ));
+        assert!(matches!(
+            permissive.process_query(&printer),
+            QueryAction::Forward { .. }
+        ));
+    }
+
+    #[test]
+    fn is_slow_respects_threshold() {
+        let resolver = Resolver::new(Blocklist::new());
+        assert!(!resolver.is_slow(10_000.0));
+
+        resolver.set_slow_query_threshold(Some(Duration::from_millis(250)));
+
+        assert!(!resolver.is_slow(249.9));
+        assert!(resolver.is_slow(250.1));
+    }
+
+    #[test]
+    fn replace_blocklist_applies_to_later_queries() {
+        let resolver = Resolver::new(Blocklist::from_adblock_format("||doubleclick.com^"));
+        let query = DnsQuery::new(1, "doubleclick.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        assert!(matches!(
+            resolver.process_query(&query),
+            QueryAction::Blocked { .. }
+        ));
+
+        let mut relaxed = Blocklist::from_adblock_format("||doubleclick.com^");
+        relaxed.add_allowlist_entry("doubleclick.com");
+        resolver.replace_blocklist(relaxed);
+
+        assert!(matches!(
+            resolver.process_query(&query),
+            QueryAction::Forward { .. }
+        ));
+    }
+
+    #[test]
+    fn blocked_answers_carry_the_configured_ttl() {
+        let resolver = Resolver::new(Blocklist::from_adblock_format("||doubleclick.com^"))
+            .with_blocked_ttl(42);
+        let query = |qtype| {
+            let query = DnsQuery::new(1, "doubleclick.com", qtype)
+                .to_bytes()
+                .unwrap();
+            match resolver.process_query(&query) {
+                QueryAction::Blocked { response, .. } => response,
+                _ => panic!("query was not blocked"),
+            }
+        };
+        // Header, question, then the answer's name pointer, type and class.
+        let ttl = 12 + "doubleclick.com".len() + 2 + 4 + 6;
+
+        let a = query(TYPE_A);
+        let txt = DnsResponse::parse(&query(TYPE_TXT)).unwrap();
+
+        assert_eq!(a[ttl..ttl + 4], 42u32.to_be_bytes());
+        assert_eq!(txt.authority[0].ttl, 42);
+        assert_eq!(
+            txt.authority[0].rdata[txt.authority[0].rdata.len() - 4..],
+            42u32.to_be_bytes()
+        );
+    }
+
+    #[test]
+    fn flushed_cache_entries_are_forwarded_again() {
+        let cache = DnsCache::new();
+        let query = DnsQuery::new(3, "flush.example.com", TYPE_A);
+        cache.put(
+            &query,
+            &query
+                .blocked_response(BlockedResponseStyle::NullIp)
+                .to_bytes(),
+        );
+        let resolver = Resolver::new(Blocklist::empty()).with_cache(cache);
+        let data = query.to_bytes().unwrap();
+
+        let before = resolver.process_query(&data);
+        let dropped = resolver.clear_cache();
+        let after = resolver.process_query(&data);
+
+        assert!(matches!(before, QueryAction::Cached { .. }));
+        assert_eq!(dropped, 1);
+        assert_eq!(resolver.cache_len(), 0);
+        assert!(matches!(after, QueryAction::Forward { .. }));
+    }
+
+    #[test]
+    fn cname_cloaked_responses_are_blocked_and_not_cached() {
+        let resolver = Resolver::new(Blocklist::from_adblock_format("||tracker.evil.com^"));
+        let query = DnsQuery::new(3, "metrics.example.com", TYPE_A);
+        let mut response = query.blocked_response(BlockedResponseStyle::NullIp);
+        let mut cname = response.answers[0].clone();
+        cname.rtype = TYPE_CNAME;
+        cname.rdata = b"\x07tracker\x04evil\x03com\x00".to_vec();
+        response.answers[0].name = "tracker.evil.com".to_string();
+        response.answers.insert(0, cname);
+
+        let answer = resolver
+            .process_response(&query, &response.to_bytes())
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(DnsResponse::parse(&answer).unwrap().rcode(), 3);
+        assert_eq!(resolver.cache_len(), 0);
+    }
+
+    #[test]
+    fn rebinding_answers_are_replaced_when_protection_is_on() {
+        let protected = Resolver::new(Blocklist::empty()).with_rebinding_protection(true);
+        let unprotected = Resolver::new(Blocklist::empty());
+        let query = DnsQuery::new(3, "rebind.example.com", TYPE_A);
+        let response = query
+            .blocked_response(BlockedResponseStyle::CustomIp(
+                Ipv4Addr::new(192, 168, 0, 1),
+                Ipv6Addr::UNSPECIFIED,
+            ))
+            .to_bytes();
+
+        let replaced = protected
+            .process_response(&query, &response)
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(DnsResponse::parse(&replaced).unwrap().rcode(), 3);
+        assert_eq!(protected.cache_len(), 0);
+        assert_eq!(protected.stats_snapshot_and_reset().rebinding_blocked, 1);
+        assert_eq!(unprotected.process_response(&query, &response), Ok(None));
+        assert_eq!(unprotected.cache_len(), 1);
+    }
+
+    #[test]
+    fn answers_to_another_question_are_dropped_and_counted() {
+        let resolver = Resolver::new(Blocklist::empty());
+        let asked = DnsQuery::new(3, "bank.example.com", TYPE_A);
+        let mut mixed_case = asked.clone();
+        mixed_case.domain = "bAnK.ExAmPlE.cOm".to_string();
+        let poisoned = DnsQuery::new(3, "evil.example.com", TYPE_A)
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+        let other_type = DnsQuery::new(3, "bank.example.com", TYPE_AAAA)
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+        let answer = mixed_case
+            .blocked_response(BlockedResponseStyle::NullIp)
+            .to_bytes();
+
+        let results = [
+            resolver.process_response(&asked, &poisoned),
+            resolver.process_response(&asked, &other_type),
+            resolver.process_response(&asked, &answer),
+        ];
+
+        assert_eq!(
+            results,
+            [
+                Err(ResolveError::QuestionMismatch),
+                Err(ResolveError::QuestionMismatch),
+                Ok(None)
+            ]
+        );
+        assert_eq!(resolver.cache_len(), 1);
+        assert_eq!(resolver.stats_snapshot_and_reset().mismatched, 2);
+    }
+
+    #[test]
+    fn client_subnet_goes_upstream_but_not_into_the_cache() {
+        let resolver = Resolver::new(Blocklist::empty()).with_ecs_prefix_len(Some(24));
+        let client = DnsQuery::new(4, "cdn.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let subnet = EdnsClientSubnet::new("203.0.113.45".parse().unwrap(), 24);
+
+        let forwarded = resolver.forwarded_query(&client, "203.0.113.45".parse().unwrap());
+        let question = DnsQuery::parse(&forwarded).unwrap();
+        let mut answer = question.blocked_response(BlockedResponseStyle::NullIp);
+        answer
+            .edns_opt
+            .as_mut()
+            .unwrap()
+            .options
+            .push(subnet.to_option());
+        let processed = resolver.process_response(&question, &answer.to_bytes());
+        let QueryAction::Cached { response, .. } = resolver.process_query(&client) else {
+            panic!("answer was not cached");
+        };
+
+        assert_eq!(question.edns_opt.unwrap().options, [subnet.to_option()]);
+        assert_eq!(subnet.address.to_string(), "203.0.113.0");
+        assert_eq!(processed, Ok(None));
+        assert_eq!(DnsResponse::parse(&response).unwrap().edns_opt, None);
+        assert_eq!(
+            Resolver::new(Blocklist::empty()).forwarded_query(&client, subnet.address),
+            &client[..]
+        );
+    }
+
+    #[tokio::test]
+    async fn resolve_forwards_and_caches() {
+        let upstream = mock_upstream(Ipv4Addr::new(10, 1, 2, 3)).await;
+        let resolver = Resolver::new(Blocklist::new());
+
+        let records = resolver
+            .resolve("example.com", TYPE_A, &[upstream])
+            .await
+            .unwrap();
+
+        assert_eq!(records, vec![RData::A(Ipv4Addr::new(10, 1, 2, 3))]);
+        assert_eq!(resolver.cache_len(), 1);
+
+        let cached = resolver.resolve("example.com", TYPE_A, &[]).await.unwrap();
+        assert_eq!(cached, records);
+    }
+
+    #[tokio::test]
+    async fn resolve_sends_rule_matches_to_their_upstreams_uncached() {
+        let public = mock_upstream(Ipv4Addr::new(10, 1, 2, 3)).await;
+        let internal = mock_upstream(Ipv4Addr::new(10, 0, 0, 53)).await;
+        let resolver =
+            Resolver::new(Blocklist::new()).with_forward_rules(ForwardRules::new([ForwardRule {
+                suffix: "corp.example".to_string(),
+                upstreams: vec![internal],
+            }]));
+
+        let matched = resolver
+            .resolve("db.corp.example", TYPE_A, &[public])
+            .await
+            .unwrap();
+        let unmatched = resolver
+            .resolve("example.com", TYPE_A, &[public])
+            .await
+            .unwrap();
+
+        assert_eq!(matched, vec![RData::A(Ipv4Addr::new(10, 0, 0, 53))]);
+        assert_eq!(unmatched, vec![RData::A(Ipv4Addr::new(10, 1, 2, 3))]);
+        assert_eq!(resolver.cache_len(), 1);
+    }
+
+    #[tokio::test]
+    async fn stale_hits_retry_a_failed_refresh_and_return_the_new_answer() {
+        let first = mock_upstream(Ipv4Addr::new(10, 1, 2, 3)).await;
+        let second = mock_upstream(Ipv4Addr::new(10, 4, 5, 6)).await;
+        let resolver = Resolver::new(Blocklist::new()).with_stale_ttl(Duration::from_secs(5));
+        let query = DnsQuery::new(0, "example.com", TYPE_A);
+
+        resolver
+            .resolve("example.com", TYPE_A, &[first])
+            .await
+            .unwrap();
+        resolver.cache.expire(&query, Duration::from_secs(1));
+        let failed = resolver.resolve("example.com", TYPE_A, &[]).await.unwrap();
+        let refreshed = resolver
+            .resolve("example.com", TYPE_A, &[second])
+            .await
+            .unwrap();
+        let cached = resolver.resolve("example.com", TYPE_A, &[]).await.unwrap();
+
+        assert_eq!(failed, vec![RData::A(Ipv4Addr::new(10, 1, 2, 3))]);
+        assert_eq!(refreshed, vec![RData::A(Ipv4Addr::new(10, 4, 5, 6))]);
+        assert_eq!(cached, refreshed);
+    }
+
+    #[tokio::test]
+    async fn resolve_blocked_domain_returns_error() {
+        let resolver = Resolver::new(Blocklist::from_adblock_format("||doubleclick.com^"));
+
+        let result = resolver.resolve("doubleclick.com", TYPE_A, &[]).await;
+
+        assert_eq!(result, Err(ResolveError::Blocked));
+    }
+
+    #[tokio::test]
+    async fn resolve_without_upstreams_returns_no_response() {
+        let resolver = Resolver::new(Blocklist::new());
+
+        let result = resolver.resolve("example.com", TYPE_A, &[]).await;
+
+        assert_eq!(result, Err(ResolveError::NoResponse));
+    }
+
+    #[tokio::test]
+    async fn resolve_rejects_invalid_names() {
+        let resolver = Resolver::new(Blocklist::new());
+
+        let result = resolver.resolve("bad..name", TYPE_A, &[]).await;
+
+        assert_eq!(result, Err(ResolveError::InvalidName));
+    }
+}
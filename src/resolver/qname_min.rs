@@ -0,0 +1,156 @@
+//! QNAME minimisation (RFC 7816).
+//!
+//! Before a name is forwarded, its ancestors are asked for one label at a
+//! time (`com`, then `example.com`) with NS queries, so each step only
+//! reveals as much of the name as it has to. Ancestors an upstream has
+//! confirmed are remembered for a while and not asked again. If an ancestor
+//! does not exist, nothing below it does either (RFC 8020) and the full name
+//! is answered NXDOMAIN without being sent. Any other failure falls back to
+//! sending the full name, as in RFC 7816's relaxed mode.
+//!
+//! Only recursive upstreams are asked for now, so the minimised queries hide
+//! nothing from them; the iterative path is what a future resolver that
+//! talks to authoritative servers itself would build on.
+
+use rustc_hash::FxHashMap;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::sync::{Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::dns::{DnsQuery, DnsResponse, TYPE_NS};
+
+/// How long an ancestor confirmed by upstream is not asked about again.
+const KNOWN_NAME_LIFETIME: Duration = Duration::from_secs(3600);
+
+/// Ancestors remembered at most; the set is emptied when it fills up.
+const MAX_KNOWN_NAMES: usize = 10_000;
+
+/// Response code of an answer saying the name does not exist.
+const RCODE_NXDOMAIN: u8 = 3;
+
+/// Transaction ID counter for minimised queries.
+static NEXT_PROBE_ID: AtomicU16 = AtomicU16::new(1);
+
+/// Whether forwarded names are minimised first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum QnameMinimization {
+    /// Forward the full name straight away
+    #[default]
+    Off,
+    /// Ask for each ancestor first; send the full name if that fails
+    Relaxed,
+}
+
+/// Ancestors an upstream confirmed to exist: zone cuts answered with NS
+/// records and empty non-terminals answered NODATA.
+#[derive(Default)]
+pub struct ZoneCuts {
+    known: Mutex<FxHashMap<String, Instant>>,
+}
+
+impl ZoneCuts {
+    /// Whether `name` was confirmed within [`KNOWN_NAME_LIFETIME`].
+    fn contains(&self, name: &str) -> bool {
+        let known = self.known.lock().unwrap_or_else(PoisonError::into_inner);
+        known
+            .get(name)
+            .is_some_and(|confirmed| confirmed.elapsed() < KNOWN_NAME_LIFETIME)
+    }
+
+    fn insert(&self, name: &str) {
+        let mut known = self.known.lock().unwrap_or_else(PoisonError::into_inner);
+        if known.len() >= MAX_KNOWN_NAMES {
+            known.clear();
+        }
+        known.insert(name.to_string(), Instant::now());
+    }
+}
+
+/// The proper ancestors of `domain` below the root, shortest first:
+/// `a.b.example.com` gives `com`, `example.com`, `b.example.com`.
+pub fn ancestors(domain: &str) -> Vec<&str> {
+    let domain = domain.trim_end_matches('.');
+    let mut names: Vec<&str> = domain
+        .match_indices('.')
+        .map(|(dot, _)| &domain[dot + 1..])
+        .collect();
+    names.reverse();
+    names
+}
+
+/// Walk down the ancestors of `domain` not yet in `cuts`, sending each an NS
+/// query through `ask`.
+///
+/// Returns the first ancestor upstream says does not exist. Stops early,
+/// returning None, when an ancestor gets no answer or an error, so the full
+/// name is forwarded as usual.
+pub async fn probe<F, Fut>(domain: &str, cuts: &ZoneCuts, ask: F) -> Option<String>
+where
+    F: Fn(Vec<u8>) -> Fut,
+    Fut: Future<Output = Option<Vec<u8>>>,
+{
+    for name in ancestors(domain) {
+        if cuts.contains(name) {
+            continue;
+        }
+        let id = NEXT_PROBE_ID.fetch_add(1, Ordering::Relaxed);
+        let query = DnsQuery::new(id, name, TYPE_NS).to_bytes()?;
+        let response = ask(query).await?;
+        match DnsResponse::parse(&response)?.rcode() {
+            0 => cuts.insert(name),
+            RCODE_NXDOMAIN => return Some(name.to_string()),
+            _ => return None,
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BlockedResponseStyle;
+
+    /// Answers NS queries NXDOMAIN for names under `missing`, NODATA
+    /// otherwise, recording every name asked.
+    async fn answer(query: Vec<u8>, missing: &str, asked: &Mutex<Vec<String>>) -> Option<Vec<u8>> {
+        let query = DnsQuery::parse(&query)?;
+        asked.lock().unwrap().push(query.domain.clone());
+        let response = if query.domain.ends_with(missing) {
+            query.nxdomain_response()
+        } else {
+            query.blocked_response(BlockedResponseStyle::NullIp)
+        };
+        Some(response.to_bytes())
+    }
+
+    #[test]
+    fn ancestors_are_listed_shortest_first() {
+        assert_eq!(
+            ancestors("a.b.example.com."),
+            ["com", "example.com", "b.example.com"]
+        );
+        assert!(ancestors("localhost").is_empty());
+    }
+
+    #[tokio::test]
+    async fn probe_asks_each_ancestor_once_and_stops_at_nxdomain() {
+        let cuts = ZoneCuts::default();
+        let asked = Mutex::new(Vec::new());
+
+        let first = probe("www.example.com", &cuts, |q| {
+            answer(q, "nope.example.com", &asked)
+        })
+        .await;
+        let second = probe("a.nope.example.com", &cuts, |q| {
+            answer(q, "nope.example.com", &asked)
+        })
+        .await;
+
+        assert_eq!(first, None);
+        assert_eq!(second.as_deref(), Some("nope.example.com"));
+        assert_eq!(
+            *asked.lock().unwrap(),
+            ["com", "example.com", "nope.example.com"]
+        );
+    }
+}
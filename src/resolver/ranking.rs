@@ -0,0 +1,126 @@
+//! Upstream ranking by answer latency.
+//!
+//! Each race win feeds the winner's latency into a moving average, and
+//! upstreams are raced fastest first, so with `--race` the fastest make up the
+//! raced set and the slower ones the fallback tier. A leader that slows down
+//! still wins the races it runs alone, so its average rises until it falls
+//! behind the next fastest. A win by an upstream ranked behind others means
+//! they were raced too and did not answer first, so they are moved behind it;
+//! that is how a leader too slow to win at all loses its place to the fallback
+//! tier. Upstreams that have not won yet follow, in their configured order.
+
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Each win moves the winner's average this fraction (1/N) of the way to
+/// its latency.
+pub const LATENCY_SMOOTHING: u64 = 8;
+
+/// Average winning latency per configured upstream, ordering the upstreams raced.
+#[derive(Default)]
+pub struct UpstreamRanker {
+    upstreams: Vec<SocketAddr>,
+    /// Average latency in microseconds, indexed like `upstreams` (0 = no
+    /// win yet).
+    latency_us: Vec<AtomicU64>,
+}
+
+impl UpstreamRanker {
+    /// Rank these upstreams, indexed as configured.
+    pub fn new(upstreams: &[SocketAddr]) -> Self {
+        Self {
+            upstreams: upstreams.to_vec(),
+            latency_us: upstreams.iter().map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.upstreams.is_empty()
+    }
+
+    /// Count a win, after `latency_ms`, for the upstream at `idx` in the
+    /// configured list.
+    pub fn record_win(&self, idx: usize, latency_ms: f64) {
+        let Some(average) = self.latency_us.get(idx) else {
+            return;
+        };
+        let sample = ((latency_ms * 1000.0) as u64).max(1);
+        let Ok(previous) = average.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+            Some(match old {
+                0 => sample,
+                old => old - old / LATENCY_SMOOTHING + sample / LATENCY_SMOOTHING,
+            })
+        }) else {
+            return;
+        };
+        // Upstreams ranked ahead of the winner were raced and lost to it.
+        let ranked_behind = if previous == 0 { u64::MAX } else { previous };
+        let behind = average.load(Ordering::Relaxed) + 1;
+        for (i, other) in self.latency_us.iter().enumerate() {
+            if i != idx {
+                let _ = other.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |old| {
+                    (old != 0 && old < ranked_behind && old < behind).then_some(behind)
+                });
+            }
+        }
+    }
+
+    /// Average winning latency of `upstream`, if it has won yet.
+    pub fn latency(&self, upstream: SocketAddr) -> Option<Duration> {
+        let idx = self.upstreams.iter().position(|&addr| addr == upstream)?;
+        match self.latency_us[idx].load(Ordering::Relaxed) {
+            0 => None,
+            us => Some(Duration::from_micros(us)),
+        }
+    }
+
+    /// `upstreams` with the fastest first; the rest keep their order.
+    pub fn rank<'a>(&self, upstreams: &'a [SocketAddr]) -> Cow<'a, [SocketAddr]> {
+        if self.is_empty() || upstreams.len() < 2 {
+            return Cow::Borrowed(upstreams);
+        }
+        let mut ranked = upstreams.to_vec();
+        ranked.sort_by_key(|&upstream| self.latency(upstream).unwrap_or(Duration::MAX));
+        Cow::Owned(ranked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fastest_upstreams_come_first_and_a_slowing_leader_loses_its_place() {
+        let upstreams: Vec<SocketAddr> = ["10.0.0.1:53", "10.0.0.2:53", "10.0.0.3:53"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let ranker = UpstreamRanker::new(&upstreams);
+
+        let unranked = ranker.rank(&upstreams).into_owned();
+        ranker.record_win(1, 20.0);
+        ranker.record_win(0, 5.0);
+        ranker.record_win(7, 1.0);
+        let ranked = ranker.rank(&upstreams).into_owned();
+        // Raced alone, the leader keeps winning until it is no longer the fastest.
+        let mut slow_wins = 0;
+        while ranker.rank(&upstreams)[0] == upstreams[0] {
+            ranker.record_win(0, 100.0);
+            slow_wins += 1;
+        }
+        let slowed = ranker.rank(&upstreams).into_owned();
+        // The new leader misses the fallback timeout and the fallback tier wins.
+        ranker.record_win(2, 25.0);
+        let overtaken = ranker.rank(&upstreams).into_owned();
+
+        assert_eq!(unranked, upstreams);
+        assert_eq!(ranked, [upstreams[0], upstreams[1], upstreams[2]]);
+        assert!(slow_wins > 1 && slow_wins < 10);
+        assert_eq!(slowed, [upstreams[1], upstreams[0], upstreams[2]]);
+        assert_eq!(overtaken, [upstreams[2], upstreams[1], upstreams[0]]);
+        assert!(ranker.latency(upstreams[2]) < ranker.latency(upstreams[1]));
+        assert_eq!(ranker.latency("10.0.0.9:53".parse().unwrap()), None);
+    }
+}
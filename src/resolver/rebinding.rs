@@ -0,0 +1,139 @@
+//! DNS rebinding protection.
+//!
+//! A rebinding attack points an attacker's public name at an address on the
+//! victim's network, so a page served from that name can reach local
+//! services as its own origin. With protection on, upstream answers giving a
+//! public name a private, loopback or link-local address are replaced with
+//! NXDOMAIN. Single-label names and names under local-only suffixes
+//! (`.local`, `.internal`, ...) may still resolve to such addresses.
+
+use std::net::IpAddr;
+
+use crate::dns::{DnsResponse, RData};
+
+/// Suffixes of names that are expected to resolve to local addresses.
+const LOCAL_SUFFIXES: &[&str] = &[
+    "local",
+    "localhost",
+    "localdomain",
+    "internal",
+    "intranet",
+    "lan",
+    "home",
+    "home.arpa",
+    "corp",
+    "private",
+];
+
+/// Check if an upstream answer for `domain` would rebind a public name to a
+/// local address.
+pub fn is_rebinding(domain: &str, response: &[u8]) -> bool {
+    if is_local_name(domain) {
+        return false;
+    }
+    let Some(response) = DnsResponse::parse(response) else {
+        return false;
+    };
+    response.answers.iter().any(|record| match record.data() {
+        RData::A(addr) => is_local_address(addr.into()),
+        RData::Aaaa(addr) => is_local_address(addr.into()),
+        _ => false,
+    })
+}
+
+/// Single-label names and names under [`LOCAL_SUFFIXES`].
+fn is_local_name(domain: &str) -> bool {
+    let domain = domain.trim_end_matches('.');
+    !domain.contains('.')
+        || LOCAL_SUFFIXES.iter().any(|suffix| {
+            domain.len().checked_sub(suffix.len()).is_some_and(|split| {
+                domain[split..].eq_ignore_ascii_case(suffix)
+                    && (split == 0 || domain.as_bytes()[split - 1] == b'.')
+            })
+        })
+}
+
+/// RFC 1918, loopback, link-local and unspecified IPv4 addresses, and their
+/// IPv6 counterparts (including unique local `fc00::/7` and IPv4-mapped).
+fn is_local_address(addr: IpAddr) -> bool {
+    match addr {
+        IpAddr::V4(v4) => {
+            v4.is_private() || v4.is_loopback() || v4.is_link_local() || v4.is_unspecified()
+        }
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => is_local_address(v4.into()),
+            None => {
+                v6.is_loopback()
+                    || v6.is_unique_local()
+                    || v6.is_unicast_link_local()
+                    || v6.is_unspecified()
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, TYPE_A, TYPE_AAAA};
+    use crate::filter::BlockedResponseStyle;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// An answer for `domain` with a single A or AAAA record for `addr`.
+    fn answer(domain: &str, addr: &str) -> Vec<u8> {
+        let (qtype, style) = match addr.parse().unwrap() {
+            IpAddr::V4(v4) => (
+                TYPE_A,
+                BlockedResponseStyle::CustomIp(v4, Ipv6Addr::UNSPECIFIED),
+            ),
+            IpAddr::V6(v6) => (
+                TYPE_AAAA,
+                BlockedResponseStyle::CustomIp(Ipv4Addr::UNSPECIFIED, v6),
+            ),
+        };
+        DnsQuery::new(1, domain, qtype)
+            .blocked_response(style)
+            .to_bytes()
+    }
+
+    #[test]
+    fn public_names_with_local_addresses_are_rebinding() {
+        for addr in [
+            "192.168.1.1",
+            "10.0.0.5",
+            "127.0.0.1",
+            "169.254.1.1",
+            "::1",
+            "fd12::1",
+            "::ffff:172.16.0.1",
+        ] {
+            assert!(
+                is_rebinding("evil.example", &answer("evil.example", addr)),
+                "{}",
+                addr
+            );
+        }
+        for addr in ["93.184.216.34", "172.32.0.1", "2606:4700::1111"] {
+            assert!(
+                !is_rebinding("example.com", &answer("example.com", addr)),
+                "{}",
+                addr
+            );
+        }
+    }
+
+    #[test]
+    fn local_names_may_resolve_to_local_addresses() {
+        for domain in ["printer.local", "NAS.Home.Arpa", "printer", "router.lan"] {
+            assert!(
+                !is_rebinding(domain, &answer(domain, "10.0.0.5")),
+                "{}",
+                domain
+            );
+        }
+        assert!(is_rebinding(
+            "printer.notlocal.com",
+            &answer("printer.notlocal.com", "10.0.0.5")
+        ));
+    }
+}
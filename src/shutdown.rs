@@ -0,0 +1,139 @@
+//! Graceful shutdown.
+//!
+//! [`ShutdownSignal`] is kept by the proxy and hands out [`Shutdown`]
+//! handles to the transports, which pass clones on to every connection and
+//! every task answering a client. Once triggered, transports stop accepting
+//! connections and reading queries; each handle is dropped when the work it
+//! guards is done, and [`ShutdownSignal::drain`] returns when none are left.
+
+use std::time::Duration;
+use tokio::sync::{mpsc, watch};
+
+/// Triggers shutdown and waits for the handles it gave out to be dropped.
+pub struct ShutdownSignal {
+    trigger: watch::Sender<bool>,
+    drained: mpsc::Receiver<()>,
+    handle: Shutdown,
+}
+
+impl Default for ShutdownSignal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ShutdownSignal {
+    pub fn new() -> Self {
+        let (trigger, triggered) = watch::channel(false);
+        let (alive, drained) = mpsc::channel(1);
+        Self {
+            trigger,
+            drained,
+            handle: Shutdown {
+                triggered,
+                _alive: alive,
+            },
+        }
+    }
+
+    /// A handle for a transport.
+    pub fn handle(&self) -> Shutdown {
+        self.handle.clone()
+    }
+
+    /// Tell every handle to stop taking new work, then wait up to `timeout`
+    /// for all of them to be dropped. Returns false on timeout.
+    pub async fn drain(self, timeout: Duration) -> bool {
+        let Self {
+            trigger,
+            mut drained,
+            handle,
+        } = self;
+        let _ = trigger.send(true);
+        drop(handle);
+        // Nothing is ever sent: recv returns once every sender is gone.
+        tokio::time::timeout(timeout, drained.recv()).await.is_ok()
+    }
+}
+
+/// A transport's, connection's or task's view of shutdown; holding it keeps
+/// [`ShutdownSignal::drain`] waiting.
+#[derive(Clone)]
+pub struct Shutdown {
+    triggered: watch::Receiver<bool>,
+    _alive: mpsc::Sender<()>,
+}
+
+impl Default for Shutdown {
+    /// A handle that is never triggered, for transports run without a signal.
+    fn default() -> Self {
+        let (_, triggered) = watch::channel(false);
+        let (alive, _) = mpsc::channel(1);
+        Self {
+            triggered,
+            _alive: alive,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Wait until shutdown is triggered; never returns for a handle whose
+    /// signal is gone without triggering.
+    pub async fn triggered(&mut self) {
+        if self
+            .triggered
+            .wait_for(|&triggered| triggered)
+            .await
+            .is_err()
+        {
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Whether shutdown has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        *self.triggered.borrow()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn drain_waits_for_every_handle() {
+        let signal = ShutdownSignal::new();
+        let mut handle = signal.handle();
+        let task = tokio::spawn(async move {
+            handle.triggered().await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(handle);
+        });
+
+        let drained = signal.drain(Duration::from_secs(5)).await;
+
+        assert!(drained);
+        assert!(task.is_finished());
+    }
+
+    #[tokio::test]
+    async fn drain_gives_up_after_the_timeout() {
+        let signal = ShutdownSignal::new();
+        let handle = signal.handle();
+
+        let drained = signal.drain(Duration::from_millis(50)).await;
+
+        assert!(!drained);
+        assert!(handle.is_triggered());
+    }
+
+    #[tokio::test]
+    async fn default_handles_are_never_triggered() {
+        let mut handle = Shutdown::default();
+
+        let triggered = tokio::time::timeout(Duration::from_millis(50), handle.triggered()).await;
+
+        assert!(triggered.is_err());
+        assert!(!handle.is_triggered());
+    }
+}
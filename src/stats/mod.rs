@@ -0,0 +1,589 @@
+//! Statistics tracking for DNS proxy.
+//!
+//! Request counters and the response time histogram run from startup, so
+//! Prometheus can scrape them; snapshots report the change since the previous
+//! snapshot. The other counters are reset by each snapshot.
+
+pub mod prometheus;
+
+use rustc_hash::FxHashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard, PoisonError, RwLock};
+
+/// Most-queried domains reported in each snapshot.
+pub const TOP_DOMAINS: usize = 10;
+
+/// Distinct domains counted per interval; further new domains are ignored.
+const MAX_TRACKED_DOMAINS: usize = 100_000;
+
+/// Upper edges of the response time histogram buckets, in milliseconds.
+///
+/// A final bucket past the last edge holds everything slower.
+pub const LATENCY_BUCKET_EDGES_MS: [f64; 12] = [
+    1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 250.0, 500.0, 1000.0, 2500.0, 5000.0,
+];
+
+/// Number of response time histogram buckets.
+pub const LATENCY_BUCKETS: usize = LATENCY_BUCKET_EDGES_MS.len() + 1;
+
+/// Atomic statistics for tracking proxy performance.
+pub struct Stats {
+    /// Running total, as are `forwarded`, `cached`, `blocked` and `local`.
+    pub requests: AtomicU64,
+    pub forwarded: AtomicU64,
+    pub cached: AtomicU64,
+    pub blocked: AtomicU64,
+    /// Requests answered locally without consulting upstreams.
+    pub local: AtomicU64,
+    /// Single-label queries answered with NXDOMAIN instead of being forwarded.
+    pub unqualified: AtomicU64,
+    /// Forwarded queries that no upstream answered.
+    pub failed: AtomicU64,
+    /// Forwarded UDP queries that timed out waiting for an upstream.
+    pub timeouts: AtomicU64,
+    /// TCP connections closed on accept because of the client cap.
+    pub tcp_rejected: AtomicU64,
+    /// UDP queries dropped because their client was over the rate limit.
+    pub rate_limited: AtomicU64,
+    /// Cache hits answered from the negative (NXDOMAIN/NODATA) cache.
+    pub negatives: AtomicU64,
+    /// UDP upstream responses dropped because they came from the wrong address.
+    pub spoofed: AtomicU64,
+    /// Upstream responses dropped because they answered a different question.
+    pub mismatched: AtomicU64,
+    /// Upstream answers replaced with NXDOMAIN by rebinding protection.
+    pub rebinding_blocked: AtomicU64,
+    /// Queries no upstream answered that got an expired cache entry instead.
+    pub stale: AtomicU64,
+    /// Failover moves to the next upstream, or limited races that needed
+    /// their fallback tier.
+    pub fallbacks: AtomicU64,
+    /// Forwarded queries answered by another query's upstream request in flight.
+    pub coalesced: AtomicU64,
+    /// Queries currently awaiting an upstream answer (gauge, not reset).
+    pending: AtomicU64,
+    /// Cumulative response time in microseconds for averaging.
+    total_response_time_us: AtomicU64,
+    /// Response times bucketed by [`LATENCY_BUCKET_EDGES_MS`] for percentiles.
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS],
+    /// Running totals as of the last snapshot.
+    reported: Mutex<Totals>,
+    /// Answer divergences per upstream (only touched when a divergence is found).
+    divergences: Mutex<FxHashMap<SocketAddr, u64>>,
+    /// Queries an upstream sat out because of its rate limit.
+    throttled: Mutex<FxHashMap<SocketAddr, u64>>,
+    /// Count queries per domain (off by default, it allocates per new domain).
+    track_domains: bool,
+    /// Queries per domain; known domains only take the read lock.
+    domains: RwLock<FxHashMap<String, AtomicU64>>,
+    /// Per-upstream win counts, indexed like the configured upstream list.
+    upstreams: Vec<UpstreamStats>,
+}
+
+/// How often one upstream answered first, and how fast.
+#[derive(Default)]
+pub struct UpstreamStats {
+    pub wins: AtomicU64,
+    /// Cumulative latency of the winning answers, in microseconds.
+    pub total_latency_us: AtomicU64,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            requests: AtomicU64::new(0),
+            forwarded: AtomicU64::new(0),
+            cached: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            local: AtomicU64::new(0),
+            unqualified: AtomicU64::new(0),
+            failed: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            tcp_rejected: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            negatives: AtomicU64::new(0),
+            spoofed: AtomicU64::new(0),
+            mismatched: AtomicU64::new(0),
+            rebinding_blocked: AtomicU64::new(0),
+            stale: AtomicU64::new(0),
+            fallbacks: AtomicU64::new(0),
+            coalesced: AtomicU64::new(0),
+            pending: AtomicU64::new(0),
+            total_response_time_us: AtomicU64::new(0),
+            latency_buckets: Default::default(),
+            reported: Mutex::default(),
+            divergences: Mutex::new(FxHashMap::default()),
+            throttled: Mutex::new(FxHashMap::default()),
+            track_domains: false,
+            domains: RwLock::new(FxHashMap::default()),
+            upstreams: Vec::new(),
+        }
+    }
+
+    /// Track wins for `count` upstreams (see [`Stats::record_upstream_win`]).
+    pub fn with_upstreams(mut self, count: usize) -> Self {
+        self.upstreams = (0..count).map(|_| UpstreamStats::default()).collect();
+        self
+    }
+
+    /// Count queries per domain for [`Stats::top_domains`].
+    pub fn with_domain_tracking(mut self, enabled: bool) -> Self {
+        self.track_domains = enabled;
+        self
+    }
+
+    pub fn record_forwarded(&self, response_time_ms: f64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.forwarded.fetch_add(1, Ordering::Relaxed);
+        self.record_response_time(response_time_ms);
+    }
+
+    pub fn record_cached(&self, response_time_ms: f64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.cached.fetch_add(1, Ordering::Relaxed);
+        self.record_response_time(response_time_ms);
+    }
+
+    pub fn record_blocked(&self, response_time_ms: f64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.blocked.fetch_add(1, Ordering::Relaxed);
+        self.record_response_time(response_time_ms);
+    }
+
+    pub fn record_local(&self, response_time_ms: f64) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.local.fetch_add(1, Ordering::Relaxed);
+        self.record_response_time(response_time_ms);
+    }
+
+    fn record_response_time(&self, response_time_ms: f64) {
+        self.total_response_time_us
+            .fetch_add((response_time_ms * 1000.0) as u64, Ordering::Relaxed);
+        let bucket = LATENCY_BUCKET_EDGES_MS.partition_point(|&edge| edge <= response_time_ms);
+        self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Estimate the `p`th percentile (0-100) response time in milliseconds
+    /// since the last snapshot.
+    pub fn response_time_percentile(&self, p: f64) -> f64 {
+        let since = self.totals().since(&self.reported());
+        percentile(&since.latency_buckets, p)
+    }
+
+    /// Running totals of the request counters and response time histogram.
+    pub fn totals(&self) -> Totals {
+        Totals {
+            requests: self.requests.load(Ordering::Relaxed),
+            forwarded: self.forwarded.load(Ordering::Relaxed),
+            cached: self.cached.load(Ordering::Relaxed),
+            blocked: self.blocked.load(Ordering::Relaxed),
+            local: self.local.load(Ordering::Relaxed),
+            response_time_us: self.total_response_time_us.load(Ordering::Relaxed),
+            latency_buckets: self
+                .latency_buckets
+                .each_ref()
+                .map(|count| count.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reported(&self) -> MutexGuard<'_, Totals> {
+        self.reported.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn record_unqualified(&self) {
+        self.unqualified.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failed(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.failed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_tcp_rejected(&self) {
+        self.tcp_rejected.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_negative(&self) {
+        self.negatives.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_spoofed(&self) {
+        self.spoofed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_mismatched(&self) {
+        self.mismatched.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rebinding_blocked(&self) {
+        self.rebinding_blocked.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_stale(&self) {
+        self.stale.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_fallback(&self) {
+        self.fallbacks.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_coalesced(&self) {
+        self.coalesced.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an answer from the upstream at `idx` that was used for the client.
+    pub fn record_upstream_win(&self, idx: usize, latency_ms: f64) {
+        if let Some(upstream) = self.upstreams.get(idx) {
+            upstream.wins.fetch_add(1, Ordering::Relaxed);
+            upstream
+                .total_latency_us
+                .fetch_add((latency_ms * 1000.0) as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Adjust the pending gauge; each UDP loop reports its own changes.
+    pub fn add_pending(&self, delta: isize) {
+        self.pending.fetch_add(delta as u64, Ordering::Relaxed);
+    }
+
+    /// Count an answer divergence involving this upstream.
+    pub fn record_divergence(&self, upstream: SocketAddr) {
+        if let Ok(mut divergences) = self.divergences.lock() {
+            *divergences.entry(upstream).or_default() += 1;
+        }
+    }
+
+    /// Count a query an upstream was skipped for because it was over its rate limit.
+    pub fn record_throttled(&self, upstream: SocketAddr) {
+        if let Ok(mut throttled) = self.throttled.lock() {
+            *throttled.entry(upstream).or_default() += 1;
+        }
+    }
+
+    /// Count a query for `domain` (no-op unless domain tracking is on).
+    pub fn record_domain(&self, domain: &str) {
+        if !self.track_domains {
+            return;
+        }
+        {
+            let domains = self.domains.read().unwrap_or_else(PoisonError::into_inner);
+            if let Some(count) = domains.get(domain) {
+                count.fetch_add(1, Ordering::Relaxed);
+                return;
+            }
+            if domains.len() >= MAX_TRACKED_DOMAINS {
+                return;
+            }
+        }
+        self.domains
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(domain.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// The `n` most-queried domains since the last snapshot, most queried first.
+    pub fn top_domains(&self, n: usize) -> Vec<(String, u64)> {
+        let domains = self.domains.read().unwrap_or_else(PoisonError::into_inner);
+        let mut counts: Vec<_> = domains
+            .iter()
+            .map(|(domain, count)| (domain.clone(), count.load(Ordering::Relaxed)))
+            .collect();
+        counts.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        counts.truncate(n);
+        counts
+    }
+
+    pub fn snapshot_and_reset(&self) -> StatsSnapshot {
+        let since = {
+            let mut reported = self.reported();
+            let totals = self.totals();
+            let since = totals.since(&reported);
+            *reported = totals;
+            since
+        };
+        let unqualified = self.unqualified.swap(0, Ordering::Relaxed);
+        let failed = self.failed.swap(0, Ordering::Relaxed);
+        let timeouts = self.timeouts.swap(0, Ordering::Relaxed);
+        let tcp_rejected = self.tcp_rejected.swap(0, Ordering::Relaxed);
+        let rate_limited = self.rate_limited.swap(0, Ordering::Relaxed);
+        let negatives = self.negatives.swap(0, Ordering::Relaxed);
+        let spoofed = self.spoofed.swap(0, Ordering::Relaxed);
+        let mismatched = self.mismatched.swap(0, Ordering::Relaxed);
+        let rebinding_blocked = self.rebinding_blocked.swap(0, Ordering::Relaxed);
+        let stale = self.stale.swap(0, Ordering::Relaxed);
+        let fallbacks = self.fallbacks.swap(0, Ordering::Relaxed);
+        let coalesced = self.coalesced.swap(0, Ordering::Relaxed);
+        let pending = self.pending.load(Ordering::Relaxed);
+
+        let mut divergences: Vec<_> = self
+            .divergences
+            .lock()
+            .map(|mut d| d.drain().collect())
+            .unwrap_or_default();
+        divergences.sort();
+        let mut throttled: Vec<_> = self
+            .throttled
+            .lock()
+            .map(|mut t| t.drain().collect())
+            .unwrap_or_default();
+        throttled.sort();
+        let top_domains = if self.track_domains {
+            let top = self.top_domains(TOP_DOMAINS);
+            self.domains
+                .write()
+                .unwrap_or_else(PoisonError::into_inner)
+                .clear();
+            top
+        } else {
+            Vec::new()
+        };
+
+        let upstreams = self
+            .upstreams
+            .iter()
+            .map(|upstream| {
+                let wins = upstream.wins.swap(0, Ordering::Relaxed);
+                let total_us = upstream.total_latency_us.swap(0, Ordering::Relaxed);
+                let avg_latency_ms = if wins > 0 {
+                    (total_us as f64 / wins as f64) / 1000.0
+                } else {
+                    0.0
+                };
+                (wins, avg_latency_ms)
+            })
+            .collect();
+
+        let avg_response_ms = if since.requests > 0 {
+            (since.response_time_us as f64 / since.requests as f64) / 1000.0
+        } else {
+            0.0
+        };
+
+        StatsSnapshot {
+            requests: since.requests,
+            forwarded: since.forwarded,
+            cached: since.cached,
+            blocked: since.blocked,
+            local: since.local,
+            unqualified,
+            failed,
+            timeouts,
+            tcp_rejected,
+            rate_limited,
+            negatives,
+            spoofed,
+            mismatched,
+            rebinding_blocked,
+            stale,
+            fallbacks,
+            coalesced,
+            pending,
+            avg_response_ms,
+            p50_ms: percentile(&since.latency_buckets, 50.0),
+            p95_ms: percentile(&since.latency_buckets, 95.0),
+            p99_ms: percentile(&since.latency_buckets, 99.0),
+            divergences,
+            throttled,
+            top_domains,
+            upstreams,
+        }
+    }
+}
+
+/// Running totals of the counters that are never reset.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Totals {
+    pub requests: u64,
+    pub forwarded: u64,
+    pub cached: u64,
+    pub blocked: u64,
+    pub local: u64,
+    pub response_time_us: u64,
+    /// Responses per [`LATENCY_BUCKET_EDGES_MS`] bucket (not cumulative).
+    pub latency_buckets: [u64; LATENCY_BUCKETS],
+}
+
+impl Totals {
+    /// The change from `earlier` to these totals.
+    fn since(&self, earlier: &Totals) -> Totals {
+        Totals {
+            requests: self.requests.saturating_sub(earlier.requests),
+            forwarded: self.forwarded.saturating_sub(earlier.forwarded),
+            cached: self.cached.saturating_sub(earlier.cached),
+            blocked: self.blocked.saturating_sub(earlier.blocked),
+            local: self.local.saturating_sub(earlier.local),
+            response_time_us: self
+                .response_time_us
+                .saturating_sub(earlier.response_time_us),
+            latency_buckets: std::array::from_fn(|i| {
+                self.latency_buckets[i].saturating_sub(earlier.latency_buckets[i])
+            }),
+        }
+    }
+}
+
+/// Interpolate the `p`th percentile linearly within its histogram bucket.
+///
+/// Percentiles falling in the open-ended last bucket report its lower edge.
+fn percentile(counts: &[u64; LATENCY_BUCKETS], p: f64) -> f64 {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let rank = p.clamp(0.0, 100.0) / 100.0 * total as f64;
+    let mut below = 0.0;
+    for (i, &count) in counts.iter().enumerate() {
+        let count = count as f64;
+        let lower = if i == 0 {
+            0.0
+        } else {
+            LATENCY_BUCKET_EDGES_MS[i - 1]
+        };
+        let Some(&upper) = LATENCY_BUCKET_EDGES_MS.get(i) else {
+            return lower;
+        };
+        if count > 0.0 && below + count >= rank {
+            return lower + (rank - below) / count * (upper - lower);
+        }
+        below += count;
+    }
+    LATENCY_BUCKET_EDGES_MS[LATENCY_BUCKET_EDGES_MS.len() - 1]
+}
+
+impl Default for Stats {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub struct StatsSnapshot {
+    pub requests: u64,
+    pub forwarded: u64,
+    pub cached: u64,
+    pub blocked: u64,
+    pub local: u64,
+    pub unqualified: u64,
+    pub failed: u64,
+    pub timeouts: u64,
+    pub tcp_rejected: u64,
+    pub rate_limited: u64,
+    pub negatives: u64,
+    pub spoofed: u64,
+    pub mismatched: u64,
+    pub rebinding_blocked: u64,
+    pub stale: u64,
+    pub fallbacks: u64,
+    pub coalesced: u64,
+    pub pending: u64,
+    pub avg_response_ms: f64,
+    /// Response time percentiles estimated from a bucketed histogram.
+    pub p50_ms: f64,
+    pub p95_ms: f64,
+    pub p99_ms: f64,
+    /// Answer divergences per upstream since the last snapshot.
+    pub divergences: Vec<(SocketAddr, u64)>,
+    /// Queries each rate-limited upstream sat out since the last snapshot.
+    pub throttled: Vec<(SocketAddr, u64)>,
+    /// The [`TOP_DOMAINS`] most-queried domains since the last snapshot (empty unless tracked).
+    pub top_domains: Vec<(String, u64)>,
+    /// Wins and average winning latency (ms) per upstream, in configuration order.
+    pub upstreams: Vec<(u64, f64)>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn top_domains_are_ranked_and_reset_with_the_snapshot() {
+        let stats = Stats::new().with_domain_tracking(true);
+        let untracked = Stats::new();
+        for domain in [
+            "a.example",
+            "b.example",
+            "b.example",
+            "c.example",
+            "b.example",
+            "c.example",
+        ] {
+            stats.record_domain(domain);
+            untracked.record_domain(domain);
+        }
+
+        let top = stats.top_domains(2);
+        let snapshot = stats.snapshot_and_reset();
+
+        assert_eq!(
+            top,
+            vec![("b.example".to_string(), 3), ("c.example".to_string(), 2)]
+        );
+        assert_eq!(snapshot.top_domains.len(), 3);
+        assert_eq!(snapshot.top_domains[0], ("b.example".to_string(), 3));
+        assert!(stats.top_domains(10).is_empty());
+        assert!(untracked.top_domains(10).is_empty());
+    }
+
+    #[test]
+    fn response_time_percentiles_are_ordered_and_interpolated() {
+        let stats = Stats::new();
+        for i in 0..100 {
+            stats.record_forwarded(f64::from(i % 10) * 0.1 + if i < 90 { 0.0 } else { 30.0 });
+        }
+        stats.record_cached(250.0);
+
+        let p50 = stats.response_time_percentile(50.0);
+        let p95 = stats.response_time_percentile(95.0);
+        let p99 = stats.response_time_percentile(99.0);
+        let snapshot = stats.snapshot_and_reset();
+
+        assert!(0.0 < p50 && p50 < 1.0);
+        assert!((20.0..=50.0).contains(&p95));
+        assert!(p50 <= p95 && p95 <= p99);
+        assert_eq!(snapshot.p50_ms, p50);
+        assert_eq!(snapshot.p99_ms, p99);
+        assert_eq!(stats.response_time_percentile(99.0), 0.0);
+    }
+
+    #[test]
+    fn slow_response_percentiles_reach_past_100ms() {
+        let stats = Stats::new();
+        for _ in 0..50 {
+            stats.record_forwarded(5.0);
+        }
+        for _ in 0..50 {
+            stats.record_forwarded(600.0);
+        }
+        stats.record_forwarded(10_000.0);
+
+        let p95 = stats.response_time_percentile(95.0);
+        let p100 = stats.response_time_percentile(100.0);
+
+        assert!((500.0..=1000.0).contains(&p95));
+        assert_eq!(p100, 5000.0);
+    }
+
+    #[test]
+    fn upstream_wins_are_averaged_per_upstream() {
+        let stats = Stats::new().with_upstreams(2);
+        stats.record_upstream_win(1, 10.0);
+        stats.record_upstream_win(1, 20.0);
+        stats.record_upstream_win(5, 1.0);
+
+        let snapshot = stats.snapshot_and_reset();
+
+        assert_eq!(snapshot.upstreams, vec![(0, 0.0), (2, 15.0)]);
+        assert_eq!(stats.snapshot_and_reset().upstreams, vec![(0, 0.0); 2]);
+    }
+}
@@ -0,0 +1,242 @@
+//! Prometheus metrics endpoint.
+//!
+//! Serves the text exposition format on `GET /metrics` over plain HTTP/1.1,
+//! one request per connection. Scrapes read the running totals straight from
+//! the stats atomics, so the query path never waits on them.
+
+use std::fmt::Write as _;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::cache::CacheStats;
+use crate::logging;
+use crate::resolver::Resolver;
+
+use super::{LATENCY_BUCKET_EDGES_MS, Totals};
+
+/// Request path scrapers are configured with.
+pub const PATH: &str = "/metrics";
+
+/// Media type of the Prometheus text format.
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+/// Upper bound for the request line and each header line.
+const MAX_LINE_BYTES: u64 = 8192;
+
+/// Upper bound for the number of header lines in one request.
+const MAX_HEADERS: usize = 64;
+
+/// How long a scraper may take to send its request.
+pub(crate) const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// HTTP server exposing the resolver's stats to Prometheus.
+pub struct StatsServer {
+    listener: TcpListener,
+}
+
+impl StatsServer {
+    /// Bind the metrics listener.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        Ok(Self {
+            listener: TcpListener::bind(addr).await?,
+        })
+    }
+
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start serving metrics.
+    pub fn start(self, resolver: Arc<Resolver>) {
+        tokio::spawn(async move {
+            loop {
+                match self.listener.accept().await {
+                    Ok((stream, _)) => {
+                        let resolver = resolver.clone();
+                        tokio::spawn(async move {
+                            let _ = serve(stream, &resolver).await;
+                        });
+                    }
+                    Err(e) => logging::error(format_args!("metrics accept error: {}", e)),
+                }
+            }
+        });
+    }
+}
+
+async fn serve(stream: TcpStream, resolver: &Resolver) -> io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request_line = tokio::time::timeout(REQUEST_TIMEOUT, read_head(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+
+    let mut parts = request_line.split(' ');
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some(PATH)) => (
+            "200 OK",
+            render(&resolver.stats().totals(), resolver.cache_stats()),
+        ),
+        (Some("GET"), Some(_)) => ("404 Not Found", String::new()),
+        _ => ("405 Method Not Allowed", String::new()),
+    };
+    let message = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    let stream = stream.get_mut();
+    stream.write_all(message.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Read the request head, returning the request line.
+pub(crate) async fn read_head(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let request_line = read_line(stream).await?;
+    for _ in 0..MAX_HEADERS {
+        if read_line(stream).await?.is_empty() {
+            return Ok(request_line);
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "too many headers",
+    ))
+}
+
+async fn read_line(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut line = String::new();
+    (&mut *stream)
+        .take(MAX_LINE_BYTES)
+        .read_line(&mut line)
+        .await?;
+    match line.strip_suffix('\n') {
+        Some(line) => Ok(line.trim_end_matches('\r').to_string()),
+        None => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "truncated request",
+        )),
+    }
+}
+
+/// Format the running totals in the Prometheus text exposition format.
+pub fn render(totals: &Totals, cache: CacheStats) -> String {
+    let mut out = String::new();
+    out.push_str(
+        "# HELP detour_requests_total DNS requests answered, by how they were answered.\n",
+    );
+    out.push_str("# TYPE detour_requests_total counter\n");
+    for (kind, count) in [
+        ("forwarded", totals.forwarded),
+        ("cached", totals.cached),
+        ("blocked", totals.blocked),
+        ("local", totals.local),
+    ] {
+        let _ = writeln!(out, "detour_requests_total{{type=\"{}\"}} {}", kind, count);
+    }
+
+    out.push_str("# HELP detour_cache_entries Responses currently cached.\n");
+    out.push_str("# TYPE detour_cache_entries gauge\n");
+    let _ = writeln!(out, "detour_cache_entries {}", cache.entries);
+
+    out.push_str("# HELP detour_cache_evictions_total Cached responses evicted to make room.\n");
+    out.push_str("# TYPE detour_cache_evictions_total counter\n");
+    let _ = writeln!(out, "detour_cache_evictions_total {}", cache.evictions);
+
+    out.push_str("# HELP detour_response_duration_ms Time to answer a request, in milliseconds.\n");
+    out.push_str("# TYPE detour_response_duration_ms histogram\n");
+    let mut cumulative = 0;
+    for (edge, count) in LATENCY_BUCKET_EDGES_MS.iter().zip(totals.latency_buckets) {
+        cumulative += count;
+        let _ = writeln!(
+            out,
+            "detour_response_duration_ms_bucket{{le=\"{}\"}} {}",
+            edge, cumulative
+        );
+    }
+    let count: u64 = totals.latency_buckets.iter().sum();
+    let _ = writeln!(
+        out,
+        "detour_response_duration_ms_bucket{{le=\"+Inf\"}} {}",
+        count
+    );
+    let _ = writeln!(
+        out,
+        "detour_response_duration_ms_sum {}",
+        totals.response_time_us as f64 / 1000.0
+    );
+    let _ = writeln!(out, "detour_response_duration_ms_count {}", count);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::Blocklist;
+    use crate::stats::Stats;
+
+    #[test]
+    fn renders_counters_gauge_and_cumulative_histogram() {
+        let stats = Stats::new();
+        stats.record_forwarded(1.5);
+        stats.record_cached(0.5);
+        stats.record_cached(250.0);
+        stats.snapshot_and_reset();
+
+        let text = render(
+            &stats.totals(),
+            CacheStats {
+                entries: 7,
+                evictions: 3,
+            },
+        );
+
+        assert!(text.contains("detour_requests_total{type=\"forwarded\"} 1\n"));
+        assert!(text.contains("detour_requests_total{type=\"cached\"} 2\n"));
+        assert!(text.contains("detour_requests_total{type=\"blocked\"} 0\n"));
+        assert!(text.contains("detour_cache_entries 7\n"));
+        assert!(text.contains("detour_cache_evictions_total 3\n"));
+        assert!(text.contains("detour_response_duration_ms_bucket{le=\"1\"} 1\n"));
+        assert!(text.contains("detour_response_duration_ms_bucket{le=\"2\"} 2\n"));
+        assert!(text.contains("detour_response_duration_ms_bucket{le=\"100\"} 2\n"));
+        assert!(text.contains("detour_response_duration_ms_bucket{le=\"500\"} 3\n"));
+        assert!(text.contains("detour_response_duration_ms_bucket{le=\"+Inf\"} 3\n"));
+        assert!(text.contains("detour_response_duration_ms_sum 252\n"));
+        assert!(text.contains("detour_response_duration_ms_count 3\n"));
+    }
+
+    #[tokio::test]
+    async fn serves_metrics_over_http() {
+        let server = StatsServer::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        resolver.record_blocked(0.2);
+        server.start(resolver);
+
+        let mut ok = String::new();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: detour\r\n\r\n")
+            .await
+            .unwrap();
+        client.read_to_string(&mut ok).await.unwrap();
+        let mut missing = String::new();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n\r\n").await.unwrap();
+        client.read_to_string(&mut missing).await.unwrap();
+
+        assert!(ok.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(ok.contains("Content-Type: text/plain; version=0.0.4\r\n"));
+        assert!(ok.ends_with("detour_response_duration_ms_count 1\n"));
+        assert!(ok.contains("detour_requests_total{type=\"blocked\"} 1\n"));
+        assert!(missing.starts_with("HTTP/1.1 404 Not Found\r\n"));
+    }
+}
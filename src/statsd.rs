@@ -0,0 +1,134 @@
+//! Push metrics to statsd (`--statsd`).
+//!
+//! Each stats interval the snapshot is sent as one UDP datagram of
+//! newline-separated statsd lines. Sending is fire-and-forget: the socket is
+//! non-blocking and send errors are ignored, so a missing collector never
+//! affects the proxy.
+
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+
+use crate::stats::StatsSnapshot;
+
+/// Sends stats snapshots to a statsd collector.
+pub struct StatsdSink {
+    socket: UdpSocket,
+    target: SocketAddr,
+    prefix: String,
+}
+
+impl StatsdSink {
+    /// Create a sink sending to `target`, prefixing every metric name with `prefix`.
+    pub fn new(target: SocketAddr, prefix: &str) -> io::Result<Self> {
+        let bind: SocketAddr = if target.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(bind)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            target,
+            prefix: prefix.to_string(),
+        })
+    }
+
+    /// Send a snapshot, ignoring any send error.
+    pub fn emit(&self, stats: &StatsSnapshot, cache_len: usize) {
+        let payload = format_lines(&self.prefix, stats, cache_len).join("\n");
+        let _ = self.socket.send_to(payload.as_bytes(), self.target);
+    }
+}
+
+/// Format a snapshot as statsd lines.
+///
+/// Counters are per-interval deltas, matching the snapshot's reset semantics.
+pub fn format_lines(prefix: &str, stats: &StatsSnapshot, cache_len: usize) -> Vec<String> {
+    let counters = [
+        ("requests", stats.requests),
+        ("forwarded", stats.forwarded),
+        ("cached", stats.cached),
+        ("blocked", stats.blocked),
+        ("local", stats.local),
+        ("errors", stats.failed),
+        ("timeouts", stats.timeouts),
+    ];
+    let mut lines: Vec<String> = counters
+        .iter()
+        .map(|(name, value)| format!("{}{}:{}|c", prefix, name, value))
+        .collect();
+    lines.push(format!("{}cache_size:{}|g", prefix, cache_len));
+    lines.push(format!("{}pending:{}|g", prefix, stats.pending));
+    if stats.requests > 0 {
+        lines.push(format!(
+            "{}response_time:{:.3}|ms",
+            prefix, stats.avg_response_ms
+        ));
+    }
+    lines
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn emits_statsd_lines_over_udp() {
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(2)))
+            .unwrap();
+        let sink = StatsdSink::new(collector.local_addr().unwrap(), "detour.").unwrap();
+        let stats = StatsSnapshot {
+            requests: 10,
+            forwarded: 4,
+            cached: 3,
+            blocked: 2,
+            local: 1,
+            unqualified: 0,
+            failed: 1,
+            timeouts: 3,
+            tcp_rejected: 0,
+            rate_limited: 0,
+            negatives: 0,
+            spoofed: 0,
+            mismatched: 0,
+            rebinding_blocked: 0,
+            stale: 0,
+            fallbacks: 0,
+            coalesced: 0,
+            pending: 2,
+            avg_response_ms: 1.5,
+            p50_ms: 1.0,
+            p95_ms: 2.0,
+            p99_ms: 3.0,
+            divergences: Vec::new(),
+            throttled: Vec::new(),
+            top_domains: Vec::new(),
+            upstreams: Vec::new(),
+        };
+
+        sink.emit(&stats, 42);
+        let mut buf = [0u8; 1500];
+        let len = collector.recv(&mut buf).unwrap();
+        let payload = std::str::from_utf8(&buf[..len]).unwrap();
+
+        assert_eq!(
+            payload.lines().collect::<Vec<_>>(),
+            vec![
+                "detour.requests:10|c",
+                "detour.forwarded:4|c",
+                "detour.cached:3|c",
+                "detour.blocked:2|c",
+                "detour.local:1|c",
+                "detour.errors:1|c",
+                "detour.timeouts:3|c",
+                "detour.cache_size:42|g",
+                "detour.pending:2|g",
+                "detour.response_time:1.500|ms",
+            ]
+        );
+    }
+}
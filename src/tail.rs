@@ -0,0 +1,233 @@
+//! Live query log streaming (`detour tail`).
+//!
+//! The proxy listens on a Unix socket. A client connects, sends one line of
+//! filters (`domain=<suffix> client=<ip> action=<action>`, all optional) and
+//! receives matching query events as verbose-mode lines until it disconnects.
+
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::sync::broadcast::error::RecvError;
+
+use crate::logging::{self, QueryEvent};
+
+/// Default path of the query stream socket.
+pub const DEFAULT_SOCKET: &str = "/run/detour-tail.sock";
+
+/// Which query events a subscriber receives.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TailFilter {
+    /// Only this domain and its subdomains
+    pub domain: Option<String>,
+    /// Only queries from this client
+    pub client: Option<IpAddr>,
+    /// Only this action (BLOCKED, CACHED, LOCAL, FORWARDED)
+    pub action: Option<String>,
+}
+
+impl TailFilter {
+    /// Parse the subscription line sent by the client.
+    pub fn parse(line: &str) -> Result<Self, String> {
+        let mut filter = Self::default();
+        for part in line.split_whitespace() {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| format!("invalid filter: {}", part))?;
+            match key {
+                "domain" => {
+                    filter.domain = Some(value.trim_end_matches('.').to_ascii_lowercase());
+                }
+                "client" => {
+                    let ip = value
+                        .parse()
+                        .map_err(|_| format!("invalid client address: {}", value))?;
+                    filter.client = Some(ip);
+                }
+                "action" => filter.action = Some(value.to_ascii_uppercase()),
+                _ => return Err(format!("unknown filter: {}", key)),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Format as a subscription line (inverse of [`Self::parse`]).
+    pub fn to_line(&self) -> String {
+        let mut parts = Vec::new();
+        if let Some(domain) = &self.domain {
+            parts.push(format!("domain={}", domain));
+        }
+        if let Some(client) = &self.client {
+            parts.push(format!("client={}", client));
+        }
+        if let Some(action) = &self.action {
+            parts.push(format!("action={}", action));
+        }
+        parts.join(" ")
+    }
+
+    fn matches(&self, event: &QueryEvent) -> bool {
+        if let Some(suffix) = &self.domain
+            && event.domain != *suffix
+            && !(event.domain.ends_with(suffix.as_str())
+                && event.domain[..event.domain.len() - suffix.len()].ends_with('.'))
+        {
+            return false;
+        }
+        if self.client.is_some_and(|ip| ip != event.client) {
+            return false;
+        }
+        self.action.as_ref().is_none_or(|a| *a == event.action)
+    }
+}
+
+/// Listen for `detour tail` subscribers on `path`, replacing a stale socket.
+pub fn serve(path: &Path) -> io::Result<()> {
+    let _ = std::fs::remove_file(path);
+    let listener = UnixListener::bind(path)?;
+    tokio::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    tokio::spawn(handle_subscriber(stream));
+                }
+                Err(e) => logging::error(format_args!("Tail accept error: {}", e)),
+            }
+        }
+    });
+    Ok(())
+}
+
+async fn handle_subscriber(stream: UnixStream) {
+    let (read, mut write) = stream.into_split();
+    let mut read = BufReader::new(read);
+    let mut line = String::new();
+    if read.read_line(&mut line).await.is_err() {
+        return;
+    }
+    let filter = match TailFilter::parse(&line) {
+        Ok(filter) => filter,
+        Err(e) => {
+            let _ = write.write_all(format!("error: {}\n", e).as_bytes()).await;
+            return;
+        }
+    };
+
+    let mut events = logging::subscribe();
+    let mut eof = [0u8; 1];
+    loop {
+        tokio::select! {
+            // Any read completing means the client hung up (or misbehaved).
+            _ = read.read(&mut eof) => return,
+            event = events.recv() => match event {
+                Ok(event) if filter.matches(&event) => {
+                    let line = format!("{}\n", event.line);
+                    if write.write_all(line.as_bytes()).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(_) => (),
+                Err(RecvError::Lagged(missed)) => {
+                    let notice = format!(
+                        "detour: subscriber too slow, dropped after missing {} events\n",
+                        missed
+                    );
+                    let _ = write.write_all(notice.as_bytes()).await;
+                    return;
+                }
+                Err(RecvError::Closed) => return,
+            },
+        }
+    }
+}
+
+/// Connect to a running instance and print matching events until Ctrl-C.
+pub async fn run_client(path: &Path, filter: &TailFilter) -> io::Result<()> {
+    let stream = UnixStream::connect(path).await.map_err(|e| {
+        io::Error::new(
+            e.kind(),
+            format!("cannot connect to {}: {}", path.display(), e),
+        )
+    })?;
+    let (read, mut write) = stream.into_split();
+    write
+        .write_all(format!("{}\n", filter.to_line()).as_bytes())
+        .await?;
+
+    let mut lines = BufReader::new(read).lines();
+    loop {
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                // Closing our half tells the server to unsubscribe.
+                let _ = write.shutdown().await;
+                return Ok(());
+            }
+            line = lines.next_line() => match line? {
+                Some(line) => println!("{}", line),
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::BlockedResponseStyle;
+    use crate::transport::{Protocol, QueryLogger};
+    use std::time::Duration;
+
+    #[test]
+    fn filter_round_trips_and_matches_suffixes() {
+        let filter =
+            TailFilter::parse("domain=Example.com. action=blocked client=10.0.0.2").unwrap();
+        let event = |domain: &str, client: &str, action: &str| QueryEvent {
+            client: client.parse().unwrap(),
+            domain: domain.to_string(),
+            action: action.to_string(),
+            line: String::new(),
+        };
+
+        assert_eq!(TailFilter::parse(&filter.to_line()), Ok(filter.clone()));
+        assert!(filter.matches(&event("ads.example.com", "10.0.0.2", "BLOCKED")));
+        assert!(!filter.matches(&event("badexample.com", "10.0.0.2", "BLOCKED")));
+        assert!(!filter.matches(&event("example.com", "10.0.0.3", "BLOCKED")));
+        assert!(!filter.matches(&event("example.com", "10.0.0.2", "CACHED")));
+        assert!(TailFilter::parse("colour=blue").is_err());
+    }
+
+    #[tokio::test]
+    async fn subscriber_receives_matching_events() {
+        let path = std::env::temp_dir().join(format!("detour-tail-{}.sock", std::process::id()));
+        serve(&path).unwrap();
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let (read, mut write) = stream.into_split();
+        write
+            .write_all(b"domain=tail-test.example action=blocked\n")
+            .await
+            .unwrap();
+        while !logging::has_subscribers() {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let logger = QueryLogger::new(Protocol::Udp);
+        let client = "192.0.2.1:5353".parse().unwrap();
+        logger.cached("ads.tail-test.example", client, 0.2);
+        logger.blocked(
+            "ads.tail-test.example",
+            client,
+            0.1,
+            BlockedResponseStyle::Refused,
+        );
+        let mut lines = BufReader::new(read).lines();
+        let line = tokio::time::timeout(Duration::from_secs(5), lines.next_line())
+            .await
+            .unwrap()
+            .unwrap()
+            .unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        assert!(line.ends_with("[UDP] ads.tail-test.example BLOCKED total=0.100ms mode=refused"));
+    }
+}
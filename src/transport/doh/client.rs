@@ -0,0 +1,279 @@
+//! DNS-over-HTTPS upstreams (RFC 8484).
+//!
+//! Each `https://` upstream keeps one HTTP/2 connection that is reused across
+//! queries and re-established once the server closes it. Queries are POSTed
+//! as `application/dns-message`; a response with any status other than 2xx
+//! fails the query. Upstreams are registered by address at startup, so the
+//! UDP and TCP forwarding paths race them alongside plain DNS upstreams and
+//! fall back to those on failure.
+
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::client::conn::http2::{self, SendRequest};
+use hyper::{Method, Request, Response, header};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+use tokio_rustls::TlsConnector;
+use tokio_rustls::rustls::pki_types::ServerName;
+use tokio_rustls::rustls::{self, RootCertStore};
+
+use super::{DNS_MESSAGE, MAX_BODY_BYTES};
+
+/// ALPN token identifying HTTP/2.
+const ALPN: &[u8] = b"h2";
+
+/// How long one query may take, including connection setup.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upstreams registered with [`register`].
+static UPSTREAMS: RwLock<Vec<Arc<DohClient>>> = RwLock::new(Vec::new());
+
+/// Client for one DNS-over-HTTPS upstream.
+pub struct DohClient {
+    addr: SocketAddr,
+    url: String,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+    /// Held while connecting so concurrent queries share one handshake.
+    sender: Mutex<Option<SendRequest<Full<Bytes>>>>,
+}
+
+impl DohClient {
+    /// Create a client POSTing to `url` (`https://host[:port]/path`) on the
+    /// server at `addr`, verifying its certificate against `roots`.
+    pub fn new(addr: SocketAddr, url: &str, roots: RootCertStore) -> io::Result<Self> {
+        let invalid = |e: &dyn std::fmt::Display| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("{}: {}", url, e))
+        };
+        let host = url_host(url).ok_or_else(|| invalid(&"not an https:// URL"))?;
+        let server_name = ServerName::try_from(host.to_string()).map_err(|e| invalid(&e))?;
+        let mut config = rustls::ClientConfig::builder_with_provider(Arc::new(
+            rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .map_err(io::Error::other)?
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        config.alpn_protocols = vec![ALPN.to_vec()];
+
+        Ok(Self {
+            addr,
+            url: url.to_string(),
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+            sender: Mutex::new(None),
+        })
+    }
+
+    /// Send a query, returning the upstream's answer.
+    pub async fn query(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        tokio::time::timeout(QUERY_TIMEOUT, self.exchange(packet))
+            .await
+            .ok()?
+    }
+
+    async fn exchange(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let body = Bytes::copy_from_slice(packet);
+        // A cached connection may have been closed by the server since the
+        // last query; in that case retry once on a fresh one.
+        for _ in 0..2 {
+            let mut sender = self.sender().await?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(&self.url)
+                .header(header::CONTENT_TYPE, DNS_MESSAGE)
+                .header(header::ACCEPT, DNS_MESSAGE)
+                .body(Full::new(body.clone()))
+                .ok()?;
+            match sender.send_request(request).await {
+                Ok(response) => return read_answer(response).await,
+                Err(_) => *self.sender.lock().await = None,
+            }
+        }
+        None
+    }
+
+    /// The cached connection, or a new one if there is none or it was closed.
+    async fn sender(&self) -> Option<SendRequest<Full<Bytes>>> {
+        let mut sender = self.sender.lock().await;
+        if let Some(open) = sender.as_ref().filter(|s| !s.is_closed()) {
+            return Some(open.clone());
+        }
+        let stream = TcpStream::connect(self.addr).await.ok()?;
+        let stream = self
+            .connector
+            .connect(self.server_name.clone(), stream)
+            .await
+            .ok()?;
+        let (fresh, connection) = http2::handshake(TokioExecutor::new(), TokioIo::new(stream))
+            .await
+            .ok()?;
+        tokio::spawn(connection);
+        *sender = Some(fresh.clone());
+        Some(fresh)
+    }
+}
+
+/// Read a DNS answer from a response; any status other than 2xx is a failure.
+async fn read_answer(response: Response<Incoming>) -> Option<Vec<u8>> {
+    if !response.status().is_success() {
+        return None;
+    }
+    let body = Limited::new(response.into_body(), MAX_BODY_BYTES)
+        .collect()
+        .await
+        .ok()?
+        .to_bytes();
+    (body.len() >= 12).then(|| body.to_vec())
+}
+
+/// The host of an `https://host[:port]/path` URL, without brackets for IPv6.
+fn url_host(url: &str) -> Option<&str> {
+    let rest = url.strip_prefix("https://")?;
+    let authority = rest.split('/').next()?;
+    let host = match authority.strip_prefix('[') {
+        Some(bracketed) => bracketed.split(']').next()?,
+        None => authority.split(':').next()?,
+    };
+    (!host.is_empty()).then_some(host)
+}
+
+/// Register a DoH upstream by its address.
+pub fn register(client: DohClient) {
+    UPSTREAMS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Arc::new(client));
+}
+
+fn find(addr: SocketAddr) -> Option<Arc<DohClient>> {
+    UPSTREAMS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|upstream| upstream.addr == addr)
+        .cloned()
+}
+
+/// Check if `addr` is a registered DoH upstream.
+pub fn is_registered(addr: SocketAddr) -> bool {
+    find(addr).is_some()
+}
+
+/// Send a query to a registered DoH upstream, returning its answer.
+pub async fn query(addr: SocketAddr, packet: &[u8]) -> Option<Vec<u8>> {
+    find(addr)?.query(packet).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, DnsResponse, RData, TYPE_A};
+    use crate::transport::dot::load_tls_config;
+    use hyper::StatusCode;
+    use hyper::service::service_fn;
+    use std::convert::Infallible;
+    use std::net::Ipv4Addr;
+    use std::path::Path;
+    use tokio::net::TcpListener;
+    use tokio_rustls::TlsAcceptor;
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+    const CERT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/cert.pem"
+    );
+    const KEY: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/key.pem"
+    );
+
+    /// Answer a DoH POST to `/dns-query` with 10.0.0.1; 404 for other paths.
+    async fn answer(request: Request<Incoming>) -> Result<Response<Full<Bytes>>, Infallible> {
+        if request.uri().path() != "/dns-query" {
+            let mut response = Response::new(Full::default());
+            *response.status_mut() = StatusCode::NOT_FOUND;
+            return Ok(response);
+        }
+        let query = request.into_body().collect().await.unwrap().to_bytes();
+        let mut response = query.to_vec();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+        response.extend_from_slice(&[0, 0, 1, 44, 0x00, 0x04, 10, 0, 0, 1]);
+        Ok(Response::new(Full::new(Bytes::from(response))))
+    }
+
+    /// Spawn an HTTP/2 server over TLS for `localhost`.
+    async fn upstream() -> SocketAddr {
+        let mut tls = (*load_tls_config(Path::new(CERT), Path::new(KEY)).unwrap()).clone();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let acceptor = TlsAcceptor::from(Arc::new(tls));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let stream = acceptor.accept(stream).await.unwrap();
+                tokio::spawn(
+                    hyper::server::conn::http2::Builder::new(TokioExecutor::new())
+                        .serve_connection(TokioIo::new(stream), service_fn(answer)),
+                );
+            }
+        });
+        local
+    }
+
+    fn client(addr: SocketAddr, path: &str) -> DohClient {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let url = format!("https://localhost:{}{}", addr.port(), path);
+        DohClient::new(addr, &url, roots).unwrap()
+    }
+
+    #[tokio::test]
+    async fn posts_queries_over_http2_and_fails_on_client_errors() {
+        let addr = upstream().await;
+        let client = client(addr, "/dns-query");
+        let missing = self::client(addr, "/missing");
+        let query = DnsQuery::new(9, "doh.example.org", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        let first = client.query(&query).await.unwrap();
+        let second = client.query(&query).await.unwrap();
+        let not_found = missing.query(&query).await;
+
+        let first = DnsResponse::parse(&first).unwrap();
+        assert_eq!(first.id, 9);
+        assert_eq!(
+            first.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(DnsResponse::parse(&second).unwrap().id, 9);
+        assert_eq!(not_found, None);
+    }
+
+    #[test]
+    fn url_host_strips_port_and_brackets() {
+        assert_eq!(
+            url_host("https://dns.example/dns-query"),
+            Some("dns.example")
+        );
+        assert_eq!(url_host("https://1.1.1.1:8443/q"), Some("1.1.1.1"));
+        assert_eq!(
+            url_host("https://[2606:4700::1111]/q"),
+            Some("2606:4700::1111")
+        );
+        assert_eq!(url_host("http://dns.example/"), None);
+    }
+}
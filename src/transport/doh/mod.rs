@@ -0,0 +1,636 @@
+//! DNS-over-HTTPS transport (RFC 8484).
+//!
+//! Serves `GET /dns-query?dns=<base64url>` and `POST /dns-query` with an
+//! `application/dns-message` body over HTTP/1.1 or HTTP/2, whichever the
+//! client speaks (chosen by ALPN over TLS). Queries go through the same
+//! resolver path as TCP, so blocking, caching and stats behave identically.
+//! Answers carry a `Cache-Control` max-age taken from the response TTL.
+//! Connections are plain HTTP unless a TLS config is given; browsers only
+//! accept `https://` DoH URLs. On shutdown the listener is closed and open
+//! connections are closed once the requests being answered are done.
+//!
+//! The `client` submodule speaks DoH the other way, to `https://` upstreams.
+
+#[cfg(feature = "doh-upstream")]
+pub mod client;
+
+use std::convert::Infallible;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Full, Limited};
+use hyper::body::{Bytes, Incoming};
+use hyper::header::{self, HeaderValue};
+use hyper::service::service_fn;
+use hyper::{Method, Request, Response, StatusCode};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
+use tokio::time::Instant;
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::rustls::ServerConfig;
+
+use crate::dns::DnsResponse;
+use crate::logging;
+use crate::resolver::Resolver;
+use crate::shutdown::Shutdown;
+
+use super::tcp::{DEFAULT_IDLE_TIMEOUT, DEFAULT_MAX_CLIENTS, answer_query};
+use super::{Protocol, QueryLogger};
+
+/// Request path DoH clients are configured with.
+pub const PATH: &str = "/dns-query";
+
+/// Media type of wire-format DNS messages.
+const DNS_MESSAGE: &str = "application/dns-message";
+
+/// ALPN tokens offered to TLS clients, HTTP/2 preferred.
+const ALPN_PROTOCOLS: [&[u8]; 2] = [b"h2", b"http/1.1"];
+
+/// Upper bound for a POST body (the largest DNS message).
+const MAX_BODY_BYTES: usize = u16::MAX as usize;
+
+/// DNS-over-HTTPS transport for DNS proxy.
+pub struct DohTransport {
+    listener: TcpListener,
+    idle_timeout: Duration,
+    max_clients: usize,
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
+}
+
+impl DohTransport {
+    /// Bind an HTTP listener for the transport.
+    pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        Ok(Self {
+            listener,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            tls: None,
+            shutdown: Shutdown::default(),
+        })
+    }
+
+    /// Serve HTTPS instead of plain HTTP, offering HTTP/2 and HTTP/1.1 over ALPN.
+    pub fn with_tls(mut self, tls: Arc<ServerConfig>) -> Self {
+        let mut tls = (*tls).clone();
+        tls.alpn_protocols = ALPN_PROTOCOLS.iter().map(|alpn| alpn.to_vec()).collect();
+        self.tls = Some(TlsAcceptor::from(Arc::new(tls)));
+        self
+    }
+
+    /// Close connections that send nothing for `timeout` (including the handshake).
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Close new connections immediately while `max` clients are connected.
+    pub fn with_max_clients(mut self, max: usize) -> Self {
+        self.max_clients = max;
+        self
+    }
+
+    /// Stop accepting connections when `shutdown` is triggered, holding it
+    /// until every open connection is closed.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Start the DNS-over-HTTPS transport.
+    pub fn start(self, upstreams: Vec<SocketAddr>, resolver: Arc<Resolver>, verbose: bool) {
+        tokio::spawn(run_accept_loop(self, upstreams, resolver, verbose));
+    }
+}
+
+async fn run_accept_loop(
+    transport: DohTransport,
+    upstreams: Vec<SocketAddr>,
+    resolver: Arc<Resolver>,
+    verbose: bool,
+) {
+    let clients = Arc::new(Semaphore::new(transport.max_clients));
+    let mut shutdown = transport.shutdown.clone();
+    loop {
+        let accepted = tokio::select! {
+            accepted = transport.listener.accept() => accepted,
+            _ = shutdown.triggered() => break,
+        };
+        match accepted {
+            Ok((client, client_addr)) => {
+                let Ok(permit) = clients.clone().try_acquire_owned() else {
+                    resolver.record_tcp_rejected();
+                    drop(client);
+                    continue;
+                };
+                let logger = QueryLogger::new(Protocol::Doh)
+                    .with_verbose(verbose)
+                    .with_exclusions(resolver.log_exclusions());
+                let connection = Arc::new(Connection {
+                    client_addr,
+                    upstreams: upstreams.clone(),
+                    resolver: resolver.clone(),
+                    logger,
+                });
+                let idle_timeout = transport.idle_timeout;
+                let tls = transport.tls.clone();
+                let shutdown = transport.shutdown.clone();
+                tokio::spawn(async move {
+                    match tls {
+                        None => serve(connection, client, idle_timeout, shutdown).await,
+                        Some(acceptor) => {
+                            let handshake =
+                                tokio::time::timeout(idle_timeout, acceptor.accept(client)).await;
+                            if let Ok(Ok(stream)) = handshake {
+                                serve(connection, stream, idle_timeout, shutdown).await;
+                            }
+                        }
+                    }
+                    drop(permit);
+                });
+            }
+            Err(e) => {
+                logging::error(format_args!("DoH accept error: {}", e));
+            }
+        }
+    }
+}
+
+/// Serve HTTP/1.1 or HTTP/2, whichever the client speaks, until it closes
+/// the connection, sends nothing for `idle_timeout` or shutdown is triggered.
+/// The last two close it gracefully: requests being answered still get
+/// their answers.
+async fn serve<S>(
+    connection: Arc<Connection>,
+    stream: S,
+    idle_timeout: Duration,
+    mut shutdown: Shutdown,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let stream = ReadTracker::new(stream);
+    let last_read = stream.last_read.clone();
+    let service = service_fn(move |request| {
+        let connection = connection.clone();
+        async move { Ok::<_, Infallible>(connection.respond(request).await) }
+    });
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let served = builder.serve_connection(TokioIo::new(stream), service);
+    tokio::pin!(served);
+    loop {
+        let idle_until = *last_read.lock().unwrap_or_else(PoisonError::into_inner) + idle_timeout;
+        tokio::select! {
+            _ = served.as_mut() => return,
+            _ = tokio::time::sleep_until(idle_until) => {
+                let last = *last_read.lock().unwrap_or_else(PoisonError::into_inner);
+                if last + idle_timeout <= Instant::now() {
+                    break;
+                }
+            }
+            _ = shutdown.triggered() => break,
+        }
+    }
+    served.as_mut().graceful_shutdown();
+    let _ = tokio::time::timeout(idle_timeout, served).await;
+}
+
+/// A client stream that notes when it last received anything, so idle
+/// connections are closed whichever HTTP version they speak.
+struct ReadTracker<S> {
+    stream: S,
+    last_read: Arc<Mutex<Instant>>,
+}
+
+impl<S> ReadTracker<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream,
+            last_read: Arc::new(Mutex::new(Instant::now())),
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ReadTracker<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let filled = buf.filled().len();
+        let read = Pin::new(&mut self.stream).poll_read(cx, buf);
+        if buf.filled().len() > filled {
+            *self
+                .last_read
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner) = Instant::now();
+        }
+        read
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ReadTracker<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write(cx, buf)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[io::IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.stream).poll_write_vectored(cx, bufs)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        self.stream.is_write_vectored()
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.stream).poll_shutdown(cx)
+    }
+}
+
+/// State shared by every request on one client connection.
+struct Connection {
+    client_addr: SocketAddr,
+    upstreams: Vec<SocketAddr>,
+    resolver: Arc<Resolver>,
+    logger: QueryLogger,
+}
+
+impl Connection {
+    async fn respond(&self, request: Request<Incoming>) -> Response<Full<Bytes>> {
+        if request.uri().path() != PATH {
+            return status(StatusCode::NOT_FOUND);
+        }
+        let query = match *request.method() {
+            Method::GET => {
+                let encoded = request
+                    .uri()
+                    .query()
+                    .unwrap_or_default()
+                    .split('&')
+                    .find_map(|param| param.strip_prefix("dns="));
+                match encoded.and_then(decode_base64url) {
+                    Some(query) => query,
+                    None => return status(StatusCode::BAD_REQUEST),
+                }
+            }
+            Method::POST => {
+                let content_type = request
+                    .headers()
+                    .get(header::CONTENT_TYPE)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or_default();
+                if !content_type.eq_ignore_ascii_case(DNS_MESSAGE) {
+                    return status(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+                }
+                match Limited::new(request.into_body(), MAX_BODY_BYTES)
+                    .collect()
+                    .await
+                {
+                    Ok(body) => body.to_bytes().to_vec(),
+                    Err(_) => return status(StatusCode::PAYLOAD_TOO_LARGE),
+                }
+            }
+            _ => {
+                let mut response = status(StatusCode::METHOD_NOT_ALLOWED);
+                response
+                    .headers_mut()
+                    .insert(header::ALLOW, HeaderValue::from_static("GET, POST"));
+                return response;
+            }
+        };
+
+        let answer = answer_query(
+            self.client_addr,
+            &query,
+            &self.upstreams,
+            &self.resolver,
+            &self.logger,
+        )
+        .await
+        .or_else(|| self.resolver.servfail(&query));
+        let Some(answer) = answer else {
+            return status(StatusCode::BAD_REQUEST);
+        };
+        let max_age = max_age(&answer);
+        let mut response = Response::new(Full::new(Bytes::from(answer)));
+        let headers = response.headers_mut();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(DNS_MESSAGE));
+        if let Ok(cache_control) = HeaderValue::try_from(format!("max-age={}", max_age)) {
+            headers.insert(header::CACHE_CONTROL, cache_control);
+        }
+        response
+    }
+}
+
+/// An empty response with this status.
+fn status(status: StatusCode) -> Response<Full<Bytes>> {
+    let mut response = Response::new(Full::default());
+    *response.status_mut() = status;
+    response
+}
+
+/// Seconds an HTTP cache may keep an answer: its lowest TTL (the SOA minimum for negative answers).
+fn max_age(response: &[u8]) -> u32 {
+    let ttl = if DnsResponse::is_negative(response) {
+        DnsResponse::negative_ttl(response).unwrap_or_default()
+    } else {
+        DnsResponse::parse_min_ttl(response, Duration::ZERO)
+    };
+    u32::try_from(ttl.as_secs()).unwrap_or(u32::MAX)
+}
+
+/// Decode unpadded (or padded) base64url, as used by the `dns` GET parameter.
+fn decode_base64url(input: &str) -> Option<Vec<u8>> {
+    let input = input.trim_end_matches("%3D").trim_end_matches('=');
+    if input.len() % 4 == 1 {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(input.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in input.bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'-' => 62,
+            b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            decoded.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, RData, TYPE_A};
+    use crate::filter::Blocklist;
+    use crate::transport::tcp::{read_dns_message, send_tcp_response};
+    use std::net::Ipv4Addr;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+
+    /// Spawn a TCP upstream answering every query with 10.0.0.1 (TTL 300).
+    async fn upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut response = read_dns_message(&mut stream).await.unwrap();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+                response.extend_from_slice(&[0, 0, 1, 44, 0x00, 0x04, 10, 0, 0, 1]);
+                send_tcp_response(&mut stream, &response).await;
+            }
+        });
+        local
+    }
+
+    async fn serve() -> (SocketAddr, Arc<Resolver>) {
+        let transport = DohTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        start(transport).await
+    }
+
+    async fn start(transport: DohTransport) -> (SocketAddr, Arc<Resolver>) {
+        let upstream = upstream().await;
+        let addr = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::from_adblock_format(
+            "||doubleclick.com^",
+        )));
+        transport.start(vec![upstream], resolver.clone(), false);
+        (addr, resolver)
+    }
+
+    fn encode_base64url(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+        let mut encoded = String::new();
+        for chunk in data.chunks(3) {
+            let n = chunk
+                .iter()
+                .enumerate()
+                .fold(0u32, |n, (i, &b)| n | u32::from(b) << (16 - 8 * i));
+            for i in 0..=chunk.len() {
+                encoded.push(ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char);
+            }
+        }
+        encoded
+    }
+
+    /// Send one request and return the response head and body.
+    async fn exchange(stream: &mut BufReader<TcpStream>, request: &[u8]) -> (String, Vec<u8>) {
+        stream.get_mut().write_all(request).await.unwrap();
+        let mut head = String::new();
+        loop {
+            let mut line = String::new();
+            stream.read_line(&mut line).await.unwrap();
+            if line == "\r\n" {
+                break;
+            }
+            head.push_str(line.trim_end());
+            head.push('\n');
+        }
+        let length = head
+            .lines()
+            .find_map(|line| line.strip_prefix("content-length: "))
+            .unwrap()
+            .parse()
+            .unwrap();
+        let mut body = vec![0; length];
+        stream.read_exact(&mut body).await.unwrap();
+        (head, body)
+    }
+
+    fn get(domain: &str) -> Vec<u8> {
+        let query = DnsQuery::new(0, domain, TYPE_A).to_bytes().unwrap();
+        format!(
+            "GET {}?dns={} HTTP/1.1\r\nHost: localhost\r\nAccept: {}\r\n\r\n",
+            PATH,
+            encode_base64url(&query),
+            DNS_MESSAGE
+        )
+        .into_bytes()
+    }
+
+    #[tokio::test]
+    async fn get_requests_are_answered_with_cache_control() {
+        let (addr, resolver) = serve().await;
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+        let (blocked_head, blocked) = exchange(&mut stream, &get("doubleclick.com")).await;
+        let (forwarded_head, forwarded) = exchange(&mut stream, &get("doh.example.org")).await;
+        let (_, cached) = exchange(&mut stream, &get("doh.example.org")).await;
+
+        assert!(blocked_head.starts_with("HTTP/1.1 200 OK\n"));
+        assert!(blocked_head.contains("content-type: application/dns-message\n"));
+        let blocked = DnsResponse::parse(&blocked).unwrap();
+        assert_eq!(blocked.answers[0].data(), RData::A(Ipv4Addr::UNSPECIFIED));
+        assert!(forwarded_head.contains("cache-control: max-age=300\n"));
+        assert_eq!(
+            DnsResponse::parse(&forwarded).unwrap().answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(cached, forwarded);
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!((stats.blocked, stats.forwarded, stats.cached), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn post_requests_are_answered() {
+        let (addr, resolver) = serve().await;
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+        let query = DnsQuery::new(7, "post.example.org", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let mut request = format!(
+            "POST {} HTTP/1.1\r\nContent-Type: {}\r\nContent-Length: {}\r\n\r\n",
+            PATH,
+            DNS_MESSAGE,
+            query.len()
+        )
+        .into_bytes();
+        request.extend_from_slice(&query);
+
+        let (head, body) = exchange(&mut stream, &request).await;
+        let (wrong_type, _) = exchange(
+            &mut stream,
+            b"POST /dns-query HTTP/1.1\r\nContent-Type: text/plain\r\nContent-Length: 0\r\n\r\n",
+        )
+        .await;
+
+        assert!(head.starts_with("HTTP/1.1 200 OK\n"));
+        let response = DnsResponse::parse(&body).unwrap();
+        assert_eq!(response.id, 7);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert!(wrong_type.starts_with("HTTP/1.1 415 "));
+        assert_eq!(resolver.stats_snapshot_and_reset().forwarded, 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_requests_are_rejected() {
+        let (addr, resolver) = serve().await;
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+        let (bad_base64, _) =
+            exchange(&mut stream, b"GET /dns-query?dns=AA*B HTTP/1.1\r\n\r\n").await;
+        let (bad_length, _) =
+            exchange(&mut stream, b"GET /dns-query?dns=AAAAA HTTP/1.1\r\n\r\n").await;
+        let (missing, _) = exchange(&mut stream, b"GET /dns-query HTTP/1.1\r\n\r\n").await;
+        let (not_dns, _) = exchange(&mut stream, b"GET /dns-query?dns=AAAA HTTP/1.1\r\n\r\n").await;
+        let (wrong_path, _) = exchange(&mut stream, b"GET /other HTTP/1.1\r\n\r\n").await;
+
+        for head in [bad_base64, bad_length, missing, not_dns] {
+            assert!(head.starts_with("HTTP/1.1 400 "), "{}", head);
+        }
+        assert!(wrong_path.starts_with("HTTP/1.1 404 "));
+        assert_eq!(resolver.stats_snapshot_and_reset().requests, 0);
+    }
+
+    #[cfg(feature = "doh-upstream")]
+    #[tokio::test]
+    async fn serves_http2_over_tls_from_pem_files() {
+        use super::client::DohClient;
+        use crate::transport::dot::load_tls_config;
+        use std::path::Path;
+        use tokio_rustls::rustls::RootCertStore;
+        use tokio_rustls::rustls::pki_types::CertificateDer;
+        use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+        let cert = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/transport/testdata/cert.pem"
+        );
+        let key = concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/src/transport/testdata/key.pem"
+        );
+        let transport = DohTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_tls(load_tls_config(Path::new(cert), Path::new(key)).unwrap());
+        let (addr, resolver) = start(transport).await;
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(cert).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        let url = format!("https://localhost:{}{}", addr.port(), PATH);
+        let client = DohClient::new(addr, &url, roots).unwrap();
+        let query = DnsQuery::new(3, "h2.example.org", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        let answer = client.query(&query).await.unwrap();
+
+        let answer = DnsResponse::parse(&answer).unwrap();
+        assert_eq!(answer.id, 3);
+        assert_eq!(
+            answer.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(resolver.stats_snapshot_and_reset().forwarded, 1);
+    }
+
+    #[tokio::test]
+    async fn idle_connections_are_closed() {
+        let transport = DohTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(100));
+        let (addr, _) = start(transport).await;
+        let mut stream = BufReader::new(TcpStream::connect(addr).await.unwrap());
+
+        let (head, _) = exchange(&mut stream, &get("idle.example.org")).await;
+        let closed = tokio::time::timeout(Duration::from_secs(2), stream.read(&mut [0; 1])).await;
+
+        assert!(head.starts_with("HTTP/1.1 200 OK\n"));
+        assert!(matches!(closed, Ok(Ok(0))));
+    }
+
+    #[test]
+    fn base64url_round_trips_with_and_without_padding() {
+        let data = b"\x00\x01detour\xfe\xff".to_vec();
+        let encoded = encode_base64url(&data);
+
+        assert_eq!(decode_base64url(&encoded), Some(data.clone()));
+        assert_eq!(decode_base64url(&format!("{}==", encoded)), Some(data));
+        assert_eq!(decode_base64url("+/8"), None);
+    }
+}
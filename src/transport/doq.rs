@@ -0,0 +1,258 @@
+//! DNS-over-QUIC upstreams (RFC 9250).
+//!
+//! Each `quic://` upstream keeps one QUIC connection that is reused across
+//! queries and re-established once the server closes it. Every query gets its
+//! own bidirectional stream carrying a length-prefixed message with ID 0, as
+//! the RFC requires; the caller's ID is restored on the answer. Upstreams are
+//! registered by address at startup, so the UDP and TCP forwarding paths race
+//! them alongside plain DNS upstreams and fall back to those on failure.
+
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+
+use quinn::crypto::rustls::QuicClientConfig;
+use quinn::{ClientConfig, Connection, Endpoint};
+use tokio::sync::Mutex;
+use tokio_rustls::rustls::{self, RootCertStore};
+
+/// Default DNS-over-QUIC port.
+pub const DEFAULT_PORT: u16 = 853;
+
+/// ALPN token identifying DNS-over-QUIC.
+const ALPN: &[u8] = b"doq";
+
+/// How long one query may take, including connection setup.
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Upstreams registered with [`register`].
+static UPSTREAMS: RwLock<Vec<Arc<DoqUpstream>>> = RwLock::new(Vec::new());
+
+struct DoqUpstream {
+    addr: SocketAddr,
+    server_name: String,
+    endpoint: Endpoint,
+    /// Held while connecting so concurrent queries share one handshake.
+    connection: Mutex<Option<Connection>>,
+}
+
+/// Register a DoQ upstream, verifying its certificate against the web PKI roots.
+pub fn register(addr: SocketAddr, server_name: &str) -> io::Result<()> {
+    let roots = RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    register_with_roots(addr, server_name, roots)
+}
+
+fn register_with_roots(
+    addr: SocketAddr,
+    server_name: &str,
+    roots: RootCertStore,
+) -> io::Result<()> {
+    let mut crypto = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_protocol_versions(&[&rustls::version::TLS13])
+    .map_err(io::Error::other)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    crypto.alpn_protocols = vec![ALPN.to_vec()];
+    let crypto = QuicClientConfig::try_from(crypto).map_err(io::Error::other)?;
+
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let mut endpoint = Endpoint::client(local)?;
+    endpoint.set_default_client_config(ClientConfig::new(Arc::new(crypto)));
+
+    UPSTREAMS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(Arc::new(DoqUpstream {
+            addr,
+            server_name: server_name.to_string(),
+            endpoint,
+            connection: Mutex::new(None),
+        }));
+    Ok(())
+}
+
+fn find(addr: SocketAddr) -> Option<Arc<DoqUpstream>> {
+    UPSTREAMS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|upstream| upstream.addr == addr)
+        .cloned()
+}
+
+/// Check if `addr` is a registered DoQ upstream.
+pub fn is_registered(addr: SocketAddr) -> bool {
+    find(addr).is_some()
+}
+
+/// Send a query to a registered DoQ upstream, returning its answer.
+pub async fn query(addr: SocketAddr, query: &[u8]) -> Option<Vec<u8>> {
+    let upstream = find(addr)?;
+    tokio::time::timeout(QUERY_TIMEOUT, upstream.exchange(query))
+        .await
+        .ok()?
+}
+
+impl DoqUpstream {
+    async fn exchange(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < 12 {
+            return None;
+        }
+        let mut message = Vec::with_capacity(2 + query.len());
+        message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+        message.extend_from_slice(&[0, 0]);
+        message.extend_from_slice(&query[2..]);
+
+        // A cached connection may have been closed by the server since the
+        // last query; in that case retry once on a fresh one.
+        for _ in 0..2 {
+            let connection = self.connection().await?;
+            if let Some(mut response) = exchange_on(&connection, &message).await {
+                response[..2].copy_from_slice(&query[..2]);
+                return Some(response);
+            }
+            connection.close_reason()?;
+        }
+        None
+    }
+
+    /// The open connection, reconnecting if there is none or it was closed.
+    async fn connection(&self) -> Option<Connection> {
+        let mut cached = self.connection.lock().await;
+        if let Some(connection) = cached.as_ref()
+            && connection.close_reason().is_none()
+        {
+            return Some(connection.clone());
+        }
+        let connection = self
+            .endpoint
+            .connect(self.addr, &self.server_name)
+            .ok()?
+            .await
+            .ok()?;
+        *cached = Some(connection.clone());
+        Some(connection)
+    }
+}
+
+/// Send one length-prefixed message on a new stream and read the answer.
+async fn exchange_on(connection: &Connection, message: &[u8]) -> Option<Vec<u8>> {
+    let (mut send, mut recv) = connection.open_bi().await.ok()?;
+    send.write_all(message).await.ok()?;
+    send.finish().ok()?;
+
+    let mut len_prefix = [0u8; 2];
+    recv.read_exact(&mut len_prefix).await.ok()?;
+    let mut response = vec![0u8; u16::from_be_bytes(len_prefix) as usize];
+    if response.len() < 12 {
+        return None;
+    }
+    recv.read_exact(&mut response).await.ok()?;
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, TYPE_A};
+    use crate::transport::dot::load_tls_config;
+    use quinn::crypto::rustls::QuicServerConfig;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio_rustls::rustls::pki_types::CertificateDer;
+    use tokio_rustls::rustls::pki_types::pem::PemObject;
+
+    const CERT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/cert.pem"
+    );
+    const KEY: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/key.pem"
+    );
+
+    /// Spawn a DoQ server echoing each query back as an answer, counting
+    /// connections and streams and recording the message IDs it sees.
+    fn server(connections: Arc<AtomicUsize>, ids: Arc<std::sync::Mutex<Vec<u16>>>) -> SocketAddr {
+        let mut tls = (*load_tls_config(Path::new(CERT), Path::new(KEY)).unwrap()).clone();
+        tls.alpn_protocols = vec![ALPN.to_vec()];
+        let config =
+            quinn::ServerConfig::with_crypto(Arc::new(QuicServerConfig::try_from(tls).unwrap()));
+        let endpoint = Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = endpoint.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Some(incoming) = endpoint.accept().await {
+                let connection = incoming.await.unwrap();
+                connections.fetch_add(1, Ordering::Relaxed);
+                let ids = ids.clone();
+                tokio::spawn(async move {
+                    while let Ok((mut send, mut recv)) = connection.accept_bi().await {
+                        let message = recv.read_to_end(u16::MAX as usize + 2).await.unwrap();
+                        let mut response = message[2..].to_vec();
+                        ids.lock()
+                            .unwrap()
+                            .push(u16::from_be_bytes([response[0], response[1]]));
+                        response[2] |= 0x80;
+                        send.write_all(&(response.len() as u16).to_be_bytes())
+                            .await
+                            .unwrap();
+                        send.write_all(&response).await.unwrap();
+                        send.finish().unwrap();
+                    }
+                });
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn queries_share_one_connection_with_a_stream_each() {
+        let connections = Arc::new(AtomicUsize::new(0));
+        let ids = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let addr = server(connections.clone(), ids.clone());
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        register_with_roots(addr, "localhost", roots).unwrap();
+        let first = DnsQuery::new(0x1234, "a.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+        let second = DnsQuery::new(0x5678, "b.example.com", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        let first_answer = query(addr, &first).await.unwrap();
+        let second_answer = query(addr, &second).await.unwrap();
+
+        assert!(is_registered(addr));
+        assert_eq!(first_answer[..2], first[..2]);
+        assert_eq!(second_answer[..2], second[..2]);
+        assert_eq!(second_answer[4..], second[4..]);
+        assert_eq!(connections.load(Ordering::Relaxed), 1);
+        assert_eq!(*ids.lock().unwrap(), vec![0, 0]);
+    }
+
+    #[tokio::test]
+    async fn unregistered_or_unreachable_upstreams_fail() {
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = closed.local_addr().unwrap();
+        drop(closed);
+        let query_bytes = DnsQuery::new(1, "example.com", TYPE_A).to_bytes().unwrap();
+        let unregistered = query(addr, &query_bytes).await;
+
+        register_with_roots(addr, "localhost", RootCertStore::empty()).unwrap();
+        let unreachable = query(addr, &query_bytes).await;
+
+        assert_eq!(unregistered, None);
+        assert_eq!(unreachable, None);
+    }
+}
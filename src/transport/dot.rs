@@ -0,0 +1,345 @@
+//! DNS-over-TLS transport (RFC 7858).
+//!
+//! Accepts TLS connections (usually on port 853) from clients such as
+//! Android Private DNS or systemd-resolved. After the handshake, messages
+//! use the same 2-byte length framing as plain TCP, so connections are
+//! served by the TCP transport's handler: several queries per connection,
+//! the same idle timeout and the same client cap.
+//!
+//! `tls://` upstreams are registered here by address at startup. The TCP
+//! forwarding path wraps its connection to them in TLS, verifying the server
+//! certificate against the system certificate store.
+
+use std::io;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use std::time::Duration;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName};
+use tokio_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+use crate::resolver::Resolver;
+use crate::shutdown::Shutdown;
+
+use super::tcp::TcpTransport;
+
+/// Default DNS-over-TLS port.
+pub const DEFAULT_PORT: u16 = 853;
+
+/// Load a PEM certificate chain and private key into a TLS server config.
+pub fn load_tls_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<ServerConfig>> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", path.display(), e),
+        )
+    };
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| invalid(cert_path, &e))?;
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| invalid(key_path, &e))?;
+
+    let config = ServerConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .and_then(|builder| builder.with_no_client_auth().with_single_cert(certs, key))
+    .map_err(|e| invalid(cert_path, &e))?;
+    Ok(Arc::new(config))
+}
+
+/// Upstreams registered with [`register_upstream`].
+static UPSTREAMS: RwLock<Vec<DotUpstream>> = RwLock::new(Vec::new());
+
+struct DotUpstream {
+    addr: SocketAddr,
+    server_name: ServerName<'static>,
+    connector: TlsConnector,
+}
+
+/// Register a DoT upstream, verifying its certificate against the system store.
+pub fn register_upstream(addr: SocketAddr, server_name: &str) -> io::Result<()> {
+    register_upstream_with_roots(addr, server_name, system_roots()?)
+}
+
+/// Load the system certificate store, failing if it has no usable certificates.
+pub fn system_roots() -> io::Result<RootCertStore> {
+    let native = rustls_native_certs::load_native_certs();
+    let mut roots = RootCertStore::empty();
+    roots.add_parsable_certificates(native.certs);
+    if roots.is_empty() {
+        let reason = match native.errors.first() {
+            Some(e) => e.to_string(),
+            None => "no certificates found".to_string(),
+        };
+        return Err(io::Error::other(format!(
+            "cannot load the system certificate store: {}",
+            reason
+        )));
+    }
+    Ok(roots)
+}
+
+/// Register a DoT upstream, verifying its certificate against `roots`.
+pub fn register_upstream_with_roots(
+    addr: SocketAddr,
+    server_name: &str,
+    roots: RootCertStore,
+) -> io::Result<()> {
+    let server_name = ServerName::try_from(server_name.to_string()).map_err(|e| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}: {}", server_name, e),
+        )
+    })?;
+    let config = ClientConfig::builder_with_provider(Arc::new(
+        tokio_rustls::rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()
+    .map_err(io::Error::other)?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+
+    UPSTREAMS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .push(DotUpstream {
+            addr,
+            server_name,
+            connector: TlsConnector::from(Arc::new(config)),
+        });
+    Ok(())
+}
+
+/// Check if `addr` is a registered DoT upstream.
+pub fn is_upstream(addr: SocketAddr) -> bool {
+    upstream_connector(addr).is_some()
+}
+
+/// The TLS connector and server name of a registered DoT upstream.
+pub(crate) fn upstream_connector(addr: SocketAddr) -> Option<(TlsConnector, ServerName<'static>)> {
+    UPSTREAMS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|upstream| upstream.addr == addr)
+        .map(|upstream| (upstream.connector.clone(), upstream.server_name.clone()))
+}
+
+/// DNS-over-TLS transport for DNS proxy.
+pub struct DotTransport {
+    inner: TcpTransport,
+}
+
+impl DotTransport {
+    /// Bind a TCP listener whose connections are served over TLS.
+    pub async fn bind(addr: SocketAddr, tls: Arc<ServerConfig>) -> io::Result<Self> {
+        let inner = TcpTransport::bind(addr)
+            .await?
+            .with_tls(TlsAcceptor::from(tls));
+        Ok(Self { inner })
+    }
+
+    /// Close connections that send nothing for `timeout` (including the handshake).
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.inner = self.inner.with_idle_timeout(timeout);
+        self
+    }
+
+    /// Close new connections immediately while `max` clients are connected.
+    pub fn with_max_clients(mut self, max: usize) -> Self {
+        self.inner = self.inner.with_max_clients(max);
+        self
+    }
+
+    /// Send keepalive probes on idle connections (see [`TcpTransport::with_keepalive`]).
+    pub fn with_keepalive(mut self, idle: Duration, interval: Duration, retries: u32) -> Self {
+        self.inner = self.inner.with_keepalive(idle, interval, retries);
+        self
+    }
+
+    /// Stop accepting connections on shutdown (see [`TcpTransport::with_shutdown`]).
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.inner = self.inner.with_shutdown(shutdown);
+        self
+    }
+
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    /// Start the DNS-over-TLS transport.
+    pub fn start(self, upstreams: Vec<SocketAddr>, resolver: Arc<Resolver>, verbose: bool) {
+        self.inner.start(upstreams, resolver, verbose);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, DnsResponse, RData, TYPE_A};
+    use crate::filter::Blocklist;
+    use crate::transport::tcp::{forward_to_upstream, read_dns_message, send_tcp_response};
+    use std::net::Ipv4Addr;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio_rustls::client::TlsStream;
+
+    const CERT: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/cert.pem"
+    );
+    const KEY: &str = concat!(
+        env!("CARGO_MANIFEST_DIR"),
+        "/src/transport/testdata/key.pem"
+    );
+
+    /// Spawn a TCP upstream answering every query with 10.0.0.1.
+    async fn upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut response = read_dns_message(&mut stream).await.unwrap();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+                response.extend_from_slice(&[0, 0, 1, 44, 0x00, 0x04, 10, 0, 0, 1]);
+                send_tcp_response(&mut stream, &response).await;
+            }
+        });
+        local
+    }
+
+    fn test_roots() -> RootCertStore {
+        let mut roots = RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(CERT).unwrap() {
+            roots.add(cert.unwrap()).unwrap();
+        }
+        roots
+    }
+
+    async fn connect(addr: SocketAddr) -> TlsStream<TcpStream> {
+        let roots = test_roots();
+        let config = ClientConfig::builder_with_provider(Arc::new(
+            tokio_rustls::rustls::crypto::ring::default_provider(),
+        ))
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap()
+    }
+
+    async fn ask(stream: &mut TlsStream<TcpStream>, id: u16, domain: &str) -> DnsResponse {
+        let query = DnsQuery::new(id, domain, TYPE_A).to_bytes().unwrap();
+        send_tcp_response(stream, &query).await;
+        let response = tokio::time::timeout(Duration::from_secs(5), read_dns_message(stream))
+            .await
+            .unwrap()
+            .unwrap();
+        DnsResponse::parse(&response).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serves_blocked_forwarded_and_cached_queries_over_tls() {
+        let upstream = upstream().await;
+        let tls = load_tls_config(Path::new(CERT), Path::new(KEY)).unwrap();
+        let transport = DotTransport::bind("127.0.0.1:0".parse().unwrap(), tls)
+            .await
+            .unwrap();
+        let addr = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::from_adblock_format(
+            "||doubleclick.com^",
+        )));
+        transport.start(vec![upstream], resolver.clone(), false);
+
+        let mut stream = connect(addr).await;
+        let blocked = ask(&mut stream, 1, "doubleclick.com").await;
+        let forwarded = ask(&mut stream, 2, "dot.example.org").await;
+        let cached = ask(&mut stream, 3, "dot.example.org").await;
+
+        assert_eq!(blocked.answers[0].data(), RData::A(Ipv4Addr::UNSPECIFIED));
+        assert_eq!(forwarded.id, 2);
+        assert_eq!(
+            forwarded.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(cached.id, 3);
+        assert_eq!(
+            cached.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!((stats.blocked, stats.forwarded, stats.cached), (1, 1, 1));
+    }
+
+    #[tokio::test]
+    async fn forwards_to_tls_upstreams() {
+        let tls = load_tls_config(Path::new(CERT), Path::new(KEY)).unwrap();
+        let server = DotTransport::bind("127.0.0.1:0".parse().unwrap(), tls)
+            .await
+            .unwrap();
+        let addr = server.local_addr().unwrap();
+        server.start(
+            vec![upstream().await],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+        register_upstream_with_roots(addr, "localhost", test_roots()).unwrap();
+        let query = DnsQuery::new(7, "tls.example.org", TYPE_A)
+            .to_bytes()
+            .unwrap();
+
+        let response =
+            tokio::time::timeout(Duration::from_secs(5), forward_to_upstream(&query, addr))
+                .await
+                .unwrap()
+                .map(|response| DnsResponse::parse(&response).unwrap())
+                .unwrap();
+
+        assert!(is_upstream(addr));
+        assert_eq!(response.id, 7);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[tokio::test]
+    async fn tcp_transports_serve_tls_from_pem_files() {
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let server = TcpTransport::bind_tls(addr, Path::new(CERT), Path::new(KEY))
+            .await
+            .unwrap();
+        let local = server.local_addr().unwrap();
+        server.start(
+            vec![upstream().await],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+
+        let response = ask(&mut connect(local).await, 3, "pem.example.org").await;
+        let missing = TcpTransport::bind_tls(addr, Path::new(CERT), Path::new("missing.pem")).await;
+        let swapped = TcpTransport::bind_tls(addr, Path::new(KEY), Path::new(CERT)).await;
+
+        assert_eq!(response.id, 3);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        for result in [missing, swapped] {
+            assert_eq!(
+                result.err().map(|e| e.kind()),
+                Some(io::ErrorKind::InvalidData)
+            );
+        }
+    }
+}
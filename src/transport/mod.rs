@@ -1,22 +1,35 @@
 //! Transport layer implementations for DNS proxy.
 //!
-//! Provides UDP and TCP transports for receiving DNS queries from clients
-//! and forwarding them to upstream servers.
+//! Provides UDP, TCP, DNS-over-TLS and DNS-over-HTTPS transports for
+//! receiving DNS queries from clients and forwarding them to upstream servers.
 
+pub mod doh;
+#[cfg(feature = "doq")]
+pub mod doq;
+pub mod dot;
 pub mod tcp;
 pub mod udp;
 
-/// Maximum size of a DNS packet (with some headroom).
+/// Default maximum size of a UDP DNS packet (with some headroom).
 pub const MAX_DNS_PACKET_SIZE: usize = 4096;
 
+use std::fmt;
 use std::net::SocketAddr;
-use std::time::SystemTime;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::dns::DnsQuery;
+use crate::filter::{BlockedResponseStyle, SuffixSet};
+use crate::logging::{self, LogEvent, Priority, QueryEvent};
+use crate::resolver::{Divergence, Resolver};
 
 /// Transport protocol identifier for logging.
 #[derive(Debug, Clone, Copy)]
 pub enum Protocol {
     Tcp,
     Udp,
+    Dot,
+    Doh,
 }
 
 impl Protocol {
@@ -24,106 +37,307 @@ impl Protocol {
         match self {
             Protocol::Tcp => "TCP",
             Protocol::Udp => "UDP",
+            Protocol::Dot => "DOT",
+            Protocol::Doh => "DOH",
         }
     }
 }
 
 /// Logger for DNS query events.
+///
+/// Query events are printed in verbose mode and streamed to any `detour tail`
+/// subscribers. Printed events are notices for blocked queries and stale
+/// answers, info for forwarded ones and debug for the rest, so syslog and
+/// journald can filter out cache hits. Domains matching the exclusion set (`--log-exclude`) are
+/// never logged.
+#[derive(Clone)]
 pub struct QueryLogger {
     protocol: Protocol,
+    verbose: bool,
+    exclude: Arc<SuffixSet>,
 }
 
 impl QueryLogger {
     pub fn new(protocol: Protocol) -> Self {
-        Self { protocol }
+        Self {
+            protocol,
+            verbose: false,
+            exclude: Arc::default(),
+        }
     }
 
-    pub fn blocked(&self, domain: &str, elapsed_ms: f64) {
-        println!(
-            "[{}] [{}] {} BLOCKED total={:.3}ms",
-            timestamp(),
-            self.protocol.as_str(),
-            domain,
-            elapsed_ms
-        );
+    /// Print query events (not only stream them to subscribers).
+    pub fn with_verbose(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Suppress all log events for domains matching these suffixes.
+    pub fn with_exclusions(mut self, exclude: Arc<SuffixSet>) -> Self {
+        self.exclude = exclude;
+        self
     }
 
-    pub fn cached(&self, domain: &str, elapsed_ms: f64) {
-        println!(
-            "[{}] [{}] {} CACHED total={:.3}ms",
-            timestamp(),
-            self.protocol.as_str(),
+    /// Check if log events for a domain are suppressed.
+    #[inline]
+    pub fn is_excluded(&self, domain: &str) -> bool {
+        self.exclude.matches(domain)
+    }
+
+    /// Log a blocked query answered in `style`.
+    pub fn blocked(
+        &self,
+        domain: &str,
+        client: SocketAddr,
+        elapsed_ms: f64,
+        style: BlockedResponseStyle,
+    ) {
+        self.query_event("BLOCKED", domain, client, elapsed_ms, None, || {
+            format!(" mode={}", style)
+        });
+    }
+
+    pub fn cached(&self, domain: &str, client: SocketAddr, elapsed_ms: f64) {
+        self.query_event("CACHED", domain, client, elapsed_ms, None, String::new);
+    }
+
+    pub fn local(&self, domain: &str, client: SocketAddr, elapsed_ms: f64) {
+        self.query_event("LOCAL", domain, client, elapsed_ms, None, String::new);
+    }
+
+    pub fn coalesced(&self, domain: &str, client: SocketAddr, elapsed_ms: f64) {
+        self.query_event("COALESCED", domain, client, elapsed_ms, None, String::new);
+    }
+
+    /// Log a query no upstream answered, answered from an expired cache entry.
+    pub fn stale(&self, domain: &str, client: SocketAddr, elapsed_ms: f64) {
+        self.query_event("STALE", domain, client, elapsed_ms, None, String::new);
+    }
+
+    /// Log a forwarded query answered by `upstream` (address and latency);
+    /// `rule` is the forwarding rule that picked the upstreams.
+    pub fn forwarded(
+        &self,
+        domain: &str,
+        client: SocketAddr,
+        total_ms: f64,
+        (from, upstream_ms): (SocketAddr, f64),
+        attempt: usize,
+        rule: Option<&str>,
+    ) {
+        self.query_event(
+            "FORWARDED",
             domain,
-            elapsed_ms
+            client,
+            total_ms,
+            Some((from, upstream_ms)),
+            || {
+                let mut detail = format!("from {}", from);
+                if attempt > 1 {
+                    detail.push_str(&format!(", attempt {}", attempt));
+                }
+                if let Some(rule) = rule {
+                    detail.push_str(&format!(", rule {}", rule));
+                }
+                format!(" upstream={:.3}ms ({})", upstream_ms, detail)
+            },
         );
     }
 
-    pub fn forwarded(&self, domain: &str, total_ms: f64, upstream_ms: f64, from: SocketAddr) {
-        println!(
-            "[{}] [{}] {} FORWARDED total={:.3}ms upstream={:.3}ms (from {})",
-            timestamp(),
-            self.protocol.as_str(),
+    /// Log a query event; `detail` builds the text after the total time, only
+    /// called if the event is printed or streamed.
+    fn query_event(
+        &self,
+        action: &str,
+        domain: &str,
+        client: SocketAddr,
+        total_ms: f64,
+        upstream: Option<(SocketAddr, f64)>,
+        detail: impl FnOnce() -> String,
+    ) {
+        let streaming = logging::has_subscribers();
+        if !(self.verbose || streaming) || self.is_excluded(domain) {
+            return;
+        }
+        let protocol = self.protocol.as_str();
+        let message = format_args!(
+            "[{}] {} {} total={:.3}ms{}",
+            protocol,
             domain,
+            action,
             total_ms,
-            upstream_ms,
-            from
+            detail()
         );
+        if self.verbose {
+            let priority = match action {
+                "BLOCKED" | "STALE" => Priority::Notice,
+                "FORWARDED" => Priority::Info,
+                _ => Priority::Debug,
+            };
+            let mut event = LogEvent::new(priority, message)
+                .with_protocol(protocol)
+                .with_domain(domain)
+                .with_action(action)
+                .with_latency(total_ms);
+            if let Some(upstream) = upstream {
+                event = event.with_upstream(upstream);
+            }
+            logging::emit(&event);
+        }
+        if streaming {
+            logging::publish(QueryEvent {
+                client: client.ip(),
+                domain: domain.to_string(),
+                action: action.to_string(),
+                line: format!("[{}] {}", logging::timestamp(), message),
+            });
+        }
     }
-}
 
-fn timestamp() -> String {
-    let now = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap_or_default();
-    let total_secs = now.as_secs();
-    
-    // Days since epoch
-    let days = total_secs / 86400;
-    
-    // Calculate year, month, day from days since 1970-01-01
-    let (year, month, day) = days_to_ymd(days);
-    
-    // Time of day
-    let day_secs = total_secs % 86400;
-    let hours = day_secs / 3600;
-    let mins = (day_secs % 3600) / 60;
-    let secs = day_secs % 60;
-    
-    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hours, mins, secs)
-}
+    /// Log a query whose total handling time exceeded the slow-query threshold.
+    ///
+    /// `upstream` carries the winning upstream and its latency for forwarded queries.
+    pub fn slow(
+        &self,
+        action: &str,
+        domain: &str,
+        qtype: u16,
+        client: SocketAddr,
+        total_ms: f64,
+        upstream: Option<(SocketAddr, f64)>,
+    ) {
+        if self.is_excluded(domain) {
+            return;
+        }
+        let detail = match upstream {
+            Some((from, upstream_ms)) => {
+                format!(" upstream={:.3}ms (from {})", upstream_ms, from)
+            }
+            None => String::new(),
+        };
+        let protocol = self.protocol.as_str();
+        let message = format_args!(
+            "[SLOW] [{}] {} type={} client={} {} total={:.3}ms{}",
+            protocol, domain, qtype, client, action, total_ms, detail
+        );
+        let mut event = LogEvent::new(Priority::Notice, message)
+            .with_protocol(protocol)
+            .with_domain(domain)
+            .with_action(action)
+            .with_latency(total_ms);
+        if let Some(upstream) = upstream {
+            event = event.with_upstream(upstream);
+        }
+        logging::emit(&event);
+    }
 
-fn days_to_ymd(days: u64) -> (u64, u64, u64) {
-    // Days since 1970-01-01
-    let mut remaining = days as i64;
-    let mut year = 1970i64;
-    
-    loop {
-        let days_in_year = if is_leap_year(year) { 366 } else { 365 };
-        if remaining < days_in_year {
-            break;
+    /// Warn that two upstreams returned materially different answers.
+    pub fn divergence(
+        &self,
+        domain: &str,
+        winner: SocketAddr,
+        other: SocketAddr,
+        divergence: &Divergence,
+    ) {
+        if self.is_excluded(domain) {
+            return;
         }
-        remaining -= days_in_year;
-        year += 1;
-    }
-    
-    let leap = is_leap_year(year);
-    let days_in_months: [i64; 12] = [
-        31, if leap { 29 } else { 28 }, 31, 30, 31, 30,
-        31, 31, 30, 31, 30, 31
-    ];
-    
-    let mut month = 1;
-    for days_in_month in days_in_months {
-        if remaining < days_in_month {
-            break;
+        logging::emit(
+            &LogEvent::new(
+                Priority::Warning,
+                format_args!(
+                    "[{}] divergence domain={} winner={} other={} {}",
+                    self.protocol.as_str(),
+                    domain,
+                    winner,
+                    other,
+                    divergence
+                ),
+            )
+            .with_protocol(self.protocol.as_str())
+            .with_domain(domain)
+            .with_action("DIVERGENCE"),
+        );
+    }
+
+    /// Emit a transport-tagged `[trace]` line for a traced domain.
+    pub fn trace(&self, domain: &str, message: fmt::Arguments) {
+        if self.is_excluded(domain) {
+            return;
         }
-        remaining -= days_in_month;
-        month += 1;
+        logging::emit(
+            &LogEvent::new(
+                Priority::Debug,
+                format_args!(
+                    "[trace] [{}] {} {}",
+                    self.protocol.as_str(),
+                    domain,
+                    message
+                ),
+            )
+            .with_protocol(self.protocol.as_str())
+            .with_domain(domain)
+            .with_action("TRACE"),
+        );
     }
-    
-    (year as u64, month, remaining as u64 + 1)
 }
 
-fn is_leap_year(year: i64) -> bool {
-    (year % 4 == 0 && year % 100 != 0) || (year % 400 == 0)
+/// Answer a query no upstream answered from a stale cache entry, if
+/// serve-stale allows one, and log it as STALE.
+pub fn stale_answer(
+    resolver: &Resolver,
+    logger: &QueryLogger,
+    query: &DnsQuery,
+    client: SocketAddr,
+    start_time: Instant,
+) -> Option<Vec<u8>> {
+    let response = resolver.stale_answer(query)?;
+    logger.stale(
+        &query.domain,
+        client,
+        start_time.elapsed().as_secs_f64() * 1000.0,
+    );
+    Some(response)
+}
+
+/// Answer a query no upstream answered: from a stale cache entry if
+/// serve-stale allows one, otherwise with SERVFAIL.
+pub fn unanswered(
+    resolver: &Resolver,
+    logger: &QueryLogger,
+    query: &[u8],
+    client: SocketAddr,
+    start_time: Instant,
+) -> Option<Vec<u8>> {
+    let query = DnsQuery::parse(query)?;
+    stale_answer(resolver, logger, &query, client, start_time)
+        .or_else(|| Some(query.servfail_response().to_bytes()))
+}
+
+/// Emit a `[trace]` line for a domain selected with `--trace-domain`.
+pub fn trace(domain: &str, message: fmt::Arguments) {
+    logging::emit(
+        &LogEvent::new(
+            Priority::Debug,
+            format_args!("[trace] {} {}", domain, message),
+        )
+        .with_domain(domain)
+        .with_action("TRACE"),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_logger_excludes_matching_domains_only() {
+        let exclude = Arc::new(SuffixSet::new(["health.example"]));
+        let logger = QueryLogger::new(Protocol::Udp).with_exclusions(exclude);
+
+        assert!(logger.is_excluded("health.example"));
+        assert!(logger.is_excluded("portal.health.example"));
+        assert!(!logger.is_excluded("wealth.example"));
+        assert!(!logger.is_excluded("example"));
+    }
 }
@@ -1,202 +1,1188 @@
 //! TCP transport for DNS queries.
 //!
 //! Handles DNS queries over TCP. Each client connection is handled
-//! independently - we read the query, race to multiple upstreams, and return
-//! the first response. TCP DNS messages are prefixed with a 2-byte length.
+//! independently - we read each query, race to multiple upstreams (or try them
+//! in turn with the failover strategy), and return the first response. TCP DNS messages are prefixed with a 2-byte length.
+//! Connections are reused for further queries (RFC 7766) until the client
+//! closes them or they sit idle past the idle timeout. The number of open
+//! client connections is capped; connections over the cap are closed at once.
+//! With keepalive probes enabled, accepted connections get `SO_KEEPALIVE` so
+//! NAT devices between a long-lived client and detour keep their mapping.
+//! On shutdown the listener is closed and each connection finishes the query
+//! it is answering, then closes instead of waiting for another.
 
+use socket2::{SockRef, TcpKeepalive};
+use std::future::Future;
 use std::io;
 use std::net::SocketAddr;
+use std::path::Path;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::time::Instant;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_rustls::TlsAcceptor;
 
-use crate::resolver::{QueryAction, Resolver};
+use crate::dns::DnsQuery;
+use crate::logging;
+use crate::resolver::{ForwardRule, InFlight, QueryAction, Resolver};
+use crate::shutdown::Shutdown;
+use crate::upstream::UpstreamStrategy;
 
-use super::{MAX_DNS_PACKET_SIZE, Protocol, QueryLogger};
+use super::{Protocol, QueryLogger, stale_answer, unanswered};
+
+/// How long a connection may sit idle between queries by default.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Concurrent client connections allowed by default.
+pub const DEFAULT_MAX_CLIENTS: usize = 1024;
+
+/// Unanswered keepalive probes before a connection is dropped by default.
+pub const DEFAULT_KEEPALIVE_RETRIES: u32 = 3;
+
+/// Wait before the first retry of a failed upstream exchange; doubled for
+/// each retry after it.
+const RETRY_BACKOFF: Duration = Duration::from_millis(100);
 
 /// TCP transport for DNS proxy.
 pub struct TcpTransport {
     listener: TcpListener,
+    idle_timeout: Duration,
+    max_clients: usize,
+    /// Keepalive probes set on accepted connections (None = off).
+    keepalive: Option<TcpKeepalive>,
+    /// Wrap connections in TLS before reading queries (DNS-over-TLS).
+    tls: Option<TlsAcceptor>,
+    shutdown: Shutdown,
 }
 
 impl TcpTransport {
     /// Bind a TCP listener for the transport.
     pub async fn bind(addr: SocketAddr) -> io::Result<Self> {
         let listener = TcpListener::bind(addr).await?;
-        Ok(Self { listener })
+        Ok(Self {
+            listener,
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            max_clients: DEFAULT_MAX_CLIENTS,
+            keepalive: None,
+            tls: None,
+            shutdown: Shutdown::default(),
+        })
+    }
+
+    /// Bind a listener serving DNS-over-TLS with the PEM certificate chain and
+    /// private key at these paths.
+    pub async fn bind_tls(addr: SocketAddr, cert_path: &Path, key_path: &Path) -> io::Result<Self> {
+        let tls = super::dot::load_tls_config(cert_path, key_path)?;
+        Ok(Self::bind(addr).await?.with_tls(TlsAcceptor::from(tls)))
+    }
+
+    /// Require a TLS handshake on every connection (see [`super::dot::DotTransport`]).
+    pub(crate) fn with_tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+
+    /// Close connections that send nothing for `timeout` (including mid-message).
+    pub fn with_idle_timeout(mut self, timeout: Duration) -> Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Close new connections immediately while `max` clients are connected.
+    pub fn with_max_clients(mut self, max: usize) -> Self {
+        self.max_clients = max;
+        self
+    }
+
+    /// Send keepalive probes on connections idle for `idle`, every `interval`,
+    /// dropping the connection after `retries` unanswered probes.
+    ///
+    /// Only matters for connections allowed to outlive the idle timeout.
+    /// Platforms without per-socket probe settings use their defaults for
+    /// `interval` and `retries`.
+    pub fn with_keepalive(mut self, idle: Duration, interval: Duration, retries: u32) -> Self {
+        let keepalive = TcpKeepalive::new().with_time(idle);
+        #[cfg(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows"
+        ))]
+        let keepalive = keepalive.with_interval(interval).with_retries(retries);
+        #[cfg(not(any(
+            target_os = "android",
+            target_os = "freebsd",
+            target_os = "linux",
+            target_os = "macos",
+            target_os = "windows"
+        )))]
+        let _ = (interval, retries);
+        self.keepalive = Some(keepalive);
+        self
+    }
+
+    /// Stop accepting connections when `shutdown` is triggered, holding it
+    /// until every open connection is closed.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Address the listener is bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Set the keepalive probes, if enabled, on an accepted connection.
+    fn apply_keepalive(&self, client: &TcpStream) {
+        if let Some(keepalive) = &self.keepalive
+            && let Err(e) = SockRef::from(client).set_tcp_keepalive(keepalive)
+        {
+            logging::warn(format_args!("TCP keepalive error: {}", e));
+        }
     }
 
     /// Start the TCP transport.
     pub fn start(self, upstreams: Vec<SocketAddr>, resolver: Arc<Resolver>, verbose: bool) {
-        tokio::spawn(run_accept_loop(self.listener, upstreams, resolver, verbose));
+        tokio::spawn(run_accept_loop(self, upstreams, resolver, verbose));
     }
 }
 
 async fn run_accept_loop(
-    listener: TcpListener,
+    transport: TcpTransport,
     upstreams: Vec<SocketAddr>,
     resolver: Arc<Resolver>,
     verbose: bool,
 ) {
+    let clients = Arc::new(Semaphore::new(transport.max_clients));
+    let mut shutdown = transport.shutdown.clone();
     loop {
-        match listener.accept().await {
-            Ok((client, _)) => {
+        let accepted = tokio::select! {
+            accepted = transport.listener.accept() => accepted,
+            _ = shutdown.triggered() => break,
+        };
+        match accepted {
+            Ok((client, client_addr)) => {
+                let Ok(permit) = clients.clone().try_acquire_owned() else {
+                    resolver.record_tcp_rejected();
+                    drop(client);
+                    continue;
+                };
+                transport.apply_keepalive(&client);
                 let resolver = resolver.clone();
                 let upstreams = upstreams.clone();
-                tokio::spawn(handle_connection(client, upstreams, resolver, verbose));
+                let idle_timeout = transport.idle_timeout;
+                let tls = transport.tls.clone();
+                let shutdown = transport.shutdown.clone();
+                tokio::spawn(async move {
+                    match tls {
+                        None => {
+                            let logger = QueryLogger::new(Protocol::Tcp);
+                            handle_connection(
+                                client,
+                                client_addr,
+                                upstreams,
+                                resolver,
+                                logger.with_verbose(verbose),
+                                idle_timeout,
+                                shutdown,
+                            )
+                            .await;
+                        }
+                        Some(acceptor) => {
+                            // The handshake counts against the idle timeout too.
+                            let handshake =
+                                tokio::time::timeout(idle_timeout, acceptor.accept(client)).await;
+                            if let Ok(Ok(stream)) = handshake {
+                                let logger = QueryLogger::new(Protocol::Dot);
+                                handle_connection(
+                                    stream,
+                                    client_addr,
+                                    upstreams,
+                                    resolver,
+                                    logger.with_verbose(verbose),
+                                    idle_timeout,
+                                    shutdown,
+                                )
+                                .await;
+                            }
+                        }
+                    }
+                    drop(permit);
+                });
             }
             Err(e) => {
-                eprintln!("TCP accept error: {}", e);
+                logging::error(format_args!("TCP accept error: {}", e));
             }
         }
     }
 }
 
-async fn handle_connection(
-    mut client: TcpStream,
+async fn handle_connection<S: AsyncRead + AsyncWrite + Unpin>(
+    mut client: S,
+    client_addr: SocketAddr,
     upstreams: Vec<SocketAddr>,
     resolver: Arc<Resolver>,
-    verbose: bool,
+    logger: QueryLogger,
+    idle_timeout: Duration,
+    mut shutdown: Shutdown,
 ) {
-    let start_time = Instant::now();
-    let logger = QueryLogger::new(Protocol::Tcp);
+    let logger = logger.with_exclusions(resolver.log_exclusions());
 
-    let query_with_len = match read_dns_message(&mut client).await {
-        Some(q) => q,
-        None => return,
-    };
+    // read_exact consumes exactly one message, so bytes of a pipelined next
+    // query stay in the socket for the following iteration.
+    loop {
+        let read = tokio::select! {
+            read = tokio::time::timeout(idle_timeout, read_dns_message(&mut client)) => read,
+            _ = shutdown.triggered() => break,
+        };
+        let Ok(Some(query)) = read else {
+            break;
+        };
+        handle_query(
+            &mut client,
+            client_addr,
+            &query,
+            &upstreams,
+            &resolver,
+            &logger,
+        )
+        .await;
+    }
+}
 
-    if query_with_len.len() <= 2 {
-        return;
+async fn handle_query<S: AsyncWrite + Unpin>(
+    client: &mut S,
+    client_addr: SocketAddr,
+    query: &[u8],
+    upstreams: &[SocketAddr],
+    resolver: &Arc<Resolver>,
+    logger: &QueryLogger,
+) {
+    if let Some(response) = answer_query(client_addr, query, upstreams, resolver, logger).await {
+        send_tcp_response(client, &response).await;
     }
-    let query = &query_with_len[2..];
+}
 
+/// Run a query through the resolver, asking the upstreams over TCP on a miss.
+///
+/// Records stats and logs the outcome; returns the answer for the client, if any.
+pub(crate) async fn answer_query(
+    client_addr: SocketAddr,
+    query: &[u8],
+    upstreams: &[SocketAddr],
+    resolver: &Arc<Resolver>,
+    logger: &QueryLogger,
+) -> Option<Vec<u8>> {
+    let start_time = Instant::now();
     match resolver.process_query(query) {
-        QueryAction::Invalid => (),
-        QueryAction::Blocked { response, domain } => {
-            send_tcp_response(&mut client, &response).await;
+        QueryAction::Invalid => None,
+        QueryAction::Blocked {
+            response,
+            domain,
+            qtype,
+        } => {
             let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
             resolver.record_blocked(elapsed);
-            if verbose {
-                logger.blocked(&domain, elapsed);
+            logger.blocked(&domain, client_addr, elapsed, resolver.blocked_style());
+            if resolver.is_slow(elapsed) {
+                logger.slow("BLOCKED", &domain, qtype, client_addr, elapsed, None);
+            }
+            Some(response)
+        }
+        QueryAction::Cached {
+            response,
+            domain,
+            qtype,
+        } => {
+            let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+            resolver.record_cached(elapsed);
+            logger.cached(&domain, client_addr, elapsed);
+            if resolver.is_slow(elapsed) {
+                logger.slow("CACHED", &domain, qtype, client_addr, elapsed, None);
             }
+            Some(response)
         }
-        QueryAction::Cached { response, domain } => {
-            send_tcp_response(&mut client, &response).await;
+        QueryAction::CachedStale {
+            response,
+            domain,
+            qtype,
+        } => {
             let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
             resolver.record_cached(elapsed);
-            if verbose {
-                logger.cached(&domain, elapsed);
-            }
-        }
-        QueryAction::Forward { domain } => {
-            let upstream_start = Instant::now();
-            if let Some((response, winner)) = race_upstreams(query, &upstreams).await {
-                send_tcp_response(&mut client, &response).await;
-                resolver.process_response(&response);
-                let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
-                resolver.record_forwarded(elapsed);
-                if verbose {
-                    logger.forwarded(
+            logger.cached(&domain, client_addr, elapsed);
+            if resolver.is_slow(elapsed) {
+                logger.slow("CACHED", &domain, qtype, client_addr, elapsed, None);
+            }
+            let refresher = resolver.clone();
+            let upstreams = upstreams.to_vec();
+            tokio::spawn(async move { refresher.refresh(&domain, qtype, &upstreams).await });
+            Some(response)
+        }
+        QueryAction::Local {
+            response,
+            domain,
+            qtype,
+        } => {
+            let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+            resolver.record_local(elapsed);
+            logger.local(&domain, client_addr, elapsed);
+            if resolver.is_slow(elapsed) {
+                logger.slow("LOCAL", &domain, qtype, client_addr, elapsed, None);
+            }
+            Some(response)
+        }
+        QueryAction::Forward {
+            domain,
+            qtype,
+            traced,
+            rule,
+            in_flight,
+        } => {
+            let forward = ForwardedQuery {
+                domain,
+                qtype,
+                traced,
+                rule,
+                in_flight,
+            };
+            forward_query(
+                client_addr,
+                query,
+                start_time,
+                forward,
+                upstreams,
+                resolver,
+                logger,
+            )
+            .await
+        }
+        QueryAction::Coalesced {
+            domain,
+            qtype,
+            answer,
+            ..
+        } => {
+            let Some(response) = answer
+                .response(u16::from_be_bytes([query[0], query[1]]))
+                .await
+            else {
+                resolver.record_failed();
+                let query = DnsQuery::parse(query)?;
+                return stale_answer(resolver, logger, &query, client_addr, start_time);
+            };
+            let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+            resolver.record_forwarded(elapsed);
+            logger.coalesced(&domain, client_addr, elapsed);
+            if resolver.is_slow(elapsed) {
+                logger.slow("COALESCED", &domain, qtype, client_addr, elapsed, None);
+            }
+            Some(response)
+        }
+    }
+}
+
+/// A query the resolver decided to forward (see [`QueryAction::Forward`]).
+pub(crate) struct ForwardedQuery {
+    pub domain: String,
+    pub qtype: u16,
+    pub traced: bool,
+    pub rule: Option<Arc<ForwardRule>>,
+    pub in_flight: InFlight,
+}
+
+/// Ask the upstreams over TCP, or the matched forwarding rule's upstreams.
+///
+/// Records stats and logs the outcome; returns the answer for the client, if any.
+pub(crate) async fn forward_query(
+    client_addr: SocketAddr,
+    query: &[u8],
+    start_time: Instant,
+    forward: ForwardedQuery,
+    upstreams: &[SocketAddr],
+    resolver: &Arc<Resolver>,
+    logger: &QueryLogger,
+) -> Option<Vec<u8>> {
+    let ForwardedQuery {
+        domain,
+        qtype,
+        traced,
+        rule,
+        in_flight,
+    } = forward;
+    let configured = match &rule {
+        Some(rule) => &rule.upstreams[..],
+        None => upstreams,
+    };
+    let upstreams = resolver.allowed_upstreams(configured);
+    if upstreams.is_empty() {
+        resolver.record_failed();
+        if traced {
+            logger.trace(
+                &domain,
+                format_args!("every upstream is over its rate limit"),
+            );
+        }
+        return unanswered(resolver, logger, query, client_addr, start_time);
+    }
+    // Rule upstreams are usually local resolvers, so their names are sent whole.
+    if rule.is_none()
+        && let Some(response) = resolver.minimize_qname(query, &upstreams).await
+    {
+        in_flight.complete(&response);
+        let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+        resolver.record_local(elapsed);
+        logger.local(&domain, client_addr, elapsed);
+        return Some(response);
+    }
+    let strategy = resolver.upstream_strategy();
+    if traced {
+        let upstream_strs: Vec<_> = upstreams.iter().map(|a| a.to_string()).collect();
+        let verb = match strategy {
+            UpstreamStrategy::Race => "racing",
+            UpstreamStrategy::Failover => "trying in order",
+        };
+        logger.trace(
+            &domain,
+            format_args!("{} upstreams {}", verb, upstream_strs.join(", ")),
+        );
+    }
+    let upstream_query = resolver.forwarded_query(query, client_addr.ip());
+    let upstream_start = Instant::now();
+    let check_divergence = resolver.should_check_divergence();
+    let answer = match strategy {
+        UpstreamStrategy::Race => {
+            race_upstreams_with_losers(&upstream_query, &upstreams, resolver).await
+        }
+        UpstreamStrategy::Failover => failover_upstreams(
+            &upstream_query,
+            &upstreams,
+            resolver.failover_timeout(),
+            resolver,
+        )
+        .await
+        .map(|(response, winner, attempt)| (response, winner, attempt, Vec::new())),
+    };
+    match answer {
+        Some((response, winner, attempt, losers)) => {
+            let question = DnsQuery::parse(query)?;
+            let Ok(replaced) = resolver.process_response(&question, &response) else {
+                resolver.record_failed();
+                if traced {
+                    logger.trace(
                         &domain,
-                        elapsed,
-                        upstream_start.elapsed().as_secs_f64() * 1000.0,
-                        winner,
+                        format_args!("upstream {} answered another question", winner),
                     );
                 }
+                return unanswered(resolver, logger, query, client_addr, start_time);
+            };
+            in_flight.complete(replaced.as_deref().unwrap_or(&response));
+            if check_divergence && !losers.is_empty() {
+                tokio::spawn(compare_losers(
+                    resolver.clone(),
+                    domain.clone(),
+                    winner,
+                    response.clone(),
+                    losers,
+                ));
             }
+            let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+            let upstream_elapsed = upstream_start.elapsed().as_secs_f64() * 1000.0;
+            resolver.record_forwarded(elapsed);
+            // Wins are tracked per global upstream; rule upstreams have no slot.
+            if rule.is_none()
+                && let Some(idx) = configured.iter().position(|&addr| addr == winner)
+            {
+                resolver.record_upstream_win(idx, upstream_elapsed);
+            }
+            if traced {
+                logger.trace(
+                    &domain,
+                    format_args!(
+                        "upstream {} answered first after {:.3}ms (attempt {}): {}",
+                        winner,
+                        upstream_elapsed,
+                        attempt,
+                        resolver.describe_response(&response)
+                    ),
+                );
+            }
+            logger.forwarded(
+                &domain,
+                client_addr,
+                elapsed,
+                (winner, upstream_elapsed),
+                attempt,
+                rule.as_ref().map(|rule| rule.suffix.as_str()),
+            );
+            if resolver.is_slow(elapsed) {
+                logger.slow(
+                    "FORWARDED",
+                    &domain,
+                    qtype,
+                    client_addr,
+                    elapsed,
+                    Some((winner, upstream_elapsed)),
+                );
+            }
+            Some(replaced.unwrap_or(response))
+        }
+        None => {
+            resolver.record_failed();
+            if traced {
+                logger.trace(&domain, format_args!("no upstream answered"));
+            }
+            unanswered(resolver, logger, query, client_addr, start_time)
         }
     }
 }
 
-async fn send_tcp_response(client: &mut TcpStream, response: &[u8]) {
-    let len_prefix = (response.len() as u16).to_be_bytes();
-    let _ = client.write_all(&len_prefix).await;
-    let _ = client.write_all(response).await;
+pub(crate) async fn send_tcp_response<S: AsyncWrite + Unpin>(client: &mut S, response: &[u8]) {
+    // One write for prefix and message, so pipelined answers are not held
+    // back by Nagle's algorithm.
+    let mut message = Vec::with_capacity(2 + response.len());
+    message.extend_from_slice(&(response.len() as u16).to_be_bytes());
+    message.extend_from_slice(response);
+    if client.write_all(&message).await.is_ok() {
+        // A no-op for plain TCP; pushes buffered records out for TLS.
+        let _ = client.flush().await;
+    }
 }
 
-async fn read_dns_message(stream: &mut TcpStream) -> Option<Vec<u8>> {
-    let mut buf = vec![0u8; MAX_DNS_PACKET_SIZE];
-    let mut total_read = 0;
+/// Read one length-prefixed DNS message, returning it without the prefix.
+///
+/// The buffer is sized from the prefix, so messages up to the 64KB TCP limit
+/// are read whole.
+pub(crate) async fn read_dns_message<S: AsyncRead + Unpin>(stream: &mut S) -> Option<Vec<u8>> {
+    let mut len_prefix = [0u8; 2];
+    stream.read_exact(&mut len_prefix).await.ok()?;
+    let msg_len = u16::from_be_bytes(len_prefix) as usize;
+    if msg_len == 0 {
+        return None;
+    }
 
-    loop {
-        match stream.read(&mut buf[total_read..]).await {
-            Ok(0) => return None,
-            Ok(n) => total_read += n,
-            Err(_) => return None,
-        }
+    let mut buf = vec![0u8; msg_len];
+    stream.read_exact(&mut buf).await.ok()?;
+    Some(buf)
+}
 
-        if total_read >= 2 {
-            let msg_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
-            if total_read >= 2 + msg_len {
-                buf.truncate(total_read);
-                return Some(buf);
-            }
+/// A query in flight to one upstream, resolving to its response (if any) and address.
+type UpstreamQuery = Pin<Box<dyn Future<Output = (Option<Vec<u8>>, SocketAddr)> + Send>>;
+
+/// Ask the upstreams with the resolver's strategy, returning the answer, its
+/// source and which attempt (1-based) produced it.
+pub(crate) async fn query_upstreams(
+    query: &[u8],
+    upstreams: &[SocketAddr],
+    resolver: &Resolver,
+) -> Option<(Vec<u8>, SocketAddr, usize)> {
+    match resolver.upstream_strategy() {
+        UpstreamStrategy::Race => race_upstreams_with_losers(query, upstreams, resolver)
+            .await
+            .map(|(response, winner, attempt, _)| (response, winner, attempt)),
+        UpstreamStrategy::Failover => {
+            failover_upstreams(query, upstreams, resolver.failover_timeout(), resolver).await
         }
     }
 }
 
-async fn race_upstreams(query: &[u8], upstreams: &[SocketAddr]) -> Option<(Vec<u8>, SocketAddr)> {
-    if upstreams.is_empty() {
-        return None;
+/// Try the upstreams one at a time, moving on after `timeout` or an error.
+///
+/// Returns the answer, its source and which attempt (1-based) produced it.
+async fn failover_upstreams(
+    query: &[u8],
+    upstreams: &[SocketAddr],
+    timeout: Duration,
+    resolver: &Resolver,
+) -> Option<(Vec<u8>, SocketAddr, usize)> {
+    let question = DnsQuery::parse(query);
+    for (i, &upstream) in upstreams.iter().enumerate() {
+        if i > 0 {
+            resolver.record_fallback();
+        }
+        let retries = resolver.upstream_retries();
+        let response =
+            tokio::time::timeout(timeout, forward_with_retries(query, upstream, retries)).await;
+        if let Some(response) = response
+            .ok()
+            .flatten()
+            .filter(|response| answers_question(resolver, question.as_ref(), response))
+        {
+            resolver.record_upstream_success(upstream);
+            return Some((response, upstream, i + 1));
+        }
+        resolver.record_upstream_failure(upstream);
     }
+    None
+}
+
+/// Race a query to the resolver's raced set of upstreams over TCP, returning
+/// the first response, its source, the attempt (2 if it came from the fallback
+/// tier) and the queries still in flight to the losing upstreams so their
+/// answers can be inspected.
+///
+/// Upstreams are raced in the resolver's ranking order. Upstreams past the
+/// race limit join the race only if none of the raced set has answered
+/// within the failover timeout. Each upstream is given up on
+/// after the resolver's upstream timeout, and an answer to another question
+/// counts as a failure; None once every one has failed.
+async fn race_upstreams_with_losers(
+    query: &[u8],
+    upstreams: &[SocketAddr],
+    resolver: &Resolver,
+) -> Option<(Vec<u8>, SocketAddr, usize, Vec<UpstreamQuery>)> {
+    use futures::stream::{FuturesUnordered, StreamExt};
 
+    let (timeout, retries) = (resolver.upstream_timeout(), resolver.upstream_retries());
+    let upstreams = &*resolver.rank_upstreams(upstreams);
+    let question = DnsQuery::parse(query);
+    let accepted = |response: &Vec<u8>| answers_question(resolver, question.as_ref(), response);
     if upstreams.len() == 1 {
-        return forward_to_upstream(query, upstreams[0])
-            .await
-            .map(|r| (r, upstreams[0]));
+        let (response, addr) = upstream_query(query, upstreams[0], timeout, retries).await;
+        let response = response.filter(accepted);
+        record_outcome(resolver, addr, response.is_some());
+        return response.map(|r| (r, addr, 1, Vec::new()));
     }
 
-    use futures::future::select_all;
-
-    let futures: Vec<_> = upstreams
+    let raced = resolver
+        .race_limit()
+        .map_or(upstreams.len(), |limit| limit.min(upstreams.len()));
+    let (raced, fallback) = upstreams.split_at(raced);
+    let mut in_flight: FuturesUnordered<UpstreamQuery> = raced
         .iter()
-        .map(|&addr| {
-            let q = query.to_vec();
-            Box::pin(async move { (forward_to_upstream(&q, addr).await, addr) })
-        })
+        .map(|&addr| upstream_query(query, addr, timeout, retries))
         .collect();
+    let fallback_timer = tokio::time::sleep(resolver.failover_timeout());
+    tokio::pin!(fallback_timer);
+    let (mut timer_fired, mut fallen_back) = (false, fallback.is_empty());
+
+    loop {
+        if !fallen_back && (timer_fired || in_flight.is_empty()) {
+            resolver.record_fallback();
+            in_flight.extend(
+                fallback
+                    .iter()
+                    .map(|&addr| upstream_query(query, addr, timeout, retries)),
+            );
+            fallen_back = true;
+        }
+        if in_flight.is_empty() {
+            return None;
+        }
+        tokio::select! {
+            Some((result, addr)) = in_flight.next() => {
+                let result = result.filter(accepted);
+                record_outcome(resolver, addr, result.is_some());
+                if let Some(response) = result {
+                    let attempt = if raced.contains(&addr) { 1 } else { 2 };
+                    return Some((response, addr, attempt, in_flight.into_iter().collect()));
+                }
+            }
+            _ = &mut fallback_timer, if !fallen_back => timer_fired = true,
+        }
+    }
+}
 
-    let mut remaining = futures;
-    while !remaining.is_empty() {
-        let ((result, addr), _, rest) = select_all(remaining).await;
-        if let Some(response) = result {
-            return Some((response, addr));
+/// Whether `response` answers `question`; answers to another question are
+/// counted as mismatched. Unparseable ones are left to the caller.
+fn answers_question(resolver: &Resolver, question: Option<&DnsQuery>, response: &[u8]) -> bool {
+    match (question, DnsQuery::parse(response)) {
+        (Some(question), Some(answered)) if !answered.same_question(question) => {
+            resolver.stats().record_mismatched();
+            false
+        }
+        _ => true,
+    }
+}
+
+/// Feed whether `upstream` answered to its circuit breaker.
+fn record_outcome(resolver: &Resolver, upstream: SocketAddr, answered: bool) {
+    if answered {
+        resolver.record_upstream_success(upstream);
+    } else {
+        resolver.record_upstream_failure(upstream);
+    }
+}
+
+/// Ask one upstream, giving up after `timeout`.
+fn upstream_query(
+    query: &[u8],
+    addr: SocketAddr,
+    timeout: Duration,
+    retries: u32,
+) -> UpstreamQuery {
+    let query = query.to_vec();
+    Box::pin(async move {
+        let response =
+            tokio::time::timeout(timeout, forward_with_retries(&query, addr, retries)).await;
+        (response.ok().flatten(), addr)
+    })
+}
+
+/// Ask `upstream`, trying again up to `retries` times after a failed
+/// exchange, waiting [`RETRY_BACKOFF`] before the first retry and twice as
+/// long before each one after.
+///
+/// Callers bound the whole loop with the upstream's timeout, so retries
+/// never stretch the time an upstream is given.
+async fn forward_with_retries(query: &[u8], upstream: SocketAddr, retries: u32) -> Option<Vec<u8>> {
+    let mut backoff = RETRY_BACKOFF;
+    for retry in 0..=retries {
+        if retry > 0 {
+            tokio::time::sleep(backoff).await;
+            backoff *= 2;
+        }
+        if let Some(response) = forward_to_upstream(query, upstream).await {
+            return Some(response);
         }
-        remaining = rest;
     }
     None
 }
 
-async fn forward_to_upstream(query: &[u8], upstream_addr: SocketAddr) -> Option<Vec<u8>> {
+/// Wait for the losing upstreams and compare their answers with the winner's.
+async fn compare_losers(
+    resolver: Arc<Resolver>,
+    domain: String,
+    winner: SocketAddr,
+    winner_response: Vec<u8>,
+    losers: Vec<UpstreamQuery>,
+) {
+    let logger = QueryLogger::new(Protocol::Tcp).with_exclusions(resolver.log_exclusions());
+    for (result, other) in futures::future::join_all(losers).await {
+        let Some(other_response) = result else {
+            continue;
+        };
+        if let Some(divergence) =
+            resolver.check_divergence(winner, &winner_response, other, &other_response)
+        {
+            logger.divergence(&domain, winner, other, &divergence);
+        }
+    }
+}
+
+pub(crate) async fn forward_to_upstream(
+    query: &[u8],
+    upstream_addr: SocketAddr,
+) -> Option<Vec<u8>> {
+    #[cfg(feature = "doq")]
+    if super::doq::is_registered(upstream_addr) {
+        return super::doq::query(upstream_addr, query).await;
+    }
+    #[cfg(feature = "doh-upstream")]
+    if super::doh::client::is_registered(upstream_addr) {
+        return super::doh::client::query(upstream_addr, query).await;
+    }
+
     let mut upstream = TcpStream::connect(upstream_addr).await.ok()?;
+    if let Some((connector, server_name)) = super::dot::upstream_connector(upstream_addr) {
+        let mut upstream = connector.connect(server_name, upstream).await.ok()?;
+        return exchange(&mut upstream, query).await;
+    }
+    exchange(&mut upstream, query).await
+}
 
-    let len_prefix = (query.len() as u16).to_be_bytes();
-    upstream.write_all(&len_prefix).await.ok()?;
-    upstream.write_all(query).await.ok()?;
+/// Send one length-prefixed query and read the answer.
+async fn exchange<S: AsyncRead + AsyncWrite + Unpin>(
+    upstream: &mut S,
+    query: &[u8],
+) -> Option<Vec<u8>> {
+    // One write, so a TLS upstream gets prefix and query in one record.
+    let mut message = Vec::with_capacity(2 + query.len());
+    message.extend_from_slice(&(query.len() as u16).to_be_bytes());
+    message.extend_from_slice(query);
+    upstream.write_all(&message).await.ok()?;
 
-    let mut buf = vec![0u8; MAX_DNS_PACKET_SIZE];
-    let mut total_read = 0;
+    read_dns_message(upstream).await
+}
 
-    loop {
-        match upstream.read(&mut buf[total_read..]).await {
-            Ok(0) => break,
-            Ok(n) => total_read += n,
-            Err(_) => return None,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::{DnsQuery, DnsResponse, TYPE_TXT};
+    use crate::filter::Blocklist;
+    use crate::resolver::{UpstreamCircuitBreaker, UpstreamRanker};
+    use crate::shutdown::ShutdownSignal;
 
-        if total_read >= 2 {
-            let msg_len = u16::from_be_bytes([buf[0], buf[1]]) as usize;
-            if total_read >= 2 + msg_len {
-                break;
+    /// Spawn a TCP upstream answering every query with ~8KB of TXT data.
+    async fn large_response_upstream() -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let query = read_dns_message(&mut stream).await.unwrap();
+                let mut rdata = Vec::new();
+                for i in 0..32u8 {
+                    rdata.push(249);
+                    rdata.extend(std::iter::repeat_n(b'a' + i % 26, 249));
+                }
+
+                let mut response = query;
+                response[2] = 0x81;
+                response[3] = 0x80;
+                response[7] = 1;
+                response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x10, 0x00, 0x01, 0, 0, 1, 44]);
+                response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+                response.extend_from_slice(&rdata);
+                send_tcp_response(&mut stream, &response).await;
             }
+        });
+        local
+    }
+
+    #[tokio::test]
+    async fn relays_responses_larger_than_udp_buffer() {
+        let upstream = large_response_upstream().await;
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+        let query = DnsQuery::new(7, "big.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send_tcp_response(&mut client, &query).await;
+        let response = read_dns_message(&mut client).await.unwrap();
+
+        assert!(response.len() > 8000);
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(forward_to_upstream(&query, upstream).await, Some(response));
+    }
+
+    #[tokio::test]
+    async fn answers_several_queries_on_one_connection() {
+        let upstream = large_response_upstream().await;
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+        let mut pipelined = Vec::new();
+        for id in 1..=10u16 {
+            let query = DnsQuery::new(id, &format!("q{}.example.com", id), TYPE_TXT)
+                .to_bytes()
+                .unwrap();
+            pipelined.extend_from_slice(&(query.len() as u16).to_be_bytes());
+            pipelined.extend_from_slice(&query);
+        }
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(&pipelined).await.unwrap();
+        let mut ids = Vec::new();
+        for _ in 0..10 {
+            let response = read_dns_message(&mut client).await.unwrap();
+            ids.push(u16::from_be_bytes([response[0], response[1]]));
         }
+
+        assert_eq!(ids, (1..=10).collect::<Vec<u16>>());
     }
 
-    if total_read <= 2 {
-        return None;
+    #[tokio::test]
+    async fn silent_connections_are_closed_after_idle_timeout() {
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_idle_timeout(Duration::from_millis(100));
+        let addr = transport.local_addr().unwrap();
+        transport.start(Vec::new(), Arc::new(Resolver::new(Blocklist::new())), false);
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn keepalive_settings_reach_the_socket() {
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_keepalive(Duration::from_secs(30), Duration::from_secs(5), 4);
+        let _client = TcpStream::connect(transport.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (accepted, _) = transport.listener.accept().await.unwrap();
+
+        transport.apply_keepalive(&accepted);
+
+        let socket = SockRef::from(&accepted);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(
+            socket.tcp_keepalive_time().unwrap(),
+            Duration::from_secs(30)
+        );
+        assert_eq!(
+            socket.tcp_keepalive_interval().unwrap(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(socket.tcp_keepalive_retries().unwrap(), 4);
     }
 
-    Some(buf[2..total_read].to_vec())
+    #[tokio::test]
+    async fn connections_over_the_cap_are_rejected() {
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_max_clients(1);
+        let addr = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        transport.start(Vec::new(), resolver.clone(), false);
+
+        let _held = TcpStream::connect(addr).await.unwrap();
+        let mut rejected = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), rejected.read(&mut buf)).await;
+
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        assert_eq!(resolver.stats_snapshot_and_reset().tcp_rejected, 1);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_the_listener_and_idle_connections() {
+        let upstream = large_response_upstream().await;
+        let signal = ShutdownSignal::new();
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap()
+            .with_shutdown(signal.handle());
+        let addr = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+        let query = DnsQuery::new(11, "drain.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send_tcp_response(&mut client, &query).await;
+        read_dns_message(&mut client).await.unwrap();
+
+        let drained = signal.drain(Duration::from_secs(5)).await;
+        let mut buf = [0u8; 1];
+        let read = tokio::time::timeout(Duration::from_secs(5), client.read(&mut buf)).await;
+
+        assert!(drained);
+        assert!(matches!(read, Ok(Ok(0)) | Ok(Err(_))));
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn failover_moves_on_from_an_upstream_that_does_not_answer() {
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let upstream = large_response_upstream().await;
+        let resolver = Resolver::new(Blocklist::new())
+            .with_upstream_strategy(UpstreamStrategy::Failover, Duration::from_millis(100));
+        let query = DnsQuery::new(9, "failover.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let (response, winner, attempt) =
+            query_upstreams(&query, &[silent_addr, upstream], &resolver)
+                .await
+                .unwrap();
+
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(winner, upstream);
+        assert_eq!(attempt, 2);
+        assert_eq!(resolver.stats_snapshot_and_reset().fallbacks, 1);
+    }
+
+    /// Spawn a TCP upstream echoing each query back as its answer after
+    /// `delay`, or answering a question about `other` instead if given.
+    async fn echo_upstream(delay: Duration, other: Option<&'static str>) -> SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let local = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let query = read_dns_message(&mut stream).await.unwrap();
+                    tokio::time::sleep(delay).await;
+                    let mut response = match other {
+                        Some(domain) => {
+                            let id = u16::from_be_bytes([query[0], query[1]]);
+                            DnsQuery::new(id, domain, TYPE_TXT).to_bytes().unwrap()
+                        }
+                        None => query,
+                    };
+                    response[2] = 0x81;
+                    response[3] = 0x80;
+                    send_tcp_response(&mut stream, &response).await;
+                });
+            }
+        });
+        local
+    }
+
+    #[tokio::test]
+    async fn races_drop_answers_to_another_question_and_keep_waiting() {
+        let mismatched = echo_upstream(Duration::ZERO, Some("evil.test")).await;
+        let upstream = echo_upstream(Duration::from_millis(50), None).await;
+        let resolver = Resolver::new(Blocklist::new()).with_circuit_breaker(
+            UpstreamCircuitBreaker::new(&[mismatched, upstream], 1, Duration::from_secs(60)),
+        );
+        let query = DnsQuery::new(14, "race.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let (response, winner, attempt) =
+            query_upstreams(&query, &[mismatched, upstream], &resolver)
+                .await
+                .unwrap();
+
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!((winner, attempt), (upstream, 1));
+        assert!(!resolver.upstream_allowed(mismatched));
+        assert!(resolver.upstream_allowed(upstream));
+        assert_eq!(resolver.stats_snapshot_and_reset().mismatched, 1);
+    }
+
+    #[tokio::test]
+    async fn failover_moves_on_from_an_answer_to_another_question() {
+        let mismatched = echo_upstream(Duration::ZERO, Some("evil.test")).await;
+        let upstream = echo_upstream(Duration::ZERO, None).await;
+        let resolver = Resolver::new(Blocklist::new())
+            .with_upstream_strategy(UpstreamStrategy::Failover, Duration::from_millis(100))
+            .with_circuit_breaker(UpstreamCircuitBreaker::new(
+                &[mismatched, upstream],
+                1,
+                Duration::from_secs(60),
+            ));
+        let query = DnsQuery::new(15, "failover.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let (_, winner, attempt) = query_upstreams(&query, &[mismatched, upstream], &resolver)
+            .await
+            .unwrap();
+
+        assert_eq!((winner, attempt), (upstream, 2));
+        assert!(!resolver.upstream_allowed(mismatched));
+        assert!(resolver.upstream_allowed(upstream));
+    }
+
+    #[tokio::test]
+    async fn limited_race_adds_the_fallback_tier_after_the_timeout() {
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let upstream = large_response_upstream().await;
+        let resolver = Resolver::new(Blocklist::new())
+            .with_upstream_strategy(UpstreamStrategy::Race, Duration::from_millis(100))
+            .with_race_limit(Some(1));
+        let query = DnsQuery::new(10, "tier.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let (response, winner, attempt) =
+            query_upstreams(&query, &[silent_addr, upstream], &resolver)
+                .await
+                .unwrap();
+
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(winner, upstream);
+        assert_eq!(attempt, 2);
+        assert_eq!(resolver.stats_snapshot_and_reset().fallbacks, 1);
+    }
+
+    #[tokio::test]
+    async fn ranking_races_the_fastest_upstreams_first() {
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let upstream = large_response_upstream().await;
+        let upstreams = [silent_addr, upstream];
+        let resolver = Resolver::new(Blocklist::new())
+            .with_upstream_strategy(UpstreamStrategy::Race, Duration::from_millis(100))
+            .with_race_limit(Some(1))
+            .with_upstream_ranker(UpstreamRanker::new(&upstreams));
+        let query = DnsQuery::new(11, "ranked.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let (_, _, unranked_attempt) = query_upstreams(&query, &upstreams, &resolver)
+            .await
+            .unwrap();
+        resolver.record_upstream_win(1, 100.0);
+        let ranked = resolver.rank_upstreams(&upstreams).into_owned();
+        let (_, winner, attempt) = query_upstreams(&query, &upstreams, &resolver)
+            .await
+            .unwrap();
+
+        assert_eq!(unranked_attempt, 2);
+        assert_eq!(ranked, [upstream, silent_addr]);
+        assert_eq!(winner, upstream);
+        assert_eq!(attempt, 1);
+        assert_eq!(resolver.stats_snapshot_and_reset().fallbacks, 1);
+    }
+
+    #[tokio::test]
+    async fn failed_exchanges_are_retried_with_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let flaky = listener.local_addr().unwrap();
+        // Hangs up on the first two connections, then answers.
+        tokio::spawn(async move {
+            for _ in 0..2 {
+                drop(listener.accept().await.unwrap());
+            }
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut response = read_dns_message(&mut stream).await.unwrap();
+                response[2] = 0x81;
+                response[3] = 0x80;
+                send_tcp_response(&mut stream, &response).await;
+            }
+        });
+        let once = Resolver::new(Blocklist::empty());
+        let retrying = Resolver::new(Blocklist::empty()).with_upstream_retries(2);
+        let query = DnsQuery::new(13, "flaky.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let without_retries = query_upstreams(&query, &[flaky], &once).await;
+        let start = Instant::now();
+        let with_retries = query_upstreams(&query, &[flaky], &retrying).await;
+
+        assert!(without_retries.is_none());
+        assert_eq!(with_retries.unwrap().0[..2], query[..2]);
+        assert!(start.elapsed() >= RETRY_BACKOFF);
+    }
+
+    #[tokio::test]
+    async fn clients_get_servfail_when_every_upstream_times_out() {
+        let silent = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let silent_addr = silent.local_addr().unwrap();
+        let transport = TcpTransport::bind("127.0.0.1:0".parse().unwrap())
+            .await
+            .unwrap();
+        let addr = transport.local_addr().unwrap();
+        let resolver =
+            Resolver::new(Blocklist::new()).with_upstream_timeout(Duration::from_millis(100));
+        transport.start(vec![silent_addr, silent_addr], Arc::new(resolver), false);
+        let query = DnsQuery::new(12, "slow.example.com", TYPE_TXT)
+            .to_bytes()
+            .unwrap();
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        send_tcp_response(&mut client, &query).await;
+        let response = tokio::time::timeout(Duration::from_secs(5), read_dns_message(&mut client))
+            .await
+            .unwrap()
+            .unwrap();
+
+        assert_eq!(response[..2], query[..2]);
+        assert_eq!(DnsResponse::parse(&response).unwrap().rcode(), 2);
+    }
 }
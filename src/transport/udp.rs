@@ -1,78 +1,448 @@
 //! UDP transport for DNS queries.
 //!
 //! Handles connectionless DNS queries over UDP. Since UDP is stateless,
-//! we track pending queries by their 16-bit query ID to route responses
-//! back to the correct client. Races queries to multiple upstreams.
+//! each forwarded query gets its own random outgoing 16-bit ID (so an
+//! off-path attacker has to guess it as well as the port), and pending queries
+//! are tracked by that ID to route responses back to the correct client
+//! (with its original ID restored). Races queries to multiple upstreams, or
+//! with the failover strategy sends to one at a time and moves on to the next
+//! when the sweep finds it has not answered in time; an upstream socket only
+//! accepts answers from the upstream it sends to.
+//! DNS-over-QUIC, DNS-over-HTTPS and DNS-over-TLS upstreams, and upstreams
+//! past the race limit (which get no socket of their own), are queried from a
+//! task each over QUIC, HTTPS, TLS or TCP; their answers are fed back as if
+//! read from that upstream's socket. Queries matching a forwarding rule are
+//! handed to a task of their own that asks the rule's upstreams over TCP and
+//! answers the client directly. With a client rate limit, queries from an
+//! address over its budget are dropped before they are parsed.
+//!
+//! A single client socket serializes packet receipt on one task. With
+//! [`UdpTransport::bind_reuseport`] several sockets share the address through
+//! `SO_REUSEPORT` and the kernel spreads clients across them; each socket runs
+//! its own loop with its own upstream sockets and pending queries, so the
+//! loops share nothing but the resolver and the client rate limits, which
+//! are kept per client address whichever socket its queries arrive on.
+//!
+//! On shutdown each loop stops reading client sockets and keeps serving
+//! upstream answers until its pending queries are answered or time out.
 
+use rustc_hash::{FxHashMap, FxHasher};
 use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
 use std::io;
-use std::net::SocketAddr;
-use std::sync::Arc;
-use std::time::Instant;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Range;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
 
-use crate::resolver::{QueryAction, Resolver};
+use crate::dns::DnsQuery;
+use crate::logging;
+use crate::resolver::{InFlight, QnameMinimization, QueryAction, Resolver};
+use crate::shutdown::Shutdown;
+use crate::upstream::{RateLimiter, UpstreamStrategy};
 
-use super::{MAX_DNS_PACKET_SIZE, Protocol, QueryLogger};
+use super::tcp::ForwardedQuery;
+use super::{MAX_DNS_PACKET_SIZE, Protocol, QueryLogger, stale_answer, unanswered};
 
 /// UDP transport for DNS proxy.
 pub struct UdpTransport {
-    socket: Arc<UdpSocket>,
-    upstream_sockets: Vec<Arc<UdpSocket>>,
+    listeners: Vec<Listener>,
+    max_packet_size: usize,
+    query_timeout: Duration,
+    /// Queries per second allowed from each client address (None = unlimited).
+    client_rate_limit: Option<u32>,
+    shutdown: Shutdown,
 }
 
 impl UdpTransport {
     /// Bind UDP sockets for the transport.
     pub async fn bind(addr: SocketAddr, upstream_count: usize) -> io::Result<Self> {
-        let socket = Arc::new(UdpSocket::bind(addr).await?);
+        let listener = Listener::new(UdpSocket::bind(addr).await?, upstream_count).await?;
+        Ok(Self::with_listeners(vec![listener]))
+    }
+
+    /// Bind `n` client sockets to `addr` with `SO_REUSEPORT`, each served by
+    /// its own loop with `upstream_count` upstream sockets of its own.
+    ///
+    /// With port 0 the first socket picks the port and the rest join it.
+    pub async fn bind_reuseport(
+        addr: SocketAddr,
+        n: usize,
+        upstream_count: usize,
+    ) -> io::Result<Self> {
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "at least one UDP socket is needed",
+            ));
+        }
+        let first = bind_reuseport_socket(addr)?;
+        let addr = first.local_addr()?;
+        let mut listeners = vec![Listener::new(first, upstream_count).await?];
+        for _ in 1..n {
+            listeners.push(Listener::new(bind_reuseport_socket(addr)?, upstream_count).await?);
+        }
+        Ok(Self::with_listeners(listeners))
+    }
+
+    fn with_listeners(listeners: Vec<Listener>) -> Self {
+        Self {
+            listeners,
+            max_packet_size: MAX_DNS_PACKET_SIZE,
+            query_timeout: DEFAULT_QUERY_TIMEOUT,
+            client_rate_limit: None,
+            shutdown: Shutdown::default(),
+        }
+    }
+
+    /// Set the receive buffer size; larger datagrams are truncated.
+    pub fn with_max_packet_size(mut self, size: usize) -> Self {
+        self.max_packet_size = size;
+        self
+    }
+
+    /// Answer SERVFAIL if no upstream responds within `timeout`.
+    pub fn with_query_timeout(mut self, timeout: Duration) -> Self {
+        self.query_timeout = timeout;
+        self
+    }
+
+    /// Drop queries from a client address beyond `pps` per second (with
+    /// bursts of up to a second's worth), counted across all client sockets;
+    /// 0 turns the limit off.
+    pub fn with_client_rate_limit(mut self, pps: u32) -> Self {
+        self.client_rate_limit = (pps > 0).then_some(pps);
+        self
+    }
+
+    /// Stop reading queries when `shutdown` is triggered, holding it until
+    /// the queries already forwarded are answered.
+    pub fn with_shutdown(mut self, shutdown: Shutdown) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Address the client sockets are bound to.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listeners[0].socket.local_addr()
+    }
+
+    /// Number of client sockets, each served by its own loop.
+    pub fn socket_count(&self) -> usize {
+        self.listeners.len()
+    }
+
+    /// Start the UDP transport, one task per client socket.
+    pub fn start(self, upstreams: Vec<SocketAddr>, resolver: Arc<Resolver>, verbose: bool) {
+        let UdpTransport {
+            listeners,
+            max_packet_size,
+            query_timeout,
+            client_rate_limit,
+            shutdown,
+        } = self;
+        let settings = LoopSettings {
+            max_packet_size,
+            query_timeout,
+        };
+        let client_limits = client_rate_limit.map(|pps| Arc::new(ClientLimits::new(pps)));
+        for listener in listeners {
+            tokio::spawn(run(
+                listener,
+                settings,
+                client_limits.clone(),
+                shutdown.clone(),
+                upstreams.clone(),
+                resolver.clone(),
+                verbose,
+            ));
+        }
+    }
+}
+
+/// Settings every loop of a transport shares.
+#[derive(Clone, Copy)]
+struct LoopSettings {
+    max_packet_size: usize,
+    query_timeout: Duration,
+}
+
+/// A client socket and the upstream sockets of the loop serving it.
+struct Listener {
+    socket: Arc<UdpSocket>,
+    upstream_sockets: Vec<Arc<UdpSocket>>,
+}
+
+impl Listener {
+    async fn new(socket: UdpSocket, upstream_count: usize) -> io::Result<Self> {
         let mut upstream_sockets = Vec::with_capacity(upstream_count);
         for _ in 0..upstream_count {
             upstream_sockets.push(Arc::new(UdpSocket::bind("0.0.0.0:0").await?));
         }
-        Ok(Self { socket, upstream_sockets })
+        Ok(Self {
+            socket: Arc::new(socket),
+            upstream_sockets,
+        })
     }
+}
 
-    /// Start the UDP transport.
-    pub fn start(self, upstreams: Vec<SocketAddr>, resolver: Arc<Resolver>, verbose: bool) {
-        tokio::spawn(run(
-            self.socket,
-            self.upstream_sockets,
-            upstreams,
-            resolver,
-            verbose,
-        ));
+/// Bind a UDP socket to `addr` that other `SO_REUSEPORT` sockets can share.
+#[cfg(unix)]
+fn bind_reuseport_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(
+        Domain::for_address(addr),
+        Type::DGRAM,
+        Some(socket2::Protocol::UDP),
+    )?;
+    socket.set_reuse_port(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+#[cfg(not(unix))]
+fn bind_reuseport_socket(_addr: SocketAddr) -> io::Result<UdpSocket> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "SO_REUSEPORT is only available on Unix",
+    ))
+}
+
+/// How long a forwarded query waits for an upstream answer by default.
+pub const DEFAULT_QUERY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often pending queries are checked against the timeout.
+const SWEEP_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Lower bound on the sweep interval when it follows a short failover timeout.
+const MIN_SWEEP_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long losing upstreams' answers are awaited when checking divergence.
+const DIVERGENCE_WINDOW: Duration = Duration::from_secs(5);
+
+/// How often clients with a full rate limit budget are forgotten.
+const CLIENT_EVICT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Independently locked shards of the client budgets, so the loops of a
+/// `SO_REUSEPORT` transport seldom wait on each other.
+const CLIENT_LIMIT_SHARDS: usize = 16;
+
+/// Per-client query budgets, shared by every loop of a transport.
+struct ClientLimits {
+    pps: u32,
+    shards: Box<[Mutex<FxHashMap<IpAddr, RateLimiter>>]>,
+    last_eviction: Mutex<Instant>,
+}
+
+impl ClientLimits {
+    fn new(pps: u32) -> Self {
+        Self {
+            pps,
+            shards: (0..CLIENT_LIMIT_SHARDS).map(|_| Mutex::default()).collect(),
+            last_eviction: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Take one query from the budget of `client`, returning false if over the rate.
+    fn allow(&self, client: IpAddr) -> bool {
+        let mut hasher = FxHasher::default();
+        client.hash(&mut hasher);
+        let Ok(mut clients) = self.shards[hasher.finish() as usize % CLIENT_LIMIT_SHARDS].lock()
+        else {
+            return true;
+        };
+        clients
+            .entry(client)
+            .or_insert_with(|| RateLimiter::new(self.pps))
+            .try_acquire()
+    }
+
+    /// Forget clients whose budget has refilled, at most once per
+    /// [`CLIENT_EVICT_INTERVAL`] across all loops.
+    fn evict_idle(&self) {
+        let Ok(mut last_eviction) = self.last_eviction.try_lock() else {
+            return;
+        };
+        if last_eviction.elapsed() < CLIENT_EVICT_INTERVAL {
+            return;
+        }
+        *last_eviction = Instant::now();
+        for shard in &self.shards {
+            if let Ok(mut clients) = shard.lock() {
+                clients.retain(|_, limiter| !limiter.is_idle());
+            }
+        }
     }
 }
 
 struct PendingQuery {
     client_addr: SocketAddr,
+    /// The ID the client used, restored in the response.
+    client_id: u16,
     domain: String,
+    qtype: u16,
+    traced: bool,
+    /// Identical queries waiting on this one's answer.
+    in_flight: InFlight,
+    /// Number of upstreams the query was sent to.
+    racing: usize,
+    check_divergence: bool,
     start_time: Instant,
     upstream_start: Instant,
+    /// The query as sent upstream, kept for later attempts.
+    upstream_query: Vec<u8>,
+    /// The upstream indices the query was sent to, in order.
+    tried: Vec<usize>,
+    /// When the latest attempt was sent.
+    attempt_start: Instant,
+    /// Limited race only: the upstreams past the limit have not been sent the query yet.
+    held_back: bool,
+}
+
+impl PendingQuery {
+    /// Which attempt (1-based) reached the upstream at `index`.
+    fn attempt(&self, index: usize) -> usize {
+        self.tried
+            .iter()
+            .position(|&i| i == index)
+            .map_or(1, |p| p + 1)
+    }
+}
+
+/// An answer fetched by a task: the upstream's index, address and message.
+type TaskAnswer = (usize, SocketAddr, Vec<u8>);
+
+/// A winning answer kept so the losing upstreams' answers can be compared with it.
+struct AnsweredQuery {
+    domain: String,
+    winner: SocketAddr,
+    response: Vec<u8>,
+    answered_at: Instant,
+    outstanding: usize,
 }
 
 async fn run(
-    socket: Arc<UdpSocket>,
-    upstream_sockets: Vec<Arc<UdpSocket>>,
+    listener: Listener,
+    settings: LoopSettings,
+    client_limits: Option<Arc<ClientLimits>>,
+    mut shutdown: Shutdown,
     upstreams: Vec<SocketAddr>,
     resolver: Arc<Resolver>,
     verbose: bool,
 ) {
-    let logger = QueryLogger::new(Protocol::Udp);
+    let Listener {
+        socket,
+        upstream_sockets,
+    } = listener;
+    let LoopSettings {
+        max_packet_size,
+        query_timeout,
+    } = settings;
+    let logger = QueryLogger::new(Protocol::Udp)
+        .with_verbose(verbose)
+        .with_exclusions(resolver.log_exclusions());
     let mut pending: HashMap<u16, PendingQuery> = HashMap::new();
-    let mut client_buf = [0u8; MAX_DNS_PACKET_SIZE];
-    let mut upstream_bufs: Vec<[u8; MAX_DNS_PACKET_SIZE]> =
-        vec![[0u8; MAX_DNS_PACKET_SIZE]; upstream_sockets.len()];
+    let mut answered: HashMap<u16, AnsweredQuery> = HashMap::new();
+    let mut client_buf = vec![0u8; max_packet_size];
+    let mut upstream_bufs = vec![vec![0u8; max_packet_size]; upstreams.len()];
+    // Both halves live here, so the receiver never reports closed.
+    let mut task_answers = mpsc::unbounded_channel::<TaskAnswer>();
+    let strategy = resolver.upstream_strategy();
+    let minimize = resolver.qname_minimization() != QnameMinimization::Off;
+    let failover_timeout = resolver.failover_timeout();
+    let raced = resolver
+        .race_limit()
+        .map_or(upstreams.len(), |limit| limit.min(upstreams.len()));
+
+    let sweep_interval = if strategy == UpstreamStrategy::Failover || raced < upstreams.len() {
+        SWEEP_INTERVAL.min(failover_timeout).max(MIN_SWEEP_INTERVAL)
+    } else {
+        SWEEP_INTERVAL
+    };
+    let mut sweep = tokio::time::interval(sweep_interval);
+    sweep.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    // Set on shutdown: client sockets are no longer read, and the loop ends
+    // once nothing is pending.
+    let mut draining = false;
 
     loop {
+        if draining && pending.is_empty() {
+            break;
+        }
         tokio::select! {
             biased;
 
-            result = socket.recv_from(&mut client_buf) => {
+            _ = shutdown.triggered(), if !draining => {
+                draining = true;
+            }
+
+            _ = sweep.tick() => {
+                if let Some(limits) = &client_limits {
+                    limits.evict_idle();
+                }
+                if strategy == UpstreamStrategy::Failover {
+                    let stalled = pending.values_mut().filter(|pq| {
+                        pq.attempt_start.elapsed() >= failover_timeout
+                            && pq.tried.last().is_some_and(|&i| i + 1 < upstreams.len())
+                    });
+                    for pq in stalled {
+                        let last = upstreams[pq.tried[pq.tried.len() - 1]];
+                        resolver.record_upstream_failure(last);
+                        if pq.traced {
+                            logger.trace(&pq.domain, format_args!("upstream {} did not answer within {:?}", last, failover_timeout));
+                        }
+                        send_next_upstream(pq, &upstreams, &upstream_sockets, &task_answers.0, &resolver, &logger).await;
+                    }
+                }
+                let held_back = pending.values_mut().filter(|pq| pq.held_back && pq.attempt_start.elapsed() >= failover_timeout);
+                for pq in held_back {
+                    if pq.traced {
+                        logger.trace(&pq.domain, format_args!("no raced upstream answered within {:?}, adding the rest", failover_timeout));
+                    }
+                    resolver.record_fallback();
+                    pq.held_back = false;
+                    send_to_all(pq, raced..upstreams.len(), &upstreams, &upstream_sockets, &task_answers.0, &resolver, &logger).await;
+                }
+
+                let expired: Vec<u16> = pending
+                    .iter()
+                    .filter(|(_, pq)| pq.upstream_start.elapsed() >= query_timeout)
+                    .map(|(&id, _)| id)
+                    .collect();
+                if expired.is_empty() {
+                    continue;
+                }
+                resolver.add_pending(-(expired.len() as isize));
+                for id in expired {
+                    let Some(pq) = pending.remove(&id) else { continue };
+                    // Failover already counted the upstreams before the last one.
+                    let silent = match strategy {
+                        UpstreamStrategy::Race => &pq.tried[..],
+                        UpstreamStrategy::Failover => &pq.tried[pq.tried.len().saturating_sub(1)..],
+                    };
+                    for &i in silent {
+                        resolver.record_upstream_failure(upstreams[i]);
+                    }
+                    let query = DnsQuery::new(pq.client_id, &pq.domain, pq.qtype);
+                    let response = stale_answer(&resolver, &logger, &query, pq.client_addr, pq.start_time)
+                        .unwrap_or_else(|| query.servfail_response().to_bytes());
+                    let _ = socket.send_to(&response, pq.client_addr).await;
+                    resolver.record_timeout();
+                    if pq.traced {
+                        logger.trace(&pq.domain, format_args!("no upstream answered within {:?}", query_timeout));
+                    }
+                }
+            }
+
+            result = socket.recv_from(&mut client_buf), if !draining => {
                 let (len, src) = match result {
                     Ok(r) => r,
                     Err(e) => {
-                        eprintln!("UDP recv error: {}", e);
+                        logging::error(format_args!("UDP recv error: {}", e));
                         continue;
                     }
                 };
@@ -80,52 +450,185 @@ async fn run(
                 if len < 12 {
                     continue;
                 }
+                if let Some(limits) = &client_limits
+                    && !limits.allow(src.ip())
+                {
+                    resolver.record_rate_limited();
+                    continue;
+                }
 
                 let start_time = Instant::now();
                 let query = &client_buf[..len];
 
                 match resolver.process_query(query) {
                     QueryAction::Invalid => continue,
-                    QueryAction::Blocked { response, domain } => {
+                    QueryAction::Blocked { response, domain, qtype } => {
                         let _ = socket.send_to(&response, src).await;
                         let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
                         resolver.record_blocked(elapsed);
-                        if verbose {
-                            logger.blocked(&domain, elapsed);
+                        logger.blocked(&domain, src, elapsed, resolver.blocked_style());
+                        if resolver.is_slow(elapsed) {
+                            logger.slow("BLOCKED", &domain, qtype, src, elapsed, None);
+                        }
+                    }
+                    QueryAction::Cached { response, domain, qtype } => {
+                        let _ = socket.send_to(&response, src).await;
+                        let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+                        resolver.record_cached(elapsed);
+                        logger.cached(&domain, src, elapsed);
+                        if resolver.is_slow(elapsed) {
+                            logger.slow("CACHED", &domain, qtype, src, elapsed, None);
                         }
                     }
-                    QueryAction::Cached { response, domain } => {
+                    QueryAction::CachedStale { response, domain, qtype } => {
                         let _ = socket.send_to(&response, src).await;
                         let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
                         resolver.record_cached(elapsed);
-                        if verbose {
-                            logger.cached(&domain, elapsed);
+                        logger.cached(&domain, src, elapsed);
+                        if resolver.is_slow(elapsed) {
+                            logger.slow("CACHED", &domain, qtype, src, elapsed, None);
+                        }
+                        let resolver = resolver.clone();
+                        let upstreams = upstreams.clone();
+                        tokio::spawn(async move { resolver.refresh(&domain, qtype, &upstreams).await });
+                    }
+                    QueryAction::Local { response, domain, qtype } => {
+                        let _ = socket.send_to(&response, src).await;
+                        let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+                        resolver.record_local(elapsed);
+                        logger.local(&domain, src, elapsed);
+                        if resolver.is_slow(elapsed) {
+                            logger.slow("LOCAL", &domain, qtype, src, elapsed, None);
                         }
                     }
-                    QueryAction::Forward { domain } => {
-                        let query_id = u16::from_be_bytes([client_buf[0], client_buf[1]]);
+                    QueryAction::Coalesced { domain, qtype, traced, answer } => {
+                        // Wait for the identical forward in flight from a task, so the
+                        // loop keeps reading its answer and everything else.
+                        let client_id = u16::from_be_bytes([query[0], query[1]]);
+                        let (socket, query, resolver, logger, shutdown) = (socket.clone(), query.to_vec(), resolver.clone(), logger.clone(), shutdown.clone());
+                        tokio::spawn(async move {
+                            let _shutdown = shutdown;
+                            let response = match tokio::time::timeout(query_timeout, answer.response(client_id)).await {
+                                Ok(Some(response)) => {
+                                    let elapsed = start_time.elapsed().as_secs_f64() * 1000.0;
+                                    resolver.record_forwarded(elapsed);
+                                    logger.coalesced(&domain, src, elapsed);
+                                    if resolver.is_slow(elapsed) {
+                                        logger.slow("COALESCED", &domain, qtype, src, elapsed, None);
+                                    }
+                                    Some(response)
+                                }
+                                Ok(None) => {
+                                    resolver.record_failed();
+                                    if traced {
+                                        logger.trace(&domain, format_args!("the forward in flight got no answer"));
+                                    }
+                                    unanswered(&resolver, &logger, &query, src, start_time)
+                                }
+                                Err(_) => {
+                                    resolver.record_timeout();
+                                    unanswered(&resolver, &logger, &query, src, start_time)
+                                }
+                            };
+                            if let Some(response) = response
+                                && let Err(e) = socket.send_to(&response, src).await
+                            {
+                                logging::error(format_args!("UDP response error: {}", e));
+                            }
+                        });
+                    }
+                    QueryAction::Forward { domain, qtype, traced, rule, in_flight } if rule.is_some() || minimize => {
+                        // Rule upstreams have no sockets here, and minimising takes several
+                        // round trips: ask over TCP from a task that answers the client
+                        // itself, SERVFAIL if no upstream answers in time.
+                        let forward = ForwardedQuery { domain: domain.clone(), qtype, traced, rule, in_flight };
+                        let (socket, query, upstreams, resolver, logger, shutdown) = (socket.clone(), query.to_vec(), upstreams.clone(), resolver.clone(), logger.clone(), shutdown.clone());
+                        tokio::spawn(async move {
+                            let _shutdown = shutdown;
+                            let answer = tokio::time::timeout(query_timeout, super::tcp::forward_query(src, &query, start_time, forward, &upstreams, &resolver, &logger)).await;
+                            let response = match answer {
+                                Ok(Some(response)) => Some(response),
+                                Ok(None) => resolver.servfail(&query),
+                                Err(_) => {
+                                    resolver.record_timeout();
+                                    if traced {
+                                        logger.trace(&domain, format_args!("no upstream answered within {:?}", query_timeout));
+                                    }
+                                    resolver.servfail(&query)
+                                }
+                            };
+                            if let Some(response) = response
+                                && let Err(e) = socket.send_to(&response, src).await
+                            {
+                                logging::error(format_args!("UDP response error: {}", e));
+                            }
+                        });
+                    }
+                    QueryAction::Forward { domain, qtype, traced, in_flight, .. } => {
+                        let client_id = u16::from_be_bytes([query[0], query[1]]);
+                        let upstream_id = allocate_id(&pending, &answered);
+                        let mut upstream_query = resolver.forwarded_query(query, src.ip()).into_owned();
+                        upstream_query[..2].copy_from_slice(&upstream_id.to_be_bytes());
                         let upstream_start = Instant::now();
-                        pending.insert(query_id, PendingQuery {
+                        let mut pq = PendingQuery {
                             client_addr: src,
+                            client_id,
                             domain,
+                            qtype,
+                            traced,
+                            in_flight,
+                            racing: 0,
+                            check_divergence: false,
                             start_time,
                             upstream_start,
-                        });
+                            upstream_query,
+                            tried: Vec::new(),
+                            attempt_start: upstream_start,
+                            held_back: false,
+                        };
+
+                        match strategy {
+                            UpstreamStrategy::Race => {
+                                send_to_all(&mut pq, 0..raced, &upstreams, &upstream_sockets, &task_answers.0, &resolver, &logger).await;
+                                if raced < upstreams.len() {
+                                    if pq.racing == 0 {
+                                        resolver.record_fallback();
+                                        send_to_all(&mut pq, raced..upstreams.len(), &upstreams, &upstream_sockets, &task_answers.0, &resolver, &logger).await;
+                                    } else {
+                                        pq.held_back = true;
+                                    }
+                                }
+                                pq.check_divergence = pq.racing > 1 && resolver.should_check_divergence();
+                            }
+                            UpstreamStrategy::Failover => {
+                                if send_next_upstream(&mut pq, &upstreams, &upstream_sockets, &task_answers.0, &resolver, &logger).await {
+                                    pq.racing = 1;
+                                }
+                            }
+                        }
 
-                        for (i, upstream_addr) in upstreams.iter().enumerate() {
-                            if let Err(e) = upstream_sockets[i].send_to(query, upstream_addr).await {
-                                eprintln!("UDP forward error to {}: {}", upstream_addr, e);
+                        if pq.racing == 0 {
+                            if let Some(response) = unanswered(&resolver, &logger, query, src, start_time) {
+                                let _ = socket.send_to(&response, src).await;
+                            }
+                            resolver.record_failed();
+                            if traced {
+                                logger.trace(&pq.domain, format_args!("every upstream is over its rate limit"));
                             }
+                            continue;
                         }
+
+                        pending.insert(upstream_id, pq);
+                        resolver.add_pending(1);
                     }
                 }
             }
 
-            result = recv_from_any(&upstream_sockets, &mut upstream_bufs) => {
+            result = recv_from_any(&upstream_sockets, &mut upstream_bufs, &mut task_answers.1) => {
                 let (sock_idx, len, from_addr) = match result {
                     Ok(r) => r,
                     Err(e) => {
-                        eprintln!("UDP upstream recv error: {}", e);
+                        logging::error(format_args!("UDP upstream recv error: {}", e));
                         continue;
                     }
                 };
@@ -134,19 +637,75 @@ async fn run(
                     continue;
                 }
 
-                let response = &upstream_bufs[sock_idx][..len];
+                // Each socket only talks to its own upstream; anything else is
+                // an off-path injection attempt.
+                if from_addr != upstreams[sock_idx] {
+                    resolver.record_spoofed();
+                    continue;
+                }
+
+                let response = &mut upstream_bufs[sock_idx][..len];
                 let query_id = u16::from_be_bytes([response[0], response[1]]);
 
                 if let Some(pq) = pending.remove(&query_id) {
-                    if let Err(e) = socket.send_to(response, pq.client_addr).await {
-                        eprintln!("UDP response error: {}", e);
+                    response[..2].copy_from_slice(&pq.client_id.to_be_bytes());
+                    let response = &*response;
+                    // An answer to another question is dropped; the query stays
+                    // pending for the other upstreams or until it times out.
+                    let processed = DnsQuery::parse(&pq.upstream_query).map(|question| resolver.process_response(&question, response));
+                    let Some(Ok(replaced)) = processed else {
+                        if pq.traced {
+                            logger.trace(&pq.domain, format_args!("upstream {} answered another question", from_addr));
+                        }
+                        pending.insert(query_id, pq);
+                        continue;
+                    };
+                    resolver.add_pending(-1);
+                    resolver.record_upstream_success(from_addr);
+                    if let Err(e) = socket.send_to(replaced.as_deref().unwrap_or(response), pq.client_addr).await {
+                        logging::error(format_args!("UDP response error: {}", e));
                     }
-                    resolver.process_response(response);
 
                     let elapsed = pq.start_time.elapsed().as_secs_f64() * 1000.0;
+                    let upstream_elapsed = pq.upstream_start.elapsed().as_secs_f64() * 1000.0;
+                    let attempt = match strategy {
+                        UpstreamStrategy::Race if sock_idx < raced => 1,
+                        UpstreamStrategy::Race => 2,
+                        UpstreamStrategy::Failover => pq.attempt(sock_idx),
+                    };
+                    pq.in_flight.complete(replaced.as_deref().unwrap_or(response));
                     resolver.record_forwarded(elapsed);
-                    if verbose {
-                        logger.forwarded(&pq.domain, elapsed, pq.upstream_start.elapsed().as_secs_f64() * 1000.0, from_addr);
+                    resolver.record_upstream_win(sock_idx, upstream_elapsed);
+                    if pq.traced {
+                        logger.trace(&pq.domain, format_args!(
+                            "upstream {} answered first after {:.3}ms (attempt {}): {}",
+                            from_addr,
+                            upstream_elapsed,
+                            attempt,
+                            resolver.describe_response(response)
+                        ));
+                    }
+                    logger.forwarded(&pq.domain, pq.client_addr, elapsed, (from_addr, upstream_elapsed), attempt, None);
+                    if resolver.is_slow(elapsed) {
+                        logger.slow("FORWARDED", &pq.domain, pq.qtype, pq.client_addr, elapsed, Some((from_addr, upstream_elapsed)));
+                    }
+                    if pq.check_divergence {
+                        answered.retain(|_, a| a.answered_at.elapsed() < DIVERGENCE_WINDOW);
+                        answered.insert(query_id, AnsweredQuery {
+                            domain: pq.domain,
+                            winner: from_addr,
+                            response: response.to_vec(),
+                            answered_at: Instant::now(),
+                            outstanding: pq.racing - 1,
+                        });
+                    }
+                } else if let Some(aq) = answered.get_mut(&query_id) {
+                    if let Some(divergence) = resolver.check_divergence(aq.winner, &aq.response, from_addr, response) {
+                        logger.divergence(&aq.domain, aq.winner, from_addr, &divergence);
+                    }
+                    aq.outstanding -= 1;
+                    if aq.outstanding == 0 {
+                        answered.remove(&query_id);
                     }
                 }
             }
@@ -154,14 +713,201 @@ async fn run(
     }
 }
 
+/// Send a query to the upstream at `index`. DNS-over-QUIC, DNS-over-HTTPS,
+/// DNS-over-TLS and upstreams without a socket are queried from a task, over
+/// QUIC, HTTPS, TLS or TCP.
+///
+/// Returns how the upstream was reached, for trace lines.
+async fn send_upstream(
+    index: usize,
+    upstream_addr: SocketAddr,
+    upstream_query: &[u8],
+    upstream_sockets: &[Arc<UdpSocket>],
+    task_answers: &mpsc::UnboundedSender<TaskAnswer>,
+) -> io::Result<&'static str> {
+    #[cfg(feature = "doq")]
+    if super::doq::is_registered(upstream_addr) {
+        let (answers, upstream_query) = (task_answers.clone(), upstream_query.to_vec());
+        tokio::spawn(async move {
+            if let Some(answer) = super::doq::query(upstream_addr, &upstream_query).await {
+                let _ = answers.send((index, upstream_addr, answer));
+            }
+        });
+        return Ok("QUIC upstream");
+    }
+    #[cfg(feature = "doh-upstream")]
+    if super::doh::client::is_registered(upstream_addr) {
+        let (answers, upstream_query) = (task_answers.clone(), upstream_query.to_vec());
+        tokio::spawn(async move {
+            if let Some(answer) = super::doh::client::query(upstream_addr, &upstream_query).await {
+                let _ = answers.send((index, upstream_addr, answer));
+            }
+        });
+        return Ok("HTTPS upstream");
+    }
+    let tls = super::dot::is_upstream(upstream_addr);
+    let Some(socket) = upstream_sockets.get(index).filter(|_| !tls) else {
+        let (answers, upstream_query) = (task_answers.clone(), upstream_query.to_vec());
+        tokio::spawn(async move {
+            if let Some(answer) =
+                super::tcp::forward_to_upstream(&upstream_query, upstream_addr).await
+            {
+                let _ = answers.send((index, upstream_addr, answer));
+            }
+        });
+        return Ok(if tls {
+            "TLS upstream"
+        } else {
+            "upstream over TCP"
+        });
+    };
+    socket.send_to(upstream_query, upstream_addr).await?;
+    Ok("upstream")
+}
+
+/// Race: send the query to every allowed upstream in `range`.
+async fn send_to_all(
+    pq: &mut PendingQuery,
+    range: Range<usize>,
+    upstreams: &[SocketAddr],
+    upstream_sockets: &[Arc<UdpSocket>],
+    task_answers: &mpsc::UnboundedSender<TaskAnswer>,
+    resolver: &Resolver,
+    logger: &QueryLogger,
+) {
+    pq.attempt_start = Instant::now();
+    for i in range {
+        let upstream_addr = upstreams[i];
+        if !resolver.upstream_allowed(upstream_addr) {
+            continue;
+        }
+        pq.racing += 1;
+        pq.tried.push(i);
+        match send_upstream(
+            i,
+            upstream_addr,
+            &pq.upstream_query,
+            upstream_sockets,
+            task_answers,
+        )
+        .await
+        {
+            Ok(kind) => {
+                if pq.traced {
+                    logger.trace(
+                        &pq.domain,
+                        format_args!("sent to {} {}", kind, upstream_addr),
+                    );
+                }
+            }
+            Err(e) => logging::error(format_args!(
+                "UDP forward error to {}: {}",
+                upstream_addr, e
+            )),
+        }
+    }
+}
+
+/// Failover: send the query to the next allowed upstream after the last one
+/// tried, moving past any that cannot be sent to.
+///
+/// Returns false if no upstream is left to try.
+async fn send_next_upstream(
+    pq: &mut PendingQuery,
+    upstreams: &[SocketAddr],
+    upstream_sockets: &[Arc<UdpSocket>],
+    task_answers: &mpsc::UnboundedSender<TaskAnswer>,
+    resolver: &Resolver,
+    logger: &QueryLogger,
+) -> bool {
+    let next = pq.tried.last().map_or(0, |&i| i + 1);
+    pq.attempt_start = Instant::now();
+    for (i, &upstream_addr) in upstreams.iter().enumerate().skip(next) {
+        if !resolver.upstream_allowed(upstream_addr) {
+            continue;
+        }
+        if !pq.tried.is_empty() {
+            resolver.record_fallback();
+        }
+        pq.tried.push(i);
+        match send_upstream(
+            i,
+            upstream_addr,
+            &pq.upstream_query,
+            upstream_sockets,
+            task_answers,
+        )
+        .await
+        {
+            Ok(kind) => {
+                if pq.traced {
+                    logger.trace(
+                        &pq.domain,
+                        format_args!(
+                            "sent to {} {} (attempt {})",
+                            kind,
+                            upstream_addr,
+                            pq.tried.len()
+                        ),
+                    );
+                }
+                return true;
+            }
+            Err(e) => logging::error(format_args!(
+                "UDP forward error to {}: {}",
+                upstream_addr, e
+            )),
+        }
+    }
+    false
+}
+
+/// Attempts at a random ID before settling for one that is in flight.
+const ID_ATTEMPTS: usize = 64;
+
+/// Pick a random outgoing query ID that is not already in flight.
+fn allocate_id(
+    pending: &HashMap<u16, PendingQuery>,
+    answered: &HashMap<u16, AnsweredQuery>,
+) -> u16 {
+    let mut id = rand::random();
+    for _ in 0..ID_ATTEMPTS {
+        if !pending.contains_key(&id) && !answered.contains_key(&id) {
+            break;
+        }
+        id = rand::random();
+    }
+    // Nearly every ID is in flight; the new query replaces the old one.
+    id
+}
+
+/// Copy an answer fetched by a task into a receive buffer, returning its length.
+///
+/// An answer too large for the buffer is cut down to its header with the TC
+/// bit set and no records, so the client retries over TCP.
+fn fill_buffer(buf: &mut [u8], answer: &[u8]) -> usize {
+    if answer.len() <= buf.len() {
+        buf[..answer.len()].copy_from_slice(answer);
+        return answer.len();
+    }
+    buf[..4].copy_from_slice(&answer[..4]);
+    buf[2] |= 0x02;
+    buf[4..12].fill(0);
+    12
+}
+
 async fn recv_from_any(
     sockets: &[Arc<UdpSocket>],
-    bufs: &mut [[u8; MAX_DNS_PACKET_SIZE]],
+    bufs: &mut [Vec<u8>],
+    tasks: &mut mpsc::UnboundedReceiver<TaskAnswer>,
 ) -> io::Result<(usize, usize, SocketAddr)> {
     use std::future::poll_fn;
     use std::task::Poll;
 
     poll_fn(|cx| {
+        if let Poll::Ready(Some((i, from, answer))) = tasks.poll_recv(cx) {
+            return Poll::Ready(Ok((i, fill_buffer(&mut bufs[i], &answer), from)));
+        }
         for (i, socket) in sockets.iter().enumerate() {
             let mut buf = tokio::io::ReadBuf::new(&mut bufs[i]);
             match socket.poll_recv_from(cx, &mut buf) {
@@ -176,3 +922,654 @@ async fn recv_from_any(
     })
     .await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::{DnsCache, STALE_ANSWER_TTL};
+    use crate::dns::{DnsResponse, RData, TYPE_A};
+    use crate::filter::{BlockedResponseStyle, Blocklist};
+    use crate::resolver::{ForwardRule, ForwardRules, UpstreamCircuitBreaker};
+    use crate::shutdown::ShutdownSignal;
+    use std::net::Ipv4Addr;
+
+    /// Answer `query` with a single A record 10.0.0.`last` (TTL 300).
+    fn answer(query: &[u8], last: u8) -> Vec<u8> {
+        let mut response = query.to_vec();
+        response[2] = 0x81;
+        response[3] = 0x80;
+        response[7] = 1;
+        response.extend_from_slice(&[0xC0, 0x0C, 0x00, 0x01, 0x00, 0x01]);
+        response.extend_from_slice(&[0, 0, 1, 44, 0x00, 0x04, 10, 0, 0, last]);
+        response
+    }
+
+    /// Spawn a UDP upstream that waits for two queries, then answers both,
+    /// with 10.0.0.1 for `a.test` and 10.0.0.2 for anything else.
+    async fn paired_upstream() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let local = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut queries = Vec::new();
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            while queries.len() < 2 {
+                let (len, from) = socket.recv_from(&mut buf).await.unwrap();
+                queries.push((buf[..len].to_vec(), from));
+            }
+            for (query, from) in queries {
+                let parsed = DnsQuery::parse(&query).unwrap();
+                let last = if parsed.domain == "a.test" { 1 } else { 2 };
+                socket.send_to(&answer(&query, last), from).await.unwrap();
+            }
+        });
+        local
+    }
+
+    async fn ask(server: SocketAddr, domain: &str) -> DnsResponse {
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = DnsQuery::new(0x1234, domain, TYPE_A).to_bytes().unwrap();
+        client.send_to(&query, server).await.unwrap();
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        DnsResponse::parse(&buf[..len]).unwrap()
+    }
+
+    #[tokio::test]
+    async fn clients_reusing_a_query_id_get_their_own_answers() {
+        let upstream = paired_upstream().await;
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+
+        let (a, b) = tokio::join!(ask(server, "a.test"), ask(server, "b.test"));
+
+        assert_eq!(a.id, 0x1234);
+        assert_eq!(b.id, 0x1234);
+        assert_eq!(a.answers[0].data(), RData::A(Ipv4Addr::new(10, 0, 0, 1)));
+        assert_eq!(b.answers[0].data(), RData::A(Ipv4Addr::new(10, 0, 0, 2)));
+    }
+
+    #[tokio::test]
+    async fn shutdown_answers_pending_queries_and_reads_no_more() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let signal = ShutdownSignal::new();
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap()
+            .with_shutdown(signal.handle());
+        let server = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream.local_addr().unwrap()],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+        let pending = tokio::spawn(ask(server, "a.test"));
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+        let query = buf[..len].to_vec();
+
+        let drain = tokio::spawn(signal.drain(Duration::from_secs(5)));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let late = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        late.send_to(
+            &DnsQuery::new(2, "b.test", TYPE_A).to_bytes().unwrap(),
+            server,
+        )
+        .await
+        .unwrap();
+        let forwarded_late =
+            tokio::time::timeout(Duration::from_millis(200), upstream.recv_from(&mut buf)).await;
+        upstream.send_to(&answer(&query, 1), from).await.unwrap();
+
+        assert!(forwarded_late.is_err());
+        assert_eq!(
+            pending.await.unwrap().answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert!(drain.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn refused_block_mode_answers_refused_and_counts_as_blocked() {
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 0)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(
+            Resolver::new(Blocklist::from_adblock_format("||doubleclick.com^"))
+                .with_blocked_response(BlockedResponseStyle::Refused),
+        );
+        transport.start(Vec::new(), resolver.clone(), false);
+
+        let response = ask(server, "doubleclick.com").await;
+
+        assert_eq!(response.rcode(), 5);
+        assert!(response.answers.is_empty());
+        assert_eq!(response.questions[0].domain, "doubleclick.com");
+        assert_eq!(resolver.stats_snapshot_and_reset().blocked, 1);
+    }
+
+    #[tokio::test]
+    async fn clients_over_the_rate_limit_are_dropped() {
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 0)
+            .await
+            .unwrap()
+            .with_client_rate_limit(2);
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::empty()));
+        transport.start(Vec::new(), resolver.clone(), false);
+        let flooder = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+        let query = DnsQuery::new(7, "printer", TYPE_A).to_bytes().unwrap();
+
+        for _ in 0..4 {
+            flooder.send_to(&query, server).await.unwrap();
+        }
+        let mut answered = 0;
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        while tokio::time::timeout(Duration::from_millis(200), flooder.recv(&mut buf))
+            .await
+            .is_ok()
+        {
+            answered += 1;
+        }
+        let other = ask(server, "printer").await;
+
+        assert_eq!(answered, 2);
+        assert_eq!(other.rcode(), 3);
+        assert_eq!(
+            resolver
+                .stats()
+                .rate_limited
+                .load(std::sync::atomic::Ordering::Relaxed),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn client_rate_limits_are_shared_by_reuseport_sockets() {
+        let transport = UdpTransport::bind_reuseport("127.0.0.1:0".parse().unwrap(), 2, 0)
+            .await
+            .unwrap()
+            .with_client_rate_limit(2);
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::empty()));
+        transport.start(Vec::new(), resolver.clone(), false);
+        let query = DnsQuery::new(7, "printer", TYPE_A).to_bytes().unwrap();
+
+        // Each port is hashed to a socket, so eight of them reach both.
+        let mut flooders = Vec::new();
+        for _ in 0..8 {
+            let flooder = UdpSocket::bind("127.0.0.2:0").await.unwrap();
+            flooder.send_to(&query, server).await.unwrap();
+            flooders.push(flooder);
+        }
+        let mut answered = 0;
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        for flooder in &flooders {
+            if tokio::time::timeout(Duration::from_millis(200), flooder.recv(&mut buf))
+                .await
+                .is_ok()
+            {
+                answered += 1;
+            }
+        }
+
+        assert_eq!(answered, 2);
+        assert_eq!(
+            resolver
+                .stats()
+                .rate_limited
+                .load(std::sync::atomic::Ordering::Relaxed),
+            6
+        );
+    }
+
+    #[tokio::test]
+    async fn unanswered_queries_get_a_stale_answer_when_serve_stale_allows() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap()
+            .with_query_timeout(Duration::from_millis(100));
+        let server = transport.local_addr().unwrap();
+        let cache = DnsCache::new().with_serve_stale(Duration::from_secs(3600));
+        let query = DnsQuery::new(1, "flaky.test", TYPE_A);
+        cache.put(&query, &answer(&query.to_bytes().unwrap(), 7));
+        cache.expire(&query, Duration::from_secs(600));
+        let resolver = Arc::new(Resolver::new(Blocklist::new()).with_cache(cache));
+        transport.start(vec![silent.local_addr().unwrap()], resolver.clone(), false);
+
+        let response = ask(server, "flaky.test").await;
+
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode(), 0);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 7))
+        );
+        assert_eq!(response.answers[0].ttl, STALE_ANSWER_TTL);
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.stale, 1);
+    }
+
+    #[tokio::test]
+    async fn unanswered_queries_time_out_with_servfail() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap()
+            .with_query_timeout(Duration::from_millis(100));
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        transport.start(vec![silent.local_addr().unwrap()], resolver.clone(), false);
+
+        let response = ask(server, "lost.test").await;
+
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(response.rcode(), 2);
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!(stats.timeouts, 1);
+        assert_eq!(stats.pending, 0);
+    }
+
+    #[tokio::test]
+    async fn answers_from_unexpected_addresses_are_dropped() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let attacker = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            attacker
+                .send_to(&answer(&buf[..len], 66), from)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            upstream
+                .send_to(&answer(&buf[..len], 1), from)
+                .await
+                .unwrap();
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        transport.start(vec![upstream_addr], resolver.clone(), false);
+
+        let response = ask(server, "spoof.test").await;
+
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(resolver.stats_snapshot_and_reset().spoofed, 1);
+    }
+
+    #[tokio::test]
+    async fn answers_to_another_question_are_dropped() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let id = u16::from_be_bytes([buf[0], buf[1]]);
+            let poisoned = DnsQuery::new(id, "evil.test", TYPE_A).to_bytes().unwrap();
+            upstream
+                .send_to(&answer(&poisoned, 66), from)
+                .await
+                .unwrap();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            upstream
+                .send_to(&answer(&buf[..len], 1), from)
+                .await
+                .unwrap();
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        transport.start(vec![upstream_addr], resolver.clone(), false);
+
+        let response = ask(server, "bank.test").await;
+
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(resolver.cache_len(), 1);
+        assert_eq!(resolver.stats_snapshot_and_reset().mismatched, 1);
+    }
+
+    #[tokio::test]
+    async fn answers_to_another_question_do_not_close_circuits() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            while let Ok((_, from)) = upstream.recv_from(&mut buf).await {
+                let id = u16::from_be_bytes([buf[0], buf[1]]);
+                let poisoned = DnsQuery::new(id, "evil.test", TYPE_A).to_bytes().unwrap();
+                upstream
+                    .send_to(&answer(&poisoned, 66), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        let spare = "127.0.0.1:9".parse().unwrap();
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap()
+            .with_query_timeout(Duration::from_millis(100));
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()).with_circuit_breaker(
+            UpstreamCircuitBreaker::new(&[upstream_addr, spare], 2, Duration::from_secs(60)),
+        ));
+        transport.start(vec![upstream_addr], resolver.clone(), false);
+
+        let first = ask(server, "bank.test").await;
+        let second = ask(server, "bank.test").await;
+
+        assert_eq!((first.rcode(), second.rcode()), (2, 2));
+        assert!(!resolver.upstream_allowed(upstream_addr));
+        assert!(resolver.upstream_allowed(spare));
+    }
+
+    #[tokio::test]
+    async fn upstream_ids_are_translated_and_answers_cached() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        // Answers only once; the second lookup has to come from the cache.
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            upstream
+                .send_to(&answer(&buf[..len], 1), from)
+                .await
+                .unwrap();
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        transport.start(
+            vec![upstream_addr],
+            Arc::new(Resolver::new(Blocklist::new())),
+            false,
+        );
+
+        let forwarded = ask(server, "ids.test").await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = DnsQuery::new(0x4321, "ids.test", TYPE_A);
+        client
+            .send_to(&query.to_bytes().unwrap(), server)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let cached = DnsResponse::parse(&buf[..len]).unwrap();
+
+        assert_eq!(forwarded.id, 0x1234);
+        assert_eq!(
+            forwarded.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(cached.id, 0x4321);
+        assert_eq!(
+            cached.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuseport_sockets_each_answer_their_clients() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            while let Ok((len, from)) = upstream.recv_from(&mut buf).await {
+                upstream
+                    .send_to(&answer(&buf[..len], 1), from)
+                    .await
+                    .unwrap();
+            }
+        });
+        let transport = UdpTransport::bind_reuseport("127.0.0.1:0".parse().unwrap(), 4, 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::empty()));
+        transport.start(vec![upstream_addr], resolver.clone(), false);
+
+        let domains: Vec<String> = (0..16).map(|i| format!("client{}.test", i)).collect();
+        let responses =
+            futures::future::join_all(domains.iter().map(|domain| ask(server, domain))).await;
+
+        assert!(
+            responses
+                .iter()
+                .all(|response| response.answers[0].data() == RData::A(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!(stats.forwarded, 16);
+        assert_eq!(stats.pending, 0);
+        assert!(UdpTransport::bind_reuseport(server, 0, 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn identical_queries_in_flight_share_one_upstream_request() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        // Holds the answer back until the second client has asked, counting
+        // any further requests that reach it meanwhile.
+        let upstream_task = tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let query = buf[..len].to_vec();
+            let mut extra = 0;
+            while tokio::time::timeout(Duration::from_millis(300), upstream.recv_from(&mut buf))
+                .await
+                .is_ok()
+            {
+                extra += 1;
+            }
+            upstream.send_to(&answer(&query, 1), from).await.unwrap();
+            extra
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(Resolver::new(Blocklist::new()));
+        transport.start(vec![upstream_addr], resolver.clone(), false);
+
+        let first = tokio::spawn(ask(server, "coalesce.test"));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let query = DnsQuery::new(0x4321, "coalesce.test", TYPE_A);
+        client
+            .send_to(&query.to_bytes().unwrap(), server)
+            .await
+            .unwrap();
+        let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+        let len = tokio::time::timeout(Duration::from_secs(5), client.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        let second = DnsResponse::parse(&buf[..len]).unwrap();
+        let first = first.await.unwrap();
+
+        assert_eq!(upstream_task.await.unwrap(), 0);
+        assert_eq!(first.id, 0x1234);
+        assert_eq!(second.id, 0x4321);
+        assert_eq!(
+            second.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        assert_eq!(resolver.stats_snapshot_and_reset().coalesced, 1);
+    }
+
+    #[tokio::test]
+    async fn failover_tries_the_next_upstream_after_a_timeout() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; MAX_DNS_PACKET_SIZE];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            upstream
+                .send_to(&answer(&buf[..len], 2), from)
+                .await
+                .unwrap();
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 2)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(
+            Resolver::new(Blocklist::new())
+                .with_upstream_strategy(UpstreamStrategy::Failover, Duration::from_millis(50)),
+        );
+        transport.start(
+            vec![silent.local_addr().unwrap(), upstream_addr],
+            resolver.clone(),
+            false,
+        );
+
+        let response = ask(server, "failover.test").await;
+
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 2))
+        );
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!(stats.fallbacks, 1);
+        assert_eq!(stats.timeouts, 0);
+    }
+
+    #[tokio::test]
+    async fn limited_race_asks_the_fallback_tier_over_tcp() {
+        let silent = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let fallback_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let query = crate::transport::tcp::read_dns_message(&mut stream)
+                .await
+                .unwrap();
+            crate::transport::tcp::send_tcp_response(&mut stream, &answer(&query, 3)).await;
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let resolver = Arc::new(
+            Resolver::new(Blocklist::new())
+                .with_upstream_strategy(UpstreamStrategy::Race, Duration::from_millis(50))
+                .with_race_limit(Some(1)),
+        );
+        transport.start(
+            vec![silent.local_addr().unwrap(), fallback_addr],
+            resolver.clone(),
+            false,
+        );
+
+        let response = ask(server, "tier.test").await;
+
+        assert_eq!(response.id, 0x1234);
+        assert_eq!(
+            response.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 3))
+        );
+        assert_eq!(resolver.stats_snapshot_and_reset().fallbacks, 1);
+    }
+
+    #[tokio::test]
+    async fn forwarding_rules_send_matches_to_their_own_upstreams() {
+        let public = paired_upstream().await;
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let internal = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let query = crate::transport::tcp::read_dns_message(&mut stream)
+                .await
+                .unwrap();
+            crate::transport::tcp::send_tcp_response(&mut stream, &answer(&query, 53)).await;
+        });
+        let transport = UdpTransport::bind("127.0.0.1:0".parse().unwrap(), 1)
+            .await
+            .unwrap();
+        let server = transport.local_addr().unwrap();
+        let rules =
+            ForwardRules::new([ForwardRule::parse(&format!("corp.test={}", internal)).unwrap()]);
+        let resolver = Arc::new(Resolver::new(Blocklist::new()).with_forward_rules(rules));
+        transport.start(vec![public], resolver.clone(), false);
+
+        let matched = ask(server, "db.corp.test").await;
+        let again = ask(server, "db.corp.test").await;
+
+        assert_eq!(
+            matched.answers[0].data(),
+            RData::A(Ipv4Addr::new(10, 0, 0, 53))
+        );
+        assert_eq!(again.rcode(), 2);
+        assert_eq!(resolver.cache_len(), 0);
+        let stats = resolver.stats_snapshot_and_reset();
+        assert_eq!((stats.forwarded, stats.cached, stats.failed), (1, 0, 1));
+    }
+
+    #[test]
+    fn allocated_ids_are_random_and_avoid_queries_in_flight() {
+        let resolver = Resolver::new(Blocklist::new());
+        let mut pending = HashMap::new();
+        for id in (0..u16::MAX).step_by(2) {
+            let domain = format!("{}.example", id);
+            let query = DnsQuery::new(id, &domain, TYPE_A).to_bytes().unwrap();
+            let QueryAction::Forward { in_flight, .. } = resolver.process_query(&query) else {
+                panic!("expected a forward");
+            };
+            pending.insert(
+                id,
+                PendingQuery {
+                    client_addr: "127.0.0.1:1".parse().unwrap(),
+                    client_id: id,
+                    domain,
+                    qtype: TYPE_A,
+                    traced: false,
+                    in_flight,
+                    racing: 1,
+                    check_divergence: false,
+                    start_time: Instant::now(),
+                    upstream_start: Instant::now(),
+                    upstream_query: Vec::new(),
+                    tried: Vec::new(),
+                    attempt_start: Instant::now(),
+                    held_back: false,
+                },
+            );
+        }
+
+        let ids: Vec<u16> = (0..1000)
+            .map(|_| allocate_id(&pending, &HashMap::new()))
+            .collect();
+
+        assert!(ids.iter().all(|id| !pending.contains_key(id)));
+        assert!(ids.iter().collect::<std::collections::HashSet<_>>().len() > 900);
+    }
+}